@@ -0,0 +1,83 @@
+// Compares the threaded, event loop and io_uring backends over loopback
+// Each backend serves a static file to several concurrent clients opening
+// one connection per request (keep-alive is not supported yet)
+// Run with: cargo bench --bench backends --features event-loop,io-uring
+
+use rust_http_web_server::{Router, Server, ServerConfig};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENTS: usize = 8;
+const REQUESTS_PER_CLIENT: usize = 500;
+const FILE_SIZE: usize = 256 * 1024;
+const REQUEST: &[u8] = b"GET /file.bin HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+// Starts the server and returns the time taken to serve every client
+fn run(base_dir: &str, config: ServerConfig) -> Duration {
+    let server = Server::new(Router::new(), base_dir).config(config);
+    let listener = server.bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    server.start(listener).unwrap();
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut response = Vec::with_capacity(FILE_SIZE + 256);
+                for _ in 0..REQUESTS_PER_CLIENT {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream.write_all(REQUEST).unwrap();
+                    response.clear();
+                    stream.read_to_end(&mut response).unwrap();
+                    assert!(response.len() > FILE_SIZE);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    start.elapsed()
+}
+
+// Prints the time per request and the throughput of a run
+fn report(name: &str, elapsed: Duration) {
+    let requests = (CLIENTS * REQUESTS_PER_CLIENT) as f64;
+    println!(
+        "{:<12} {:>8.0} ns/request {:>8.0} MiB/s",
+        name,
+        elapsed.as_nanos() as f64 / requests,
+        requests * FILE_SIZE as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+}
+
+fn main() {
+    let base_dir = std::env::temp_dir().join("rust-http-web-server-backends");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::write(base_dir.join("file.bin"), vec![b'x'; FILE_SIZE]).unwrap();
+    let base_dir = base_dir.to_str().unwrap();
+
+    report("threaded:", run(base_dir, ServerConfig::default()));
+    report(
+        "event loop:",
+        run(
+            base_dir,
+            ServerConfig {
+                event_loop_threads: Some(1),
+                ..ServerConfig::default()
+            },
+        ),
+    );
+    report(
+        "io_uring:",
+        run(
+            base_dir,
+            ServerConfig {
+                io_uring_entries: Some(256),
+                ..ServerConfig::default()
+            },
+        ),
+    );
+}
@@ -0,0 +1,69 @@
+// Compares serving a small static file through the cached fast path
+// against the regular parse/route/static pipeline
+// Run with: cargo bench --bench fast_path
+
+use rust_http_web_server::{FileCache, Router, serve_connection};
+use std::io::{Cursor, Read, Write};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 50_000;
+const REQUEST: &[u8] = b"GET /style.css HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+// In-memory stream replaying one request and discarding the response
+struct BenchStream {
+    read_data: Cursor<&'static [u8]>,
+    written: usize,
+}
+
+impl Read for BenchStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_data.read(buf)
+    }
+}
+
+impl Write for BenchStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Serves the request ITERATIONS times and returns the total time taken
+fn run(base_dir: &str, router: &Router) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut stream = BenchStream {
+            read_data: Cursor::new(REQUEST),
+            written: 0,
+        };
+        serve_connection(&mut stream, base_dir, router);
+        assert!(stream.written > 0);
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let base_dir = std::env::temp_dir().join("rust-http-web-server-bench");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::write(base_dir.join("style.css"), "body { color: blue; }").unwrap();
+    let base_dir = base_dir.to_str().unwrap();
+
+    let normal = Router::new();
+    let mut cached = Router::new();
+    cached.cache_static_files(FileCache::new(64 * 1024, 256));
+
+    let normal_time = run(base_dir, &normal);
+    let cached_time = run(base_dir, &cached);
+
+    println!(
+        "normal path: {:>8.0} ns/request",
+        normal_time.as_nanos() as f64 / ITERATIONS as f64
+    );
+    println!(
+        "fast path:   {:>8.0} ns/request",
+        cached_time.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
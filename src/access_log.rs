@@ -0,0 +1,542 @@
+// Access log
+// serve_stream hands every request it parsed to the log and counts the
+// status and the bytes of the response as they are sent; once the request
+// is done, a line in the Combined Log Format of Apache and nginx is
+// appended to the file, the size counting the response headers:
+// 127.0.0.1 - - [16/Oct/2026:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 "-" "curl/8.5.0"
+//
+// The file is rotated by the server once it would grow past a size or has
+// been open for a while: it is renamed after the time of the rotation, e.g.
+// access.log.20261016-135536, optionally compressed with gzip on a
+// background thread, and the oldest rotated files beyond the number kept
+// are removed. Tools such as logrotate rename the file themselves and then
+// ask the server to reopen the path with SIGUSR1.
+//
+// Log pipelines such as ELK or Loki rather take one JSON object per line
+// (see LogFormat::Json), with the timing of the request; a request's
+// X-Request-Id header, set by a load balancer or the client, is logged
+// along to follow it across services.
+//
+// Instead of a file, lines can go to syslog or to the systemd journal (see
+// the syslog and journald modules), which rotate logs themselves.
+
+#[cfg(unix)]
+mod journald;
+mod syslog;
+
+#[cfg(unix)]
+pub use journald::Journald;
+pub use syslog::Syslog;
+
+use crate::Request;
+use crate::escape::json_string;
+use crate::http_date::civil_from_days;
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+#[cfg(any(unix, feature = "compression"))]
+use std::thread;
+#[cfg(unix)]
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Where requests are logged, one line per request in the Combined Log
+/// Format or as JSON (see ServerConfig::access_log): a file, syslog or the
+/// journal
+/// A file grows forever unless it is rotated, by the server with max_size
+/// or rotate_every, or by an external tool with reopen. Clones write to the
+/// same destination
+/// Example:
+/// let log = AccessLog::new("logs/access.log")?
+///     .max_size(100 << 20)
+///     .rotate_every(Duration::from_secs(24 * 3600))
+///     .keep(7);
+/// let config = ServerConfig { access_log: Some(log), ..Default::default() };
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    sink: Sink,
+    format: LogFormat,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    keep: Option<usize>,
+    #[cfg(feature = "compression")]
+    compress: bool,
+}
+
+/// How AccessLog formats the line of a request
+/// Example: AccessLog::new("logs/access.json")?.format(LogFormat::Json)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    // The Combined Log Format of Apache and nginx
+    #[default]
+    Combined,
+    // A JSON object with the fields timestamp (RFC 3339, UTC), method,
+    // path, status, duration_ms, bytes, client_ip and request_id, the
+    // latter two null when unknown
+    Json,
+}
+
+// Destination of the lines
+#[derive(Clone, Debug)]
+enum Sink {
+    // Appended to the file at the path
+    File(PathBuf, Arc<Mutex<LogFile>>),
+    Syslog(Arc<Syslog>),
+    #[cfg(unix)]
+    Journald(Arc<Journald>),
+}
+
+// The file being appended to
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+// What is known of a request before its response
+struct Entry {
+    client: Option<IpAddr>,
+    time: SystemTime,
+    started: Instant,
+    method: String,
+    path: String,
+    query: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+// A request being served, logged when dropped if a response was sent
+pub(crate) struct Pending<'a> {
+    log: &'a AccessLog,
+    entry: RefCell<Option<Entry>>,
+    status: Cell<u16>,
+    sent: Cell<u64>,
+}
+
+impl AccessLog {
+    /// Opens the file for appending, creating it if needed
+    pub fn new(path: impl AsRef<Path>) -> io::Result<AccessLog> {
+        let path = path.as_ref().to_path_buf();
+        let file = LogFile::open(&path)?;
+        Ok(AccessLog::with(Sink::File(
+            path,
+            Arc::new(Mutex::new(file)),
+        )))
+    }
+
+    /// Sends the lines to a syslog daemon or server
+    /// Example: AccessLog::syslog(Syslog::udp("10.0.0.5:514")?.app_name("shop"))
+    pub fn syslog(syslog: Syslog) -> AccessLog {
+        AccessLog::with(Sink::Syslog(Arc::new(syslog)))
+    }
+
+    /// Sends the lines to the systemd journal
+    /// Example: AccessLog::journald(Journald::new()?.identifier("shop"))
+    #[cfg(unix)]
+    pub fn journald(journald: Journald) -> AccessLog {
+        AccessLog::with(Sink::Journald(Arc::new(journald)))
+    }
+
+    fn with(sink: Sink) -> AccessLog {
+        AccessLog {
+            sink,
+            format: LogFormat::Combined,
+            max_size: None,
+            rotate_every: None,
+            keep: None,
+            #[cfg(feature = "compression")]
+            compress: false,
+        }
+    }
+
+    /// Sets the format of the lines, the Combined Log Format by default
+    pub fn format(mut self, format: LogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    /// Rotates the file before a line would make it larger than bytes
+    /// Rotation settings only apply to files
+    /// Panics if bytes is 0
+    pub fn max_size(mut self, bytes: u64) -> AccessLog {
+        assert!(bytes > 0, "access log size limit must be at least 1 byte");
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates the file once it has been written to for this long
+    /// Panics if interval is zero
+    pub fn rotate_every(mut self, interval: Duration) -> AccessLog {
+        assert!(
+            !interval.is_zero(),
+            "access log rotation interval cannot be zero"
+        );
+        self.rotate_every = Some(interval);
+        self
+    }
+
+    /// Keeps only the newest rotated files, removing older ones after each
+    /// rotation; all of them are kept by default
+    pub fn keep(mut self, files: usize) -> AccessLog {
+        self.keep = Some(files);
+        self
+    }
+
+    /// Compresses rotated files with gzip, adding .gz to their name
+    #[cfg(feature = "compression")]
+    pub fn compress(mut self, enabled: bool) -> AccessLog {
+        self.compress = enabled;
+        self
+    }
+
+    /// Rotates the file now, whatever its size and age; does nothing for
+    /// other destinations
+    pub fn rotate(&self) -> io::Result<()> {
+        match &self.sink {
+            Sink::File(path, file) => self.rotate_file(path, &mut file.lock().unwrap()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens the path again, e.g. after another program renamed the file;
+    /// does nothing for other destinations
+    /// Lines go on to the previous file if it fails
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Sink::File(path, file) = &self.sink {
+            let reopened = LogFile::open(path)?;
+            *file.lock().unwrap() = reopened;
+        }
+        Ok(())
+    }
+
+    /// Reopens the file whenever the process receives SIGUSR1, as logrotate
+    /// expects with a postrotate script such as "kill -USR1 $(cat app.pid)"
+    /// The signal can only serve one log per process
+    /// Example: AccessLog::new("/var/log/app/access.log")?.reopen_on_sigusr1()?;
+    #[cfg(unix)]
+    pub fn reopen_on_sigusr1(&self) -> io::Result<JoinHandle<()>> {
+        let mut signals = crate::signal::SignalPipe::user1()?;
+        let log = self.clone();
+        thread::Builder::new()
+            .name("log-reopener".to_string())
+            .spawn(move || {
+                while signals.wait().is_ok() {
+                    if let Err(e) = log.reopen() {
+                        eprintln!("Failed to reopen access log {}: {}", log.sink, e);
+                    }
+                }
+            })
+    }
+
+    // Sends a line to the destination
+    fn append(&self, line: &str) {
+        let sent = match &self.sink {
+            Sink::File(path, file) => return self.append_to_file(path, file, line),
+            Sink::Syslog(syslog) => syslog.send(line),
+            #[cfg(unix)]
+            Sink::Journald(journald) => journald.send(line),
+        };
+        if let Err(e) = sent {
+            eprintln!("Failed to send access log line to {}: {}", self.sink, e);
+        }
+    }
+
+    // Appends a line, rotating the file first if it is due
+    fn append_to_file(&self, path: &Path, file: &Mutex<LogFile>, line: &str) {
+        let mut file = file.lock().unwrap();
+        let too_large = self
+            .max_size
+            .is_some_and(|max| file.size > 0 && file.size + line.len() as u64 > max);
+        let too_old = self
+            .rotate_every
+            .is_some_and(|interval| file.opened.elapsed() >= interval);
+        if (too_large || too_old)
+            && let Err(e) = self.rotate_file(path, &mut file)
+        {
+            eprintln!("Failed to rotate access log {}: {}", path.display(), e);
+            // Try again after another period rather than on every line
+            file.size = 0;
+            file.opened = Instant::now();
+        }
+        match file.file.write_all(line.as_bytes()) {
+            Ok(()) => file.size += line.len() as u64,
+            Err(e) => eprintln!("Failed to write access log {}: {}", path.display(), e),
+        }
+    }
+
+    // Renames the file and opens a new one in its place
+    fn rotate_file(&self, path: &Path, current: &mut LogFile) -> io::Result<()> {
+        let rotated = rotated_path(path);
+        fs::rename(path, &rotated)?;
+        *current = LogFile::open(path)?;
+
+        #[cfg(feature = "compression")]
+        if self.compress {
+            let (log, path) = (self.clone(), path.to_path_buf());
+            let spawned = thread::Builder::new()
+                .name("log-compressor".to_string())
+                .spawn(move || {
+                    if let Err(e) = gzip(&rotated) {
+                        eprintln!("Failed to compress {}: {}", rotated.display(), e);
+                    }
+                    log.prune(&path);
+                });
+            if let Err(e) = spawned {
+                eprintln!("Failed to spawn log compression thread: {}", e);
+            }
+            return Ok(());
+        }
+        self.prune(path);
+        Ok(())
+    }
+
+    // Removes the oldest files rotated from the path beyond the number kept
+    fn prune(&self, path: &Path) {
+        let Some(keep) = self.keep else {
+            return;
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+            return;
+        };
+        let prefix = format!("{}.", prefix);
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        // Rotated names sort by the time of their rotation
+        let mut rotated: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| {
+                name.strip_prefix(&prefix)
+                    .is_some_and(|stamp| stamp.starts_with(|c: char| c.is_ascii_digit()))
+            })
+            .collect();
+        rotated.sort();
+        for name in &rotated[..rotated.len().saturating_sub(keep)] {
+            if let Err(e) = fs::remove_file(dir.join(name)) {
+                eprintln!("Failed to remove old access log {}: {}", name, e);
+            }
+        }
+    }
+}
+
+// Names the destination in error messages
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::File(path, _) => write!(f, "{}", path.display()),
+            Sink::Syslog(syslog) => write!(f, "{}", syslog),
+            #[cfg(unix)]
+            Sink::Journald(_) => f.write_str("the journal"),
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+}
+
+impl Entry {
+    // Formats the line logged for the request and its response
+    fn line(&self, format: LogFormat, status: u16, sent: u64) -> String {
+        match format {
+            LogFormat::Combined => self.combined(status, sent),
+            LogFormat::Json => self.json(status, sent),
+        }
+    }
+
+    fn combined(&self, status: u16, sent: u64) -> String {
+        let target = match self.query.is_empty() {
+            true => escape(&self.path),
+            false => escape(&format!("{}?{}", self.path, self.query)),
+        };
+        let (year, month, day, seconds) = civil_time(self.time);
+        format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {} \"{}\" \"{}\"\n",
+            self.client
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60,
+            escape(&self.method),
+            target,
+            escape(&self.version),
+            status,
+            sent,
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+        )
+    }
+
+    fn json(&self, status: u16, sent: u64) -> String {
+        let optional = |value: Option<&str>| value.map_or_else(|| "null".to_string(), json_string);
+        format!(
+            "{{\"timestamp\":\"{}\",\"method\":{},\"path\":{},\"status\":{},\
+             \"duration_ms\":{:.3},\"bytes\":{},\"client_ip\":{},\"request_id\":{}}}\n",
+            rfc3339(self.time),
+            json_string(&self.method),
+            json_string(&self.path),
+            status,
+            self.started.elapsed().as_secs_f64() * 1000.0,
+            sent,
+            optional(self.client.map(|ip| ip.to_string()).as_deref()),
+            optional(self.request_id.as_deref()),
+        )
+    }
+}
+
+impl<'a> Pending<'a> {
+    pub(crate) fn new(log: &'a AccessLog) -> Pending<'a> {
+        Pending {
+            log,
+            entry: RefCell::new(None),
+            status: Cell::new(0),
+            sent: Cell::new(0),
+        }
+    }
+
+    // Records the request once it is parsed
+    pub(crate) fn begin(&self, request: &Request) {
+        *self.entry.borrow_mut() = Some(Entry {
+            client: request.client_ip(),
+            time: SystemTime::now(),
+            started: Instant::now(),
+            method: request.method.clone(),
+            path: request.path.clone(),
+            query: request.query.clone(),
+            version: request.version.clone(),
+            referer: request.header("Referer").map(str::to_string),
+            user_agent: request.header("User-Agent").map(str::to_string),
+            request_id: request.header("X-Request-Id").map(str::to_string),
+        });
+    }
+
+    // Records the status of the final response
+    pub(crate) fn respond(&self, status: u16) {
+        self.status.set(status);
+    }
+
+    // Counts bytes of the response
+    pub(crate) fn count(&self, len: u64) {
+        self.sent.set(self.sent.get() + len);
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take()
+            && self.status.get() != 0
+        {
+            self.log
+                .append(&entry.line(self.log.format, self.status.get(), self.sent.get()));
+        }
+    }
+}
+
+// Returns a free name for the file rotated from the path now
+fn rotated_path(path: &Path) -> PathBuf {
+    let (year, month, day, seconds) = civil_time(SystemTime::now());
+    let mut name = path.to_path_buf().into_os_string();
+    name.push(format!(
+        ".{}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    ));
+    let mut rotated = PathBuf::from(&name);
+    let mut suffix = 1;
+    while rotated.exists() || rotated.with_added_extension("gz").exists() {
+        let mut numbered = name.clone();
+        numbered.push(format!("-{:03}", suffix));
+        rotated = PathBuf::from(numbered);
+        suffix += 1;
+    }
+    rotated
+}
+
+// Returns the UTC date of a time and the seconds since midnight
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u64) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    (year, month, day, seconds % 86_400)
+}
+
+// Formats a time as an RFC 3339 timestamp in UTC with milliseconds, e.g.
+// 2026-10-16T13:55:36.123Z
+fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, seconds) = civil_time(time);
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_millis())
+        .unwrap_or(0);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        millis
+    )
+}
+
+// Escapes quotes, backslashes and control characters, so a field cannot
+// end its quotes or forge another line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Compresses a rotated file to the same name with .gz, then removes it
+#[cfg(feature = "compression")]
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let output = File::create(path.with_added_extension("gz"))?;
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
@@ -0,0 +1,74 @@
+// journald output of the access log
+// Every line is sent to the journal's native socket as a datagram of
+// fields (see systemd.journal-fields(7)): the line as MESSAGE, the
+// informational PRIORITY and the SYSLOG_IDENTIFIER journalctl -t filters
+// on. A value holding a newline is sent in the binary form, its length
+// before it, as the native protocol requires.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+// Where journald listens for native messages
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The systemd journal access log lines are sent to (see
+/// AccessLog::journald)
+/// Lines are logged with the informational priority and, unless set
+/// otherwise, the program's name as identifier
+/// Example:
+/// let journald = Journald::new()?.identifier("shop");
+/// let config = ServerConfig { access_log: Some(AccessLog::journald(journald)), ..Default::default() };
+/// Then: journalctl -t shop
+#[derive(Debug)]
+pub struct Journald {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl Journald {
+    /// Connects to the journal of the system
+    pub fn new() -> io::Result<Journald> {
+        Journald::at(JOURNAL_SOCKET)
+    }
+
+    // Connects to the journal listening on the socket
+    pub(crate) fn at(path: impl AsRef<Path>) -> io::Result<Journald> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        let identifier = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "access".to_string());
+        Ok(Journald { socket, identifier })
+    }
+
+    /// Sets SYSLOG_IDENTIFIER, the name journalctl -t selects lines by
+    pub fn identifier(mut self, identifier: &str) -> Journald {
+        self.identifier = identifier.to_string();
+        self
+    }
+
+    // Sends a line as one entry
+    pub(super) fn send(&self, line: &str) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(line.len() + 64);
+        field(&mut datagram, "MESSAGE", line.trim_end_matches('\n'));
+        field(&mut datagram, "PRIORITY", "6");
+        field(&mut datagram, "SYSLOG_IDENTIFIER", &self.identifier);
+        self.socket.send(&datagram)?;
+        Ok(())
+    }
+}
+
+// Appends a field in the native protocol's text or binary form
+fn field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
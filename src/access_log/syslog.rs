@@ -0,0 +1,160 @@
+// Syslog output of the access log
+// Every line is sent as an RFC 5424 message with the informational
+// severity, one per datagram, over UDP (RFC 5426) or the local daemon's
+// unix socket. Datagrams are not acknowledged: lines sent while the
+// collector is down are lost, and lines too long for a datagram are
+// dropped with an error.
+
+use super::rfc3339;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::process;
+use std::time::SystemTime;
+
+// Severity of access log messages
+const INFORMATIONAL: u8 = 6;
+
+/// A syslog daemon or server access log lines are sent to (see
+/// AccessLog::syslog)
+/// Messages follow RFC 5424 with the local7 facility, the program's name as
+/// APP-NAME and "access" as MSGID, unless set otherwise
+/// Example:
+/// let syslog = Syslog::udp("logs.internal:514")?.facility(16).app_name("shop");
+/// let config = ServerConfig { access_log: Some(AccessLog::syslog(syslog)), ..Default::default() };
+#[derive(Debug)]
+pub struct Syslog {
+    transport: Transport,
+    // Where messages go, for error messages
+    destination: String,
+    facility: u8,
+    app_name: String,
+    hostname: String,
+}
+
+#[derive(Debug)]
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Syslog {
+    /// Sends to a syslog server over UDP, e.g. "10.0.0.5:514"
+    pub fn udp(addr: impl ToSocketAddrs) -> io::Result<Syslog> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, "no address for the syslog server")
+        })?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Syslog::over(Transport::Udp(socket), addr.to_string()))
+    }
+
+    /// Sends to the local syslog daemon through its socket, usually /dev/log
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+        let destination = path.as_ref().display().to_string();
+        Ok(Syslog::over(Transport::Unix(socket), destination))
+    }
+
+    fn over(transport: Transport, destination: String) -> Syslog {
+        let app_name = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        Syslog {
+            transport,
+            destination,
+            facility: 23,
+            app_name: header_field(&app_name, 48),
+            hostname: header_field(&hostname(), 255),
+        }
+    }
+
+    /// Sets the facility, from 0 (kern) to 23 (local7)
+    /// Panics on larger values
+    pub fn facility(mut self, facility: u8) -> Syslog {
+        assert!(
+            facility <= 23,
+            "syslog facility must be 0 to 23, got {}",
+            facility
+        );
+        self.facility = facility;
+        self
+    }
+
+    /// Sets the APP-NAME field, which syslog daemons filter on
+    pub fn app_name(mut self, name: &str) -> Syslog {
+        self.app_name = header_field(name, 48);
+        self
+    }
+
+    // Sends a line as one message
+    pub(super) fn send(&self, line: &str) -> io::Result<()> {
+        let message = format!(
+            "<{}>1 {} {} {} {} access - {}",
+            self.facility * 8 + INFORMATIONAL,
+            rfc3339(SystemTime::now()),
+            self.hostname,
+            self.app_name,
+            process::id(),
+            line.trim_end_matches('\n')
+        );
+        match &self.transport {
+            Transport::Udp(socket) => socket.send(message.as_bytes())?,
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(message.as_bytes())?,
+        };
+        Ok(())
+    }
+}
+
+// Names the destination in error messages
+impl fmt::Display for Syslog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "syslog at {}", self.destination)
+    }
+}
+
+// Keeps the printable ASCII characters a header field may hold, up to max;
+// "-" stands for an empty field
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    match field.is_empty() {
+        true => "-".to_string(),
+        false => field,
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is writable for the length passed
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return String::new();
+    }
+    let len = name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
@@ -0,0 +1,593 @@
+// Certificates from an ACME CA (RFC 8555) such as Let's Encrypt
+// A thread named "acme" keeps a certificate for the configured domains in the
+// cache directory and installed in the TLS listener: the cached one is served
+// right away, and a new one is ordered when it is missing or about to expire,
+// checking twice a day. Domains are validated with HTTP-01 challenges, whose
+// answers the port 80 router serves (see Router::acme_challenges). Requests to
+// the CA are signed with an ES256 account key kept in the cache directory.
+
+use crate::tls::{self, TlsConfig};
+use crate::{Request, Response, Router, StatusCode};
+use rcgen::{CertificateParams, KeyPair};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// How often the certificate's expiry is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+// Wait before trying again after a failed order
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+// Pending authorizations and orders are polled this often, this many times
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_POLLS: u32 = 60;
+// Connect, read and write timeout of requests to the CA
+const CA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Certificates issued and renewed automatically by an ACME CA
+/// The port 80 router must answer the CA's challenges (see
+/// Router::acme_challenges), and start hands the certificates to a
+/// TlsConfig; domains must resolve to this server
+/// Example:
+/// let acme = Acme::new(&["example.com", "www.example.com"], "/var/lib/site/acme")
+///     .contact("admin@example.com");
+/// let mut http = Router::new();
+/// http.acme_challenges(&acme);
+/// Server::new(http, "").start(TcpListener::bind("0.0.0.0:80")?)?;
+/// let tls = TlsConfig::new()?;
+/// acme.start(&tls)?;
+/// Server::new(router, "static")
+///     .config(ServerConfig { tls: Some(tls), ..ServerConfig::default() })
+///     .run("0.0.0.0:443")?;
+#[derive(Clone, Debug)]
+pub struct Acme {
+    directory: String,
+    domains: Vec<String>,
+    contacts: Vec<String>,
+    cache_dir: PathBuf,
+    renew_before: Duration,
+    // Key authorizations of the challenges being validated, by token
+    challenges: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Acme {
+    /// Let's Encrypt's production directory, the default
+    pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+    /// Let's Encrypt's staging directory, with untrusted certificates and
+    /// much higher rate limits, for trying a setup out
+    pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+    /// Obtains one certificate covering the domains, keeping it and the
+    /// account key in cache_dir
+    /// Panics without domains or with wildcard domains, which HTTP-01
+    /// challenges cannot validate
+    pub fn new(domains: &[&str], cache_dir: &str) -> Acme {
+        assert!(!domains.is_empty(), "no domain to obtain a certificate for");
+        for domain in domains {
+            assert!(!domain.contains('*'), "invalid ACME domain {}", domain);
+        }
+        Acme {
+            directory: Acme::LETS_ENCRYPT.to_string(),
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            contacts: Vec::new(),
+            cache_dir: PathBuf::from(cache_dir),
+            renew_before: Duration::from_secs(30 * 86_400),
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Replaces the CA's directory URL
+    /// Example: Acme::new(&["example.com"], "acme").directory(Acme::LETS_ENCRYPT_STAGING)
+    pub fn directory(mut self, url: &str) -> Acme {
+        self.directory = url.to_string();
+        self
+    }
+
+    /// Adds an email address the CA may send expiry warnings to
+    pub fn contact(mut self, email: &str) -> Acme {
+        let email = email.strip_prefix("mailto:").unwrap_or(email);
+        self.contacts.push(format!("mailto:{}", email));
+        self
+    }
+
+    /// Sets how long before its expiry the certificate is replaced (30 days
+    /// by default)
+    pub fn renew_before(mut self, window: Duration) -> Acme {
+        self.renew_before = window;
+        self
+    }
+
+    /// Installs the cached certificate into tls if there is one, then keeps
+    /// it valid from a new thread named "acme"
+    /// Failed orders are printed and tried again an hour later
+    pub fn start(&self, tls: &TlsConfig) -> io::Result<JoinHandle<()>> {
+        fs::create_dir_all(&self.cache_dir)?;
+        if let Ok((key, _)) = self.cached_certificate() {
+            tls.install(&self.domains, key);
+        }
+        let acme = self.clone();
+        let tls = tls.clone();
+        thread::Builder::new()
+            .name("acme".to_string())
+            .spawn(move || {
+                loop {
+                    let wait = match acme.renew_if_due(&tls) {
+                        Ok(()) => CHECK_INTERVAL,
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to obtain a certificate for {}: {}",
+                                acme.domains.join(", "),
+                                e
+                            );
+                            RETRY_INTERVAL
+                        }
+                    };
+                    thread::sleep(wait);
+                }
+            })
+    }
+
+    // Returns the key authorization for a challenge token being validated
+    fn key_authorization(&self, token: &str) -> Option<String> {
+        self.challenges.lock().unwrap().get(token).cloned()
+    }
+
+    // Orders a new certificate if the cached one is missing or expires soon
+    fn renew_if_due(&self, tls: &TlsConfig) -> io::Result<()> {
+        if let Ok((_, expires)) = self.cached_certificate()
+            && expires
+                .duration_since(SystemTime::now())
+                .is_ok_and(|left| left > self.renew_before)
+        {
+            return Ok(());
+        }
+
+        let (cert_chain, private_key) = self.order()?;
+        let key = tls::parse_certified_key(cert_chain.as_bytes(), private_key.as_bytes())?;
+        write_private(&self.cache_path("-key.pem"), private_key.as_bytes())?;
+        fs::write(self.cache_path(".pem"), &cert_chain)?;
+        tls.install(&self.domains, key);
+        eprintln!(
+            "Installed a new certificate for {}",
+            self.domains.join(", ")
+        );
+        Ok(())
+    }
+
+    // Loads the cached certificate with its expiry
+    fn cached_certificate(&self) -> io::Result<(rustls::sign::CertifiedKey, SystemTime)> {
+        let cert_chain = fs::read(self.cache_path(".pem"))?;
+        let private_key = fs::read(self.cache_path("-key.pem"))?;
+        let key = tls::parse_certified_key(&cert_chain, &private_key)?;
+        let expires = key
+            .end_entity_cert()
+            .ok()
+            .and_then(|certificate| tls::not_after(certificate))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid certificate"))?;
+        Ok((key, expires))
+    }
+
+    // Cache file of the certificate, named after the first domain
+    fn cache_path(&self, suffix: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{}{}", self.domains[0], suffix))
+    }
+
+    // Loads the account key, creating it on first use
+    fn account_key(&self) -> io::Result<EcdsaKeyPair> {
+        let path = self.cache_dir.join("account-key.der");
+        let pkcs8 = match fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &SystemRandom::new(),
+                )
+                .map_err(|_| io::Error::other("failed to generate the account key"))?;
+                write_private(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid account key"))
+    }
+
+    // Runs an order to completion: account, authorizations, finalization
+    // Returns the PEM certificate chain and private key
+    fn order(&self) -> io::Result<(String, String)> {
+        let mut client = Client::new(&self.directory, self.account_key()?)?;
+        let new_account = client.endpoint("newAccount")?;
+        let account = client.post(
+            &new_account,
+            Some(json!({ "termsOfServiceAgreed": true, "contact": self.contacts })),
+        )?;
+        client.kid = Some(account.location()?);
+
+        let identifiers: Vec<Value> = self
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = client.endpoint("newOrder")?;
+        let reply = client.post(&new_order, Some(json!({ "identifiers": identifiers })))?;
+        let order_url = reply.location()?;
+        let order = reply.json()?;
+
+        for authorization in order["authorizations"].as_array().into_iter().flatten() {
+            let url = authorization.as_str().unwrap_or_default();
+            self.authorize(&mut client, url)?;
+        }
+
+        let key = KeyPair::generate().map_err(io::Error::other)?;
+        let csr = CertificateParams::new(self.domains.clone())
+            .and_then(|params| params.serialize_request(&key))
+            .map_err(io::Error::other)?;
+        let finalize = string_field(&order, "finalize")?;
+        client.post(&finalize, Some(json!({ "csr": base64url(csr.der()) })))?;
+        let order = client.poll(&order_url, "processing")?;
+        let certificate = string_field(&order, "certificate")?;
+        let cert_chain = client.post(&certificate, None)?.body;
+        let cert_chain = String::from_utf8(cert_chain)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid certificate chain"))?;
+        Ok((cert_chain, key.serialize_pem()))
+    }
+
+    // Answers the HTTP-01 challenge of an authorization and waits for the
+    // CA to validate it
+    fn authorize(&self, client: &mut Client, url: &str) -> io::Result<()> {
+        let authorization = client.post(url, None)?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .ok_or_else(|| io::Error::other("the CA offers no HTTP-01 challenge"))?;
+        let token = string_field(challenge, "token")?;
+        let key_authorization = format!("{}.{}", token, client.thumbprint);
+        self.challenges
+            .lock()
+            .unwrap()
+            .insert(token.clone(), key_authorization);
+
+        let result = client
+            .post(&string_field(challenge, "url")?, Some(json!({})))
+            .and_then(|_| client.poll(url, "pending"));
+        self.challenges.lock().unwrap().remove(&token);
+        result.map(|_| ())
+    }
+}
+
+// Registers the route answering HTTP-01 challenges
+pub fn mount(router: &mut Router, acme: &Acme) {
+    let acme = acme.clone();
+    router.get(
+        "/.well-known/acme-challenge/{token}",
+        move |request: Request| {
+            let token = request.param("token").unwrap_or("");
+            match acme.key_authorization(token) {
+                Some(key_authorization) => Response::ok("text/plain", key_authorization),
+                None => Response::from_status(StatusCode::NOT_FOUND),
+            }
+        },
+    );
+}
+
+// Signs and sends requests to the CA
+struct Client {
+    directory: Value,
+    key: EcdsaKeyPair,
+    // Public key as a JWK, in the canonical form of RFC 7638
+    jwk: String,
+    thumbprint: String,
+    // Account URL, identifying the key once the account exists
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    fn new(directory_url: &str, key: EcdsaKeyPair) -> io::Result<Client> {
+        let directory = send("GET", directory_url, None)?.json()?;
+        // Uncompressed point: 0x04, then 32 bytes each of x and y
+        let point = key.public_key().as_ref();
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            base64url(&point[1..33]),
+            base64url(&point[33..65])
+        );
+        let thumbprint =
+            base64url(ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes()).as_ref());
+        Ok(Client {
+            directory,
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    // URL of a resource listed in the directory
+    fn endpoint(&self, name: &str) -> io::Result<String> {
+        string_field(&self.directory, name)
+    }
+
+    // Sends a signed POST, or a POST-as-GET without payload
+    // A rejected nonce is replaced and the request sent once more
+    fn post(&mut self, url: &str, payload: Option<Value>) -> io::Result<Reply> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => send("HEAD", &self.endpoint("newNonce")?, None)?
+                    .header("Replay-Nonce")
+                    .ok_or_else(|| io::Error::other("the CA sent no nonce"))?
+                    .to_string(),
+            };
+            let body = self.sign(url, &nonce, payload.as_ref())?;
+            let reply = send("POST", url, Some(body.as_bytes()))?;
+            self.nonce = reply.header("Replay-Nonce").map(str::to_string);
+            if reply.status < 400 {
+                return Ok(reply);
+            }
+            let problem = reply.json().unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(io::Error::other(format!(
+                "{} from {}: {}",
+                reply.status,
+                url,
+                problem["detail"].as_str().unwrap_or("no details")
+            )));
+        }
+    }
+
+    // Fetches an authorization or order until it leaves the waiting status
+    // (or "ready" for orders), failing unless it ends up valid
+    fn poll(&mut self, url: &str, waiting: &str) -> io::Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let resource = self.post(url, None)?.json()?;
+            let status = resource["status"].as_str().unwrap_or_default();
+            if status == "valid" {
+                return Ok(resource);
+            }
+            if status != waiting && status != "ready" {
+                return Err(io::Error::other(format!(
+                    "{} became {}: {}",
+                    url,
+                    status,
+                    resource.get("error").unwrap_or(&Value::Null)
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("{} is still pending", url),
+        ))
+    }
+
+    // Builds a flattened JWS (RFC 7515) signed with the account key
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> io::Result<String> {
+        let key_id = match &self.kid {
+            Some(kid) => format!(r#""kid":{}"#, Value::from(kid.as_str())),
+            None => format!(r#""jwk":{}"#, self.jwk),
+        };
+        let protected = format!(
+            r#"{{"alg":"ES256",{},"nonce":{},"url":{}}}"#,
+            key_id,
+            Value::from(nonce),
+            Value::from(url)
+        );
+        let protected = base64url(protected.as_bytes());
+        let payload = match payload {
+            Some(payload) => base64url(payload.to_string().as_bytes()),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| io::Error::other("failed to sign the request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64url(signature.as_ref()),
+        })
+        .to_string())
+    }
+}
+
+// A response from the CA
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn location(&self) -> io::Result<String> {
+        self.header("Location")
+            .map(str::to_string)
+            .ok_or_else(|| io::Error::other("the CA sent no Location"))
+    }
+
+    fn json(&self) -> io::Result<Value> {
+        serde_json::from_slice(&self.body).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+// Sends one request on a new connection and reads the whole response
+// Besides https, plain http URLs are accepted for local test CAs
+fn send(method: &str, url: &str, body: Option<&[u8]>) -> io::Result<Reply> {
+    let invalid_url = || io::Error::new(ErrorKind::InvalidInput, format!("invalid URL {}", url));
+    let (scheme, rest) = url.split_once("://").ok_or_else(invalid_url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().map_err(|_| invalid_url())?)
+        }
+        _ => (authority, if scheme == "http" { 80 } else { 443 }),
+    };
+
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rust-http-web-server\r\nConnection: close\r\n",
+        method, path, authority
+    );
+    if let Some(body) = body {
+        request.push_str("Content-Type: application/jose+json\r\n");
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body.unwrap_or_default());
+
+    let stream = TcpStream::connect((host.trim_matches(['[', ']']), port))?;
+    stream.set_read_timeout(Some(CA_TIMEOUT))?;
+    stream.set_write_timeout(Some(CA_TIMEOUT))?;
+    let mut response = Vec::new();
+    match scheme {
+        "http" => {
+            let mut stream = stream;
+            stream.write_all(&request)?;
+            stream.read_to_end(&mut response)?;
+        }
+        "https" => {
+            let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+                .map_err(|_| invalid_url())?;
+            let connection = ClientConnection::new(Arc::clone(client_config()), name)
+                .map_err(io::Error::other)?;
+            let mut stream = StreamOwned::new(connection, stream);
+            stream.write_all(&request)?;
+            match stream.read_to_end(&mut response) {
+                // Servers closing without close_notify have still sent everything
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        _ => return Err(invalid_url()),
+    }
+    parse_reply(&response)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid response from the CA"))
+}
+
+// TLS settings for connections to the CA, trusting the Mozilla root store
+fn client_config() -> &'static Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        Arc::new(config)
+    })
+}
+
+// Splits a response into status, headers and body, decoding chunked bodies
+fn parse_reply(response: &[u8]) -> Option<Reply> {
+    let head_len = response.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&response[..head_len]);
+    let mut lines = head.lines();
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut reply = Reply {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    let mut rest = &response[head_len..];
+    if reply
+        .header("Transfer-Encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+            let size = std::str::from_utf8(&rest[..line_end]).ok()?;
+            let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                break;
+            }
+            reply.body.extend_from_slice(rest.get(..size)?);
+            rest = rest.get(size + 2..)?;
+        }
+    } else {
+        let length = reply
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(rest.len());
+        reply.body = rest[..length.min(rest.len())].to_vec();
+    }
+    Some(reply)
+}
+
+fn string_field(object: &Value, name: &str) -> io::Result<String> {
+    object[name]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::other(format!("the CA sent no {}", name)))
+}
+
+// Writes a file only the owner can read
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+// Encodes unpadded base64url, as used throughout JWS
+fn base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| {
+            bits | (byte as u32) << (16 - 8 * index)
+        });
+        for index in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
@@ -0,0 +1,289 @@
+// Built-in administration endpoints under /_admin
+// The server mounts them on its router when ServerConfig::admin is set, and
+// again on every reload. Requests must come from an allowed address and
+// carry the configured bearer token. Handlers reach the server through a
+// weak handle, so the router holding them doesn't keep the server alive.
+// The optional status page at /_status takes the token as a Basic auth
+// password too, so that browsers can open it. The statistics endpoints and
+// the status page need the metrics feature.
+
+#[cfg(feature = "metrics")]
+mod dashboard;
+
+use crate::credentials::{basic_credentials, secret_matches};
+use crate::escape::json_string;
+use crate::forwarded::IpRange;
+#[cfg(feature = "metrics")]
+use crate::server::PROMETHEUS_TYPE;
+use crate::server::WeakServer;
+use crate::{Next, Request, Response, Router, Server, StatusCode};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Path prefix of the administration endpoints
+pub const ADMIN_PREFIX: &str = "/_admin";
+
+/// Path of the HTML status page (see AdminConfig::status_page)
+#[cfg(feature = "metrics")]
+pub const STATUS_PAGE_PATH: &str = "/_status";
+
+// Requests listed on the status page
+pub(crate) const RECENT_REQUESTS: usize = 50;
+
+type ReloadFn = Arc<dyn Fn(&Server) -> io::Result<()> + Send + Sync>;
+
+/// Settings of the administration endpoints (see ServerConfig::admin)
+/// - GET /_admin/status: uptime, listeners and storage usage as JSON
+/// - GET /_admin/stats: the counters of Server::stats as JSON (metrics
+///   feature)
+/// - GET /_admin/metrics: the same in the Prometheus text format (metrics
+///   feature)
+/// - GET /_admin/config: the server settings, as text
+/// - POST /_admin/drain: stops accepting connections; serve returns once
+///   the connections being served are finished
+/// - POST /_admin/reload: runs the on_reload function
+/// - GET /_status, if enabled: an HTML page of the live statistics (metrics
+///   feature)
+///
+/// Example:
+/// let admin = AdminConfig::new(&env::var("ADMIN_TOKEN")?)
+///     .allow(&["127.0.0.1", "10.1.0.0/16"])
+///     .on_reload(|server| {
+///         server.reload(build_router(), "static");
+///         Ok(())
+///     });
+/// let config = ServerConfig { admin: Some(admin), ..ServerConfig::default() };
+#[derive(Clone)]
+pub struct AdminConfig {
+    token: String,
+    allowed: Vec<IpRange>,
+    on_reload: Option<ReloadFn>,
+    status_page: bool,
+}
+
+impl AdminConfig {
+    /// Creates the settings for requests carrying "Authorization: Bearer
+    /// <token>", from the loopback addresses only
+    /// Panics on an empty token
+    pub fn new(token: &str) -> AdminConfig {
+        assert!(!token.is_empty(), "the admin token must not be empty");
+        AdminConfig {
+            token: token.to_string(),
+            allowed: ["127.0.0.0/8", "::1"]
+                .iter()
+                .filter_map(|range| IpRange::parse(range))
+                .collect(),
+            on_reload: None,
+            status_page: false,
+        }
+    }
+
+    /// Replaces the client addresses or CIDR ranges allowed to connect
+    /// Behind trusted proxies (see Router::trusted_proxies) the forwarded
+    /// client address is checked
+    /// Panics on entries that are not addresses or ranges
+    pub fn allow(mut self, ranges: &[&str]) -> AdminConfig {
+        self.allowed = ranges
+            .iter()
+            .map(|range| {
+                IpRange::parse(range)
+                    .unwrap_or_else(|| panic!("invalid admin client range {:?}", range))
+            })
+            .collect();
+        self
+    }
+
+    /// Sets the function POST /_admin/reload runs, typically rebuilding the
+    /// router and passing it to Server::reload
+    pub fn on_reload(
+        mut self,
+        reload: impl Fn(&Server) -> io::Result<()> + Send + Sync + 'static,
+    ) -> AdminConfig {
+        self.on_reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// Serves an HTML page at /_status showing connections, request and
+    /// error rates, latencies by route, the last requests answered and the
+    /// uptime, refreshed every few seconds
+    /// The same clients and token are allowed as for the other endpoints;
+    /// browsers ask for the token as the password of any user name
+    #[cfg(feature = "metrics")]
+    pub fn status_page(mut self, enabled: bool) -> AdminConfig {
+        self.status_page = enabled;
+        self
+    }
+
+    // Returns true if the status page is served, for which the server keeps
+    // the last requests answered
+    pub(crate) fn serves_status_page(&self) -> bool {
+        self.status_page
+    }
+
+    // Returns true if the request may use the endpoints, carrying the token
+    // as a bearer token or as the password of Basic credentials
+    fn authorizes(&self, request: &Request) -> bool {
+        let authorization = request.header("Authorization").unwrap_or("");
+        let password = basic_credentials(request, "Authorization").map(|(_, password)| password);
+        let token = match &password {
+            Some(password) => password.as_str(),
+            None => authorization.strip_prefix("Bearer ").unwrap_or(""),
+        };
+        secret_matches(token, &self.token)
+    }
+}
+
+// Never shows the token, the configuration is served by /_admin/config
+impl fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("token", &"<hidden>")
+            .field("allowed", &self.allowed)
+            .field("on_reload", &self.on_reload.is_some())
+            .field("status_page", &self.status_page)
+            .finish()
+    }
+}
+
+// Registers the endpoints on the router
+pub fn mount(router: &mut Router, admin: &AdminConfig, server: WeakServer) {
+    let admin = Arc::new(admin.clone());
+    let server = Arc::new(server);
+    #[cfg(feature = "metrics")]
+    if admin.status_page {
+        let guard = Arc::clone(&admin);
+        let handle = Arc::clone(&server);
+        router.get(STATUS_PAGE_PATH, move |request: Request| {
+            match refusal(&guard, &request, "Basic realm=\"status\"") {
+                Some(refused) => refused,
+                None => with_server(&handle, dashboard::render),
+            }
+        });
+    }
+    router.scope(ADMIN_PREFIX, |endpoints| {
+        let guard = Arc::clone(&admin);
+        endpoints.layer(move |request: &Request, next: &Next| {
+            match refusal(&guard, request, "Bearer realm=\"admin\"") {
+                Some(refused) => refused,
+                None => next.run(request),
+            }
+        });
+
+        let handle = Arc::clone(&server);
+        endpoints.get("/status", move || with_server(&handle, status));
+        #[cfg(feature = "metrics")]
+        {
+            let handle = Arc::clone(&server);
+            endpoints.get("/stats", move || {
+                with_server(&handle, |server| {
+                    Response::ok("application/json", server.stats().to_json())
+                })
+            });
+            let handle = Arc::clone(&server);
+            endpoints.get("/metrics", move || {
+                with_server(&handle, |server| {
+                    Response::ok(PROMETHEUS_TYPE, server.stats().to_prometheus())
+                })
+            });
+        }
+        let handle = Arc::clone(&server);
+        endpoints.get("/config", move || with_server(&handle, config));
+        let handle = Arc::clone(&server);
+        endpoints.post("/drain", move || {
+            with_server(&handle, |server| {
+                server.drain();
+                Response::from_status(StatusCode::ACCEPTED)
+            })
+        });
+        let handle = Arc::clone(&server);
+        endpoints.post("/reload", move || {
+            with_server(&handle, |server| match &admin.on_reload {
+                None => Response::from_status(StatusCode::NOT_IMPLEMENTED)
+                    .with_body("text/plain", "No reload function configured"),
+                Some(reload) => match reload(server) {
+                    Ok(()) => Response::from_status(StatusCode::NO_CONTENT),
+                    Err(e) => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .with_body("text/plain", e.to_string()),
+                },
+            })
+        });
+    });
+}
+
+// Returns the response refusing a request from a client that is not allowed
+// or without the token, challenging it to authenticate
+fn refusal(admin: &AdminConfig, request: &Request, challenge: &str) -> Option<Response> {
+    let allowed = request
+        .client_ip()
+        .is_some_and(|ip| admin.allowed.iter().any(|range| range.contains(ip)));
+    if !allowed {
+        return Some(Response::from_status(StatusCode::FORBIDDEN));
+    }
+    if !admin.authorizes(request) {
+        return Some(
+            Response::from_status(StatusCode::UNAUTHORIZED)
+                .with_header("WWW-Authenticate", challenge),
+        );
+    }
+    None
+}
+
+// Runs an endpoint with the server, which may be gone when a request
+// outlives it
+fn with_server(server: &WeakServer, endpoint: impl FnOnce(&Server) -> Response) -> Response {
+    match server.upgrade() {
+        Some(server) => endpoint(&server),
+        None => Response::from_status(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+// Describes the running server as JSON
+fn status(server: &Server) -> Response {
+    let listeners: Vec<String> = server
+        .local_addrs()
+        .iter()
+        .map(|addr| json_string(&addr.to_string()))
+        .collect();
+    #[cfg(feature = "static")]
+    let storage: Vec<String> = server
+        .storage_usage()
+        .iter()
+        .map(|usage| {
+            format!(
+                "{{\"prefix\":{},\"used\":{},\"limit\":{}}}",
+                json_string(&usage.prefix),
+                usage.used,
+                usage.limit
+            )
+        })
+        .collect();
+    #[cfg(not(feature = "static"))]
+    let storage: Vec<String> = Vec::new();
+    let body = format!(
+        "{{\"uptime_secs\":{},\"accepting\":{},\"listeners\":[{}],\"base_dir\":{},\"storage\":[{}]}}",
+        server.uptime().as_secs(),
+        server.is_accepting(),
+        listeners.join(","),
+        json_string(&server.site().base_dir),
+        storage.join(",")
+    );
+    Response::ok("application/json", body)
+}
+
+// Dumps the server settings, leaving out TLS key material
+fn config(server: &Server) -> Response {
+    #[allow(unused_mut)]
+    let mut config = server.settings().clone();
+    #[cfg(feature = "tls")]
+    let tls = config.tls.take().is_some();
+    #[cfg(not(feature = "tls"))]
+    let tls = false;
+    let body = format!(
+        "base_dir: {:?}\ntls: {}\n{:#?}\n",
+        server.site().base_dir,
+        tls,
+        config
+    );
+    Response::ok("text/plain", body)
+}
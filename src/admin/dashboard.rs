@@ -0,0 +1,171 @@
+// HTML status page
+// Rendered on every request from a snapshot of the statistics, with no
+// script: the page reloads itself every few seconds. Error rates are the
+// share of 4xx and 5xx responses since the server was created, overall and
+// by route.
+
+use crate::escape::escape_html;
+use crate::{Histogram, Response, Server};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Seconds between reloads of the page
+const REFRESH_SECS: u32 = 5;
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{padding:.3em .8em;border-bottom:1px solid #ddd;text-align:left}\
+td.n{text-align:right;font-variant-numeric:tabular-nums}\
+.error{color:#b00}";
+
+// Renders the page for the server's current statistics
+pub(super) fn render(server: &Server) -> Response {
+    let stats = server.stats();
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\">\
+         <title>Server status</title><style>{}</style></head><body>\n\
+         <h1>Server status</h1>\n<p>Up {}, {}; refreshed every {} seconds</p>\n",
+        REFRESH_SECS,
+        STYLE,
+        uptime(stats.uptime),
+        match &stats.drain {
+            Some(drain) => match drain.time_left {
+                Some(left) => format!("draining, aborting in {}", uptime(left)),
+                None => "draining".to_string(),
+            },
+            None => "accepting connections".to_string(),
+        },
+        REFRESH_SECS
+    );
+
+    let rows = [
+        ("Active connections", stats.active_connections.to_string()),
+        ("Connections", stats.total_connections.to_string()),
+        ("Requests", stats.total_requests.to_string()),
+        ("Requests in flight", stats.in_flight_requests.to_string()),
+        ("Client errors (4xx)", errors(&stats.statuses, 400)),
+        ("Server errors (5xx)", errors(&stats.statuses, 500)),
+        ("Received", bytes(stats.bytes_in)),
+        ("Sent", bytes(stats.bytes_out)),
+        ("Latency p50 / p95 / p99", percentiles(&stats.latency)),
+    ];
+    html.push_str("<table>\n");
+    for (label, value) in rows {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td class=\"n\">{}</td></tr>",
+            label, value
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Routes</h2>\n<table>\n<tr><th>Method</th><th>Route</th><th>Requests</th>\
+         <th>4xx</th><th>5xx</th><th>p50 / p95 / p99</th></tr>\n",
+    );
+    for route in &stats.routes {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td>\
+             <td class=\"n\">{}</td><td class=\"n\">{}</td></tr>",
+            escape_html(&route.method),
+            escape_html(&route.route),
+            route.latency.count,
+            errors(&route.statuses, 400),
+            errors(&route.statuses, 500),
+            percentiles(&route.latency)
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Recent requests</h2>\n<table>\n<tr><th>Time (UTC)</th><th>Client</th>\
+         <th>Method</th><th>Path</th><th>Status</th><th>Latency</th></tr>\n",
+    );
+    for request in &stats.recent {
+        let secs = request
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let _ = writeln!(
+            html,
+            "<tr><td>{:02}:{:02}:{:02}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td class=\"n{}\">{}</td><td class=\"n\">{}</td></tr>",
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60,
+            request
+                .client_ip
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            escape_html(&request.method),
+            escape_html(&request.path),
+            if request.status >= 500 { " error" } else { "" },
+            request.status,
+            millis(request.latency)
+        );
+    }
+    html.push_str("</table>\n</body></html>\n");
+
+    Response::ok("text/html; charset=utf-8", html).with_header("Cache-Control", "no-store")
+}
+
+// Formats the responses with a status in the hundred starting at class,
+// and their share of all responses
+fn errors(statuses: &BTreeMap<u16, u64>, class: u16) -> String {
+    let total: u64 = statuses.values().sum();
+    let errors: u64 = statuses
+        .range(class..class + 100)
+        .map(|(_, count)| count)
+        .sum();
+    match total {
+        0 => "0".to_string(),
+        total => format!("{} ({:.1}%)", errors, errors as f64 * 100.0 / total as f64),
+    }
+}
+
+fn percentiles(histogram: &Histogram) -> String {
+    [0.5, 0.95, 0.99]
+        .map(|quantile| histogram.quantile(quantile).map_or("-".to_string(), millis))
+        .join(" / ")
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn bytes(count: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if count < 1024 {
+        return format!("{} B", count);
+    }
+    let mut value = count as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// Formats the uptime as e.g. "2d 3h 4m 5s", leaving out leading zeros
+fn uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let parts = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let start = parts
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(parts.len() - 1);
+    parts[start..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,152 @@
+// Static assets compiled into the binary
+// Assets are held in memory by path, so a site ships as one executable
+// without a static directory next to it. ETags are derived from the content,
+// so they stay the same across restarts and builds for unchanged files, and
+// Last-Modified is the time the assets were loaded.
+
+use crate::conditional::{self, Validators};
+use crate::http_date::format_http_date;
+use crate::mime::content_type_for;
+use crate::{Request, Response, Router, StatusCode};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Builds Assets from files read at compile time, relative to the crate
+/// root (the directory of Cargo.toml)
+/// Example:
+/// let assets = embed_assets!("static", ["index.html", "css/site.css", "app.js"]);
+/// router.assets("/", assets);
+#[macro_export]
+macro_rules! embed_assets {
+    ($dir:literal, [$($file:literal),* $(,)?]) => {
+        $crate::Assets::new()$(.add(
+            $file,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $dir, "/", $file)),
+        ))*
+    };
+}
+
+/// Files served from memory (see Router::assets and embed_assets!)
+/// Paths are relative to the mount prefix, "index.html" answers the prefix
+/// itself and directories ending in "/". Files can also come from a table
+/// generated by a build script
+/// Example:
+/// let assets = Assets::new()
+///     .add("index.html", include_bytes!("../static/index.html"))
+///     .add("robots.txt", "User-agent: *\nDisallow:\n".as_bytes());
+#[derive(Clone)]
+pub struct Assets {
+    // Keyed by path without the leading "/" (e.g. "css/site.css")
+    files: HashMap<String, Arc<Asset>>,
+    loaded: SystemTime,
+}
+
+struct Asset {
+    bytes: Cow<'static, [u8]>,
+    etag: String,
+}
+
+impl Assets {
+    /// Creates an empty set of assets
+    pub fn new() -> Assets {
+        Assets {
+            files: HashMap::new(),
+            loaded: SystemTime::now(),
+        }
+    }
+
+    /// Adds a file, replacing any other at the same path
+    pub fn add(mut self, path: &str, bytes: impl Into<Cow<'static, [u8]>>) -> Assets {
+        let bytes = bytes.into();
+        let etag = format!("\"{:016x}-{:x}\"", fnv1a(&bytes), bytes.len());
+        self.files.insert(
+            path.trim_start_matches('/').to_string(),
+            Arc::new(Asset { bytes, etag }),
+        );
+        self
+    }
+
+    /// Returns the content of a file
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        self.files
+            .get(path.trim_start_matches('/'))
+            .map(|asset| &*asset.bytes)
+    }
+
+    /// Returns the number of files
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns true if there are no files
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // Answers a request for a path under the mount prefix
+    fn serve(&self, request: &Request, path: &str) -> Response {
+        let path = if path.is_empty() || path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            path.to_string()
+        };
+        let Some(asset) = self.files.get(&path) else {
+            return Response::from_status(StatusCode::NOT_FOUND);
+        };
+        let validators = Validators {
+            etag: asset.etag.clone(),
+            modified: self.loaded,
+        };
+        if let Some(response) = conditional::evaluate(request, Some(&validators)) {
+            return response;
+        }
+        Response::ok(content_type_for(&path), asset.bytes.to_vec())
+            .with_header("ETag", &asset.etag)
+            .with_header("Last-Modified", &format_http_date(self.loaded))
+    }
+}
+
+impl Default for Assets {
+    fn default() -> Assets {
+        Assets::new()
+    }
+}
+
+impl<P: AsRef<str>, B: Into<Cow<'static, [u8]>>> FromIterator<(P, B)> for Assets {
+    fn from_iter<I: IntoIterator<Item = (P, B)>>(files: I) -> Assets {
+        files
+            .into_iter()
+            .fold(Assets::new(), |assets, (path, bytes)| {
+                assets.add(path.as_ref(), bytes)
+            })
+    }
+}
+
+impl fmt::Debug for Assets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut paths: Vec<&String> = self.files.keys().collect();
+        paths.sort();
+        f.debug_struct("Assets").field("files", &paths).finish()
+    }
+}
+
+// Registers the assets' route on the router
+pub fn mount(router: &mut Router, prefix: &str, assets: Assets) {
+    let assets = Arc::new(assets);
+    router.scope(prefix, |files| {
+        files.get("/{*path}", move |request: Request| {
+            let path = request.param("path").unwrap_or("").to_string();
+            assets.serve(&request, &path)
+        });
+    });
+}
+
+// FNV-1a hash of the content, for ETags
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,99 @@
+// async fn route handlers
+// Wrapped in Async, a function returning a future is registered like any
+// other handler. Its future is spawned on a shared tokio runtime started on
+// first use, so handlers can await tokio-based database drivers and HTTP
+// clients. The server's backends are blocking, so the connection's thread
+// waits for the future to finish; the runtime's threads are only busy while
+// the future is polled. Futures never run on the caller's own runtime, so
+// handlers called from within one (e.g. TestClient in a #[tokio::test])
+// block its thread without deadlocking it.
+
+use crate::extract::FromRequest;
+use crate::{IntoResponse, Request, Response, RouteHandler, StatusCode};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::sync::mpsc;
+use tokio::runtime::{Builder, Runtime};
+
+/// A route handler made of an async function or closure, taking up to four
+/// extractors like other handlers and resolving to an IntoResponse value
+/// The future runs on a shared tokio runtime with timers enabled, while the
+/// connection's thread blocks until it resolves: async handlers can await
+/// tokio I/O and timers, but don't free the worker for other connections
+/// Example:
+/// async fn show_user(Path(id): Path<u32>) -> Result<Json<User>, Error> {
+///     let user = database.find_user(id).await?;
+///     Ok(Json(user))
+/// }
+/// router.get("/users/{id}", Async(show_user));
+/// router.get("/slow", Async(|| async { tokio::time::sleep(delay).await; "done" }));
+#[derive(Clone, Copy, Debug)]
+pub struct Async<F>(pub F);
+
+// Implements RouteHandler for async functions with the given extractor
+// arguments, like impl_route_handler does for plain functions
+macro_rules! impl_async_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg,)*> RouteHandler<($($arg,)*)> for Async<F>
+        where
+            F: Fn($($arg),*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse + Send,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, request: &Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(request) {
+                        Ok(value) => value,
+                        Err(rejection) => return rejection,
+                    };
+                )*
+                match run((self.0)($($arg),*)) {
+                    Some(output) => output.into_response_to(request),
+                    None => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR),
+                }
+            }
+        }
+    };
+}
+
+impl_async_handler!();
+impl_async_handler!(A);
+impl_async_handler!(A, B);
+impl_async_handler!(A, B, C);
+impl_async_handler!(A, B, C, D);
+
+// Runs the future on a runtime and waits for its output
+// Returns None if it panicked or no runtime could be started
+fn run<Fut>(future: Fut) -> Option<Fut::Output>
+where
+    Fut: Future + Send + 'static,
+    Fut::Output: Send,
+{
+    let (sender, receiver) = mpsc::sync_channel(1);
+    runtime()?.spawn(async move {
+        let _ = sender.send(future.await);
+    });
+    // The sender is dropped without sending if the future panicked
+    receiver.recv().ok()
+}
+
+// The runtime async handlers run on
+fn runtime() -> Option<&'static Runtime> {
+    static RUNTIME: OnceLock<Result<Runtime, String>> = OnceLock::new();
+    let runtime = RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("async-handler")
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())
+    });
+    match runtime {
+        Ok(runtime) => Some(runtime),
+        Err(e) => {
+            eprintln!("Cannot start the runtime for async handlers: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Size of the buffer used to copy streamed bodies
+const STREAM_CHUNK_SIZE: usize = 8 * 1024;
+
+/// The body of a Response
+/// Full bodies are sent with a Content-Length header. Streamed bodies are
+/// produced while the response is written, so they never have to fit in
+/// memory; they are sent with chunked transfer encoding unless the response
+/// already carries a Content-Length header
+/// Example: Response::ok("text/csv", Body::from_reader(File::open("big.csv")?))
+pub enum Body {
+    Full(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    /// Creates a body streamed from a reader until it reports end of file
+    pub fn from_reader(reader: impl Read + Send + 'static) -> Body {
+        Body::Reader(Box::new(reader))
+    }
+
+    /// Creates a body streamed from an iterator of byte chunks
+    /// Example: Body::from_chunks((1..=3).map(|n| format!("line {}\n", n)))
+    pub fn from_chunks<I>(chunks: I) -> Body
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: Into<Vec<u8>> + 'static,
+    {
+        Body::Chunks(Box::new(chunks.into_iter().map(Into::into)))
+    }
+
+    /// Returns the bytes of a full body, or None for streamed bodies
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the body is produced while it is written
+    pub fn is_streamed(&self) -> bool {
+        !matches!(self, Body::Full(_))
+    }
+
+    /// Limits how fast the body is sent, in bytes per second
+    /// The writing thread sleeps whenever it gets ahead of the rate
+    pub fn throttle(self, bytes_per_second: u64) -> Body {
+        Body::Reader(Box::new(Throttled {
+            inner: self.into_reader(),
+            bytes_per_second: bytes_per_second.max(1),
+            started: Instant::now(),
+            sent: 0,
+        }))
+    }
+
+    /// Returns a reader producing the body's bytes
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            Body::Full(bytes) => Box::new(Cursor::new(bytes)),
+            Body::Reader(reader) => reader,
+            Body::Chunks(chunks) => Box::new(ChunksReader {
+                chunks,
+                current: Cursor::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Writes the body as is
+    pub fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        self.write_chunks(writer, |writer, chunk| writer.write_all(chunk))?;
+        Ok(())
+    }
+
+    /// Writes the body using chunked transfer encoding, including the final empty chunk
+    pub fn write_chunked(self, writer: &mut impl Write) -> std::io::Result<()> {
+        self.write_chunks(writer, |writer, chunk| {
+            write!(writer, "{:X}\r\n", chunk.len())?;
+            writer.write_all(chunk)?;
+            writer.write_all(b"\r\n")
+        })?;
+        writer.write_all(b"0\r\n\r\n")
+    }
+
+    // Feeds every non-empty piece of the body to write_chunk
+    // An empty chunk would end a chunked body early, so those are skipped
+    fn write_chunks<W: Write>(
+        self,
+        writer: &mut W,
+        mut write_chunk: impl FnMut(&mut W, &[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        match self {
+            Body::Full(bytes) if bytes.is_empty() => Ok(()),
+            Body::Full(bytes) => write_chunk(writer, &bytes),
+            Body::Reader(mut reader) => {
+                let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+                loop {
+                    match reader.read(&mut buffer) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => write_chunk(writer, &buffer[..n])?,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Body::Chunks(chunks) => {
+                for chunk in chunks.filter(|chunk| !chunk.is_empty()) {
+                    write_chunk(writer, &chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::Full(Vec::new())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            Body::Reader(_) => f.write_str("Reader(..)"),
+            Body::Chunks(_) => f.write_str("Chunks(..)"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Full(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Full(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Full(text.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Full(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Body {
+    fn from(bytes: &[u8; N]) -> Body {
+        Body::Full(bytes.to_vec())
+    }
+}
+
+// Full bodies compare equal to their bytes, streamed bodies to nothing
+impl PartialEq<[u8]> for Body {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == Some(other)
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for Body {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self.as_bytes() == Some(&other[..])
+    }
+}
+
+impl PartialEq<Vec<u8>> for Body {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.as_bytes() == Some(&other[..])
+    }
+}
+
+// Reads the chunks of an iterator one after the other
+struct ChunksReader {
+    chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+// Reader that sleeps to keep its average throughput under a rate
+struct Throttled {
+    inner: Box<dyn Read + Send>,
+    bytes_per_second: u64,
+    started: Instant,
+    sent: u64,
+}
+
+impl Read for Throttled {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Never hand out more than a second's worth at once
+        let limit = buf.len().min(self.bytes_per_second as usize);
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.sent += n as u64;
+
+        let due = Duration::from_secs_f64(self.sent as f64 / self.bytes_per_second as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,315 @@
+// Request bodies read while the handler runs
+// Routes marked with Router::stream_body get their request before the body
+// was read. The handler runs on a scoped thread, and every read of its
+// BodyReader that runs out of bytes asks the connection thread for the next
+// chunk, which only then reads it from the client. A handler answering
+// without reading the body (e.g. rejecting an upload) therefore never waits
+// for the client to send it, and "Expect: 100-continue" is only answered
+// once the handler starts reading.
+
+use crate::{Request, Response, Router, read_or_probe};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The body of a request, read as it arrives from the client
+/// On routes registered with Router::stream_body, bytes are read off the
+/// connection as the handler reads them, decoding chunked transfer encoding.
+/// Elsewhere the reader goes over the buffered request.body
+/// Example:
+/// fn upload(mut body: BodyReader) -> Response {
+///     let mut file = File::create("upload.bin").unwrap();
+///     match io::copy(&mut body, &mut file) {
+///         Ok(_) => Response::new(201, "Created"),
+///         Err(e) => Response::bad_request(&e.to_string()),
+///     }
+/// }
+#[derive(Debug)]
+pub struct BodyReader {
+    // Bytes received but not read yet
+    chunk: Vec<u8>,
+    offset: usize,
+    // Where further chunks come from, None once the body ended
+    stream: Option<BodyStream>,
+}
+
+impl BodyReader {
+    pub(crate) fn new(request: &Request) -> BodyReader {
+        match &request.body_stream {
+            Some(stream) => BodyReader {
+                chunk: Vec::new(),
+                offset: 0,
+                stream: Some(stream.clone()),
+            },
+            None => BodyReader {
+                chunk: request.body.clone(),
+                offset: 0,
+                stream: None,
+            },
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            let Some(stream) = &self.stream else {
+                return Ok(0);
+            };
+            let chunk = stream.next_chunk()?;
+            if chunk.is_empty() {
+                self.stream = None;
+            }
+            self.chunk = chunk;
+            self.offset = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.offset);
+        buf[..len].copy_from_slice(&self.chunk[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+/// Handle on the connection a streamed request body is read from
+/// Clones of a request share it, so the body can only be read once
+#[derive(Clone, Debug)]
+pub struct BodyStream(Arc<Mutex<Channel>>);
+
+#[derive(Debug)]
+struct Channel {
+    messages: Sender<Message>,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+}
+
+// What the handler thread tells the connection thread
+enum Message {
+    // Read the next chunk of the body, an empty one marks its end
+    Pull,
+    // The handler returned
+    Done(Option<Response>),
+}
+
+impl BodyStream {
+    // Asks the connection thread for the next chunk and waits for it
+    fn next_chunk(&self) -> io::Result<Vec<u8>> {
+        let channel = self.0.lock().unwrap();
+        let gone = || io::Error::new(ErrorKind::BrokenPipe, "request already answered");
+        channel.messages.send(Message::Pull).map_err(|_| gone())?;
+        channel.chunks.recv().map_err(|_| gone())?
+    }
+}
+
+/// Answers a request whose handler streams its body
+/// respond runs on its own thread while this one reads the body from the
+/// stream on demand. Returns the status to answer with instead if the body
+/// framing is invalid or unsupported
+pub fn handle(
+    stream: &mut (impl Read + Write),
+    mut request: Request,
+    buffered: &[u8],
+    router: &Router,
+    respond: impl FnOnce(&mut Request) -> Option<Response> + Send,
+) -> Result<Option<Response>, &'static str> {
+    let framing = framing(&request)?;
+    let mut source = Source {
+        stream,
+        pending: buffered.to_vec(),
+        framing,
+        continue_expected: request
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")),
+        read_size: router.body_read_size(),
+        line_limit: router.head_size_limit(),
+    };
+
+    let (messages, received) = mpsc::channel();
+    let (chunks, pulled) = mpsc::channel();
+    let done = messages.clone();
+    request.body = Vec::new();
+    request.body_stream = Some(BodyStream(Arc::new(Mutex::new(Channel {
+        messages,
+        chunks: pulled,
+    }))));
+
+    thread::scope(|scope| {
+        let handler = scope.spawn(move || {
+            let response = respond(&mut request);
+            let _ = done.send(Message::Done(response));
+        });
+        let mut response = None;
+        for message in received.iter() {
+            match message {
+                Message::Pull => {
+                    // The handler may have given up on the body meanwhile
+                    let _ = chunks.send(source.next_chunk());
+                }
+                Message::Done(done) => {
+                    response = done;
+                    break;
+                }
+            }
+        }
+        if let Err(panic) = handler.join() {
+            std::panic::resume_unwind(panic);
+        }
+        Ok(response)
+    })
+}
+
+// How the end of a streamed body is found
+enum Framing {
+    // Bytes left until Content-Length is reached
+    Length(usize),
+    // Bytes left in the current chunk, 0 when a chunk size line is next
+    Chunked(usize),
+    Ended,
+    // The body was malformed or the client went away
+    Failed(ErrorKind, &'static str),
+}
+
+// Returns the framing announced by the request headers
+fn framing(request: &Request) -> Result<Framing, &'static str> {
+    if let Some(encoding) = request.header("Transfer-Encoding") {
+        // Both headers at once is a request smuggling attempt (RFC 9112 6.1)
+        if request.header("Content-Length").is_some() {
+            return Err("400 Bad Request");
+        }
+        return match encoding.trim().eq_ignore_ascii_case("chunked") {
+            true => Ok(Framing::Chunked(0)),
+            false => Err("501 Not Implemented"),
+        };
+    }
+    match request.header("Content-Length") {
+        None => Ok(Framing::Length(0)),
+        Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => value
+            .parse()
+            .map(Framing::Length)
+            .map_err(|_| "413 Payload Too Large"),
+        Some(_) => Err("400 Bad Request"),
+    }
+}
+
+// The body of a request as it is read from the connection
+struct Source<'a, S: Read + Write> {
+    stream: &'a mut S,
+    // Bytes read from the connection but not handed out yet
+    pending: Vec<u8>,
+    framing: Framing,
+    continue_expected: bool,
+    // Most bytes requested per read
+    read_size: usize,
+    // Longest chunk size or trailer line accepted
+    line_limit: usize,
+}
+
+impl<S: Read + Write> Source<'_, S> {
+    // Returns the next part of the body, or an empty chunk at its end
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        // Clients that sent part of the body already don't wait for the go-ahead
+        if std::mem::take(&mut self.continue_expected)
+            && self.pending.is_empty()
+            && !matches!(self.framing, Framing::Length(0))
+        {
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            self.stream.flush()?;
+        }
+        match self.decode() {
+            Ok(chunk) => Ok(chunk),
+            Err(e) => {
+                self.framing = Framing::Failed(e.kind(), "request body is malformed or incomplete");
+                Err(e)
+            }
+        }
+    }
+
+    fn decode(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            match self.framing {
+                Framing::Ended | Framing::Length(0) => {
+                    self.framing = Framing::Ended;
+                    return Ok(Vec::new());
+                }
+                Framing::Failed(kind, message) => return Err(io::Error::new(kind, message)),
+                Framing::Length(remaining) => {
+                    let data = self.take(remaining)?;
+                    self.framing = Framing::Length(remaining - data.len());
+                    return Ok(data);
+                }
+                Framing::Chunked(0) => {
+                    let line = self.read_line()?;
+                    let size = String::from_utf8_lossy(&line);
+                    let size = size.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(malformed("invalid chunk size"));
+                    }
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| malformed("chunk size too large"))?;
+                    if size > 0 {
+                        self.framing = Framing::Chunked(size);
+                        continue;
+                    }
+                    // Trailer fields are ignored, up to the blank line ending them
+                    while !self.read_line()?.is_empty() {}
+                    self.framing = Framing::Ended;
+                }
+                Framing::Chunked(remaining) => {
+                    let data = self.take(remaining)?;
+                    let left = remaining - data.len();
+                    if left == 0 && !self.read_line()?.is_empty() {
+                        return Err(malformed("chunk data longer than its size"));
+                    }
+                    self.framing = Framing::Chunked(left);
+                    return Ok(data);
+                }
+            }
+        }
+    }
+
+    // Returns up to max bytes, reading from the connection if none are pending
+    fn take(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        if self.pending.is_empty() {
+            self.fill(max.min(self.read_size))?;
+        }
+        let len = max.min(self.pending.len());
+        Ok(self.pending.drain(..len).collect())
+    }
+
+    // Returns the next line without its CRLF
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.pending.windows(2).position(|w| w == b"\r\n") {
+                let line = self.pending[..end].to_vec();
+                self.pending.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.pending.len() > self.line_limit {
+                return Err(malformed("chunk size or trailer line too long"));
+            }
+            self.fill(self.read_size)?;
+        }
+    }
+
+    // Appends up to len bytes read from the connection to the pending ones
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        let filled = self.pending.len();
+        self.pending.resize(filled + len, 0);
+        match read_or_probe(self.stream, &mut self.pending[filled..]) {
+            Some(bytes_read) => {
+                self.pending.truncate(filled + bytes_read);
+                Ok(())
+            }
+            None => {
+                self.pending.truncate(filled);
+                Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed before the end of the request body",
+                ))
+            }
+        }
+    }
+}
+
+fn malformed(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,89 @@
+// Support for backends that buffer whole requests before serving them
+// The event loop and io_uring backends read a request without blocking,
+// then run the regular pipeline over it in memory; HTTP/2 streams are
+// served the same way.
+
+#[cfg(any(feature = "event-loop", all(feature = "io-uring", target_os = "linux")))]
+use crate::Request;
+use crate::{FileTransfer, Peer, Router, serve_stream};
+use std::io::{self, Cursor, Read, Write};
+
+// Returns true once raw holds a whole request (head and Content-Length body),
+// or enough of it to know the request will be rejected by the router's limits
+#[cfg(any(feature = "event-loop", all(feature = "io-uring", target_os = "linux")))]
+pub fn request_complete(raw: &[u8], router: &Router) -> bool {
+    let max_head_size = router.head_size_limit();
+    let Some(head_len) = head_len(raw) else {
+        return raw.len() >= max_head_size;
+    };
+    if head_len > max_head_size {
+        return true;
+    }
+    let request = Request::parse(&raw[..head_len]);
+    if request.header("Transfer-Encoding").is_some() {
+        return true;
+    }
+    match request.header("Content-Length").map(str::parse::<usize>) {
+        None => true,
+        Some(Ok(length)) if length <= router.body_size_limit() => raw.len() >= head_len + length,
+        Some(_) => true,
+    }
+}
+
+// Returns true if the buffered head asks for "100 Continue" before the body
+#[cfg(any(feature = "event-loop", all(feature = "io-uring", target_os = "linux")))]
+pub fn expects_continue(raw: &[u8]) -> bool {
+    let Some(head_len) = head_len(raw) else {
+        return false;
+    };
+    Request::parse(&raw[..head_len])
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+}
+
+// Length of the request head including the blank line, if it is complete
+#[cfg(any(feature = "event-loop", all(feature = "io-uring", target_os = "linux")))]
+fn head_len(raw: &[u8]) -> Option<usize> {
+    raw.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|index| index + 4)
+}
+
+// Runs a buffered request through the pipeline and returns the response bytes
+pub fn serve(
+    input: Vec<u8>,
+    base_dir: &str,
+    router: &Router,
+    transfer: FileTransfer,
+    peer: Peer,
+) -> Vec<u8> {
+    let mut exchange = Exchange {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    };
+    serve_stream(&mut exchange, base_dir, router, transfer, peer, false);
+    exchange.output
+}
+
+// In-memory stream holding one request and collecting the response
+struct Exchange {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Exchange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Exchange {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use crate::conditional::etag;
+use crate::http_date::format_http_date;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{IoSlice, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// In-memory LRU cache of small static files
+/// Cached files are answered by a fast path in serve_connection that skips
+/// request parsing and routing, writing precomposed headers and the body at once.
+/// Each hit costs a single stat of the file: entries whose size or mtime changed
+/// on disk are dropped and reloaded. When the cache is full the least recently
+/// used file makes room for the new one
+/// Example: router.cache_static_files(FileCache::new(64 * 1024, 256));
+pub struct FileCache {
+    max_file_size: u64,
+    max_entries: usize,
+    // Keyed by request path (e.g. "/style.css")
+    entries: RwLock<HashMap<String, Arc<CachedFile>>>,
+    // Incremented on every hit, used to find the least recently used entry
+    clock: AtomicU64,
+}
+
+/// A cached static file with its precomposed response head
+pub struct CachedFile {
+    head: Vec<u8>,
+    body: Vec<u8>,
+    etag: String,
+    file_path: PathBuf,
+    modified: SystemTime,
+    // Value of the cache clock at the last hit
+    last_used: AtomicU64,
+}
+
+impl FileCache {
+    /// Creates a cache holding up to max_entries files of at most max_file_size bytes
+    pub fn new(max_file_size: u64, max_entries: usize) -> FileCache {
+        FileCache {
+            max_file_size,
+            max_entries,
+            entries: RwLock::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached file for a request path
+    /// Returns None, dropping the entry, if the file changed or vanished on disk
+    pub fn get(&self, path: &str) -> Option<Arc<CachedFile>> {
+        let file = self.entries.read().unwrap().get(path).cloned()?;
+        if !file.is_fresh() {
+            let mut entries = self.entries.write().unwrap();
+            // Another thread may have reloaded the entry in the meantime
+            if entries
+                .get(path)
+                .is_some_and(|current| Arc::ptr_eq(current, &file))
+            {
+                entries.remove(path);
+            }
+            return None;
+        }
+        file.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(file)
+    }
+
+    // Drops the entry for a request path, e.g. after the file was replaced
+    // within the mtime granularity of the file system
+    pub(crate) fn forget(&self, path: &str) {
+        self.entries.write().unwrap().remove(path);
+    }
+
+    /// Reads a file into the cache if it is small enough
+    /// Parameters:
+    /// - path: Request path the file is served under
+    /// - file_path: Location of the file on disk
+    /// - content_type: Content-Type sent with the file
+    ///
+    /// Returns the cached file, or None if it is too large or unreadable
+    pub fn load(
+        &self,
+        path: &str,
+        file_path: impl AsRef<Path>,
+        content_type: &str,
+    ) -> Option<Arc<CachedFile>> {
+        self.load_with_headers(path, file_path.as_ref(), content_type, "")
+    }
+
+    // Same as load, with extra header lines (each ending in "\r\n") in the cached head
+    pub(crate) fn load_with_headers(
+        &self,
+        path: &str,
+        file_path: &Path,
+        content_type: &str,
+        headers: &str,
+    ) -> Option<Arc<CachedFile>> {
+        let metadata = fs::metadata(file_path).ok()?;
+        if !metadata.is_file() || metadata.len() > self.max_file_size || self.max_entries == 0 {
+            return None;
+        }
+        let modified = metadata.modified().ok()?;
+        let body = fs::read(file_path).ok()?;
+        let etag = etag(modified, body.len());
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nETag: {}\r\n\
+             Last-Modified: {}\r\n{}\r\n",
+            content_type,
+            body.len(),
+            etag,
+            format_http_date(modified),
+            headers
+        );
+        let file = Arc::new(CachedFile {
+            head: head.into_bytes(),
+            body,
+            etag,
+            file_path: file_path.to_path_buf(),
+            modified,
+            last_used: AtomicU64::new(self.tick()),
+        });
+
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(path) {
+            // Evict the least recently used entry
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, file)| file.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(path.to_string(), Arc::clone(&file));
+        Some(file)
+    }
+
+    /// Returns the number of cached files
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    /// Returns true if no file is cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all cached files
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    // Advances the LRU clock
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl CachedFile {
+    /// Returns the ETag sent with the file, quotes included
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    // Returns true if the file on disk still has the cached size and mtime
+    fn is_fresh(&self) -> bool {
+        fs::metadata(&self.file_path).is_ok_and(|metadata| {
+            metadata.len() == self.body.len() as u64
+                && metadata
+                    .modified()
+                    .is_ok_and(|modified| modified == self.modified)
+        })
+    }
+
+    /// Writes the complete response using vectored writes
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut slices = [IoSlice::new(&self.head), IoSlice::new(&self.body)];
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match writer.write_vectored(slices) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(written) => IoSlice::advance_slices(&mut slices, written),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks up a raw request in the cache without parsing it into a Request
+/// Only complete "GET <path> HTTP/1.x" requests carrying a Host header and no
+/// conditional (If-*) or Range headers qualify; the query string is ignored
+/// like it is for regular static files
+pub fn fast_path_lookup(cache: &FileCache, raw: &[u8]) -> Option<Arc<CachedFile>> {
+    let rest = raw.strip_prefix(b"GET ")?;
+    let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+    let line = &rest[..line_end];
+    let (target, version) = line.split_at(line.iter().position(|&b| b == b' ')?);
+    if !version.starts_with(b" HTTP/1.") {
+        return None;
+    }
+    if !raw.windows(7).any(|w| w == b"\r\nHost:") || !raw.ends_with(b"\r\n\r\n") {
+        return None;
+    }
+    if raw.windows(5).any(|w| w.eq_ignore_ascii_case(b"\r\nIf-"))
+        || raw
+            .windows(8)
+            .any(|w| w.eq_ignore_ascii_case(b"\r\nRange:"))
+    {
+        return None;
+    }
+    let path = match target.iter().position(|&b| b == b'?') {
+        Some(index) => &target[..index],
+        None => target,
+    };
+    cache.get(std::str::from_utf8(path).ok()?)
+}
@@ -0,0 +1,320 @@
+// Gzip compression of route responses
+// A response is compressed when the client accepts gzip, its Content-Type is
+// in the compressible list, its body is at least the minimum size (streamed
+// bodies without a Content-Length always qualify) and its path is not
+// excluded. Responses that are already encoded, partial, or marked
+// Cache-Control: no-transform are sent as they are. Compressed bodies are
+// streamed, and strong ETags become weak since the bytes no longer match.
+//
+// With a cache directory, responses carrying a validator (ETag or
+// Last-Modified) are compressed once per version: the output is written to
+// a file named after the path, validators, encoding and level while it is
+// sent, and later requests for the same version are answered from it. The
+// query is left out, so clients can't make up new entries by the thousand.
+// A file only takes its name once the whole body went through, so bodies
+// cut short are never served from the cache.
+
+use crate::glob::pattern_matches;
+use crate::{Body, Middleware, Next, Request, Response};
+use flate2::read::GzEncoder;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Content types compressed unless replaced with content_types
+const DEFAULT_CONTENT_TYPES: [&str; 7] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+    "application/manifest+json",
+];
+
+/// Middleware compressing responses with gzip
+/// Defaults: bodies of at least 1 KiB, text types plus JSON, JavaScript,
+/// XML, WebAssembly and SVG, level 6. A route opts out with an exclude
+/// pattern or by sending Cache-Control: no-transform. Static files from
+/// the base directory are sent uncompressed, since routes are all it runs
+/// around; Assets mounted with Router::assets are routes and get compressed
+/// Example:
+/// router.layer(
+///     Compression::new()
+///         .min_size(512)
+///         .level(9)
+///         .exclude("/downloads/**")
+///         .cache_dir("/var/cache/site/gzip"),
+/// );
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+    content_types: Vec<String>,
+    level: u32,
+    // Path patterns of routes never compressed
+    excluded: Vec<String>,
+    // Directory compressed bodies of versioned responses are kept in
+    cache_dir: Option<PathBuf>,
+}
+
+impl Compression {
+    /// Creates the middleware with the default settings
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            content_types: DEFAULT_CONTENT_TYPES.map(str::to_string).to_vec(),
+            level: 6,
+            excluded: Vec::new(),
+            cache_dir: None,
+        }
+    }
+
+    /// Sets the smallest body worth compressing, in bytes
+    pub fn min_size(mut self, bytes: usize) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Replaces the compressible content types
+    /// Entries ending in "/" match every subtype, others the exact media type
+    /// Example: Compression::new().content_types(&["text/", "application/json"])
+    pub fn content_types(mut self, content_types: &[&str]) -> Compression {
+        self.content_types = content_types
+            .iter()
+            .map(|content_type| content_type.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Sets the gzip level, from 0 (fastest) to 9 (smallest)
+    /// Panics on levels above 9
+    pub fn level(mut self, level: u32) -> Compression {
+        assert!(level <= 9, "invalid compression level {}", level);
+        self.level = level;
+        self
+    }
+
+    /// Leaves responses for paths matching the pattern uncompressed, with
+    /// the same patterns as Router::cache_control
+    /// Example: Compression::new().exclude("/events").exclude("*.zip")
+    pub fn exclude(mut self, pattern: &str) -> Compression {
+        self.excluded.push(pattern.to_string());
+        self
+    }
+
+    /// Keeps compressed bodies in dir, created if missing, so responses
+    /// carrying an ETag or Last-Modified header are compressed once per
+    /// version instead of on every request
+    /// Entries are keyed by path and validators, not the query string:
+    /// routes whose body depends on the query must vary their validators
+    /// Files of versions no longer served are not removed; clear the
+    /// directory when deploying new content
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Compression {
+        let dir = dir.into();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!(
+                "Cannot create compression cache directory {}: {}",
+                dir.display(),
+                e
+            );
+        }
+        self.cache_dir = Some(dir);
+        self
+    }
+
+    // Returns the cache file of a response's compressed body, None for
+    // responses without a validator or without a cache directory
+    fn cache_path(&self, request: &Request, response: &Response) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+        let etag = response.header("ETag");
+        let modified = response.header("Last-Modified");
+        if response.status != 200 || (etag.is_none() && modified.is_none()) {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (&request.path, etag, modified).hash(&mut hasher);
+        ("gzip", self.level).hash(&mut hasher);
+        Some(dir.join(format!("{:016x}.gz", hasher.finish())))
+    }
+
+    // Returns true if the response's body may be compressed
+    fn is_compressible(&self, response: &Response) -> bool {
+        if matches!(response.status, 204 | 206 | 304)
+            || response.header("Content-Encoding").is_some()
+            || response.header("Content-Range").is_some()
+            || response
+                .header("Cache-Control")
+                .is_some_and(|value| has_directive(value, "no-transform"))
+        {
+            return false;
+        }
+        let Some(content_type) = response.header("Content-Type") else {
+            return false;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let listed = self.content_types.iter().any(|listed| {
+            if listed.ends_with('/') {
+                media_type.starts_with(listed.as_str())
+            } else {
+                media_type == *listed
+            }
+        });
+        let size = match &response.body {
+            Body::Full(bytes) => Some(bytes.len()),
+            _ => response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok()),
+        };
+        listed && size.is_none_or(|size| size >= self.min_size)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let mut response = next.run(request);
+        if self
+            .excluded
+            .iter()
+            .any(|pattern| pattern_matches(pattern, &request.path))
+            || !self.is_compressible(&response)
+        {
+            return response;
+        }
+        // The body depends on Accept-Encoding from here on, whatever it says
+        let accepts_gzip = request
+            .vary_header("Accept-Encoding")
+            .is_some_and(accepts_gzip);
+        if !accepts_gzip {
+            return response;
+        }
+
+        let body = std::mem::take(&mut response.body);
+        response
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        let cache_path = self.cache_path(request, &response);
+        let cached = cache_path
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| Some((file.metadata().ok()?.len(), file)));
+        if let Some((len, file)) = cached {
+            response.body = Body::from_reader(file);
+            response = response.with_header("Content-Length", &len.to_string());
+        } else {
+            let level = flate2::Compression::new(self.level);
+            let gzip = GzEncoder::new(body.into_reader(), level);
+            response.body = match cache_path {
+                Some(path) => Body::from_reader(CacheFill::new(gzip, path)),
+                None => Body::from_reader(gzip),
+            };
+        }
+        for (name, value) in &mut response.headers {
+            if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                value.insert_str(0, "W/");
+            }
+        }
+        response.with_header("Content-Encoding", "gzip")
+    }
+}
+
+// Copies a compressed body into the cache while it is read, naming the file
+// once the end of the body was reached
+struct CacheFill<R: Read> {
+    inner: R,
+    // None once writing failed or the file was completed
+    file: Option<File>,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl<R: Read> CacheFill<R> {
+    fn new(inner: R, path: PathBuf) -> CacheFill<R> {
+        // Unique, so concurrent requests for the same version don't mix
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let temp = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        CacheFill {
+            inner,
+            file: File::create(&temp).ok(),
+            temp,
+            path,
+        }
+    }
+}
+
+impl<R: Read> Read for CacheFill<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if let Some(file) = &mut self.file {
+            if bytes_read == 0 {
+                self.file = None;
+                if fs::rename(&self.temp, &self.path).is_err() {
+                    remove_quietly(&self.temp);
+                }
+            } else if file.write_all(&buf[..bytes_read]).is_err() {
+                self.file = None;
+                remove_quietly(&self.temp);
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read> Drop for CacheFill<R> {
+    fn drop(&mut self) {
+        // The body was not read to the end, e.g. the client went away
+        if self.file.take().is_some() {
+            remove_quietly(&self.temp);
+        }
+    }
+}
+
+// Removes a partial cache file; it is garbage either way
+fn remove_quietly(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+// Returns true if an Accept-Encoding value allows gzip, explicitly or
+// through "*", with a non-zero weight
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    let weight = |coding: &str| {
+        accept_encoding.split(',').find_map(|entry| {
+            let mut params = entry.split(';');
+            if !params.next()?.trim().eq_ignore_ascii_case(coding) {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some(quality)
+        })
+    };
+    weight("gzip")
+        .or_else(|| weight("*"))
+        .is_some_and(|q| q > 0.0)
+}
+
+// Returns true if a Cache-Control value contains the directive
+fn has_directive(cache_control: &str, directive: &str) -> bool {
+    cache_control
+        .split(',')
+        .any(|entry| entry.trim().eq_ignore_ascii_case(directive))
+}
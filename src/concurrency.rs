@@ -0,0 +1,82 @@
+// Concurrency limit for route handlers
+// A counter of requests inside the chain acts as a semaphore without a
+// queue: a request arriving when all permits are taken is answered right
+// away instead of waiting, so an expensive endpoint under load sheds
+// requests rather than piling up worker threads behind it.
+
+use crate::{Middleware, Next, Request, Response, StatusCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Middleware letting at most max requests run the handlers behind it at
+/// once; the others are answered with 503 Service Unavailable and a
+/// Retry-After header (1 second by default)
+/// Added with Router::with it limits that route, or the group's routes
+/// together; added with layer inside a scope, every route of the scope
+/// shares the limit
+/// Example:
+/// router
+///     .post("/reports/render", render_report)
+///     .with(ConcurrencyLimit::new(4).retry_after(Duration::from_secs(10)));
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    max: usize,
+    retry_after: Duration,
+    running: AtomicUsize,
+}
+
+// Gives a permit back when the request is done, also if the handler panicked
+struct Permit<'a>(&'a AtomicUsize);
+
+impl ConcurrencyLimit {
+    /// Creates a limit of max concurrent requests
+    /// Panics if max is 0
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        assert!(max > 0, "ConcurrencyLimit needs at least one permit");
+        ConcurrencyLimit {
+            max,
+            retry_after: Duration::from_secs(1),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the delay sent in Retry-After with shed requests, rounded up to
+    /// whole seconds
+    pub fn retry_after(mut self, delay: Duration) -> ConcurrencyLimit {
+        self.retry_after = delay;
+        self
+    }
+
+    /// Returns how many requests are running the handlers behind it
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // Takes a permit if one is free
+    fn acquire(&self) -> Option<Permit<'_>> {
+        self.running
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |running| {
+                (running < self.max).then_some(running + 1)
+            })
+            .ok()?;
+        Some(Permit(&self.running))
+    }
+}
+
+impl Middleware for ConcurrencyLimit {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let Some(_permit) = self.acquire() else {
+            let seconds =
+                self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+            return Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_header("Retry-After", &seconds.to_string());
+        };
+        next.run(request)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+}
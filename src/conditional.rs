@@ -0,0 +1,136 @@
+// Conditional requests (RFC 9110 section 13)
+// Preconditions are evaluated in the order of section 13.2.2: If-Match, or
+// If-Unmodified-Since without it, then If-None-Match, or If-Modified-Since
+// without it for GET and HEAD. A failed If-Match or If-Unmodified-Since
+// gets 412, a matching If-None-Match 304 for GET and HEAD and 412 for other
+// methods, so writes can be made safe against lost updates. If-Range is
+// left to range requests.
+
+use crate::http_date::{format_http_date, parse_http_date};
+use crate::{Request, Response, StatusCode};
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Headers taking part in the evaluation
+const CONDITIONAL_HEADERS: [&str; 4] = [
+    "If-Match",
+    "If-Unmodified-Since",
+    "If-None-Match",
+    "If-Modified-Since",
+];
+
+// Current validators of a resource
+pub struct Validators {
+    pub etag: String,
+    pub modified: SystemTime,
+}
+
+impl Validators {
+    // Builds the validators of a file or directory from its metadata
+    pub fn of(metadata: &Metadata) -> Option<Validators> {
+        let modified = metadata.modified().ok()?;
+        Some(Validators {
+            etag: etag(modified, metadata.len() as usize),
+            modified,
+        })
+    }
+
+    // Returns the ETag and Last-Modified header lines, each ending in "\r\n"
+    #[cfg(feature = "static")]
+    pub fn header_lines(&self) -> String {
+        format!(
+            "ETag: {}\r\nLast-Modified: {}\r\n",
+            self.etag,
+            format_http_date(self.modified)
+        )
+    }
+}
+
+// Returns true if the request carries any precondition header
+pub fn is_conditional(request: &Request) -> bool {
+    CONDITIONAL_HEADERS
+        .iter()
+        .any(|name| request.header(name).is_some())
+}
+
+// Evaluates the request's preconditions against the target resource
+// current is None if the resource does not exist
+// Returns the 304 or 412 response to send instead of performing the method,
+// or None if the request should proceed
+pub fn evaluate(request: &Request, current: Option<&Validators>) -> Option<Response> {
+    // Steps 1 and 2: the client's copy must still be the current one
+    if let Some(tags) = request.header("If-Match") {
+        if !current.is_some_and(|current| matches_any(tags, &current.etag, true)) {
+            return Some(Response::from_status(StatusCode::PRECONDITION_FAILED));
+        }
+    } else if let Some(since) = request
+        .header("If-Unmodified-Since")
+        .and_then(parse_http_date)
+        && let Some(current) = current
+        && whole_seconds(current.modified) > whole_seconds(since)
+    {
+        return Some(Response::from_status(StatusCode::PRECONDITION_FAILED));
+    }
+
+    // Steps 3 and 4: nothing to send if the client's copy is still current
+    let safe = matches!(request.method.as_str(), "GET" | "HEAD");
+    let current = current?;
+    if let Some(tags) = request.header("If-None-Match") {
+        if !matches_any(tags, &current.etag, false) {
+            return None;
+        }
+        if !safe {
+            return Some(Response::from_status(StatusCode::PRECONDITION_FAILED));
+        }
+    } else {
+        let since = request
+            .header("If-Modified-Since")
+            .and_then(parse_http_date)
+            .filter(|_| safe)?;
+        if whole_seconds(current.modified) > whole_seconds(since) {
+            return None;
+        }
+    }
+    Some(
+        Response::from_status(StatusCode::NOT_MODIFIED)
+            .with_header("ETag", &current.etag)
+            .with_header("Last-Modified", &format_http_date(current.modified)),
+    )
+}
+
+// Builds an ETag from a file's modification time and size
+// Example: "17a0c1f2e3b4c5d6-2a"
+pub fn etag(modified: SystemTime, len: usize) -> String {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", nanos, len)
+}
+
+// Returns true if a comma-separated entity-tag list (or "*") matches the ETag
+// Strong comparison requires both tags to be strong, weak comparison only
+// compares their opaque parts; ETags built here are always strong, those
+// stored by the proxy's cache may be weak
+pub(crate) fn matches_any(tags: &str, etag: &str, strong: bool) -> bool {
+    if tags.trim() == "*" {
+        return true;
+    }
+    let (weak_etag, opaque) = match etag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, etag),
+    };
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| match tag.strip_prefix("W/") {
+            Some(weak) => !strong && weak == opaque,
+            None => tag == opaque && !(strong && weak_etag),
+        })
+}
+
+// Drops the sub-second part HTTP dates cannot express
+fn whole_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
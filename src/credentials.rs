@@ -0,0 +1,59 @@
+// Credentials
+// Basic credentials decoded from an Authorization or Proxy-Authorization
+// header, and the comparison of a secret against the expected one, shared by
+// the admin endpoints, WebDAV, .override authentication, tus and the forward
+// proxy, along with the base64 decoding they, tus metadata and h2c upgrades
+// need. Secrets are compared in time that depends only on their lengths, so
+// they can't be guessed byte by byte.
+
+use crate::Request;
+
+// Returns the user name and password of Basic credentials in the header
+pub(crate) fn basic_credentials(request: &Request, header: &str) -> Option<(String, String)> {
+    let encoded = request.header(header)?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64_decode(encoded.trim(), STANDARD)?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Returns true if the secret given is the one expected, comparing in
+// constant time
+pub(crate) fn secret_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Characters standing for 62 and 63 in standard base64
+pub(crate) const STANDARD: &[u8; 2] = b"+/";
+// Characters standing for 62 and 63 in base64url, as used by HTTP2-Settings
+#[cfg(feature = "http2")]
+pub(crate) const URL_SAFE: &[u8; 2] = b"-_";
+
+// Decodes base64 in the alphabet ending with the two characters given, with
+// or without padding
+pub(crate) fn base64_decode(input: &str, alphabet: &[u8; 2]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(input.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in input.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            _ if byte == alphabet[0] => 62,
+            _ if byte == alphabet[1] => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(decoded)
+}
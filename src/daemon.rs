@@ -0,0 +1,129 @@
+// Running as a daemon
+// daemonize detaches the process from the terminal that started it the
+// traditional way: fork so the shell gets its prompt back, setsid to leave
+// the controlling terminal, fork again so the daemon can never acquire
+// another one, then point stdin at /dev/null and stdout and stderr at the
+// log. The working directory is kept, as the static directory is usually
+// relative to it. It must run before any thread is spawned: only the
+// calling thread survives a fork.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Detaches the process from its terminal and runs it in the background
+/// Returns in the daemon only; the process that called it exits. stdin
+/// reads from /dev/null and stdout and stderr, eprintln! messages included,
+/// go to the log file, appended to, or to /dev/null without one. Call it
+/// first thing in main, before starting any thread
+/// Example:
+/// daemonize(Some(Path::new("/var/log/app/server.log")))?;
+/// let _pid_file = PidFile::create("/run/app.pid")?;
+pub fn daemonize(log: Option<&Path>) -> io::Result<()> {
+    // Opened up front so that errors still reach the terminal
+    let null = File::open("/dev/null")?;
+    let output = match log {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let _ = io::stdout().flush();
+
+    fork_and_exit_parent()?;
+    // SAFETY: setsid takes no arguments; the child is not a group leader
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+
+    for (file, fd) in [
+        (&null, libc::STDIN_FILENO),
+        (&output, libc::STDOUT_FILENO),
+        (&output, libc::STDERR_FILENO),
+    ] {
+        // SAFETY: both descriptors are open; dup2 closes the previous fd
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Forks, the parent exiting right away and the child returning
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: the process is single-threaded, as daemonize requires
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // Skips destructors and atexit handlers, which belong to the child
+        // SAFETY: _exit ends the process without touching its state
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// A file holding the process id, as read by init scripts and logrotate
+/// ("kill -USR1 $(cat /run/app.pid)"), removed when dropped
+/// Creating it fails if the file names a process that is still running,
+/// so a second instance doesn't start by mistake; a file left behind by a
+/// process that died is replaced
+/// Example:
+/// let _pid_file = PidFile::create("/run/app.pid")?;
+/// server.run("0.0.0.0:80")?; // The file is removed once run returns
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the id of the current process to the file
+    pub fn create(path: impl AsRef<Path>) -> io::Result<PidFile> {
+        let path = path.as_ref();
+        if let Some(pid) = fs::read_to_string(path)
+            .ok()
+            .and_then(|text| text.trim().parse::<libc::pid_t>().ok())
+        {
+            if pid != process::id() as libc::pid_t && running(pid) {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} names process {}, still running", path.display(), pid),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Tells whether a process with the id exists, even one of another user
+fn running(pid: libc::pid_t) -> bool {
+    // 0 and negative ids stand for process groups
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 only checks that the process could be signalled
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -0,0 +1,221 @@
+// Debug capture of requests and responses
+// While enabled, the exchanges of matching paths are printed to stderr with
+// their headers and the start of their bodies, to see exactly what a client
+// sends and gets back. The request is printed before the handlers run, so
+// it shows up even if they hang or panic, and the response once they
+// return; both carry the same exchange number. Bodies are cut at a limit
+// and printed on one line, as text when they are UTF-8 and byte-escaped
+// otherwise. Streamed response bodies are printed once they were sent, and
+// request bodies streamed to the handler (see Router::stream_body) are not
+// captured.
+
+use crate::glob::pattern_matches;
+use crate::{Body, Middleware, Next, Request, Response};
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+// Headers whose values are masked, in lowercase
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Middleware printing the requests of matching paths and their responses,
+/// headers and bodies included, to diagnose client integrations
+/// Bodies are cut after 1 KiB (see max_body) and the values of
+/// Authorization, Proxy-Authorization, Cookie and Set-Cookie are masked.
+/// Clones share the switch, so a clone kept aside turns capture on and off
+/// while the server runs
+/// Example:
+/// let capture = DebugCapture::new().path("/api/payments/**").max_body(4096);
+/// router.layer(capture.clone());
+/// // Later, e.g. from an admin route
+/// capture.set_enabled(false);
+#[derive(Clone, Debug)]
+pub struct DebugCapture {
+    // Path patterns captured, all paths if empty
+    patterns: Vec<String>,
+    max_body: usize,
+    enabled: Arc<AtomicBool>,
+    // Numbers the exchanges, shared by clones
+    exchanges: Arc<AtomicU64>,
+}
+
+// A streamed response body printing its start once it was sent
+struct Captured {
+    inner: Box<dyn Read + Send>,
+    exchange: u64,
+    max_body: usize,
+    head: Vec<u8>,
+    sent: u64,
+}
+
+impl DebugCapture {
+    /// Creates the middleware capturing every path, enabled
+    pub fn new() -> DebugCapture {
+        DebugCapture {
+            patterns: Vec::new(),
+            max_body: 1024,
+            enabled: Arc::new(AtomicBool::new(true)),
+            exchanges: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Only captures paths matching the pattern, with the same patterns as
+    /// Router::cache_control; may be called for several patterns
+    /// Example: DebugCapture::new().path("/webhooks/**").path("/api/orders")
+    pub fn path(mut self, pattern: &str) -> DebugCapture {
+        self.patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Sets how many bytes of each body are printed
+    pub fn max_body(mut self, bytes: usize) -> DebugCapture {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Turns capture on or off, for this middleware and all its clones
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns true while exchanges are captured
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn captures(&self, request: &Request) -> bool {
+        self.is_enabled()
+            && (self.patterns.is_empty()
+                || self
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern_matches(pattern, &request.path)))
+    }
+}
+
+impl Default for DebugCapture {
+    fn default() -> DebugCapture {
+        DebugCapture::new()
+    }
+}
+
+impl Middleware for DebugCapture {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        if !self.captures(request) {
+            return next.run(request);
+        }
+        let exchange = self.exchanges.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut capture = format!(
+            "--> #{} {} {}{}{} {} from {}\n",
+            exchange,
+            request.method,
+            request.path,
+            if request.query.is_empty() { "" } else { "?" },
+            request.query,
+            request.version,
+            request
+                .client_ip()
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+        );
+        let mut headers: Vec<_> = request.headers.iter().collect();
+        headers.sort();
+        for (name, value) in headers {
+            write_header(&mut capture, name, value);
+        }
+        if request.body_stream.is_some() {
+            capture.push_str("    body streamed to the handler, not captured\n");
+        } else if !request.body.is_empty() {
+            write_body(
+                &mut capture,
+                &request.body,
+                request.body.len() as u64,
+                self.max_body,
+            );
+        }
+        eprint!("{}", capture);
+
+        let started = Instant::now();
+        let mut response = next.run(request);
+        let mut capture = format!(
+            "<-- #{} {} {} in {:.1} ms\n",
+            exchange,
+            response.status,
+            response.reason,
+            started.elapsed().as_secs_f64() * 1000.0
+        );
+        for (name, value) in &response.headers {
+            write_header(&mut capture, name, value);
+        }
+        match &response.body {
+            Body::Full(bytes) if bytes.is_empty() => {}
+            Body::Full(bytes) => write_body(&mut capture, bytes, bytes.len() as u64, self.max_body),
+            Body::Reader(_) | Body::Chunks(_) => {
+                capture.push_str("    body streamed, printed once sent\n");
+                let body = std::mem::take(&mut response.body);
+                response.body = Body::from_reader(Captured {
+                    inner: body.into_reader(),
+                    exchange,
+                    max_body: self.max_body,
+                    head: Vec::new(),
+                    sent: 0,
+                });
+            }
+        }
+        eprint!("{}", capture);
+        response
+    }
+}
+
+impl Read for Captured {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let room = self.max_body.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&buf[..len.min(room)]);
+        self.sent += len as u64;
+        Ok(len)
+    }
+}
+
+// Prints the body when the response is done with it, sent in full or not
+impl Drop for Captured {
+    fn drop(&mut self) {
+        let mut capture = format!("<-- #{} streamed body sent\n", self.exchange);
+        write_body(&mut capture, &self.head, self.sent, self.max_body);
+        eprint!("{}", capture);
+    }
+}
+
+fn write_header(capture: &mut String, name: &str, value: &str) {
+    let value = match SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        true => "[masked]",
+        false => value,
+    };
+    let _ = writeln!(capture, "    {}: {}", name, value);
+}
+
+// Appends the start of a body of len bytes, escaped so that it stays on one
+// line: as text if it is UTF-8, byte by byte otherwise
+fn write_body(capture: &mut String, body: &[u8], len: u64, max_body: usize) {
+    let shown = &body[..body.len().min(max_body)];
+    let text = match std::str::from_utf8(shown) {
+        Ok(text) => text.escape_debug().to_string(),
+        // Text cut in the middle of a character
+        Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&shown[..e.valid_up_to()])
+            .escape_debug()
+            .to_string(),
+        Err(_) => shown.escape_ascii().to_string(),
+    };
+    let cut = match len > shown.len() as u64 {
+        true => ", cut",
+        false => "",
+    };
+    let _ = writeln!(capture, "    body ({} bytes{}): {}", len, cut, text);
+}
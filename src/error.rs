@@ -0,0 +1,118 @@
+// Errors returned by fallible route handlers
+// A handler returning Result<_, Error> can use ? on any error type: it
+// becomes a 500 (404 or 403 for io errors saying the file is missing or
+// off limits) whose message is the reason phrase, so internal details
+// don't reach the client. Server errors are logged with the request they
+// failed and the chain of underlying errors.
+
+use crate::{IntoResponse, Request, Response, StatusCode};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// An error answered with a status and a plain text message
+/// Example:
+/// fn show_user(Path(id): Path<u32>) -> Result<Json<User>, Error> {
+///     let data = std::fs::read_to_string(format!("users/{}.json", id))?;
+///     let user = serde_json::from_str(&data)?;
+///     if banned(&user) {
+///         return Err(Error::new(StatusCode::FORBIDDEN, "user is banned"));
+///     }
+///     Ok(Json(user))
+/// }
+pub struct Error {
+    status: StatusCode,
+    message: String,
+    source: Option<Box<dyn StdError + Send + Sync>>,
+}
+
+impl Error {
+    /// Creates an error answered with the status and message
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Error {
+        Error {
+            status,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Creates a 400 Bad Request error
+    pub fn bad_request(message: impl Into<String>) -> Error {
+        Error::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// Creates a 404 Not Found error
+    pub fn not_found(message: impl Into<String>) -> Error {
+        Error::new(StatusCode::NOT_FOUND, message)
+    }
+
+    /// Keeps the error that caused this one, logged with it
+    pub fn with_source(mut self, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Error {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Returns the status the error is answered with
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the message sent to the client
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+// Any error can be returned with ?, as an internal error unless it is an io
+// error about a missing or forbidden file
+impl<E: StdError + Send + Sync + 'static> From<E> for Error {
+    fn from(error: E) -> Error {
+        let source: Box<dyn StdError + Send + Sync> = Box::new(error);
+        let status = match source.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::NotFound) => StatusCode::NOT_FOUND,
+            Some(io::ErrorKind::PermissionDenied) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Error {
+            status,
+            message: status.reason().to_string(),
+            source: Some(source),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status.as_u16(), self.message)?;
+        // Followed by the chain of causes
+        let mut source = self.source.as_deref().map(|source| source as &dyn StdError);
+        while let Some(error) = source {
+            write!(f, ": {}", error)?;
+            source = error.source();
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Error")
+            .field("status", &self.status)
+            .field("message", &self.message)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        Response::from_status(self.status).with_body("text/plain", self.message)
+    }
+
+    fn into_response_to(self, request: &Request) -> Response {
+        if self.status.as_u16() >= 500 {
+            eprintln!("{} {} failed: {}", request.method, request.path, self);
+        }
+        self.into_response()
+    }
+}
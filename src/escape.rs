@@ -0,0 +1,33 @@
+// Escaping of text
+// Quoting for the JSON written by the admin endpoints, the access log, the
+// statistics and directory listings, and escaping for the HTML of listings,
+// Markdown pages and the status page, and the XML of WebDAV.
+
+use std::fmt::Write as _;
+
+// Quotes and escapes a string for JSON
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Escapes text for use in HTML
+#[cfg(any(feature = "static", feature = "metrics", feature = "webdav"))]
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,250 @@
+// Event-driven connection handling
+// Each event loop thread multiplexes many non-blocking connections with
+// epoll/kqueue (through mio) instead of parking one thread per connection.
+// Requests are buffered until complete, then run through the regular
+// pipeline in memory, and the response is written back as the socket accepts
+// it. Handlers run on the loop thread, so slow handlers stall every
+// connection of that loop; responses (including static files) are buffered
+// in memory before being sent.
+
+use crate::buffered::{self, expects_continue, request_complete};
+use crate::proxy_protocol::{self, Preamble};
+use crate::server::ACCEPT_CHECK_INTERVAL;
+use crate::stats::OpenConnection;
+use crate::{FileTransfer, IDLE_PROBE_INTERVAL, MAX_IDLE_PROBES, Peer, Server};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+const LISTENER: Token = Token(usize::MAX);
+
+// A connection owned by an event loop
+struct Connection {
+    stream: TcpStream,
+    input: Vec<u8>,
+    // Response bytes and how many of them were sent
+    output: Vec<u8>,
+    written: usize,
+    responded: bool,
+    continue_sent: bool,
+    last_active: Instant,
+    remote_addr: Option<SocketAddr>,
+    // The input should start with a PROXY protocol header not read yet
+    awaiting_proxy_header: bool,
+    _open: OpenConnection,
+}
+
+// Outcome of handling a readiness event
+enum Progress {
+    Pending,
+    Done,
+}
+
+/// Serves connections from the listener on the current thread until polling
+/// fails, or until the listener was handed over and its connections are done
+/// Several loops can share clones of the same listener
+pub fn run(listener: std::net::TcpListener, server: &Server) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    let mut events = Events::with_capacity(1024);
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 0;
+    let idle_limit = IDLE_PROBE_INTERVAL * MAX_IDLE_PROBES;
+    let mut accepting = true;
+
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(ACCEPT_CHECK_INTERVAL)) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                accept_all(&listener, &poll, &mut connections, &mut next_token, server);
+                continue;
+            }
+            let token = event.token();
+            let Some(connection) = connections.get_mut(&token) else {
+                continue;
+            };
+            connection.last_active = Instant::now();
+            match connection.advance(&poll, token, server) {
+                Ok(Progress::Pending) => {}
+                Ok(Progress::Done) | Err(_) => {
+                    if let Some(mut connection) = connections.remove(&token) {
+                        let _ = poll.registry().deregister(&mut connection.stream);
+                    }
+                }
+            }
+        }
+
+        // Drop connections that stayed silent as long as the threaded backend tolerates
+        connections.retain(|_, connection| connection.last_active.elapsed() < idle_limit);
+
+        if accepting && !server.is_accepting() {
+            accepting = false;
+            poll.registry().deregister(&mut listener)?;
+        }
+        if !accepting && connections.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+// Accepts every pending connection and registers it for reading
+fn accept_all(
+    listener: &TcpListener,
+    poll: &Poll,
+    connections: &mut HashMap<Token, Connection>,
+    next_token: &mut usize,
+    server: &Server,
+) {
+    let proxy_protocol = server.expects_proxy_header();
+    loop {
+        let (mut stream, remote_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                return;
+            }
+        };
+
+        server.configure_connection(&stream);
+
+        let token = Token(*next_token);
+        *next_token = (*next_token + 1) % LISTENER.0;
+        if let Err(e) = poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)
+        {
+            eprintln!("Failed to register connection: {}", e);
+            continue;
+        }
+        connections.insert(
+            token,
+            Connection {
+                _open: server.open_connection(socket_clone(&stream)),
+                stream,
+                input: Vec::new(),
+                output: Vec::new(),
+                written: 0,
+                responded: false,
+                continue_sent: false,
+                last_active: Instant::now(),
+                remote_addr: Some(remote_addr),
+                awaiting_proxy_header: proxy_protocol,
+            },
+        );
+    }
+}
+
+impl Connection {
+    // Reads what is available, responds once the request is complete and
+    // writes as much of the response as the socket takes
+    fn advance(&mut self, poll: &Poll, token: Token, server: &Server) -> io::Result<Progress> {
+        if !self.responded {
+            let closed = self.read_available()?;
+            if closed && self.input.is_empty() {
+                return Ok(Progress::Done);
+            }
+            if self.awaiting_proxy_header {
+                match proxy_protocol::parse(&self.input) {
+                    Preamble::Incomplete(_) if !closed => return Ok(Progress::Pending),
+                    Preamble::Header { len, source } => {
+                        self.input.drain(..len);
+                        self.remote_addr = source.or(self.remote_addr);
+                        self.awaiting_proxy_header = false;
+                    }
+                    _ => return Ok(Progress::Done),
+                }
+            }
+            if !closed && !request_complete(&self.input, &server.site().router) {
+                self.send_continue();
+                return Ok(Progress::Pending);
+            }
+            self.respond(server);
+            poll.registry()
+                .reregister(&mut self.stream, token, Interest::WRITABLE)?;
+        }
+        self.write_available()
+    }
+
+    // Reads until the socket would block
+    // Returns true if the client closed its side
+    fn read_available(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(true),
+                Ok(n) => self.input.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Tells clients sending "Expect: 100-continue" to go ahead with the body
+    fn send_continue(&mut self) {
+        if !self.continue_sent && expects_continue(&self.input) {
+            self.continue_sent = true;
+            let _ = self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+    }
+
+    // Runs the buffered request through the regular pipeline, with the
+    // router and base directory current at the time
+    fn respond(&mut self, server: &Server) {
+        let input = std::mem::take(&mut self.input);
+        let site = server.site();
+        self.output = buffered::serve(
+            input,
+            &site.base_dir,
+            &site.router,
+            FileTransfer::Copy,
+            Peer {
+                addr: self.remote_addr,
+                ..Peer::default()
+            },
+        );
+        self.responded = true;
+    }
+
+    // Writes until the socket would block or the response is sent
+    fn write_available(&mut self) -> io::Result<Progress> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Progress::Pending),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Progress::Done)
+    }
+}
+
+// Duplicates the socket, so that a drain deadline can abort the connection
+#[cfg(unix)]
+fn socket_clone(stream: &TcpStream) -> Option<std::net::TcpStream> {
+    use std::os::fd::AsFd;
+    let fd = stream.as_fd().try_clone_to_owned().ok()?;
+    Some(std::net::TcpStream::from(fd))
+}
+
+#[cfg(not(unix))]
+fn socket_clone(_stream: &TcpStream) -> Option<std::net::TcpStream> {
+    None
+}
@@ -0,0 +1,77 @@
+// Typed extractors for route handlers
+// Each extractor parses one part of the request before the handler runs.
+// When parsing fails the handler is skipped and a 400 Bad Request is sent.
+
+use crate::{BodyReader, Request, Response};
+use std::collections::HashMap;
+
+#[cfg(feature = "router")]
+mod typed;
+#[cfg(feature = "router")]
+pub use typed::{Json, Path, Query};
+
+/// Types that can be built from an incoming request
+/// Returning Err skips the handler and sends the given response instead
+pub trait FromRequest: Sized {
+    fn from_request(request: &Request) -> Result<Self, Response>;
+}
+
+/// Gives the handler the whole request
+impl FromRequest for Request {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.clone())
+    }
+}
+
+/// All request headers
+pub struct Headers(pub HashMap<String, String>);
+
+impl Headers {
+    /// Returns the value of a header, comparing names case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(Headers(request.headers.clone()))
+    }
+}
+
+/// The raw request body, byte for byte
+/// Example: fn upload(Bytes(data): Bytes) -> Response
+pub struct Bytes(pub Vec<u8>);
+
+impl FromRequest for Bytes {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(Bytes(request.body.clone()))
+    }
+}
+
+/// The request body, read as the handler goes on routes registered with
+/// Router::stream_body
+/// Example: fn upload(mut body: BodyReader) -> Response
+impl FromRequest for BodyReader {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.body_reader())
+    }
+}
+
+/// The request body as text, rejected with 400 if it is not valid UTF-8
+pub struct Text(pub String);
+
+impl FromRequest for Text {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        match String::from_utf8(request.body.clone()) {
+            Ok(text) => Ok(Text(text)),
+            Err(e) => Err(Response::bad_request(&format!(
+                "Request body is not valid UTF-8: {}",
+                e.utf8_error()
+            ))),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Rules for serving files from a directory
+// Static files and WebDAV shares both refuse paths through symbolic links
+// the router's SymlinkPolicy doesn't follow, and hide files and directories
+// whose names start with "." unless Router::allow_hidden lets them through.
+// WebDAV shares hold a copy of the rules, which the router updates as they
+// are set, so they apply whether set before or after the share is mounted.
+
+#[cfg(feature = "static")]
+use crate::overrides::OVERRIDE_FILE;
+use std::fs::{self, Metadata};
+use std::path::Path;
+
+/// Which symbolic links under the base directory static files are served
+/// through (see Router::symlinks)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    // Links are followed wherever they point
+    #[default]
+    Follow,
+    // A link is only followed if its owner also owns its target, like
+    // Apache's SymLinksIfOwnerMatch; links are refused where files have no
+    // owner (Windows)
+    FollowIfSameOwner,
+    // Paths going through a link are refused
+    Deny,
+}
+
+// The router's symbolic link policy and the patterns of hidden files served
+// nonetheless
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FileRules {
+    pub symlinks: SymlinkPolicy,
+    pub hidden_allowed: Vec<String>,
+}
+
+impl FileRules {
+    // Tells whether a file, by its path relative to the root, is hidden:
+    // it goes through a hidden name no allow_hidden pattern matches
+    pub(crate) fn hides(&self, relative: &Path) -> bool {
+        if !is_hidden(relative) {
+            return false;
+        }
+        // Override files hold passwords, whatever the patterns
+        #[cfg(feature = "static")]
+        if relative.ends_with(OVERRIDE_FILE) {
+            return true;
+        }
+        let path = relative.iter().fold(String::new(), |path, name| {
+            path + "/" + &*name.to_string_lossy()
+        });
+        !self
+            .hidden_allowed
+            .iter()
+            .any(|pattern| crate::glob::pattern_matches(pattern, &path))
+    }
+}
+
+// Tells whether the policy lets the relative path be served from base_dir
+// Every component under base_dir is checked, so a link to a directory
+// refuses the files under it too; base_dir itself may be a link. Components
+// that don't exist pass, the file is then not found
+pub fn links_allowed(policy: SymlinkPolicy, base_dir: &Path, relative: &Path) -> bool {
+    if policy == SymlinkPolicy::Follow {
+        return true;
+    }
+    let mut path = base_dir.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        let Ok(link) = fs::symlink_metadata(&path) else {
+            return true;
+        };
+        if !link.file_type().is_symlink() {
+            continue;
+        }
+        let allowed = policy == SymlinkPolicy::FollowIfSameOwner
+            && fs::metadata(&path).is_ok_and(|target| same_owner(&link, &target));
+        if !allowed {
+            return false;
+        }
+    }
+    true
+}
+
+// Tells whether the relative path goes through a hidden file or directory,
+// one whose name starts with "." such as .env or .git
+pub fn is_hidden(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|component| component.as_os_str().as_encoded_bytes().starts_with(b"."))
+}
+
+#[cfg(unix)]
+fn same_owner(link: &Metadata, target: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    link.uid() == target.uid()
+}
+
+#[cfg(not(unix))]
+fn same_owner(_link: &Metadata, _target: &Metadata) -> bool {
+    false
+}
@@ -0,0 +1,135 @@
+// Client address and scheme behind reverse proxies
+// Proxies report the client they forwarded for in Forwarded (RFC 7239) or
+// X-Forwarded-For/X-Forwarded-Proto. Anyone can send those headers, so they
+// are only believed when the peer is a trusted proxy, and the forwarding
+// chain is walked from the nearest hop back, skipping trusted proxies: the
+// first other address is the client.
+
+use crate::Request;
+use std::net::IpAddr;
+
+// An IP address or CIDR range, e.g. "10.0.0.0/8", "::1" or "fd00::/8"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    // Parses an address or CIDR range
+    // Returns None for anything else, or a prefix longer than the address
+    pub fn parse(range: &str) -> Option<IpRange> {
+        let (address, prefix_len) = match range.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len.parse().ok()?)),
+            None => (range, None),
+        };
+        let network: IpAddr = address.parse().ok()?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+
+    // Returns true if the address falls within the range
+    // IPv4-mapped IPv6 addresses match IPv4 ranges
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Fills in the request's forwarded client address and scheme if its peer
+// is one of the trusted proxies
+pub fn resolve(request: &mut Request, trusted: &[IpRange]) {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    let Some(peer) = request.remote_addr else {
+        return;
+    };
+    if !is_trusted(peer.ip()) {
+        return;
+    }
+
+    // Hops as (address, scheme), the client first and the nearest proxy last
+    let hops: Vec<(Option<IpAddr>, Option<String>)> = match request.header("Forwarded") {
+        Some(forwarded) => forwarded.split(',').map(parse_forwarded_element).collect(),
+        None => {
+            let proto = request
+                .header("X-Forwarded-Proto")
+                .and_then(|proto| proto.split(',').next())
+                .map(|proto| proto.trim().to_ascii_lowercase());
+            request
+                .header("X-Forwarded-For")
+                .unwrap_or("")
+                .split(',')
+                .filter(|node| !node.trim().is_empty())
+                .map(|node| (parse_node(node), proto.clone()))
+                .collect()
+        }
+    };
+
+    // Walk back until an address that is not a trusted proxy; an
+    // unparseable hop ends the walk since nothing before it can be checked
+    let mut client = None;
+    for (ip, proto) in hops.into_iter().rev() {
+        client = Some((ip, proto));
+        match ip {
+            Some(ip) if is_trusted(ip) => {}
+            _ => break,
+        }
+    }
+    if let Some((ip, proto)) = client {
+        request.forwarded_ip = ip;
+        request.forwarded_proto = proto;
+    }
+}
+
+// Parses one Forwarded element, e.g. for="[2001:db8::17]:4711";proto=https
+fn parse_forwarded_element(element: &str) -> (Option<IpAddr>, Option<String>) {
+    let mut ip = None;
+    let mut proto = None;
+    for pair in element.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => ip = parse_node(value),
+            "proto" => proto = Some(value.to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+    (ip, proto)
+}
+
+// Parses a node address with an optional port: "192.0.2.60", "192.0.2.60:8080",
+// "[2001:db8::17]:4711" or a bare IPv6 address
+// Obfuscated identifiers ("_hidden") and "unknown" give None
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (address, port) = node.rsplit_once(':')?;
+        port.parse::<u16>().ok()?;
+        address.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
@@ -0,0 +1,28 @@
+// Path patterns used by cache rules and compression exclusions
+
+// Matches a request path against a pattern
+// Patterns starting with "/" match the whole path, others only the file name;
+// "*" matches within a path segment and "**" across segments
+// Example: "/assets/**" matches "/assets/js/app.js", "*.html" matches "/docs/index.html"
+pub fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let path = if path == "/" { "/index.html" } else { path };
+    if pattern.starts_with('/') {
+        glob_matches(pattern.as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob_matches(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+// Matches text against a glob made of literal bytes, "*" and "**"
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        [b'*', rest @ ..] => {
+            let segment_end = text.iter().position(|&b| b == b'/').unwrap_or(text.len());
+            (0..=segment_end).any(|skip| glob_matches(rest, &text[skip..]))
+        }
+        [first, rest @ ..] => text.first() == Some(first) && glob_matches(rest, &text[1..]),
+    }
+}
@@ -0,0 +1,99 @@
+// Route handlers replaced while the server runs
+// A HotSwap is registered like any handler, and its clones share one slot
+// holding the current version. Requests take the version current when they
+// start, so a swap never interrupts a request in flight, and the previous
+// version is kept to switch back to.
+
+use crate::middleware::BoxedHandler;
+use crate::{Request, Response, RouteHandler};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// A route handler that can be replaced atomically while the server runs,
+/// e.g. to roll out a new implementation and roll it back if it misbehaves
+/// Example:
+/// let users = HotSwap::new(list_users_v1);
+/// router.get("/users", users.clone());
+/// // later, from an admin route or a deploy hook
+/// let version = users.swap(list_users_v2);
+/// if errors_rising() {
+///     users.rollback();
+/// }
+#[derive(Clone)]
+pub struct HotSwap {
+    slot: Arc<RwLock<Slot>>,
+}
+
+struct Slot {
+    current: Arc<Version>,
+    previous: Option<Arc<Version>>,
+    // Last version number handed out
+    latest: u64,
+}
+
+struct Version {
+    number: u64,
+    handler: BoxedHandler,
+}
+
+impl HotSwap {
+    /// Creates a slot serving handler as version 1
+    pub fn new<Args, H: RouteHandler<Args>>(handler: H) -> HotSwap {
+        HotSwap {
+            slot: Arc::new(RwLock::new(Slot {
+                current: version(1, handler),
+                previous: None,
+                latest: 1,
+            })),
+        }
+    }
+
+    /// Serves handler from now on and returns its version number
+    /// Requests already running finish with the handler they started with
+    pub fn swap<Args, H: RouteHandler<Args>>(&self, handler: H) -> u64 {
+        let mut slot = self.slot.write().unwrap();
+        slot.latest += 1;
+        let next = version(slot.latest, handler);
+        slot.previous = Some(std::mem::replace(&mut slot.current, next));
+        slot.latest
+    }
+
+    /// Goes back to the handler served before the last swap, which becomes
+    /// the one a further rollback returns to
+    /// Returns the version now served, or None if there was no swap
+    pub fn rollback(&self) -> Option<u64> {
+        let mut slot = self.slot.write().unwrap();
+        let previous = slot.previous.take()?;
+        slot.previous = Some(std::mem::replace(&mut slot.current, previous));
+        Some(slot.current.number)
+    }
+
+    /// Returns the version being served
+    pub fn version(&self) -> u64 {
+        self.slot.read().unwrap().current.number
+    }
+}
+
+impl RouteHandler<()> for HotSwap {
+    fn call(&self, request: &Request) -> Response {
+        // Release the lock before running the handler, so swaps don't wait
+        let current = Arc::clone(&self.slot.read().unwrap().current);
+        (current.handler)(request)
+    }
+}
+
+impl fmt::Debug for HotSwap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HotSwap")
+            .field("version", &self.version())
+            .finish()
+    }
+}
+
+// Wraps a handler as a numbered version
+fn version<Args, H: RouteHandler<Args>>(number: u64, handler: H) -> Arc<Version> {
+    Arc::new(Version {
+        number,
+        handler: Arc::new(move |request: &Request| handler.call(request)),
+    })
+}
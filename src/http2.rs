@@ -0,0 +1,854 @@
+// HTTP/2 over cleartext TCP (h2c, RFC 9113)
+// Clients either start the connection with the HTTP/2 preface ("prior
+// knowledge", as gRPC does) or ask an HTTP/1.1 request to be upgraded with
+// "Upgrade: h2c" (RFC 7540 section 3.2), in which case that request becomes
+// stream 1. Streams are answered one at a time in the order their requests
+// complete: each request is turned back into an HTTP/1.1 request and run
+// through the regular pipeline in memory, like the buffered backends do,
+// and the response is sent as HEADERS and DATA frames within the client's
+// flow control windows. Server push and priorities are not used.
+
+mod hpack;
+
+use crate::credentials::{URL_SAFE, base64_decode};
+use crate::{FileTransfer, Peer, Request, Router, buffered, read_or_probe};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+
+// Sent by clients before their first frame
+const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+// Request line of the preface, which reads like an HTTP/1.1 request head
+pub const PREFACE_HEAD: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Largest frame payload accepted, the protocol's default
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+// Streams a client may have open at once
+const MAX_CONCURRENT_STREAMS: usize = 100;
+// Flow control window of new streams and of the connection, before settings
+const DEFAULT_WINDOW: i64 = 65_535;
+// Largest flow control window allowed
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// Largest header block accepted, compressed, as a multiple of the router's
+// head size limit; a block must be decoded even when its request is refused,
+// so this bounds the work per request
+const MAX_HEADER_BLOCK_HEADS: usize = 4;
+
+// Returns true if an HTTP/1.1 request asks to continue over h2c
+// Upgrade must offer h2c, and HTTP2-Settings be present and listed in
+// Connection along with Upgrade
+pub fn is_upgrade(request: &Request) -> bool {
+    let lists = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|listed| listed.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    lists("Upgrade", "h2c")
+        && request.header("HTTP2-Settings").is_some()
+        && lists("Connection", "Upgrade")
+        && lists("Connection", "HTTP2-Settings")
+}
+
+// Serves a connection that started with the preface
+// received holds what was read off the stream so far, preface included
+pub fn serve(
+    stream: impl Read + Write,
+    received: &[u8],
+    base_dir: &str,
+    router: &Router,
+    peer: Peer,
+) {
+    let mut connection = Connection::new(stream, base_dir, router, peer);
+    connection.received = received.to_vec();
+    connection.run();
+}
+
+// Switches a connection to HTTP/2 and answers the request that asked for it
+// on stream 1
+pub fn upgrade(
+    stream: impl Read + Write,
+    request: Request,
+    base_dir: &str,
+    router: &Router,
+    peer: Peer,
+) {
+    let mut connection = Connection::new(stream, base_dir, router, peer);
+    // The settings are acknowledged by the 101 response itself
+    let settings = request
+        .header("HTTP2-Settings")
+        .and_then(|settings| base64_decode(settings.trim(), URL_SAFE));
+    match settings.map(|settings| connection.apply_settings(&settings)) {
+        Some(Ok(())) => {}
+        _ => {
+            let _ = connection
+                .stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+            return;
+        }
+    }
+    if connection
+        .stream
+        .write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n",
+        )
+        .is_err()
+    {
+        return;
+    }
+
+    let mut target = request.path.clone();
+    if !request.query.is_empty() {
+        target = format!("{}?{}", target, request.query);
+    }
+    let mut fields = vec![
+        (":method".to_string(), request.method.clone()),
+        (":scheme".to_string(), "http".to_string()),
+        (":path".to_string(), target),
+    ];
+    if let Some(host) = request.header("Host") {
+        fields.push((":authority".to_string(), host.to_string()));
+    }
+    for (name, value) in &request.headers {
+        let name = name.to_ascii_lowercase();
+        if !matches!(
+            name.as_str(),
+            "host" | "connection" | "upgrade" | "http2-settings" | "content-length"
+        ) && !is_connection_specific(&name, value)
+        {
+            fields.push((name, value.clone()));
+        }
+    }
+    connection.open(1, fields, false);
+    connection.streams.get_mut(&1).unwrap().body = request.body;
+    connection.ready.push_back(1);
+    connection.run();
+}
+
+// Why the exchange stopped
+enum ConnectionError {
+    // The peer went away or the socket failed
+    Closed,
+    // The peer broke the protocol; the code is sent in GOAWAY
+    Protocol(u32),
+}
+
+type Result<T> = std::result::Result<T, ConnectionError>;
+
+// A response of the HTTP/1.1 pipeline, to be sent on a stream
+struct Answer {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+// A request being received or answered
+struct Stream {
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    // END_STREAM not received yet
+    receiving: bool,
+    // The body went past the router's body size limit and was dropped
+    too_large: bool,
+    // Bytes the client is ready to receive on this stream
+    send_window: i64,
+}
+
+struct Connection<'a, S> {
+    stream: S,
+    // Bytes read before switching to HTTP/2, consumed before the stream's
+    received: Vec<u8>,
+    base_dir: &'a str,
+    router: &'a Router,
+    peer: Peer,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, Stream>,
+    // Streams whose request is complete, in completion order
+    ready: VecDeque<u32>,
+    // Highest stream opened by the client
+    last_stream_id: u32,
+    // Header block continued in CONTINUATION frames: stream, END_STREAM
+    // flag of its HEADERS frame and the fragments so far
+    partial_block: Option<(u32, bool, Vec<u8>)>,
+    // Bytes the client is ready to receive on the connection
+    send_window: i64,
+    // Client settings
+    initial_window: i64,
+    max_frame_size: usize,
+    // The client sent GOAWAY
+    closing: bool,
+}
+
+impl<'a, S: Read + Write> Connection<'a, S> {
+    fn new(stream: S, base_dir: &'a str, router: &'a Router, peer: Peer) -> Connection<'a, S> {
+        Connection {
+            stream,
+            received: Vec::new(),
+            base_dir,
+            router,
+            peer,
+            decoder: hpack::Decoder::new(),
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+            last_stream_id: 0,
+            partial_block: None,
+            send_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            closing: false,
+        }
+    }
+
+    // Runs the exchange, telling the client why it ended on protocol errors
+    fn run(&mut self) {
+        match self.exchange() {
+            Ok(()) => {
+                let _ = self.go_away(NO_ERROR);
+            }
+            Err(ConnectionError::Protocol(code)) => {
+                let _ = self.go_away(code);
+            }
+            Err(ConnectionError::Closed) => {}
+        }
+    }
+
+    fn exchange(&mut self) -> Result<()> {
+        let settings = [
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.router.head_size_limit().min(u32::MAX as usize) as u32,
+            ),
+        ];
+        let payload: Vec<u8> = settings
+            .iter()
+            .flat_map(|(id, value)| [id.to_be_bytes().as_slice(), &value.to_be_bytes()].concat())
+            .collect();
+        self.write_frame(SETTINGS, 0, 0, &payload)?;
+        self.stream.flush().map_err(|_| ConnectionError::Closed)?;
+
+        let mut preface = [0; PREFACE.len()];
+        self.fill(&mut preface)?;
+        if preface != *PREFACE {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR));
+        }
+
+        loop {
+            while let Some(id) = self.ready.pop_front() {
+                self.respond(id)?;
+            }
+            if self.closing && self.streams.is_empty() {
+                return Ok(());
+            }
+            let frame = self.read_frame()?;
+            self.process(frame)?;
+        }
+    }
+
+    // Fills the buffer with bytes received earlier, then from the stream
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let buffered = self.received.len().min(buffer.len());
+        buffer[..buffered].copy_from_slice(&self.received[..buffered]);
+        self.received.drain(..buffered);
+        let mut filled = buffered;
+        while filled < buffer.len() {
+            match read_or_probe(&mut self.stream, &mut buffer[filled..]) {
+                Some(bytes_read) => filled += bytes_read,
+                None => return Err(ConnectionError::Closed),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        let mut header = [0; 9];
+        self.fill(&mut header)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR));
+        }
+        let mut payload = vec![0; len];
+        self.fill(&mut payload)?;
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<()> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&len[1..]);
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream
+            .write_all(&frame)
+            .map_err(|_| ConnectionError::Closed)
+    }
+
+    fn reset(&mut self, stream_id: u32, code: u32) -> Result<()> {
+        self.streams.remove(&stream_id);
+        self.ready.retain(|&id| id != stream_id);
+        self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes())?;
+        self.stream.flush().map_err(|_| ConnectionError::Closed)
+    }
+
+    fn go_away(&mut self, code: u32) -> Result<()> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)?;
+        self.stream.flush().map_err(|_| ConnectionError::Closed)
+    }
+
+    // Handles a frame received from the client
+    fn process(&mut self, frame: Frame) -> Result<()> {
+        let protocol_error = Err(ConnectionError::Protocol(PROTOCOL_ERROR));
+        if let Some((stream_id, ..)) = self.partial_block
+            && (frame.kind != CONTINUATION || frame.stream_id != stream_id)
+        {
+            return protocol_error;
+        }
+        match frame.kind {
+            DATA => self.receive_data(frame),
+            HEADERS => {
+                if frame.stream_id == 0 {
+                    return protocol_error;
+                }
+                let mut fragment = unpad(&frame)?;
+                if frame.flags & PRIORITY_FLAG != 0 {
+                    fragment = fragment
+                        .get(5..)
+                        .ok_or(ConnectionError::Protocol(PROTOCOL_ERROR))?;
+                }
+                let end_stream = frame.flags & END_STREAM != 0;
+                let fragment = fragment.to_vec();
+                if frame.flags & END_HEADERS != 0 {
+                    self.receive_headers(frame.stream_id, end_stream, &fragment)
+                } else {
+                    self.partial_block = Some((frame.stream_id, end_stream, fragment));
+                    Ok(())
+                }
+            }
+            CONTINUATION => {
+                let Some((stream_id, end_stream, mut block)) = self.partial_block.take() else {
+                    return protocol_error;
+                };
+                block.extend_from_slice(&frame.payload);
+                if block.len() > MAX_HEADER_BLOCK_HEADS * self.router.head_size_limit() {
+                    return Err(ConnectionError::Protocol(ENHANCE_YOUR_CALM));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.receive_headers(stream_id, end_stream, &block)
+                } else {
+                    self.partial_block = Some((stream_id, end_stream, block));
+                    Ok(())
+                }
+            }
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+                    return protocol_error;
+                }
+                if frame.payload.len() != 4 {
+                    return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR));
+                }
+                self.streams.remove(&frame.stream_id);
+                self.ready.retain(|&id| id != frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => {
+                if frame.stream_id != 0 {
+                    return protocol_error;
+                }
+                if frame.flags & ACK != 0 {
+                    return Ok(());
+                }
+                self.apply_settings(&frame.payload)?;
+                self.write_frame(SETTINGS, ACK, 0, &[])?;
+                self.stream.flush().map_err(|_| ConnectionError::Closed)
+            }
+            PUSH_PROMISE => protocol_error,
+            PING => {
+                if frame.stream_id != 0 {
+                    return protocol_error;
+                }
+                if frame.payload.len() != 8 {
+                    return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR));
+                }
+                if frame.flags & ACK != 0 {
+                    return Ok(());
+                }
+                self.write_frame(PING, ACK, 0, &frame.payload)?;
+                self.stream.flush().map_err(|_| ConnectionError::Closed)
+            }
+            GOAWAY => {
+                self.closing = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                let Ok(increment) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+                    return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR));
+                };
+                let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+                if frame.stream_id == 0 {
+                    if increment == 0 {
+                        return protocol_error;
+                    }
+                    self.send_window += increment;
+                    if self.send_window > MAX_WINDOW {
+                        return Err(ConnectionError::Protocol(FLOW_CONTROL_ERROR));
+                    }
+                    return Ok(());
+                }
+                if frame.stream_id > self.last_stream_id {
+                    return protocol_error;
+                }
+                let overflow = match self.streams.get_mut(&frame.stream_id) {
+                    Some(_) if increment == 0 => {
+                        return self.reset(frame.stream_id, PROTOCOL_ERROR);
+                    }
+                    Some(stream) => {
+                        stream.send_window += increment;
+                        stream.send_window > MAX_WINDOW
+                    }
+                    // Closed streams may still get updates in flight
+                    None => false,
+                };
+                if overflow {
+                    return self.reset(frame.stream_id, FLOW_CONTROL_ERROR);
+                }
+                Ok(())
+            }
+            // Unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn receive_data(&mut self, frame: Frame) -> Result<()> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR));
+        }
+        // The whole frame counts against flow control, padding included;
+        // give the room back right away since bodies are buffered anyway
+        if !frame.payload.is_empty() {
+            let increment = (frame.payload.len() as u32).to_be_bytes();
+            self.write_frame(WINDOW_UPDATE, 0, 0, &increment)?;
+            if self
+                .streams
+                .get(&frame.stream_id)
+                .is_some_and(|stream| stream.receiving && frame.flags & END_STREAM == 0)
+            {
+                self.write_frame(WINDOW_UPDATE, 0, frame.stream_id, &increment)?;
+            }
+            self.stream.flush().map_err(|_| ConnectionError::Closed)?;
+        }
+        let data = unpad(&frame)?;
+        // Data for streams answered or reset already is dropped
+        let Some(stream) = self.streams.get_mut(&frame.stream_id) else {
+            return Ok(());
+        };
+        if !stream.receiving {
+            return self.reset(frame.stream_id, STREAM_CLOSED);
+        }
+        if stream.body.len() + data.len() > self.router.body_size_limit() {
+            stream.too_large = true;
+            stream.body = Vec::new();
+        } else if !stream.too_large {
+            stream.body.extend_from_slice(data);
+        }
+        if frame.flags & END_STREAM != 0 || stream.too_large {
+            stream.receiving = false;
+            self.ready.push_back(frame.stream_id);
+        }
+        Ok(())
+    }
+
+    // Handles a complete header block: a new request, or trailers
+    fn receive_headers(&mut self, stream_id: u32, end_stream: bool, block: &[u8]) -> Result<()> {
+        // The block is decoded whatever becomes of the stream, to keep the
+        // dynamic table in sync with the client's
+        let fields = self
+            .decoder
+            .decode(block)
+            .map_err(|_| ConnectionError::Protocol(COMPRESSION_ERROR))?;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            // Trailers end the request and are dropped
+            if !stream.receiving || !end_stream {
+                return self.reset(stream_id, PROTOCOL_ERROR);
+            }
+            stream.receiving = false;
+            self.ready.push_back(stream_id);
+            return Ok(());
+        }
+        if stream_id.is_multiple_of(2) {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR));
+        }
+        if stream_id <= self.last_stream_id {
+            return Err(ConnectionError::Protocol(STREAM_CLOSED));
+        }
+        self.last_stream_id = stream_id;
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS || self.closing {
+            return self.reset(stream_id, REFUSED_STREAM);
+        }
+        self.open(stream_id, fields, !end_stream);
+        if end_stream {
+            self.ready.push_back(stream_id);
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, stream_id: u32, fields: Vec<(String, String)>, receiving: bool) {
+        self.last_stream_id = self.last_stream_id.max(stream_id);
+        self.streams.insert(
+            stream_id,
+            Stream {
+                fields,
+                body: Vec::new(),
+                receiving,
+                too_large: false,
+                send_window: self.initial_window,
+            },
+        );
+    }
+
+    // Applies the payload of a SETTINGS frame
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<()> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(ConnectionError::Protocol(FLOW_CONTROL_ERROR));
+                    }
+                    // Open streams' windows move by the difference
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(ConnectionError::Protocol(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                        return Err(ConnectionError::Protocol(PROTOCOL_ERROR));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // Responses never use the client's dynamic table, and the
+                // other settings only concern what the client receives
+                SETTINGS_HEADER_TABLE_SIZE => {}
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Answers a complete request
+    fn respond(&mut self, stream_id: u32) -> Result<()> {
+        // The client may have reset the stream in the meantime
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        let answer = if stream.too_large {
+            Answer {
+                status: 413,
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        } else {
+            let body = std::mem::take(&mut stream.body);
+            let Some(raw) = request_bytes(&stream.fields, body) else {
+                return self.reset(stream_id, PROTOCOL_ERROR);
+            };
+            let output = buffered::serve(
+                raw,
+                self.base_dir,
+                self.router,
+                FileTransfer::Copy,
+                self.peer.clone(),
+            );
+            match parse_response(&output) {
+                Some(response) => response,
+                None => return self.reset(stream_id, INTERNAL_ERROR),
+            }
+        };
+        let receiving = stream.receiving || stream.too_large;
+        let head = stream
+            .fields
+            .iter()
+            .any(|(name, value)| name == ":method" && value == "HEAD");
+        let Answer {
+            status,
+            headers,
+            mut body,
+        } = answer;
+        if head {
+            body.clear();
+        }
+
+        let mut block = Vec::new();
+        hpack::encode_field(&mut block, ":status", &status.to_string());
+        for (name, value) in &headers {
+            let name = name.to_ascii_lowercase();
+            if !is_connection_specific(&name, value) {
+                hpack::encode_field(&mut block, &name, value);
+            }
+        }
+        let end_stream = if body.is_empty() { END_STREAM } else { 0 };
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(fragment) = fragments.next() {
+            let mut flags = if kind == HEADERS { end_stream } else { 0 };
+            if fragments.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream_id, fragment)?;
+            kind = CONTINUATION;
+        }
+
+        let mut sent = 0;
+        while sent < body.len() {
+            let Some(stream) = self.streams.get(&stream_id) else {
+                return Ok(());
+            };
+            let window = self.send_window.min(stream.send_window);
+            if window <= 0 {
+                // Wait for WINDOW_UPDATE, handling whatever else comes first
+                self.stream.flush().map_err(|_| ConnectionError::Closed)?;
+                let frame = self.read_frame()?;
+                self.process(frame)?;
+                continue;
+            }
+            let len = (body.len() - sent)
+                .min(window as usize)
+                .min(self.max_frame_size);
+            let flags = if sent + len == body.len() {
+                END_STREAM
+            } else {
+                0
+            };
+            self.write_frame(DATA, flags, stream_id, &body[sent..sent + len])?;
+            self.send_window -= len as i64;
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.send_window -= len as i64;
+            }
+            sent += len;
+        }
+        self.stream.flush().map_err(|_| ConnectionError::Closed)?;
+
+        // A response sent before the whole request tells the client to stop
+        if receiving && self.streams.contains_key(&stream_id) {
+            return self.reset(stream_id, NO_ERROR);
+        }
+        self.streams.remove(&stream_id);
+        Ok(())
+    }
+}
+
+// Returns the frame's payload without padding
+fn unpad(frame: &Frame) -> Result<&[u8]> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&padding, rest) = frame
+        .payload
+        .split_first()
+        .ok_or(ConnectionError::Protocol(PROTOCOL_ERROR))?;
+    rest.len()
+        .checked_sub(padding as usize)
+        .map(|len| &rest[..len])
+        .ok_or(ConnectionError::Protocol(PROTOCOL_ERROR))
+}
+
+// Returns true for headers that only make sense on a single HTTP/1.1
+// connection; TE is only allowed to announce trailers
+fn is_connection_specific(name: &str, value: &str) -> bool {
+    match name {
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" => true,
+        "te" => !value.trim().eq_ignore_ascii_case("trailers"),
+        _ => false,
+    }
+}
+
+// Builds the HTTP/1.1 request equivalent to a stream's request
+// Returns None if the request is malformed (RFC 9113 section 8.1.1)
+fn request_bytes(fields: &[(String, String)], body: Vec<u8>) -> Option<Vec<u8>> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = String::new();
+    let mut cookies = Vec::new();
+    let mut regular = false;
+    for (name, value) in fields {
+        let invalid = |text: &str| text.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'));
+        if name.is_empty() || invalid(name) || invalid(value) {
+            return None;
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return None,
+            };
+            // Pseudo-headers come first, once each
+            if regular || slot.replace(value.as_str()).is_some() {
+                return None;
+            }
+            continue;
+        }
+        regular = true;
+        if name.bytes().any(|b| b.is_ascii_uppercase() || b == b':')
+            || is_connection_specific(name, value)
+        {
+            return None;
+        }
+        match name.as_str() {
+            "cookie" => cookies.push(value.as_str()),
+            "content-length" => {
+                if value.trim().parse::<usize>().ok()? != body.len() {
+                    return None;
+                }
+            }
+            "host" if authority.is_some() => {}
+            _ => headers.push_str(&format!("{}: {}\r\n", name, value)),
+        }
+    }
+
+    let (method, path) = (method?, path?);
+    if scheme.is_none() || method == "CONNECT" || path.is_empty() {
+        return None;
+    }
+    let mut raw = format!("{} {} HTTP/1.1\r\n", method, path);
+    if let Some(authority) = authority {
+        raw.push_str(&format!("Host: {}\r\n", authority));
+    }
+    raw.push_str(&headers);
+    if !cookies.is_empty() {
+        raw.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+    if !body.is_empty() {
+        raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    raw.push_str("\r\n");
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(&body);
+    Some(raw)
+}
+
+// Splits an HTTP/1.1 response produced by the pipeline into status, headers
+// and body, decoding chunked bodies and skipping interim responses
+fn parse_response(mut output: &[u8]) -> Option<Answer> {
+    loop {
+        let head_len = output.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let head = String::from_utf8_lossy(&output[..head_len]);
+        let mut lines = head.lines();
+        let status: u16 = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+        let rest = &output[head_len..];
+        if (100..200).contains(&status) {
+            output = rest;
+            continue;
+        }
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let header = |wanted: &str| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value.as_str())
+        };
+        let body = if header("Transfer-Encoding")
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+        {
+            dechunk(rest)?
+        } else {
+            match header("Content-Length").and_then(|length| length.parse::<usize>().ok()) {
+                Some(length) => rest[..length.min(rest.len())].to_vec(),
+                None => rest.to_vec(),
+            }
+        };
+        return Some(Answer {
+            status,
+            headers,
+            body,
+        });
+    }
+}
+
+// Decodes a chunked body, dropping chunk extensions and trailers
+fn dechunk(mut input: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = input.windows(2).position(|w| w == b"\r\n")?;
+        let line = std::str::from_utf8(&input[..line_end]).ok()?;
+        let size_field = line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_field, 16).ok()?;
+        input = &input[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(input.get(..size)?);
+        input = input.get(size + 2..)?;
+    }
+}
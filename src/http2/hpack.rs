@@ -0,0 +1,565 @@
+// HPACK header compression (RFC 7541)
+// Requests are decoded with the full format: static and dynamic tables and
+// Huffman-coded strings. Responses are encoded as literals that never enter
+// the client's dynamic table, which keeps the encoder stateless.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+// Size of the dynamic table the server allows (SETTINGS_HEADER_TABLE_SIZE),
+// the protocol's default
+pub const TABLE_SIZE: usize = 4096;
+
+// Fields of the static table (Appendix A), from index 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Huffman code and its length in bits for every byte value, then EOS
+// (Appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+// Symbol standing for the end of a string, never valid inside one
+const EOS: u16 = 256;
+
+// The header block could not be decoded; the connection must be closed
+// with COMPRESSION_ERROR since the tables are out of sync
+#[derive(Debug, PartialEq)]
+pub struct DecodeError;
+
+// Decoding state of a connection: the dynamic table the client fills
+pub struct Decoder {
+    // Newest entry first
+    table: VecDeque<(String, String)>,
+    // Sum of the entry sizes, name and value length plus 32 each
+    size: usize,
+    // Current maximum, lowered or raised again by the client up to TABLE_SIZE
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    // Decodes a complete header block into (name, value) fields in order
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut input = block;
+        while let Some(&first) = input.first() {
+            if first & 0x80 != 0 {
+                // Indexed field
+                let index = decode_integer(&mut input, 7)?;
+                fields.push(self.field(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal added to the dynamic table
+                let (name, value) = self.literal(&mut input, 6)?;
+                self.insert(name.clone(), value.clone());
+                fields.push((name, value));
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update
+                let max_size = decode_integer(&mut input, 5)?;
+                if max_size > TABLE_SIZE {
+                    return Err(DecodeError);
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // Literal without indexing or never indexed
+                fields.push(self.literal(&mut input, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    // Returns the field at an index of the combined static and dynamic tables
+    fn field(&self, index: usize) -> Result<(String, String), DecodeError> {
+        match index {
+            0 => Err(DecodeError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - 62).cloned().ok_or(DecodeError),
+        }
+    }
+
+    // Decodes a literal field whose name index has a prefix of the given size
+    fn literal(&self, input: &mut &[u8], prefix: u8) -> Result<(String, String), DecodeError> {
+        let name = match decode_integer(input, prefix)? {
+            0 => decode_string(input)?,
+            index => self.field(index)?.0,
+        };
+        let value = decode_string(input)?;
+        Ok((name, value))
+    }
+
+    // Adds an entry, evicting the oldest ones to make room
+    // Entries larger than the whole table just empty it
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + 32;
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    // Evicts entries until room bytes fit under the maximum size
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + 32;
+        }
+    }
+}
+
+// Appends a field as a literal never added to the client's dynamic table,
+// with the name taken from the static table when it is there
+pub fn encode_field(out: &mut Vec<u8>, name: &str, value: &str) {
+    match STATIC_TABLE.iter().position(|(known, _)| *known == name) {
+        Some(index) => encode_integer(out, 0x00, 4, index + 1),
+        None => {
+            out.push(0x00);
+            encode_string(out, name);
+        }
+    }
+    encode_string(out, value);
+}
+
+// Appends an integer with a prefix of the given size; flags fill the bits
+// of the first byte above the prefix
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Appends a string literal without Huffman coding
+fn encode_string(out: &mut Vec<u8>, value: &str) {
+    encode_integer(out, 0x00, 7, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+// Reads an integer with a prefix of the given size off the input
+fn decode_integer(input: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, mut rest) = input.split_first().ok_or(DecodeError)?;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, remaining) = rest.split_first().ok_or(DecodeError)?;
+            rest = remaining;
+            // Anything past 28 bits is an attack rather than a header
+            if shift > 21 {
+                return Err(DecodeError);
+            }
+            value += (byte as usize & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *input = rest;
+    Ok(value)
+}
+
+// Reads a string literal off the input, decoding Huffman-coded ones
+// Invalid UTF-8 is replaced, as in request heads sent over HTTP/1.1
+fn decode_string(input: &mut &[u8]) -> Result<String, DecodeError> {
+    let huffman = input.first().is_some_and(|first| first & 0x80 != 0);
+    let len = decode_integer(input, 7)?;
+    if len > input.len() {
+        return Err(DecodeError);
+    }
+    let (raw, rest) = input.split_at(len);
+    *input = rest;
+    let bytes = if huffman {
+        decode_huffman(raw)?
+    } else {
+        raw.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Decodes a Huffman-coded string
+// The code is canonical: codes of the same length are consecutive and
+// ordered like their symbols, so a code is found from the first code of its
+// length. Padding must be fewer than 8 bits, all set (a prefix of EOS)
+fn decode_huffman(raw: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let codes = codes_by_length();
+    let mut decoded = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0usize;
+    for byte in raw {
+        for shift in (0..8).rev() {
+            code = (code << 1) | (byte >> shift & 1) as u32;
+            len += 1;
+            let (first, symbols) = &codes[len];
+            if code >= *first && ((code - first) as usize) < symbols.len() {
+                let symbol = symbols[(code - first) as usize];
+                if symbol == EOS {
+                    return Err(DecodeError);
+                }
+                decoded.push(symbol as u8);
+                code = 0;
+                len = 0;
+            } else if len == 30 {
+                return Err(DecodeError);
+            }
+        }
+    }
+    if len >= 8 || code != (1 << len) - 1 {
+        return Err(DecodeError);
+    }
+    Ok(decoded)
+}
+
+// First code and symbols in code order for each code length, up to 30 bits
+fn codes_by_length() -> &'static [(u32, Vec<u16>); 31] {
+    static CODES: OnceLock<[(u32, Vec<u16>); 31]> = OnceLock::new();
+    CODES.get_or_init(|| {
+        let mut codes: [(u32, Vec<u16>); 31] = std::array::from_fn(|_| (u32::MAX, Vec::new()));
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let (first, symbols) = &mut codes[len as usize];
+            *first = (*first).min(code);
+            symbols.push(symbol as u16);
+        }
+        codes
+    })
+}
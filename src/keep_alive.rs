@@ -0,0 +1,523 @@
+// Persistent connections on the threaded backend
+// serve_stream answers a single request, so a kept-alive connection runs it
+// in a loop on a Tracked stream. The wrapper watches the bytes going through:
+// it finds where each request ends, so bytes of the next one that were read
+// ahead are replayed to it, and checks that the response went out in full
+// with a length the client can rely on. The connection is only reused when
+// both are certain; anything else (upgrades, bodies ended by closing the
+// connection, bodies cut short by an error) closes it as before.
+
+use crate::stats::Counters;
+use crate::{FileTransfer, IDLE_PROBE_INTERVAL, Peer, Router, serve_stream};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// How often idle connections look up to check whether the server drains
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits of a persistent connection
+#[derive(Clone, Copy, Debug)]
+pub struct KeepAlive {
+    // Longest wait for the next request
+    pub timeout: Duration,
+    // Requests served before the connection is closed
+    pub max_requests: Option<usize>,
+}
+
+/// A stream over a TCP socket, whose read timeout is adjusted while waiting
+/// for the next request
+pub trait OnSocket: Read + Write {
+    fn socket(&self) -> &TcpStream;
+}
+
+impl OnSocket for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// Serves requests on the stream until the client or the limits end the
+/// connection. With send_files set, uncached static files are sent with
+/// sendfile(2) to the socket
+#[cfg_attr(not(all(feature = "static", unix)), allow(unused_variables))]
+pub fn serve(
+    stream: &mut impl OnSocket,
+    base_dir: &str,
+    router: &Router,
+    peer: Peer,
+    limits: KeepAlive,
+    send_files: bool,
+) {
+    let mut tracked = Tracked::new(stream, router.head_size_limit(), router.counters());
+    let mut served = 0;
+    loop {
+        served += 1;
+        let last = limits.max_requests.is_some_and(|max| served >= max);
+        // Bytes read past the previous request were counted with it already
+        if let Some(counters) = router.counters() {
+            counters.uncount_in(tracked.request.excess.len());
+        }
+        tracked.start_request(last);
+
+        #[cfg(all(feature = "static", unix))]
+        {
+            let mut deferred = None;
+            let transfer = match send_files {
+                true => FileTransfer::Defer(&mut deferred),
+                false => FileTransfer::Copy,
+            };
+            serve_stream(&mut tracked, base_dir, router, transfer, peer.clone(), true);
+
+            // The body bypasses the wrapper, so it is counted here
+            if let Some((mut file, len)) = deferred {
+                use std::os::fd::AsRawFd;
+                let socket = tracked.inner.socket().as_raw_fd();
+                if crate::socket::send_file(socket, &mut *tracked.inner, &mut file, len).is_err() {
+                    return;
+                }
+                tracked.response.count_body(&[], len);
+            }
+        }
+        #[cfg(not(all(feature = "static", unix)))]
+        serve_stream(
+            &mut tracked,
+            base_dir,
+            router,
+            FileTransfer::Copy,
+            peer.clone(),
+            true,
+        );
+
+        if tracked.last || !tracked.reusable() || !tracked.wait_for_request(limits.timeout) {
+            return;
+        }
+    }
+}
+
+// A stream following the requests and responses going through it
+struct Tracked<'a, S: OnSocket> {
+    inner: &'a mut S,
+    // Bytes read ahead of the current request, replayed first
+    pending: Vec<u8>,
+    request: RequestFrame,
+    response: ResponseFrame,
+    // Close after this response
+    last: bool,
+    // Tells whether the server drains, which closes the connection too
+    counters: Option<&'a Counters>,
+    // Start of a final response held until its status line is complete
+    held: Vec<u8>,
+    failed: bool,
+    // Longest request head the router accepts
+    head_limit: usize,
+}
+
+impl<'a, S: OnSocket> Tracked<'a, S> {
+    fn new(inner: &'a mut S, head_limit: usize, counters: Option<&'a Counters>) -> Tracked<'a, S> {
+        Tracked {
+            inner,
+            pending: Vec::new(),
+            request: RequestFrame::default(),
+            response: ResponseFrame::default(),
+            last: false,
+            counters,
+            held: Vec::new(),
+            failed: false,
+            head_limit,
+        }
+    }
+
+    // Resets the state for the next request, replaying the bytes read past
+    // the end of the previous one
+    fn start_request(&mut self, last: bool) {
+        let mut pending = std::mem::take(&mut self.request.excess);
+        pending.append(&mut self.pending);
+        self.pending = pending;
+        self.request = RequestFrame::default();
+        self.response = ResponseFrame::default();
+        self.last = last;
+    }
+
+    fn draining(&self) -> bool {
+        self.counters.is_some_and(|counters| counters.draining())
+    }
+
+    // Returns true if the exchange completed in a way that lets the
+    // connection carry another one
+    fn reusable(&self) -> bool {
+        !self.failed && self.held.is_empty() && self.request.complete() && self.response.complete()
+    }
+
+    // Waits up to timeout for the first bytes of the next request
+    // Returns false if the connection closed, stayed idle too long or the
+    // server started draining in the meantime
+    fn wait_for_request(&mut self, timeout: Duration) -> bool {
+        if !self.pending.is_empty() || !self.request.excess.is_empty() {
+            return true;
+        }
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; 1024];
+        let mut last_probe = Instant::now();
+        let received = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero()
+                || self.draining()
+                || self
+                    .inner
+                    .socket()
+                    .set_read_timeout(Some(remaining.min(DRAIN_CHECK_INTERVAL)))
+                    .is_err()
+            {
+                break false;
+            }
+            match self.inner.read(&mut buffer) {
+                Ok(0) => break false,
+                Ok(bytes_read) => {
+                    self.pending.extend_from_slice(&buffer[..bytes_read]);
+                    break true;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                // Probe the idle peer like read_or_probe does, as often
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if last_probe.elapsed() < IDLE_PROBE_INTERVAL {
+                        continue;
+                    }
+                    last_probe = Instant::now();
+                    if self.inner.write(&[]).is_err() || self.inner.flush().is_err() {
+                        break false;
+                    }
+                }
+                Err(_) => break false,
+            }
+        };
+        received
+            && self
+                .inner
+                .socket()
+                .set_read_timeout(Some(IDLE_PROBE_INTERVAL))
+                .is_ok()
+    }
+
+    // Sends the held bytes, with Connection: close after the status line when
+    // the connection ends with this response, which it does once the server
+    // started draining
+    fn release_held(&mut self) -> io::Result<()> {
+        let held = std::mem::take(&mut self.held);
+        let Some(line_end) = held.windows(2).position(|w| w == b"\r\n") else {
+            return self.inner.write_all(&held);
+        };
+        let (status_line, rest) = held.split_at(line_end + 2);
+        let informational = status_line.get(9) == Some(&b'1');
+        if !informational && self.draining() {
+            self.last = true;
+        }
+        let closing = self.last || !self.request.persistent();
+        if informational || !closing {
+            return self.inner.write_all(&held);
+        }
+        self.inner.write_all(status_line)?;
+        self.inner.write_all(b"Connection: close\r\n")?;
+        self.inner.write_all(rest)
+    }
+}
+
+impl<S: OnSocket> Read for Tracked<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = if self.pending.is_empty() {
+            self.inner.read(buf)?
+        } else {
+            let bytes_read = buf.len().min(self.pending.len());
+            buf[..bytes_read].copy_from_slice(&self.pending[..bytes_read]);
+            self.pending.drain(..bytes_read);
+            bytes_read
+        };
+        self.request.observe(&buf[..bytes_read], self.head_limit);
+        Ok(bytes_read)
+    }
+}
+
+impl<S: OnSocket> Write for Tracked<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = if self.response.at_head_start() || !self.held.is_empty() {
+            // Hold the start of a response until its status line is complete
+            self.held.extend_from_slice(buf);
+            self.response.observe(buf, self.request.is_head);
+            if self.held.windows(2).any(|w| w == b"\r\n") {
+                self.release_held()
+            } else {
+                Ok(())
+            }
+        } else {
+            self.response.observe(buf, self.request.is_head);
+            self.inner.write_all(buf)
+        };
+        match result {
+            Ok(()) => Ok(buf.len()),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match self.held.is_empty() {
+            true => self.inner.flush(),
+            false => self.release_held().and_then(|()| self.inner.flush()),
+        };
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+// What the bytes read so far say about the current request
+#[derive(Default)]
+struct RequestFrame {
+    head: Vec<u8>,
+    head_complete: bool,
+    // Body length announced, None if the body has no known length
+    body_len: Option<u64>,
+    body_read: u64,
+    // The client keeps the connection open after the response
+    keep_alive: bool,
+    is_head: bool,
+    // Bytes read past the end of the request
+    excess: Vec<u8>,
+}
+
+impl RequestFrame {
+    fn observe(&mut self, input: &[u8], head_limit: usize) {
+        let after_head;
+        let mut data = input;
+        if !self.head_complete {
+            let searched = self.head.len().saturating_sub(3);
+            self.head.extend_from_slice(data);
+            let Some(index) = self.head[searched..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+            else {
+                // Keep no more than serve_stream accepts
+                self.head.truncate(head_limit.saturating_add(4));
+                return;
+            };
+            let head_len = searched + index + 4;
+            after_head = self.head.split_off(head_len);
+            self.head_complete = true;
+            self.parse_head();
+            data = &after_head;
+        }
+
+        let remaining = match self.body_len {
+            Some(len) => len - self.body_read,
+            None => 0,
+        };
+        let body = data.len().min(remaining as usize);
+        self.body_read += body as u64;
+        self.excess.extend_from_slice(&data[body..]);
+    }
+
+    // Reads the framing and persistence of the request from its head
+    fn parse_head(&mut self) {
+        let head = String::from_utf8_lossy(&self.head);
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or("");
+        self.is_head = request_line.starts_with("HEAD ");
+        self.keep_alive = request_line.ends_with(" HTTP/1.1");
+        self.body_len = Some(0);
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Length") {
+                self.body_len = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                self.body_len = None;
+            } else if name.eq_ignore_ascii_case("Connection")
+                && value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("close"))
+            {
+                self.keep_alive = false;
+            }
+        }
+    }
+
+    // Returns true if the client did not ask to close, as far as known yet
+    fn persistent(&self) -> bool {
+        self.head_complete && self.keep_alive && self.body_len.is_some()
+    }
+
+    // Returns true if the whole request was read and allows another one
+    fn complete(&self) -> bool {
+        self.persistent() && self.body_len == Some(self.body_read)
+    }
+}
+
+// What the bytes written so far say about the current response
+#[derive(Default)]
+struct ResponseFrame {
+    head: Vec<u8>,
+    body: Option<BodyFrame>,
+    // The handler asked to close the connection
+    close: bool,
+}
+
+// How the end of a response body is known, and how far it got
+enum BodyFrame {
+    None { extra: bool },
+    Length { len: u64, written: u64 },
+    Chunked(Chunked),
+    // Ends when the connection closes
+    Unframed,
+}
+
+impl ResponseFrame {
+    fn at_head_start(&self) -> bool {
+        self.head.is_empty() && self.body.is_none()
+    }
+
+    fn observe(&mut self, input: &[u8], request_is_head: bool) {
+        let after_head;
+        let mut data = input;
+        if self.body.is_none() {
+            let searched = self.head.len().saturating_sub(3);
+            self.head.extend_from_slice(data);
+            let Some(index) = self.head[searched..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+            else {
+                return;
+            };
+            let head_len = searched + index + 4;
+            after_head = self.head.split_off(head_len);
+            if self.head.get(9) == Some(&b'1') && self.head.get(9..12) != Some(b"101") {
+                // An interim response such as 100 Continue, the final one follows
+                self.head.clear();
+                return self.observe(&after_head, request_is_head);
+            }
+            self.body = Some(self.parse_head(request_is_head));
+            data = &after_head;
+        }
+        self.count_body(data, data.len() as u64);
+    }
+
+    // Counts len bytes of body, of which data holds the ones written through
+    // the wrapper
+    fn count_body(&mut self, data: &[u8], len: u64) {
+        match &mut self.body {
+            Some(BodyFrame::None { extra }) => *extra |= len > 0,
+            Some(BodyFrame::Length { written, .. }) => *written += len,
+            Some(BodyFrame::Chunked(chunked)) => chunked.observe(data),
+            Some(BodyFrame::Unframed) | None => {}
+        }
+    }
+
+    fn parse_head(&mut self, request_is_head: bool) -> BodyFrame {
+        let head = String::from_utf8_lossy(&self.head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().and_then(|line| line.get(9..12)).unwrap_or("");
+        let mut body = BodyFrame::Unframed;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Length") {
+                body = match value.parse() {
+                    Ok(len) => BodyFrame::Length { len, written: 0 },
+                    Err(_) => BodyFrame::Unframed,
+                };
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                body = match value.eq_ignore_ascii_case("chunked") {
+                    true => BodyFrame::Chunked(Chunked::new()),
+                    false => BodyFrame::Unframed,
+                };
+            } else if name.eq_ignore_ascii_case("Connection")
+                && value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("close"))
+            {
+                self.close = true;
+            }
+        }
+        match status {
+            "101" => BodyFrame::Unframed,
+            "204" | "304" => BodyFrame::None { extra: false },
+            _ if request_is_head && !matches!(body, BodyFrame::Unframed) => {
+                BodyFrame::None { extra: false }
+            }
+            _ => body,
+        }
+    }
+
+    // Returns true if a final response was sent in full and lets the
+    // connection go on
+    fn complete(&self) -> bool {
+        let sent = match &self.body {
+            Some(BodyFrame::None { extra }) => !extra,
+            Some(BodyFrame::Length { len, written }) => len == written,
+            Some(BodyFrame::Chunked(chunked)) => matches!(chunked, Chunked::Done),
+            Some(BodyFrame::Unframed) | None => false,
+        };
+        sent && !self.close
+    }
+}
+
+// Progress through a chunked body
+enum Chunked {
+    // In a chunk size line, with the size read so far and whether an
+    // extension started
+    Size(u64, bool),
+    Data(u64),
+    // The CRLF after a chunk's data
+    DataEnd,
+    // In the trailer section, with the length of the current line
+    Trailer(usize),
+    Done,
+    Invalid,
+}
+
+impl Chunked {
+    fn new() -> Chunked {
+        Chunked::Size(0, false)
+    }
+
+    fn observe(&mut self, mut data: &[u8]) {
+        while let Some(&byte) = data.first() {
+            if let Chunked::Data(remaining) = self {
+                let taken = data.len().min(*remaining as usize);
+                *remaining -= taken as u64;
+                data = &data[taken..];
+                if *remaining == 0 {
+                    *self = Chunked::DataEnd;
+                }
+                continue;
+            }
+            data = &data[1..];
+            *self = match (std::mem::replace(self, Chunked::Invalid), byte) {
+                (Chunked::Size(0, _), b'\n') => Chunked::Trailer(0),
+                (Chunked::Size(size, _), b'\n') => Chunked::Data(size),
+                (Chunked::Size(size, extension), b'\r') => Chunked::Size(size, extension),
+                (Chunked::Size(size, _), b';') => Chunked::Size(size, true),
+                (Chunked::Size(size, true), _) => Chunked::Size(size, true),
+                (Chunked::Size(size, false), _) => match (byte as char).to_digit(16) {
+                    Some(digit) => size.checked_mul(16).map_or(Chunked::Invalid, |size| {
+                        Chunked::Size(size + digit as u64, false)
+                    }),
+                    None => Chunked::Invalid,
+                },
+                (Chunked::DataEnd, b'\r') => Chunked::DataEnd,
+                (Chunked::DataEnd, b'\n') => Chunked::new(),
+                (Chunked::Trailer(0), b'\n') => Chunked::Done,
+                (Chunked::Trailer(_), b'\n') => Chunked::Trailer(0),
+                (Chunked::Trailer(len), b'\r') => Chunked::Trailer(len),
+                (Chunked::Trailer(len), _) => Chunked::Trailer(len + 1),
+                _ => Chunked::Invalid,
+            };
+        }
+    }
+}
@@ -1,15 +1,206 @@
+use keep_alive::KeepAlive;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, copy};
+use std::io::ErrorKind;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+mod access_log;
+#[cfg(feature = "acme")]
+mod acme;
+mod admin;
+#[cfg(feature = "static")]
+mod assets;
+#[cfg(feature = "async")]
+mod async_handler;
+mod body;
+mod body_reader;
+#[cfg(any(
+    feature = "event-loop",
+    feature = "http2",
+    all(feature = "io-uring", target_os = "linux")
+))]
+mod buffered;
+#[cfg(feature = "static")]
+mod cache;
+#[cfg(feature = "compression")]
+mod compression;
+mod concurrency;
+#[cfg(any(feature = "static", feature = "webdav", feature = "proxy"))]
+#[cfg_attr(not(any(feature = "static", feature = "webdav")), allow(dead_code))]
+mod conditional;
+mod credentials;
+#[cfg(unix)]
+mod daemon;
+mod debug_capture;
+mod error;
+mod escape;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+pub mod extract;
+#[cfg(any(feature = "static", feature = "webdav"))]
+mod file_rules;
+mod forwarded;
+mod glob;
+mod hot_swap;
+#[cfg(feature = "http2")]
+mod http2;
+#[cfg_attr(not(any(feature = "static", feature = "webdav")), allow(dead_code))]
+mod http_date;
+mod keep_alive;
+#[cfg(feature = "static")]
+mod listing;
+#[cfg(feature = "static")]
+mod live_reload;
+#[cfg(feature = "markdown")]
+mod markdown;
+mod method;
+mod middleware;
+mod mime;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+#[cfg(feature = "static")]
+mod overrides;
+mod plugin;
+mod pool;
+pub mod prelude;
+#[cfg(feature = "proxy")]
+mod proxy;
+mod proxy_protocol;
+#[cfg(feature = "static")]
+mod publish;
+#[cfg(feature = "static")]
+mod range;
+mod request;
+mod response;
+mod response_cache;
+mod route_tree;
+mod router;
+#[cfg(feature = "scripting")]
+mod scripts;
+mod security_headers;
+mod server;
+#[cfg(windows)]
+mod service;
+#[cfg(unix)]
+mod signal;
+mod socket;
+#[cfg(feature = "static")]
+mod ssi;
+#[cfg(feature = "static")]
+mod static_files;
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+mod stats;
+mod status;
+pub mod testing;
 mod tests;
+mod timeout;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tus")]
+mod tus;
+mod upgrade;
+mod upload;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod vary;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "webdav")]
+mod webdav;
+
+#[cfg(unix)]
+pub use access_log::Journald;
+pub use access_log::{AccessLog, LogFormat, Syslog};
+#[cfg(feature = "acme")]
+pub use acme::Acme;
+#[cfg(feature = "metrics")]
+pub use admin::STATUS_PAGE_PATH;
+pub use admin::{ADMIN_PREFIX, AdminConfig};
+#[cfg(feature = "static")]
+pub use assets::Assets;
+#[cfg(feature = "async")]
+pub use async_handler::Async;
+pub use body::Body;
+pub use body_reader::BodyReader;
+#[cfg(feature = "static")]
+pub use cache::{CachedFile, FileCache};
+#[cfg(feature = "compression")]
+pub use compression::Compression;
+pub use concurrency::ConcurrencyLimit;
+#[cfg(unix)]
+pub use daemon::{PidFile, daemonize};
+pub use debug_capture::DebugCapture;
+pub use error::Error;
+#[cfg(any(feature = "static", feature = "webdav"))]
+pub use file_rules::SymlinkPolicy;
+pub use hot_swap::HotSwap;
+#[cfg(feature = "static")]
+pub use listing::{DirectoryListing, ListingColumn};
+pub use method::Method;
+pub use middleware::{Middleware, Next};
+pub use plugin::Plugin;
+#[cfg(feature = "proxy")]
+pub use proxy::{
+    Balance, CircuitBreaker, ForwardProxy, HashKey, HealthCheck, Proxy, ProxyCache, RetryPolicy,
+};
+#[cfg(feature = "static")]
+pub use publish::{Publish, QuotaUsage};
+pub use request::Request;
+pub use response::{IntoResponse, Response};
+pub use response_cache::ResponseCache;
+pub use router::{RouteHandler, Router, TrailingSlash};
+#[cfg(feature = "scripting")]
+pub use scripts::Scripts;
+pub use security_headers::{ContentSecurityPolicy, CspDirective, CspSource, SecurityHeaders};
+pub use server::{Server, ServerConfig};
+#[cfg(windows)]
+pub use service::{install_service, uninstall_service};
+#[cfg(feature = "static")]
+pub use static_files::{DEFAULT_FAVICON, RobotsPolicy};
+#[cfg(feature = "metrics")]
+pub use stats::{DEFAULT_LATENCY_BUCKETS, Histogram, RecentRequest, RouteStats, Stats};
+pub use status::StatusCode;
+pub use timeout::Timeout;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+#[cfg(feature = "tus")]
+pub use tus::Tus;
+pub use upload::{Upload, UploadedFile};
+#[cfg(feature = "wasm")]
+pub use wasm::WasmHandler;
 
 // Define a type alias for HTTP request handlers
 // Each handler returns a tuple of (response_body: String, content_type: String)
 pub type Handler = fn() -> (String, String);
 
+// Define a type alias for the response body writer returned by handle_request
+// The function streams the response body into the given writer
+pub type StreamFn = Box<dyn Fn(&mut dyn Write) -> std::io::Result<()>>;
+
+/// Read timeout to configure on accepted streams
+/// When a read times out the connection is considered idle and gets probed
+pub const IDLE_PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Number of consecutive idle probes after which a silent connection is dropped
+pub const MAX_IDLE_PROBES: u32 = 4;
+
+/// Largest request head (request line and headers) accepted by default, in bytes
+/// Longer heads are answered with 431 Request Header Fields Too Large
+/// (see Router::max_head_size)
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Largest request body accepted by default, in bytes
+/// Requests announcing a longer Content-Length are answered with 413 Payload
+/// Too Large (see Router::max_body_size)
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+// Number of bytes requested by the first read of a request head by default
+const READ_CHUNK_SIZE: usize = 1024;
+// Number of bytes requested per read of a request body by default
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Parses the first line of an HTTP request into its components
 /// Returns a tuple of (HTTP_METHOD, REQUEST_PATH, HTTP_PROTOCOL)
 /// Example: "GET /index.html HTTP/1.1" -> ("GET", "/index.html", "HTTP/1.1")
@@ -47,30 +238,33 @@ pub fn parse_request(request: &str) -> (String, String, HashMap<String, String>)
 /// - path: Request path
 /// - base_dir: Base directory for serving static files
 /// - routes: HashMap of custom route handlers
+///
 /// Returns a tuple of (status_code, reason_phrase, content_type, response_writer_function)
 pub fn handle_request(
     method: &str,
     path: &str,
     base_dir: &str,
     routes: &HashMap<String, Handler>,
-) -> (
-    u16,
-    String,
-    String,
-    Box<dyn Fn(&mut dyn Write) -> std::io::Result<()>>,
-) {
-    // Only handle GET requests, return 405 for other methods
+) -> (u16, String, String, StreamFn) {
+    // Only handle GET requests
+    // Return 405 if the resource exists (under GET) and 404 if it does not exist at all
     if method != "GET" {
-        return (
-            405,
-            "Method Not Allowed".to_string(),
-            "text/plain".to_string(),
-            Box::new(|_| Ok(())),
-        );
-    }
+        #[cfg(feature = "static")]
+        let exists =
+            routes.contains_key(path) || static_files::static_file(base_dir, path).is_some();
+        #[cfg(not(feature = "static"))]
+        let exists = routes.contains_key(path);
 
-    // Check if path matches any custom routes
-    if let Some(handler) = routes.get(path) {
+        if exists {
+            return (
+                405,
+                "Method Not Allowed".to_string(),
+                "text/plain".to_string(),
+                Box::new(|_| Ok(())),
+            );
+        }
+    } else if let Some(handler) = routes.get(path) {
+        // Path matches a custom route
         let (body, content_type) = handler();
         let body_bytes = body.into_bytes();
         return (
@@ -84,45 +278,27 @@ pub fn handle_request(
         );
     }
 
-    // Handle root path by serving index.html
-    let path = if path == "/" {
-        "index.html"
-    } else {
-        path.trim_start_matches('/')
-    };
-
-    // Construct file path by joining base directory and request path
-    let file_path = Path::new(base_dir).join(path).to_str().unwrap().to_string();
-
     // Serve static files if they exist
-    if Path::new(&file_path).exists() {
-        // Determine content type based on file extension
-        let content_type = match Path::new(path).extension().and_then(|s| s.to_str()) {
-            Some("html") => "text/html",
-            Some("css") => "text/css",
-            _ => "application/octet-stream",
-        };
-
-        (
+    #[cfg(feature = "static")]
+    if method == "GET"
+        && let Some((file_path, content_type)) = static_files::static_file(base_dir, path)
+    {
+        return (
             200,
             "OK".to_string(),
             content_type.to_string(),
-            Box::new(move |writer| {
-                let file = File::open(&file_path).unwrap();
-                let mut reader = BufReader::new(file);
-                copy(&mut reader, writer)?;
-                Ok(())
-            }),
-        )
-    } else {
-        // Return 404 if file not found
-        (
-            404,
-            "Not Found".to_string(),
-            "text/plain".to_string(),
-            Box::new(|_| Ok(())),
-        )
+            static_files::file_writer(file_path),
+        );
     }
+
+    // Return 404 if file not found
+    let _ = base_dir; // Only used for static files
+    (
+        404,
+        "Not Found".to_string(),
+        "text/plain".to_string(),
+        Box::new(|_| Ok(())),
+    )
 }
 
 /// Handles an individual HTTP connection
@@ -131,38 +307,563 @@ pub fn handle_request(
 /// - base_dir: Base directory for serving static files
 /// - routes: HashMap of custom route handlers
 pub fn handle_connection(
-    mut stream: impl Read + Write,
+    stream: impl Read + Write,
     base_dir: &str,
     routes: &HashMap<String, Handler>,
 ) {
-    // Read request into buffer
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+    serve_connection(stream, base_dir, &Router::from(routes));
+}
+
+/// Handles an individual HTTP connection using a Router
+/// Parameters:
+/// - stream: The TCP stream for the connection (must implement Read + Write)
+/// - base_dir: Base directory for serving static files
+/// - router: Routes tried before falling back to static files
+pub fn serve_connection(stream: impl Read + Write, base_dir: &str, router: &Router) {
+    serve_stream(
+        stream,
+        base_dir,
+        router,
+        FileTransfer::Copy,
+        Peer::default(),
+        true,
+    );
+}
+
+/// Handles a TCP connection using a Router
+/// Same as serve_connection, but static files are sent with sendfile(2) on
+/// Linux, going from the page cache to the socket without a userspace copy,
+/// and requests carry the peer's address
+pub fn serve_tcp_connection(stream: TcpStream, base_dir: &str, router: &Router) {
+    let peer = Peer {
+        addr: stream.peer_addr().ok(),
+        ..Peer::default()
+    };
+    serve_tcp_stream(stream, base_dir, router, peer, None);
+}
+
+// Same as serve_tcp_connection, with the client already known, serving
+// further requests on the connection within the keep-alive limits
+fn serve_tcp_stream(
+    mut stream: TcpStream,
+    base_dir: &str,
+    router: &Router,
+    peer: Peer,
+    keep_alive: Option<KeepAlive>,
+) {
+    let peer = Peer {
+        socket: stream.try_clone().ok().map(Arc::new),
+        ..peer
+    };
+    if let Some(limits) = keep_alive {
+        return keep_alive::serve(&mut stream, base_dir, router, peer, limits, true);
+    }
+    #[cfg(unix)]
+    let transfer = FileTransfer::SendFile(std::os::fd::AsRawFd::as_raw_fd(&stream));
+    #[cfg(not(unix))]
+    let transfer = FileTransfer::Copy;
+    serve_stream(stream, base_dir, router, transfer, peer, true);
+}
+
+// The client at the other end of a connection
+#[derive(Clone, Debug, Default)]
+struct Peer {
+    // The TCP peer, or the one announced in a PROXY protocol header
+    pub addr: Option<SocketAddr>,
+    // Certificate (DER) and subject a TLS client authenticated with
+    pub certificate: Option<Vec<u8>>,
+    pub subject: Option<String>,
+    // The socket, on plain TCP connections that can be handed over
+    pub socket: Option<Arc<TcpStream>>,
+}
+
+// How uncached static files reach the client
+#[cfg_attr(not(all(feature = "io-uring", target_os = "linux")), allow(dead_code))]
+enum FileTransfer<'a> {
+    // Copied through the stream like any other body
+    Copy,
+    // Sent with sendfile(2) to the socket behind the stream
+    SendFile(i32),
+    // Handed back after the head was written, for the caller to send
+    Defer(&'a mut Option<(std::fs::File, u64)>),
+}
+
+// Parses a request head, taking the client from the peer or from the
+// headers of trusted proxies
+fn parse_from_peer(head: &[u8], peer: &Peer, router: &Router) -> Request {
+    let mut request = Request::parse(head);
+    request.remote_addr = peer.addr;
+    request.client_certificate = peer.certificate.clone();
+    request.client_subject = peer.subject.clone();
+    forwarded::resolve(&mut request, router.proxy_ranges());
+    request
+}
+
+// Serves a connection, sending static files as the transfer mode says
+// live is false for requests buffered in memory, which cannot switch to
+// another protocol
+#[cfg_attr(not(feature = "static"), allow(unused_mut))]
+#[cfg_attr(
+    any(not(feature = "static"), not(feature = "http2")),
+    allow(unused_variables)
+)]
+fn serve_stream(
+    stream: impl Read + Write,
+    base_dir: &str,
+    router: &Router,
+    mut transfer: FileTransfer,
+    peer: Peer,
+    live: bool,
+) {
+    // Count the request in the server's statistics and log it once answered
+    // (measured is dropped first, so writing the line is not timed)
+    let logged = router.access_log().map(access_log::Pending::new);
+    #[cfg(feature = "metrics")]
+    let measured = router.counters().map(stats::Measured::new);
+    #[cfg(not(feature = "metrics"))]
+    let measured: Option<stats::Measured> = None;
+    let mut stream = stats::Metered::new(stream, measured.as_ref(), logged.as_ref());
+
+    // Read the request head into a buffer borrowed from the shared pool
+    let mut buffer = pool::shared().get();
+    let head_len = match read_request_head(&mut stream, &mut buffer, router) {
+        HeadRead::Complete(head_len) => head_len,
+        HeadRead::Closed => return, // Client closed the connection or it went dead
+        HeadRead::TooLarge => {
+            return write_empty_response(&mut stream, "431 Request Header Fields Too Large");
+        }
+        HeadRead::Incomplete => return write_empty_response(&mut stream, "400 Bad Request"),
+    };
+    if let Some(measured) = &measured {
+        measured.begin();
+    }
+
+    // Clients with prior knowledge of HTTP/2 open with its preface
+    #[cfg(feature = "http2")]
+    if live && buffer.starts_with(http2::PREFACE_HEAD) {
+        // Its streams are counted as they go through this function
+        return http2::serve(stream.inner, &buffer, base_dir, router, peer);
+    }
+
+    // Answer GETs for cached small files without going through the pipeline,
+    // unless override files must be looked for
+    #[cfg(feature = "static")]
+    if let Some(cache) = router.file_cache()
+        && router.override_files().is_none()
+        && let Some(file) = cache::fast_path_lookup(cache, &buffer[..])
+    {
+        if logged.is_some() || measured.as_ref().is_some_and(|m| m.keeps_recent()) {
+            let request = parse_from_peer(&buffer[..head_len], &peer, router);
+            if let Some(logged) = &logged {
+                logged.begin(&request);
+            }
+            if let Some(measured) = &measured {
+                measured.describe(&request);
+            }
+        }
+        if file.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
 
-    // Parse request
-    let request = String::from_utf8_lossy(&buffer[..]);
-    let (method, path, headers) = parse_request(&request);
+    let mut request = parse_from_peer(&buffer[..head_len], &peer, router);
+    if let Some(logged) = &logged {
+        logged.begin(&request);
+    }
+    if let Some(measured) = &measured {
+        measured.describe(&request);
+    }
 
     // Validate request has Host header (required by HTTP/1.1)
-    if !headers.contains_key("Host") && method != "" {
-        let response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
-        stream.write_all(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+    if !request.headers.contains_key("Host") && !request.method.is_empty() {
+        return write_empty_response(&mut stream, "400 Bad Request");
+    }
+
+    // Routes streaming the body read it themselves while they run, and so
+    // do files published to the static root
+    #[cfg(feature = "static")]
+    let publishes = router.publishes(&request);
+    #[cfg(not(feature = "static"))]
+    let publishes = false;
+    if publishes || router.streams_body(&request) {
+        // The handler runs on another thread, which hands the route back
+        let matched = Mutex::new(None);
+        let respond = |request: &mut Request| {
+            let response = match publishes {
+                #[cfg(feature = "static")]
+                true => router.handle_publish(request, base_dir),
+                _ => router.handle(request),
+            };
+            *matched.lock().unwrap() = Some((request.method.clone(), request.route.take()));
+            response
+        };
+        match body_reader::handle(&mut stream, request, &buffer[head_len..], router, respond) {
+            Ok(Some(response)) => {
+                if response.write_to(&mut stream).is_ok() {
+                    let _ = stream.flush();
+                }
+            }
+            Ok(None) => {}
+            Err(status) => write_empty_response(&mut stream, status),
+        }
+        if let Some(measured) = &measured
+            && let Some((method, route)) = matched.into_inner().unwrap()
+        {
+            measured.route(&method, route.as_deref());
+        }
+        return;
+    }
+
+    // Read exactly Content-Length bytes of body, part of which may already be buffered
+    request.body = match read_request_body(&mut stream, &request, &buffer[head_len..], router) {
+        Ok(body) => body,
+        Err(status) => return write_empty_response(&mut stream, status),
+    };
+
+    // Continue over HTTP/2 if the client asks to
+    #[cfg(feature = "http2")]
+    if live && http2::is_upgrade(&request) {
+        return http2::upgrade(stream.inner, request, base_dir, router, peer);
+    }
+
+    // In live reload mode, start watching the directory being served
+    #[cfg(feature = "static")]
+    if let Some(live) = router.live_reloader() {
+        live.watch(base_dir);
+    }
+
+    // Let the router answer first
+    // Clients may hang up mid-response (e.g. closing an event stream), which is not an error
+    if let Some(mut response) = router.handle(&mut request) {
+        if let Some(measured) = &measured {
+            measured.route(&request.method, request.route.as_deref());
+        }
+        // Switch protocols, handing the connection over with the bytes read
+        // past the request
+        if let Some(upgrade) = response.take_upgrade() {
+            let socket = peer.socket.as_deref().filter(|_| live);
+            let leftover = buffer[head_len..].get(request.body.len()..).unwrap_or(&[]);
+            return upgrade::hand_over(&mut stream, response, upgrade, socket, leftover);
+        }
+        if response.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
+
+    // Static files are looked up by the decoded path, which must name a file
+    // under the base directory that may be served
+    #[cfg(feature = "static")]
+    let overrides = match static_files::admit(router, base_dir, &mut request) {
+        Ok(headers) => headers,
+        Err(response) => {
+            if response.write_to(&mut stream).is_ok() {
+                let _ = stream.flush();
+            }
+            return;
+        }
+    };
+
+    // Expand server-side includes and inject the live reload script
+    #[cfg(feature = "static")]
+    if let Some(response) = static_files::render_page(router, base_dir, &request) {
+        let response = static_files::with_path_headers(router, &request.path, &overrides, response);
+        if response.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
+
+    // Answer conditional requests for static files with 304 or 412
+    #[cfg(feature = "static")]
+    if request.method == "GET"
+        && let Some((file_path, _)) = static_files::static_file(base_dir, &request.path)
+        && let Some(response) = static_files::preconditions(&request, &file_path)
+    {
+        let response = static_files::with_path_headers(router, &request.path, &overrides, response);
+        if response.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
+
+    // Send the requested parts of static files
+    #[cfg(feature = "static")]
+    if request.method == "GET"
+        && request.header("Range").is_some()
+        && let Some((file_path, content_type)) = static_files::static_file(base_dir, &request.path)
+        && let Some(response) = range::range_response(&request, &file_path, content_type)
+    {
+        let response = static_files::with_path_headers(router, &request.path, &overrides, response);
+        if response.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
+
+    // Caching policy and headers configured for the path, sent with static files
+    #[cfg(feature = "static")]
+    let path_headers = static_files::path_header_lines(router, &request.path, &overrides);
+    #[cfg(not(feature = "static"))]
+    let path_headers = String::new();
+
+    // Fall back to static files, caching small ones when enabled
+    #[cfg(feature = "static")]
+    if request.method == "GET"
+        && let Some(cache) = router.file_cache()
+        && router.override_files().is_none()
+        && let Some((file_path, content_type)) = static_files::static_file(base_dir, &request.path)
+        && let Some(file) =
+            cache.load_with_headers(&request.path, &file_path, content_type, &path_headers)
+    {
+        if file.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
+
+    // Let the kernel copy static files to real sockets, or leave them to the caller
+    #[cfg(feature = "static")]
+    if request.method == "GET"
+        && !matches!(transfer, FileTransfer::Copy)
+        && let Some((file_path, content_type)) = static_files::static_file(base_dir, &request.path)
+        && let Ok(mut file) = std::fs::File::open(&file_path)
+        && let Ok(metadata) = file.metadata()
+        && metadata.is_file()
+    {
+        let validators = conditional::Validators::of(&metadata)
+            .map(|validators| validators.header_lines())
+            .unwrap_or_default();
+        let mut head = pool::shared().get();
+        write!(
+            head,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}\r\n",
+            content_type,
+            metadata.len(),
+            validators,
+            path_headers
+        )
+        .unwrap();
+        if stream.write_all(&head).is_err() {
+            return;
+        }
+        match &mut transfer {
+            FileTransfer::SendFile(socket_fd) => {
+                // Clients aborting a download reset the connection
+                let sent =
+                    socket::send_file(*socket_fd, &mut stream.inner, &mut file, metadata.len());
+                if sent.is_err() {
+                    return;
+                }
+            }
+            FileTransfer::Defer(slot) => **slot = Some((file, metadata.len())),
+            FileTransfer::Copy => {}
+        }
+        stream.count_out(metadata.len());
+        let _ = stream.flush();
+        return;
+    }
+
+    // Send large files straight from a memory map when enabled
+    #[cfg(all(feature = "mmap", unix))]
+    if request.method == "GET"
+        && let Some(min_size) = router.mmap_threshold()
+        && let Some((file_path, content_type)) = static_files::static_file(base_dir, &request.path)
+        && let Some(map) = mmap::map_file(&file_path, min_size)
+    {
+        let mut head = pool::shared().get();
+        write!(
+            head,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}\r\n",
+            content_type,
+            map.len(),
+            static_files::validator_lines(&file_path),
+            path_headers
+        )
+        .unwrap();
+        if stream.write_all(&head).is_ok() && stream.write_all(&map).is_ok() {
+            let _ = stream.flush();
+        }
         return;
     }
 
-    // Handle request and generate response
     let (status, reason, content_type, stream_fn) =
-        handle_request(&method, &path, base_dir, routes);
+        handle_request(&request.method, &request.path, base_dir, &HashMap::new());
+
+    // Nothing matched, let the application's not-found handler answer
+    if status == 404
+        && let Some(response) = router.handle_not_found(&request)
+    {
+        if response.write_to(&mut stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    }
 
     // Write response headers
-    let response = format!(
-        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n\r\n",
-        status, reason, content_type,
-    );
+    // Static files are only served for GET, so that is all a 405 can allow
+    // and only served files get validators and the caching policy
+    let allow = if status == 405 { "Allow: GET\r\n" } else { "" };
+    #[cfg(feature = "static")]
+    let file_headers = match static_files::static_file(base_dir, &request.path) {
+        Some((file_path, _)) if status == 200 => {
+            static_files::validator_lines(&file_path) + path_headers.as_str()
+        }
+        _ => String::new(),
+    };
+    #[cfg(not(feature = "static"))]
+    let file_headers = path_headers;
+    let mut head = pool::shared().get();
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}{}\r\n",
+        status, reason, content_type, allow, file_headers,
+    )
+    .unwrap();
 
     // Send response
-    stream.write_all(response.as_bytes()).unwrap();
-    stream_fn(&mut stream).unwrap();
-    stream.flush().unwrap();
+    if stream.write_all(&head).is_ok() && stream_fn(&mut stream).is_ok() {
+        let _ = stream.flush();
+    }
+}
+
+// Outcome of reading a request head
+enum HeadRead {
+    // The buffer holds the head, of the given length, and possibly the start of the body
+    Complete(usize),
+    // The client closed the connection before sending anything
+    Closed,
+    // No blank line within MAX_HEAD_SIZE bytes
+    TooLarge,
+    // The connection ended in the middle of the head
+    Incomplete,
+}
+
+// Reads until the buffer contains the blank line ending the request head
+// Reads start at the router's read size and double up to its head limit.
+// Bytes following the head in the last read stay in the buffer
+fn read_request_head(
+    stream: &mut (impl Read + Write),
+    buffer: &mut Vec<u8>,
+    router: &Router,
+) -> HeadRead {
+    let max_head_size = router.head_size_limit();
+    let mut read_size = router.head_read_size();
+    loop {
+        let filled = buffer.len();
+        buffer.resize(filled + read_size, 0);
+        read_size = (read_size * 2).min(max_head_size.max(1));
+        let bytes_read = read_or_probe(stream, &mut buffer[filled..]);
+        buffer.truncate(filled + bytes_read.unwrap_or(0));
+        if bytes_read.is_none() {
+            return if filled == 0 {
+                HeadRead::Closed
+            } else {
+                HeadRead::Incomplete
+            };
+        }
+
+        // The terminator may straddle the previous read
+        let search_from = filled.saturating_sub(3);
+        if let Some(index) = buffer[search_from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+        {
+            let head_len = search_from + index + 4;
+            return if head_len > max_head_size {
+                HeadRead::TooLarge
+            } else {
+                HeadRead::Complete(head_len)
+            };
+        }
+        if buffer.len() >= max_head_size {
+            return HeadRead::TooLarge;
+        }
+    }
+}
+
+// Reads the request body announced by the Content-Length header
+// buffered holds the body bytes that arrived together with the head; bytes
+// beyond Content-Length are dropped. Clients waiting for "Expect: 100-continue"
+// are told to go ahead once the length has been accepted. The rest is read in
+// chunks of the router's body read size
+// Returns the body, or the status line to answer with if it cannot be read
+fn read_request_body(
+    stream: &mut (impl Read + Write),
+    request: &Request,
+    buffered: &[u8],
+    router: &Router,
+) -> Result<Vec<u8>, &'static str> {
+    if request.header("Transfer-Encoding").is_some() {
+        return Err("501 Not Implemented");
+    }
+    let length = match request.header("Content-Length") {
+        None => return Ok(Vec::new()),
+        Some(value) if value.bytes().all(|b| b.is_ascii_digit()) => value
+            .parse::<usize>()
+            .map_err(|_| "413 Payload Too Large")?,
+        Some(_) => return Err("400 Bad Request"),
+    };
+    if length > router.body_size_limit() {
+        return Err("413 Payload Too Large");
+    }
+
+    let mut body = buffered[..buffered.len().min(length)].to_vec();
+    if body.len() < length
+        && request
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        let sent = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        if sent.and_then(|_| stream.flush()).is_err() {
+            return Err("400 Bad Request");
+        }
+    }
+    while body.len() < length {
+        let filled = body.len();
+        body.resize(filled + router.body_read_size().min(length - filled), 0);
+        match read_or_probe(stream, &mut body[filled..]) {
+            Some(bytes_read) => body.truncate(filled + bytes_read),
+            None => return Err("400 Bad Request"), // Connection ended mid-body
+        }
+    }
+    Ok(body)
+}
+
+// Writes a response consisting of a status line only
+fn write_empty_response(stream: &mut impl Write, status: &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+    if stream.write_all(response.as_bytes()).is_ok() {
+        let _ = stream.flush();
+    }
+}
+
+/// Reads from the stream, probing the connection while it stays idle
+/// A zero-byte read means the client closed its side of the connection.
+/// Read timeouts (see IDLE_PROBE_INTERVAL) trigger a zero-byte write probe so
+/// half-open sockets are detected instead of blocking the thread forever.
+/// Returns the number of bytes read, or None if the connection is gone
+pub fn read_or_probe(stream: &mut (impl Read + Write), buffer: &mut [u8]) -> Option<usize> {
+    let mut idle_probes = 0;
+    loop {
+        match stream.read(buffer) {
+            Ok(0) => return None,
+            Ok(bytes_read) => return Some(bytes_read),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                idle_probes += 1;
+                if idle_probes > MAX_IDLE_PROBES || !probe_connection(stream) {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+// Writes zero bytes and flushes to check whether the peer is still reachable
+// Returns false if the stream reports an error
+fn probe_connection(stream: &mut impl Write) -> bool {
+    stream.write(&[]).is_ok() && stream.flush().is_ok()
 }
@@ -0,0 +1,295 @@
+// Directory listings
+// Requests naming a static directory without an index file get a page
+// listing its entries, directories first and then files, by name. The page
+// is put together from the header, the table of entries and the footer,
+// styled by the CSS, all of which DirectoryListing replaces; clients asking
+// for JSON may get the entries as an array of objects instead. Entries the
+// router doesn't serve, hidden files and refused links, are left out.
+
+use crate::escape::{escape_html, json_string};
+use crate::file_rules::links_allowed;
+use crate::http_date::civil_from_days;
+use crate::mime::content_type_for;
+use crate::request::percent_encode;
+use crate::{Request, Response, Router, StatusCode};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_HEADER: &str = "<h1>Index of {path}</h1>\n";
+
+const DEFAULT_STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse}\
+th,td{padding:.3em .8em;border-bottom:1px solid #ddd;text-align:left}\
+td.n{text-align:right;font-variant-numeric:tabular-nums}";
+
+/// A column of directory listings, shown after the name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingColumn {
+    // Size of files, e.g. "1.5 KiB"
+    Size,
+    // Modification time, in UTC
+    Modified,
+    // Content type of files, "directory" for directories
+    Type,
+}
+
+/// Settings of the pages listing static directories without an index file
+/// (see Router::directory_listing)
+/// "{path}" in the header and footer is replaced with the directory's path
+/// Example:
+/// router.directory_listing(
+///     DirectoryListing::new()
+///         .header("<h1>Downloads: {path}</h1>")
+///         .footer("<p>Mirrored nightly</p>")
+///         .style("body{font-family:monospace}")
+///         .columns(&[ListingColumn::Size])
+///         .json(true),
+/// );
+#[derive(Clone, Debug)]
+pub struct DirectoryListing {
+    header: String,
+    footer: String,
+    style: String,
+    columns: Vec<ListingColumn>,
+    json: bool,
+}
+
+// An entry of a listed directory
+struct Entry {
+    name: String,
+    directory: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl DirectoryListing {
+    /// Creates the settings: an "Index of" heading, the built-in style,
+    /// size and modification time columns and no JSON
+    pub fn new() -> DirectoryListing {
+        DirectoryListing {
+            header: DEFAULT_HEADER.to_string(),
+            footer: String::new(),
+            style: DEFAULT_STYLE.to_string(),
+            columns: vec![ListingColumn::Size, ListingColumn::Modified],
+            json: false,
+        }
+    }
+
+    /// Sets the HTML shown before the table
+    pub fn header(mut self, html: &str) -> DirectoryListing {
+        self.header = html.to_string();
+        self
+    }
+
+    /// Sets the HTML shown after the table
+    pub fn footer(mut self, html: &str) -> DirectoryListing {
+        self.footer = html.to_string();
+        self
+    }
+
+    /// Replaces the built-in CSS of the page
+    /// Example: DirectoryListing::new().style("@import url(/listing.css);")
+    pub fn style(mut self, css: &str) -> DirectoryListing {
+        self.style = css.to_string();
+        self
+    }
+
+    /// Sets the columns shown after the name, in order
+    pub fn columns(mut self, columns: &[ListingColumn]) -> DirectoryListing {
+        self.columns = columns.to_vec();
+        self
+    }
+
+    /// Answers requests accepting application/json and not text/html with
+    /// the entries as JSON objects carrying the name, "directory" and the
+    /// columns: [{"name": "a.txt", "directory": false, "size": 12}]
+    pub fn json(mut self, enabled: bool) -> DirectoryListing {
+        self.json = enabled;
+        self
+    }
+
+    // Lists the directory at relative under base_dir, for the request
+    pub(crate) fn render(
+        &self,
+        router: &Router,
+        base_dir: &Path,
+        relative: &Path,
+        request: &Request,
+    ) -> Response {
+        let Ok(read) = fs::read_dir(base_dir.join(relative)) else {
+            return Response::from_status(StatusCode::FORBIDDEN);
+        };
+        let mut entries: Vec<Entry> = read
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let path = relative.join(&name);
+                if router.hides(&path) || !links_allowed(router.symlink_policy(), base_dir, &path) {
+                    return None;
+                }
+                // Links are described by their target
+                let metadata = fs::metadata(entry.path()).ok()?;
+                Some(Entry {
+                    name,
+                    directory: metadata.is_dir(),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| b.directory.cmp(&a.directory).then(a.name.cmp(&b.name)));
+
+        let path = relative.iter().fold(String::from("/"), |path, name| {
+            path + &*name.to_string_lossy() + "/"
+        });
+        let accept = request.header("Accept").unwrap_or("");
+        let response = match self.json && accept.contains("application/json") {
+            true if !accept.contains("text/html") => {
+                Response::ok("application/json", self.json_body(&entries))
+            }
+            _ => Response::ok("text/html; charset=utf-8", self.page(&path, &entries)),
+        };
+        match self.json {
+            true => response.with_header("Vary", "Accept"),
+            false => response,
+        }
+    }
+
+    // Renders the HTML page for the directory at path
+    fn page(&self, path: &str, entries: &[Entry]) -> String {
+        let escaped = escape_html(path);
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <title>Index of {}</title><style>{}</style></head><body>\n{}<table>\n<tr><th>Name</th>",
+            escaped,
+            self.style,
+            self.header.replace("{path}", &escaped)
+        );
+        for column in &self.columns {
+            html.push_str(match column {
+                ListingColumn::Size => "<th>Size</th>",
+                ListingColumn::Modified => "<th>Modified</th>",
+                ListingColumn::Type => "<th>Type</th>",
+            });
+        }
+        html.push_str("</tr>\n");
+        if path != "/" {
+            html.push_str("<tr><td><a href=\"../\">../</a></td>");
+            html.push_str(&"<td></td>".repeat(self.columns.len()));
+            html.push_str("</tr>\n");
+        }
+        for entry in entries {
+            let slash = if entry.directory { "/" } else { "" };
+            let _ = write!(
+                html,
+                "<tr><td><a href=\"{}{}\">{}{}</a></td>",
+                escape_html(&percent_encode(&entry.name)),
+                slash,
+                escape_html(&entry.name),
+                slash
+            );
+            for column in &self.columns {
+                let _ = match column {
+                    ListingColumn::Size if entry.directory => {
+                        write!(html, "<td class=\"n\">-</td>")
+                    }
+                    ListingColumn::Size => {
+                        write!(html, "<td class=\"n\">{}</td>", bytes(entry.size))
+                    }
+                    ListingColumn::Modified => write!(
+                        html,
+                        "<td>{}</td>",
+                        entry.modified.map_or("-".to_string(), |time| {
+                            // Shown to the minute: "2024-05-01 12:30"
+                            timestamp(time)[..16].replace('T', " ")
+                        })
+                    ),
+                    ListingColumn::Type => write!(html, "<td>{}</td>", entry_type(entry)),
+                };
+            }
+            html.push_str("</tr>\n");
+        }
+        let _ = write!(
+            html,
+            "</table>\n{}</body></html>\n",
+            self.footer.replace("{path}", &escaped)
+        );
+        html
+    }
+
+    // Renders the entries as a JSON array
+    fn json_body(&self, entries: &[Entry]) -> String {
+        let objects: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let mut object = format!(
+                    "{{\"name\":{},\"directory\":{}",
+                    json_string(&entry.name),
+                    entry.directory
+                );
+                for column in &self.columns {
+                    let _ = match column {
+                        ListingColumn::Size if entry.directory => Ok(()),
+                        ListingColumn::Size => write!(object, ",\"size\":{}", entry.size),
+                        ListingColumn::Modified => match entry.modified {
+                            Some(time) => write!(object, ",\"modified\":\"{}\"", timestamp(time)),
+                            None => Ok(()),
+                        },
+                        ListingColumn::Type => {
+                            write!(object, ",\"type\":{}", json_string(entry_type(entry)))
+                        }
+                    };
+                }
+                object + "}"
+            })
+            .collect();
+        format!("[{}]\n", objects.join(","))
+    }
+}
+
+impl Default for DirectoryListing {
+    fn default() -> DirectoryListing {
+        DirectoryListing::new()
+    }
+}
+
+fn entry_type(entry: &Entry) -> &'static str {
+    match entry.directory {
+        true => "directory",
+        false => content_type_for(&entry.name),
+    }
+}
+
+// Formats a time in UTC as "2024-05-01T12:30:05Z"
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn bytes(count: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if count < 1024 {
+        return format!("{} B", count);
+    }
+    let mut value = count as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,139 @@
+// Development live reload
+// A watcher thread polls the static directory for changes. Served HTML pages
+// (see static_files::render_page) get a small script that listens to a server-sent event stream and reloads
+// the page when the watcher reports a change.
+
+use crate::{Body, Response};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Path of the event stream the injected script listens to
+pub const LIVE_RELOAD_PATH: &str = "/__live-reload";
+
+// How often the static directory is scanned for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+// Idle event streams send a comment this often so closed tabs are noticed
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const SCRIPT: &str = "<script>new EventSource(\"/__live-reload\")\
+    .addEventListener(\"reload\", () => location.reload());</script>";
+
+/// Watches the static directory and tells open pages to reload
+#[derive(Default)]
+pub struct LiveReload {
+    // Bumped on every change, streams wait for it to move
+    generation: Mutex<u64>,
+    changed: Condvar,
+    watching: OnceLock<()>,
+}
+
+impl LiveReload {
+    /// Starts watching dir on a thread named "live-reload", once
+    /// The thread stops when the LiveReload is dropped
+    pub fn watch(self: &Arc<Self>, dir: &str) {
+        self.watching.get_or_init(|| {
+            let live = Arc::downgrade(self);
+            let dir = dir.to_string();
+            let spawned = thread::Builder::new()
+                .name("live-reload".to_string())
+                .spawn(move || poll_directory(live, &dir));
+            if let Err(e) = spawned {
+                eprintln!("Failed to start live reload watcher: {}", e);
+            }
+        });
+    }
+
+    /// Returns a server-sent event stream sending a "reload" event on every change
+    pub fn events(self: &Arc<Self>) -> Response {
+        let live = Arc::clone(self);
+        let mut seen = *live.generation.lock().unwrap();
+        let mut greeted = false;
+        let events = std::iter::from_fn(move || {
+            if !greeted {
+                greeted = true;
+                return Some(b"retry: 1000\n\n".to_vec());
+            }
+            let generation = live.generation.lock().unwrap();
+            let (generation, _) = live
+                .changed
+                .wait_timeout_while(generation, KEEP_ALIVE_INTERVAL, |generation| {
+                    *generation == seen
+                })
+                .unwrap();
+            if *generation == seen {
+                return Some(b": keep-alive\n\n".to_vec());
+            }
+            seen = *generation;
+            Some(b"event: reload\ndata: \n\n".to_vec())
+        });
+        Response::new(200, "OK")
+            .with_header("Cache-Control", "no-store")
+            .with_body("text/event-stream", Body::from_chunks(events))
+    }
+
+    // Wakes every event stream
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+}
+
+// Inserts the reload script before the closing body tag, or appends it
+pub fn inject_script(mut html: Vec<u8>) -> Vec<u8> {
+    let lower = html.to_ascii_lowercase();
+    let at = lower
+        .windows(7)
+        .rposition(|w| w == b"</body>")
+        .unwrap_or(html.len());
+    html.splice(at..at, SCRIPT.bytes());
+    html
+}
+
+// Scans dir until the LiveReload is gone, notifying it of changes
+fn poll_directory(live: Weak<LiveReload>, dir: &str) {
+    let mut last = fingerprint(Path::new(dir));
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let Some(live) = live.upgrade() else {
+            return;
+        };
+        let current = fingerprint(Path::new(dir));
+        if current != last {
+            last = current;
+            live.notify();
+        }
+    }
+}
+
+// Combines the path, size and modification time of every file under dir
+// Independent of directory iteration order
+fn fingerprint(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut combined: u64 = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        if metadata.is_dir() {
+            fingerprint(&path).hash(&mut hasher);
+        } else {
+            metadata.len().hash(&mut hasher);
+            metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .hash(&mut hasher);
+        }
+        combined = combined.wrapping_add(hasher.finish());
+    }
+    combined
+}
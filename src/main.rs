@@ -1,49 +1,171 @@
-// Import required modules and types from our library and standard library
-use rust_http_web_server::{Handler, handle_connection}; // Custom types and functions
-use std::collections::HashMap;  // For storing route handlers
-use std::net::TcpListener;     // For handling TCP connections
-use std::thread;               // For multi-threading support
+// Import required modules and types from our library
+use rust_http_web_server::prelude::*; // Server, Router, Response and friends
+use std::path::PathBuf;
+use std::time::Duration;
 
-fn main() {
-    // Create and bind TCP listener to localhost port 8080
-    // unwrap() is used here as we want to panic if server fails to start
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    
-    // Print server startup message with URL
-    println!("Server running on http://127.0.0.1:8080");
+// Command line options
+// --daemon             detach from the terminal and run in the background
+// --pid-file <path>    write the process id to the file, removed on exit
+// --log-file <path>    where a daemon's output goes (/dev/null by default)
+// --install-service    register the binary as a Windows service
+// --uninstall-service  stop and remove the Windows service
+// --service            run as the Windows service (passed by the service
+//                      control manager)
+#[derive(Default)]
+struct Options {
+    daemon: bool,
+    pid_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    install_service: bool,
+    uninstall_service: bool,
+    service: bool,
+}
+
+// Name of the Windows service
+#[cfg(windows)]
+const SERVICE_NAME: &str = "rust-http-web-server";
+
+// Parses the command line, exiting with a usage message on errors
+fn parse_options() -> Options {
+    let mut options = Options::default();
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--daemon") => options.daemon = true,
+            Some("--pid-file") => options.pid_file = args.next().map(PathBuf::from),
+            Some("--log-file") => options.log_file = args.next().map(PathBuf::from),
+            Some("--install-service") => options.install_service = true,
+            Some("--uninstall-service") => options.uninstall_service = true,
+            Some("--service") => options.service = true,
+            _ => {
+                eprintln!("Unknown option {}", arg.to_string_lossy());
+                eprintln!(
+                    "Usage: rust-http-web-server [--daemon] [--pid-file <path>] [--log-file <path>]\n\
+                     \x20      rust-http-web-server --install-service | --uninstall-service"
+                );
+                std::process::exit(2);
+            }
+        }
+    }
+    options
+}
+
+// Builds the routes served by the binary
+// Called at startup and again on every SIGHUP
+fn build_router() -> Router {
+    // Router maps HTTP methods and URL patterns to handler functions
+    let mut router = Router::new();
 
-    // Initialize route handler map
-    // HashMap<String, Handler> maps URL paths to their handler functions
-    let mut routes: HashMap<String, Handler> = HashMap::new();
-    
     // Register API routes
     // This example adds a single route "/api/hello" that returns JSON
-    routes.insert("/api/hello".to_string(), || {
-        // Handler returns a tuple of (response_body, content_type)
-        (
-            r#"{"message": "Hello, API!"}"#.to_string(),  // JSON response
-            "application/json".to_string(),                // Content-Type header
+    router.get("/api/hello", || {
+        // Handler returns a Response with the JSON body and its Content-Type
+        Response::ok(
+            "application/json",                  // Content-Type header
+            r#"{"message": "Hello, API!"}"#,     // JSON response
         )
     });
+    router
+}
 
-    // Main server loop
-    // Continuously accept incoming connections
-    for stream in listener.incoming() {
-        // Safely unwrap the Result<TcpStream, Error>
-        let stream = stream.unwrap();
-        
-        // Clone routes for the new thread
-        // This is necessary because each thread needs its own copy
-        let routes = routes.clone();
-        
-        // Spawn a new thread for each connection
-        // This allows handling multiple connections concurrently
-        thread::spawn(move || {
-            // Handle the connection with:
-            // - The TCP stream
-            // - "static" as the base directory for static files
-            // - Reference to the routes HashMap
-            handle_connection(stream, "static", &routes);
-        });
+fn main() {
+    let options = parse_options();
+
+    // Go to the background before anything else: only the calling thread
+    // survives the fork. Startup errors still reach the terminal
+    #[cfg(unix)]
+    if options.daemon {
+        daemonize(options.log_file.as_deref()).unwrap();
+    }
+    #[cfg(not(unix))]
+    if options.daemon || options.pid_file.is_some() || options.log_file.is_some() {
+        eprintln!("--daemon, --pid-file and --log-file are only supported on unix");
+        std::process::exit(2);
+    }
+
+    // Register or remove the Windows service, from an administrator prompt;
+    // it is then controlled with "sc start rust-http-web-server" and
+    // "sc stop rust-http-web-server"
+    #[cfg(windows)]
+    {
+        if options.install_service {
+            install_service(SERVICE_NAME, "Rust HTTP web server", &["--service"]).unwrap();
+            println!("Service {} installed", SERVICE_NAME);
+            return;
+        }
+        if options.uninstall_service {
+            uninstall_service(SERVICE_NAME).unwrap();
+            println!("Service {} removed", SERVICE_NAME);
+            return;
+        }
+        // Services start in C:\Windows\System32, while the static directory
+        // is next to the executable
+        if options.service {
+            let exe = std::env::current_exe().unwrap();
+            std::env::set_current_dir(exe.parent().unwrap()).unwrap();
+        }
     }
+    #[cfg(not(windows))]
+    if options.install_service || options.uninstall_service || options.service {
+        eprintln!("Services are only supported on Windows");
+        std::process::exit(2);
+    }
+
+    // Write the PID file, removed when _pid_file is dropped as main returns
+    #[cfg(unix)]
+    let _pid_file = options.pid_file.map(|path| PidFile::create(path).unwrap());
+
+    // Append a line per request to the file named by ACCESS_LOG, if set
+    let access_log = std::env::var_os("ACCESS_LOG").map(|path| AccessLog::new(path).unwrap());
+
+    // Create the server with:
+    // - The router for API routes
+    // - "static" as the base directory for static files
+    // - Up to 30 seconds for open connections to finish when shutting down
+    // Listener options (TCP Fast Open, defer accept) are off by default
+    let server = Server::new(build_router(), "static").config(ServerConfig {
+        drain_timeout: Some(Duration::from_secs(30)),
+        access_log: access_log.clone(),
+        ..ServerConfig::default()
+    });
+
+    // Signals, as sent by service managers and logrotate (unix only):
+    // - SIGTERM or SIGINT (Ctrl-C) drains the server, so the loop below ends
+    //   once the connections are finished; a second one aborts them
+    // - SIGHUP rebuilds the routes and swaps them in, without dropping
+    //   connections
+    // - SIGUSR1 reopens the access log after it was rotated
+    #[cfg(unix)]
+    {
+        server.drain_on_sigterm().unwrap();
+        server
+            .reload_on_sighup(|server| {
+                server.reload(build_router(), "static");
+                Ok(())
+            })
+            .unwrap();
+        if let Some(log) = &access_log {
+            log.reopen_on_sigusr1().unwrap();
+        }
+    }
+
+    // Bind TCP listener to localhost port 8080
+    // unwrap() is used here as we want to panic if server fails to start
+    let listener = server.bind("127.0.0.1:8080").unwrap();
+
+    // As a service, stop requests drain the server, and the service stops
+    // once the open connections are finished
+    #[cfg(windows)]
+    if options.service {
+        server.run_as_service(SERVICE_NAME, listener).unwrap();
+        return;
+    }
+
+    // Print server startup message with URL
+    println!("Server running on http://127.0.0.1:8080");
+
+    // Main server loop
+    // Connections are accepted on an "acceptor" thread and each one is
+    // handled on its own "worker-N" thread; wait here until the acceptor stops
+    server.start(listener).unwrap().join().unwrap();
 }
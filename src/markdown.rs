@@ -0,0 +1,63 @@
+// Markdown rendering for static .md files
+
+use crate::escape::escape_html;
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag, TagEnd, html};
+use std::path::Path;
+
+// Page used when no template is configured
+pub const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>\n\
+<html>\n\
+<head>\n\
+<meta charset=\"utf-8\">\n\
+<title>{title}</title>\n\
+</head>\n\
+<body>\n\
+{content}\n\
+</body>\n\
+</html>\n";
+
+// Renders a Markdown document into the template
+// The title is the text of the first top-level heading, or the file name
+pub fn render(source: &str, template: &str, file_path: &Path) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let events: Vec<Event> = Parser::new_ext(source, options).collect();
+
+    let title = first_heading(&events).unwrap_or_else(|| {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    });
+    let mut content = String::new();
+    html::push_html(&mut content, events.into_iter());
+
+    // Substitute the title first, so "{title}" inside the document stays as written
+    template
+        .replace("{title}", &escape_html(&title))
+        .replace("{content}", &content)
+}
+
+// Returns the text of the first level 1 heading
+fn first_heading(events: &[Event]) -> Option<String> {
+    let start = events.iter().position(|event| {
+        matches!(
+            event,
+            Event::Start(Tag::Heading {
+                level: HeadingLevel::H1,
+                ..
+            })
+        )
+    })?;
+    let mut title = String::new();
+    for event in &events[start + 1..] {
+        match event {
+            Event::End(TagEnd::Heading(_)) => break,
+            Event::Text(text) | Event::Code(text) => title.push_str(text),
+            _ => {}
+        }
+    }
+    Some(title)
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// An HTTP request method
+/// Router methods accept either a Method or a method name
+/// Example: router.route(Method::Patch, "/users/{id}", update_user);
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+    // Any other token, e.g. WebDAV's PROPFIND
+    Other(String),
+}
+
+impl Method {
+    /// Returns the method name as sent on the wire
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Other(name) => name,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = std::convert::Infallible;
+
+    // Method names are case-sensitive, so "get" is an extension method
+    fn from_str(name: &str) -> Result<Method, Self::Err> {
+        Ok(match name {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            other => Method::Other(other.to_string()),
+        })
+    }
+}
+
+impl AsRef<str> for Method {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
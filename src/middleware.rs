@@ -0,0 +1,68 @@
+use crate::{Request, Response, vary};
+use std::sync::Arc;
+
+// Type-erased handler stored in the routing table
+pub(crate) type BoxedHandler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Code that runs around route handlers
+/// A middleware can answer the request itself (e.g. reject it) or call
+/// next.run(request) and inspect or modify the handler's response
+/// Example:
+/// router.layer(|request: &Request, next: &Next| match request.header("Authorization") {
+///     Some(_) => next.run(request),
+///     None => Response::new(401, "Unauthorized"),
+/// });
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: &Request, next: &Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, &Next) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the middleware chain, ending with the route handler
+pub struct Next<'a> {
+    stack: &'a [&'a Arc<dyn Middleware>],
+    handler: &'a BoxedHandler,
+}
+
+impl<'a> Next<'a> {
+    /// Passes the request on to the next middleware or the handler
+    pub fn run(&self, request: &Request) -> Response {
+        run_chain(self.stack, self.handler, request)
+    }
+
+    // Returns an owned copy of the rest of the chain, to run it on another thread
+    pub(crate) fn detach(&self) -> impl Fn(&Request) -> Response + Send + 'static {
+        let stack: Vec<Arc<dyn Middleware>> = self.stack.iter().map(|m| Arc::clone(m)).collect();
+        let handler = Arc::clone(self.handler);
+        move |request: &Request| {
+            let stack: Vec<&Arc<dyn Middleware>> = stack.iter().collect();
+            run_chain(&stack, &handler, request)
+        }
+    }
+}
+
+// Runs the request through the middleware stack and then the handler
+// Each layer's response gets the headers it read with Request::vary_header
+pub(crate) fn run_chain(
+    stack: &[&Arc<dyn Middleware>],
+    handler: &BoxedHandler,
+    request: &Request,
+) -> Response {
+    match stack.split_first() {
+        Some((middleware, rest)) => {
+            let next = Next {
+                stack: rest,
+                handler,
+            };
+            vary::collect(request, || middleware.handle(request, &next))
+        }
+        None => vary::collect(request, || handler(request)),
+    }
+}
@@ -0,0 +1,12 @@
+// Content types derived from file extensions
+
+use std::path::Path;
+
+// Returns the content type for a file path based on its extension
+pub fn content_type_for(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|s| s.to_str()) {
+        Some("html" | "shtml") => "text/html",
+        Some("css") => "text/css",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,77 @@
+// Read-only memory maps of static files
+// Large files are written straight from the page cache instead of being
+// copied through a userspace buffer chunk by chunk. If a mapped file is
+// truncated while it is being sent the process receives SIGBUS, so only
+// enable mapping for files that are replaced atomically (e.g. by rename)
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+/// A file mapped into memory, unmapped on drop
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps a whole file read-only
+    /// Empty files cannot be mapped and return an InvalidInput error
+    pub fn map(file: &File) -> io::Result<Mmap> {
+        use std::os::fd::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        // SAFETY: the fd is a valid open file and the kernel picks the address
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The file is sent front to back, let the kernel read ahead
+        // SAFETY: ptr and len describe the mapping created above
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for len bytes until drop
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: ptr and len describe a mapping owned by this value
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Maps the file at file_path if it is at least min_size bytes
+/// Returns None for smaller files or if mapping fails, so callers fall back to reading
+pub fn map_file(file_path: &Path, min_size: u64) -> Option<Mmap> {
+    let file = File::open(file_path).ok()?;
+    if file.metadata().ok()?.len() < min_size {
+        return None;
+    }
+    Mmap::map(&file).ok()
+}
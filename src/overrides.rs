@@ -0,0 +1,351 @@
+// Per-directory override files
+// With Router::directory_overrides, a .webserver.toml file in a directory of
+// the static root changes how the files under it are served, much like
+// Apache's .htaccess: it names the index files of directories, redirects
+// paths, requires Basic authentication and adds response headers. The files
+// of every directory from the root down to the requested path apply, inner
+// ones winning. Each file is parsed when first needed and kept until its
+// modification time or size changes, so a request costs one stat per
+// directory level. A file that fails to parse answers every request under
+// it with 500, rather than serving the subtree without its settings.
+//
+// Files are written in TOML:
+//
+//   index = ["index.htm", "default.html"]
+//
+//   [headers]
+//   Cache-Control = "no-cache"
+//
+//   [redirects]
+//   "old.html" = "/new.html"
+//   "archive/**" = "https://archive.example.com/"
+//
+//   [auth]
+//   realm = "Staff"
+//
+//   [auth.users]
+//   alice = "correct horse battery staple"
+
+use crate::credentials::{basic_credentials, secret_matches};
+use crate::glob::pattern_matches;
+use crate::request::percent_encode;
+use crate::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use toml::{Table, Value};
+
+/// Name of the override file read in each directory of the static root
+pub const OVERRIDE_FILE: &str = ".webserver.toml";
+
+// Index file of directories when override files name none
+const DEFAULT_INDEX: &str = "index.html";
+
+// Override files read so far, by path
+#[derive(Default)]
+pub(crate) struct OverrideFiles {
+    files: RwLock<HashMap<PathBuf, Arc<Loaded>>>,
+}
+
+// An override file as last read, with what tells whether it changed since
+struct Loaded {
+    modified: Option<SystemTime>,
+    len: u64,
+    settings: Result<Settings, String>,
+}
+
+// Settings of one override file
+#[derive(Default)]
+struct Settings {
+    index: Option<Vec<String>>,
+    headers: Vec<(String, String)>,
+    // Path patterns below the directory and the locations they redirect to
+    redirects: Vec<(String, String)>,
+    auth: Option<Auth>,
+}
+
+#[derive(Clone)]
+struct Auth {
+    realm: String,
+    // User names and passwords
+    users: Vec<(String, String)>,
+}
+
+// Settings of all the override files applying to a path
+#[derive(Default)]
+pub(crate) struct Overrides {
+    index: Option<Vec<String>>,
+    headers: Vec<(String, String)>,
+    redirect: Option<String>,
+    auth: Option<Auth>,
+}
+
+impl OverrideFiles {
+    // Collects the settings of the override files in base_dir and in each
+    // directory on the way to the relative path
+    // Returns Err with a message if one of them is invalid
+    pub(crate) fn resolve(&self, base_dir: &Path, relative: &Path) -> Result<Overrides, String> {
+        let mut overrides = Overrides::default();
+        let mut directory = base_dir.to_path_buf();
+        // Request path of the directory, ending in "/"
+        let mut url = String::from("/");
+        let mut components = relative.components();
+        loop {
+            let path = directory.join(OVERRIDE_FILE);
+            if let Some(loaded) = self.load(&path) {
+                let settings = loaded
+                    .settings
+                    .as_ref()
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                overrides.add(settings, &url, components.as_path());
+            }
+            let Some(component) = components.next() else {
+                return Ok(overrides);
+            };
+            directory.push(component);
+            url.push_str(&percent_encode(&component.as_os_str().to_string_lossy()));
+            url.push('/');
+        }
+    }
+
+    // Returns the override file at path, read again if it changed, or None
+    // if there is none
+    fn load(&self, path: &Path) -> Option<Arc<Loaded>> {
+        let Ok(metadata) = fs::metadata(path) else {
+            if self.files.read().unwrap().contains_key(path) {
+                self.files.write().unwrap().remove(path);
+            }
+            return None;
+        };
+        let modified = metadata.modified().ok();
+        if let Some(loaded) = self.files.read().unwrap().get(path)
+            && loaded.modified == modified
+            && loaded.len == metadata.len()
+        {
+            return Some(Arc::clone(loaded));
+        }
+        let settings = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| parse(&text));
+        if let Err(e) = &settings {
+            eprintln!("Invalid override file {}: {}", path.display(), e);
+        }
+        let loaded = Arc::new(Loaded {
+            modified,
+            len: metadata.len(),
+            settings,
+        });
+        self.files
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), Arc::clone(&loaded));
+        Some(loaded)
+    }
+}
+
+impl Overrides {
+    // Adds the settings of the file in the directory at url, over those of
+    // the directories above; rest is the path below the directory
+    fn add(&mut self, settings: &Settings, url: &str, rest: &Path) {
+        if let Some(index) = &settings.index {
+            self.index = Some(index.clone());
+        }
+        for (name, value) in &settings.headers {
+            self.headers
+                .retain(|(set, _)| !set.eq_ignore_ascii_case(name));
+            self.headers.push((name.clone(), value.clone()));
+        }
+        if let Some(auth) = &settings.auth {
+            self.auth = Some(auth.clone());
+        }
+        let rest = rest.iter().fold(String::new(), |path, name| {
+            path + "/" + &*name.to_string_lossy()
+        });
+        let redirect = settings
+            .redirects
+            .iter()
+            .find(|(pattern, _)| pattern_matches(pattern, &rest));
+        if let Some((_, location)) = redirect {
+            self.redirect = Some(
+                match location.starts_with('/') || location.contains("://") {
+                    true => location.clone(),
+                    false => format!("{}{}", url, location),
+                },
+            );
+        }
+    }
+
+    // Returns the response answering the request instead of the file, if
+    // credentials are missing or the path is redirected
+    pub(crate) fn answer(&self, request: &Request) -> Option<Response> {
+        if let Some(auth) = &self.auth
+            && !auth.authorizes(request)
+        {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", auth.realm);
+            return Some(
+                Response::from_status(StatusCode::UNAUTHORIZED)
+                    .with_header("WWW-Authenticate", &challenge),
+            );
+        }
+        let location = self.redirect.as_ref()?;
+        let location = match request.query.as_str() {
+            "" => location.clone(),
+            query if !location.contains('?') => format!("{}?{}", location, query),
+            _ => location.clone(),
+        };
+        Some(redirect(request, &location))
+    }
+
+    // Returns true if the path needs credentials ([auth])
+    pub(crate) fn requires_auth(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    // Returns the name of the first index file found in the directory,
+    // index.html unless override files name others
+    pub(crate) fn index_file(&self, directory: &Path) -> Option<&str> {
+        let names: Vec<&str> = match &self.index {
+            Some(index) => index.iter().map(String::as_str).collect(),
+            None => vec![DEFAULT_INDEX],
+        };
+        names
+            .into_iter()
+            .find(|name| directory.join(name).is_file())
+    }
+
+    // Returns the headers to send with the file, in the order they were set
+    pub(crate) fn into_headers(self) -> Vec<(String, String)> {
+        self.headers
+    }
+}
+
+impl Auth {
+    // Returns true if the request carries the Basic credentials of a user
+    fn authorizes(&self, request: &Request) -> bool {
+        let Some((user, password)) = basic_credentials(request, "Authorization") else {
+            return false;
+        };
+        self.users
+            .iter()
+            .any(|(name, expected)| *name == user && secret_matches(&password, expected))
+    }
+}
+
+// Redirects the request to location
+// 301 may turn other methods into GET, 308 keeps the method and body
+pub(crate) fn redirect(request: &Request, location: &str) -> Response {
+    let status = match request.method.as_str() {
+        "GET" | "HEAD" => StatusCode::MOVED_PERMANENTLY,
+        _ => StatusCode::PERMANENT_REDIRECT,
+    };
+    Response::from_status(status).with_header("Location", location)
+}
+
+// Parses an override file
+// Unknown tables and keys are errors, so that a typo can't silently leave a
+// directory unprotected
+fn parse(text: &str) -> Result<Settings, String> {
+    let table = text.parse::<Table>().map_err(|e| match e.span() {
+        Some(span) => format!(
+            "line {}: {}",
+            text[..span.start].matches('\n').count() + 1,
+            e.message()
+        ),
+        None => e.message().to_string(),
+    })?;
+    let mut settings = Settings::default();
+    for (key, value) in table {
+        let invalid = |what: &str, name: &str| format!("invalid {} {:?}", what, name);
+        match (key.as_str(), value) {
+            ("index", Value::Array(names)) => {
+                let names = names
+                    .into_iter()
+                    .map(|name| string(name).filter(|name| is_file_name(name)))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| invalid("index file name in", &key))?;
+                settings.index = Some(names);
+            }
+            ("headers", Value::Table(headers)) => {
+                for (name, value) in headers {
+                    let token = !name.is_empty()
+                        && name
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+                    let value = string(value)
+                        .filter(|value| token && is_header_safe(value))
+                        .ok_or_else(|| invalid("header", &name))?;
+                    settings.headers.push((name, value));
+                }
+            }
+            ("redirects", Value::Table(redirects)) => {
+                for (pattern, location) in redirects {
+                    let location = string(location)
+                        .filter(|location| is_header_safe(location))
+                        .ok_or_else(|| invalid("redirect location for", &pattern))?;
+                    // Patterns are paths below the directory; a trailing
+                    // slash makes no difference
+                    let pattern = format!("/{}", pattern.trim_matches('/'));
+                    settings.redirects.push((pattern, location));
+                }
+            }
+            ("auth", Value::Table(auth)) => settings.auth = Some(parse_auth(auth)?),
+            _ => return Err(format!("unexpected key {:?}", key)),
+        }
+    }
+    Ok(settings)
+}
+
+// Parses the [auth] table, which needs at least one user in [auth.users]
+fn parse_auth(table: Table) -> Result<Auth, String> {
+    let mut realm = None;
+    let mut users = Vec::new();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("realm", value) => {
+                let value = string(value)
+                    .filter(|value| is_header_safe(value) && !value.contains('"'))
+                    .ok_or_else(|| format!("invalid value of {:?}", key))?;
+                realm = Some(value);
+            }
+            ("users", Value::Table(table)) => {
+                for (user, password) in table {
+                    let password = string(password)
+                        .ok_or_else(|| format!("invalid password of {:?}", user))?;
+                    users.push((user, password));
+                }
+            }
+            _ => return Err(format!("unexpected key {:?} in [auth]", key)),
+        }
+    }
+    if users.is_empty() {
+        return Err("[auth] needs at least one user in [auth.users]".to_string());
+    }
+    Ok(Auth {
+        realm: realm.unwrap_or_else(|| "Restricted".to_string()),
+        users,
+    })
+}
+
+// Returns the text of a string value
+fn string(value: Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text),
+        _ => None,
+    }
+}
+
+// Returns true for a name of a file in the directory itself
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains('/')
+}
+
+// Returns true if the text can go in a header value as is
+fn is_header_safe(text: &str) -> bool {
+    !text.chars().any(|c| c.is_control() && c != '\t')
+}
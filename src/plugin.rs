@@ -0,0 +1,81 @@
+// Server extensions
+// Plugins are added to a Server, which starts them, lets them register
+// their routes and middleware on its router (again on every reload, so they
+// survive it) and stops them once the server is done. They are kept in the
+// order they were added and stopped in reverse.
+
+use crate::{Router, Server};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An extension of the server, e.g. a metrics exporter or an
+/// authentication provider, added with Server::plugin
+/// Only register is required; init and shutdown do nothing by default
+/// Example:
+/// struct RequestCounter(Arc<AtomicU64>);
+/// impl Plugin for RequestCounter {
+///     fn name(&self) -> &str {
+///         "request-counter"
+///     }
+///     fn register(&self, router: &mut Router) {
+///         let count = Arc::clone(&self.0);
+///         router.on_request(move |_| {
+///             count.fetch_add(1, Ordering::Relaxed);
+///         });
+///         let count = Arc::clone(&self.0);
+///         router.get("/requests", move || {
+///             Response::ok("text/plain", count.load(Ordering::Relaxed).to_string())
+///         });
+///     }
+/// }
+/// let server = Server::new(router, "static").plugin(RequestCounter(Arc::default()))?;
+pub trait Plugin: Send + Sync + 'static {
+    /// Names the plugin in messages
+    fn name(&self) -> &str;
+
+    /// Starts the plugin, before it registers anything. The server may be
+    /// kept, e.g. to read its stats; an error keeps the plugin out
+    fn init(&self, _server: &Server) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Adds the plugin's routes, middleware and hooks to the router
+    /// Called when the plugin is added and for the router of every reload
+    fn register(&self, router: &mut Router);
+
+    /// Stops the plugin, once the server stopped serving
+    fn shutdown(&self) {}
+}
+
+// The plugins of a server, shared by its clones
+#[derive(Default)]
+pub(crate) struct Registry {
+    plugins: Mutex<Vec<Arc<dyn Plugin>>>,
+    stopped: AtomicBool,
+}
+
+impl Registry {
+    // Keeps a started plugin
+    pub(crate) fn add(&self, plugin: Arc<dyn Plugin>) {
+        self.plugins.lock().unwrap().push(plugin);
+    }
+
+    // Lets every plugin register on a router
+    pub(crate) fn register(&self, router: &mut Router) {
+        for plugin in self.plugins.lock().unwrap().iter() {
+            plugin.register(router);
+        }
+    }
+
+    // Stops the plugins, last added first; only the first call does
+    pub(crate) fn shutdown(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let plugins = self.plugins.lock().unwrap().clone();
+        for plugin in plugins.iter().rev() {
+            plugin.shutdown();
+        }
+    }
+}
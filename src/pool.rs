@@ -0,0 +1,81 @@
+// Pool of byte buffers shared by all connections
+// Connections borrow a buffer for reading requests and composing response
+// heads and hand it back when done, so a busy server stops allocating fresh
+// buffers for every request once the pool has warmed up
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, OnceLock};
+
+// Buffers kept by the shared pool, roughly one per concurrently served request
+const SHARED_POOL_BUFFERS: usize = 256;
+// Larger buffers are freed instead of pooled, so one huge request does not pin memory
+const SHARED_POOL_MAX_CAPACITY: usize = 64 * 1024;
+
+/// A pool of reusable byte buffers
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+/// A buffer borrowed from a pool, returned to it on drop
+/// Buffers always start out empty
+pub struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl BufferPool {
+    /// Creates a pool keeping up to max_buffers buffers of at most max_capacity bytes
+    pub fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+            max_capacity,
+        }
+    }
+
+    /// Borrows a buffer, allocating a new one if the pool is empty
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer { buffer, pool: self }
+    }
+
+    // Takes a buffer back unless it is too large or the pool is full
+    fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > self.max_capacity {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+/// Returns the pool shared by all connections of the process
+pub fn shared() -> &'static BufferPool {
+    static POOL: OnceLock<BufferPool> = OnceLock::new();
+    POOL.get_or_init(|| BufferPool::new(SHARED_POOL_BUFFERS, SHARED_POOL_MAX_CAPACITY))
+}
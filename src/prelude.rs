@@ -0,0 +1,43 @@
+// Commonly used types, for glob import
+// Example: use rust_http_web_server::prelude::*;
+// Items are only added here, never removed or renamed, so downstream code
+// importing the prelude keeps compiling as the API grows
+
+#[cfg(feature = "acme")]
+pub use crate::Acme;
+#[cfg(feature = "async")]
+pub use crate::Async;
+#[cfg(feature = "compression")]
+pub use crate::Compression;
+#[cfg(feature = "scripting")]
+pub use crate::Scripts;
+#[cfg(feature = "metrics")]
+pub use crate::Stats;
+#[cfg(feature = "tls")]
+pub use crate::TlsConfig;
+#[cfg(feature = "tus")]
+pub use crate::Tus;
+#[cfg(feature = "wasm")]
+pub use crate::WasmHandler;
+pub use crate::extract::{Bytes, FromRequest, Headers, Text};
+#[cfg(feature = "router")]
+pub use crate::extract::{Json, Path, Query};
+pub use crate::{
+    AccessLog, AdminConfig, Body, BodyReader, ConcurrencyLimit, ContentSecurityPolicy,
+    CspDirective, CspSource, DebugCapture, HotSwap, IntoResponse, LogFormat, Method, Middleware,
+    Next, Plugin, Request, Response, ResponseCache, RouteHandler, Router, SecurityHeaders, Server,
+    ServerConfig, StatusCode, Syslog, Timeout, Upload, UploadedFile,
+};
+#[cfg(feature = "static")]
+pub use crate::{
+    Assets, DirectoryListing, ListingColumn, Publish, QuotaUsage, RobotsPolicy, SymlinkPolicy,
+    embed_assets,
+};
+#[cfg(feature = "proxy")]
+pub use crate::{
+    Balance, CircuitBreaker, ForwardProxy, HashKey, HealthCheck, Proxy, ProxyCache, RetryPolicy,
+};
+#[cfg(unix)]
+pub use crate::{Journald, PidFile, daemonize};
+#[cfg(windows)]
+pub use crate::{install_service, uninstall_service};
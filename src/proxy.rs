@@ -0,0 +1,614 @@
+// Reverse proxy
+// Requests routed to a Proxy are forwarded to an upstream HTTP/1.1 server,
+// or in turn to those of a pool (see the pool module) that health checks
+// find up (see the health module), each on a new connection, and its
+// response is streamed back. Hop-by-hop headers are dropped both ways, and
+// the upstream learns about the client through X-Forwarded-For,
+// X-Forwarded-Proto and X-Forwarded-Host. Upstreams that can't be reached
+// or answer with something other than HTTP/1.x get 502, upstreams not
+// answering within the timeout 504. With a ProxyCache, GET responses are
+// stored and revalidated (see the cache module), with a CircuitBreaker a
+// failing upstream is left alone for a while (see the breaker module), a
+// RetryPolicy sends failed requests again when that is safe (see the retry
+// module), and ForwardProxy tunnels CONNECT requests (see the forward
+// module).
+//
+// With the websocket feature, WebSocket handshakes are forwarded with their
+// Upgrade header; once the upstream answers 101, the client's connection is
+// handed over and bytes are copied both ways until either side closes or the
+// tunnel stays idle. Without it they are forwarded like any other request.
+
+mod breaker;
+mod cache;
+mod forward;
+mod health;
+mod pool;
+mod retry;
+mod tunnel;
+
+pub use breaker::CircuitBreaker;
+pub use cache::ProxyCache;
+pub use forward::ForwardProxy;
+pub use health::HealthCheck;
+pub use pool::{Balance, HashKey};
+pub use retry::RetryPolicy;
+
+use crate::{Body, Request, Response, RouteHandler, Router, StatusCode};
+use pool::{Pool, Upstream};
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Once};
+use std::thread;
+use std::time::Duration;
+
+// Time allowed to connect and for every read and write by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Time a tunnel may stay silent in both directions by default
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Methods forwarded by Router::proxy
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+// Headers meaningful for a single connection only (RFC 9110 section 7.6.1)
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// A route handler forwarding requests to an upstream server, or spreading
+/// them over several
+/// The request path and query are forwarded as they are, Host names the
+/// upstream and the original one is sent as X-Forwarded-Host
+/// Example:
+/// router.proxy("/api", Proxy::new("http://127.0.0.1:3000").timeout(Duration::from_secs(5)));
+/// router.get("/legacy/{*path}", Proxy::new("http://legacy.internal"));
+#[derive(Clone)]
+pub struct Proxy {
+    pool: Pool,
+    timeout: Duration,
+    #[cfg(feature = "websocket")]
+    idle_timeout: Duration,
+    cache: Option<ProxyCache>,
+    breaker: Option<CircuitBreaker>,
+    retry: Option<RetryPolicy>,
+    health_check: Option<HealthCheck>,
+    // Starts the health checks once; they stop when the last clone is gone
+    checks_started: Arc<Once>,
+}
+
+impl Proxy {
+    /// Creates a proxy to an http:// URL without a path
+    /// Panics on other URLs
+    pub fn new(url: &str) -> Proxy {
+        Proxy {
+            pool: Pool::new(Upstream::parse(url)),
+            timeout: DEFAULT_TIMEOUT,
+            #[cfg(feature = "websocket")]
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            cache: None,
+            breaker: None,
+            retry: None,
+            health_check: None,
+            checks_started: Arc::new(Once::new()),
+        }
+    }
+
+    /// Adds another upstream; requests go to each in turn
+    /// Example: Proxy::new("http://10.0.0.1:3000").upstream("http://10.0.0.2:3000")
+    /// Panics on invalid URLs, like new
+    pub fn upstream(mut self, url: &str) -> Proxy {
+        self.pool.add(Upstream::parse(url));
+        self
+    }
+
+    /// Sets the weight of the upstream given last (1 by default), so it
+    /// gets that many requests for every one sent to an upstream of weight 1
+    /// Example:
+    /// Proxy::new("http://big.internal:3000").weight(3)
+    ///     .upstream("http://small.internal:3000")
+    /// Panics if weight is 0
+    pub fn weight(mut self, weight: u32) -> Proxy {
+        self.pool.set_weight(weight);
+        self
+    }
+
+    /// Sets how requests are spread over the upstreams, each in turn by
+    /// default
+    pub fn balance(mut self, balance: Balance) -> Proxy {
+        self.pool.balance = balance;
+        self
+    }
+
+    /// Probes the upstreams in the background, taking those that fail out
+    /// of rotation until they recover
+    pub fn health_check(mut self, check: HealthCheck) -> Proxy {
+        self.health_check = Some(check);
+        self.checks_started = Arc::new(Once::new());
+        self
+    }
+
+    /// Sets how long connecting and each read or write may take before the
+    /// request is answered with 504
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long a proxied WebSocket may go without traffic either way
+    /// before it is closed (5 minutes by default)
+    #[cfg(feature = "websocket")]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Proxy {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Stores cacheable responses and revalidates them with the upstream
+    pub fn cache(mut self, cache: ProxyCache) -> Proxy {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sends idempotent requests again when the upstream fails them
+    pub fn retry(mut self, policy: RetryPolicy) -> Proxy {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Stops sending requests to the upstream for a while when too many of
+    /// them fail
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Proxy {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    // Forwards a request with the extra headers, retrying it as the policy
+    // allows and answering failures with 502 or 504
+    fn forward(&self, request: &Request, extra: &[(&str, &str)]) -> Response {
+        let retry = self
+            .retry
+            .as_ref()
+            .filter(|retry| retry.applies_to(request));
+        let mut attempt = 1;
+        loop {
+            if let Some(breaker) = &self.breaker
+                && !breaker.admit()
+            {
+                return breaker.short_circuit(request);
+            }
+            let upstream = self.pool.select(request);
+            let result = self.send(upstream, request, extra);
+            if let Some(breaker) = &self.breaker {
+                let failed = result
+                    .as_ref()
+                    .map_or(true, |response| response.status >= 500);
+                breaker.record(&self.pool, failed);
+            }
+            let Some(delay) = retry.and_then(|retry| retry.delay(attempt, &result)) else {
+                let response = self.answer(upstream, result);
+                return match self.pool.affinity_cookie(request, upstream) {
+                    Some(cookie) => response.with_header("Set-Cookie", &cookie),
+                    None => response,
+                };
+            };
+            if let Err(e) = &result {
+                eprintln!("Proxy to {} failed, retrying: {}", upstream.authority, e);
+            }
+            // A response not passed on closes its connection when dropped
+            drop(result);
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    // Passes on the upstream's response, or answers the error with 502 or 504
+    fn answer(&self, upstream: &Upstream, result: io::Result<Response>) -> Response {
+        result.unwrap_or_else(|e| {
+            eprintln!("Proxy to {} failed: {}", upstream.authority, e);
+            let status = match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            Response::from_status(status)
+        })
+    }
+
+    // Sends a request upstream and returns the response, its body still
+    // being read from the connection
+    fn send(
+        &self,
+        upstream: &Upstream,
+        request: &Request,
+        extra: &[(&str, &str)],
+    ) -> io::Result<Response> {
+        let mut stream = upstream.connect(self.timeout)?;
+        stream.write_all(&self.request_head(upstream, request, extra))?;
+        stream.write_all(&request.body)?;
+        let mut reader = BufReader::new(stream);
+        // Interim responses (100 Continue) are not passed on
+        let head = loop {
+            let head = read_head(&mut reader)?;
+            if !(100..200).contains(&head.status) || head.status == 101 {
+                break head;
+            }
+        };
+        #[cfg(feature = "websocket")]
+        if head.status == 101 && is_websocket(request) {
+            return Ok(self.switch(head, reader));
+        }
+        response_from(request, head, reader)
+    }
+
+    // Passes a 101 on to the client, then relays the connection to the
+    // upstream once the client's is handed over
+    #[cfg(feature = "websocket")]
+    fn switch(&self, head: Head, reader: BufReader<TcpStream>) -> Response {
+        let listed = connection_tokens(header_value(&head.headers, "Connection"));
+        let protocol = header_value(&head.headers, "Upgrade")
+            .unwrap_or("websocket")
+            .to_string();
+        let mut response = Response::new(head.status, &head.reason);
+        response.headers = head
+            .headers
+            .into_iter()
+            .filter(|(name, _)| !is_hop_by_hop(name, &listed))
+            .collect();
+        let idle_timeout = self.idle_timeout;
+        response
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", &protocol)
+            .with_upgrade(move |upgraded| {
+                // Either side may have sent frames right after the handshake
+                let early = reader.buffer().to_vec();
+                let mut upstream = reader.into_inner();
+                let mut client = upgraded.socket;
+                if client.write_all(&early).is_ok()
+                    && upstream.write_all(&upgraded.leftover).is_ok()
+                    && let Err(e) = tunnel::tunnel(client, upstream, Some(idle_timeout))
+                {
+                    eprintln!("WebSocket tunnel failed: {}", e);
+                }
+            })
+    }
+
+    // Formats the head of the forwarded request
+    fn request_head(
+        &self,
+        upstream: &Upstream,
+        request: &Request,
+        extra: &[(&str, &str)],
+    ) -> Vec<u8> {
+        let mut head = format!("{} {}", request.method, request.path);
+        if !request.query.is_empty() {
+            let _ = write!(head, "?{}", request.query);
+        }
+        let _ = write!(head, " HTTP/1.1\r\nHost: {}\r\n", upstream.authority);
+        let listed = connection_tokens(request.header("Connection"));
+        let mut forwarded_for = None;
+        for (name, value) in &request.headers {
+            if is_hop_by_hop(name, &listed)
+                || [
+                    "Host",
+                    "Content-Length",
+                    "X-Forwarded-Proto",
+                    "X-Forwarded-Host",
+                ]
+                .iter()
+                .any(|skipped| name.eq_ignore_ascii_case(skipped))
+                || extra
+                    .iter()
+                    .any(|(extra, _)| name.eq_ignore_ascii_case(extra))
+            {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                forwarded_for = Some(value.as_str());
+                continue;
+            }
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        // The peer is appended to the chain, like every proxy does
+        if let Some(ip) = request.remote_addr.map(|addr| addr.ip()) {
+            match forwarded_for {
+                Some(chain) => {
+                    let _ = write!(head, "X-Forwarded-For: {}, {}\r\n", chain, ip);
+                }
+                None => {
+                    let _ = write!(head, "X-Forwarded-For: {}\r\n", ip);
+                }
+            }
+        }
+        let _ = write!(head, "X-Forwarded-Proto: {}\r\n", request.scheme());
+        if let Some(host) = request.header("Host") {
+            let _ = write!(head, "X-Forwarded-Host: {}\r\n", host);
+        }
+        for (name, value) in extra {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        if !request.body.is_empty() || matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") {
+            let _ = write!(head, "Content-Length: {}\r\n", request.body.len());
+        }
+        // The connection stays open for the WebSocket if the upstream agrees
+        #[cfg(feature = "websocket")]
+        if let Some(upgrade) = request.header("Upgrade").filter(|_| is_websocket(request)) {
+            let _ = write!(head, "Upgrade: {}\r\nConnection: Upgrade\r\n\r\n", upgrade);
+            return head.into_bytes();
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+}
+
+impl RouteHandler<()> for Proxy {
+    fn call(&self, request: &Request) -> Response {
+        if let Some(check) = &self.health_check {
+            self.checks_started.call_once(|| {
+                check.spawn(
+                    self.pool.upstreams.clone(),
+                    Arc::downgrade(&self.checks_started),
+                )
+            });
+        }
+        #[cfg(feature = "websocket")]
+        let relayed = is_websocket(request);
+        #[cfg(not(feature = "websocket"))]
+        let relayed = false;
+        match &self.cache {
+            Some(cache) if !relayed => cache.handle(request, |extra| self.forward(request, extra)),
+            _ => self.forward(request, &[]),
+        }
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Proxy");
+        debug
+            .field("upstreams", &self.pool.to_string())
+            .field("timeout", &self.timeout);
+        #[cfg(feature = "websocket")]
+        debug.field("idle_timeout", &self.idle_timeout);
+        debug
+            .field("cache", &self.cache)
+            .field("breaker", &self.breaker)
+            .field("retry", &self.retry)
+            .field("health_check", &self.health_check)
+            .finish()
+    }
+}
+
+// Registers the proxy for every path under the router's prefix
+pub fn mount(router: &mut Router, prefix: &str, proxy: Proxy) {
+    router.scope(prefix, |upstream| {
+        for method in METHODS {
+            upstream.route(method, "/{*path}", proxy.clone());
+        }
+    });
+}
+
+// Status line and headers of an upstream response
+struct Head {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+// Reads a response head
+fn read_head(reader: &mut impl BufRead) -> io::Result<Head> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid response from the upstream");
+    let mut limited = reader.take(crate::MAX_HEAD_SIZE as u64);
+    let mut line = String::new();
+    if limited.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "upstream closed the connection",
+        ));
+    }
+    let mut status_line = line.trim_end().splitn(3, ' ');
+    if !status_line
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(invalid());
+    }
+    let status: u16 = status_line
+        .next()
+        .and_then(|status| status.parse().ok())
+        .filter(|status| (100..600).contains(status))
+        .ok_or_else(invalid)?;
+    let reason = status_line.next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if limited.read_line(&mut line)? == 0 {
+            return Err(invalid());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(Head {
+                status,
+                reason,
+                headers,
+            });
+        }
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// Builds the response passed to the client, with the body framed as the
+// upstream sent it
+fn response_from(
+    request: &Request,
+    head: Head,
+    reader: BufReader<TcpStream>,
+) -> io::Result<Response> {
+    let Head {
+        status,
+        reason,
+        headers,
+    } = head;
+    let header = |name: &str| header_value(&headers, name);
+    let chunked = header("Transfer-Encoding")
+        .is_some_and(|coding| coding.to_ascii_lowercase().contains("chunked"));
+    let length = match header("Content-Length") {
+        Some(length) => Some(length.parse::<u64>().map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid Content-Length from the upstream",
+            )
+        })?),
+        None => None,
+    };
+    let listed = connection_tokens(header("Connection"));
+    let mut response = Response::new(status, &reason);
+    response.headers = headers
+        .iter()
+        .filter(|(name, _)| {
+            !is_hop_by_hop(name, &listed) && !name.eq_ignore_ascii_case("Content-Length")
+        })
+        .cloned()
+        .collect();
+
+    if request.method == "HEAD" || matches!(status, 204 | 304) {
+        // Without a body; a HEAD response keeps the length of the GET one
+        if let Some(length) = length.filter(|_| status != 204) {
+            response.body = Body::from_reader(io::empty());
+            response = response.with_header("Content-Length", &length.to_string());
+        }
+        return Ok(response);
+    }
+    response.body = if chunked {
+        Body::from_reader(ChunkedReader::new(reader))
+    } else if let Some(length) = length {
+        response = response.with_header("Content-Length", &length.to_string());
+        Body::from_reader(reader.take(length))
+    } else {
+        // Delimited by the upstream closing the connection
+        Body::from_reader(reader)
+    };
+    Ok(response)
+}
+
+// Returns the value of a header, comparing names case-insensitively
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// Returns true for a GET asking to switch to the WebSocket protocol
+#[cfg(feature = "websocket")]
+fn is_websocket(request: &Request) -> bool {
+    let listed = connection_tokens(request.header("Connection"));
+    request.method == "GET"
+        && request
+            .header("Upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        && listed
+            .iter()
+            .any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
+
+// Returns true for headers not to be forwarded: the hop-by-hop ones and
+// those the Connection header lists
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+        || listed
+            .iter()
+            .any(|listed| name.eq_ignore_ascii_case(listed))
+}
+
+// Returns the header names listed in a Connection header
+fn connection_tokens(connection: Option<&str>) -> Vec<String> {
+    connection
+        .unwrap_or("")
+        .split(',')
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+// Decodes a chunked body, ending after the last chunk and its trailers
+struct ChunkedReader<R: BufRead> {
+    inner: R,
+    // Bytes left in the current chunk, None before the next size line
+    remaining: Option<u64>,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: None,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if (&mut self.inner).take(1024).read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "upstream closed the connection within a chunked body",
+            ));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            match self.remaining {
+                None => {
+                    let line = self.read_line()?;
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            "invalid chunk size from the upstream",
+                        )
+                    })?;
+                    if size == 0 {
+                        // Skip the trailers
+                        while !self.read_line()?.is_empty() {}
+                        self.done = true;
+                    } else {
+                        self.remaining = Some(size);
+                    }
+                }
+                Some(0) => {
+                    self.read_line()?;
+                    self.remaining = None;
+                }
+                Some(remaining) => {
+                    let max = buf.len().min(remaining as usize);
+                    let bytes_read = self.inner.read(&mut buf[..max])?;
+                    if bytes_read == 0 {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "upstream closed the connection within a chunk",
+                        ));
+                    }
+                    self.remaining = Some(remaining - bytes_read as u64);
+                    return Ok(bytes_read);
+                }
+            }
+        }
+    }
+}
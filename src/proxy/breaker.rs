@@ -0,0 +1,194 @@
+// Circuit breaker of a reverse proxy
+// The outcomes of the last requests sent upstream are kept in a window:
+// errors reaching or reading from the upstream, timeouts included, and 5xx
+// responses count as failures. Once enough of them failed, the circuit
+// opens and requests are answered without contacting the upstream, which
+// spares clients the timeouts and the upstream the load while it recovers.
+// After a cool-down, one request goes through as a probe: the circuit
+// closes if it succeeds and opens again otherwise.
+
+use crate::middleware::BoxedHandler;
+use crate::{Request, Response, RouteHandler, StatusCode};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Circuit breaker of a reverse proxy (see Proxy::circuit_breaker)
+/// The circuit opens when at least min_requests of the last window
+/// requests were sent and failure_ratio of them failed. While open,
+/// requests get 502 Bad Gateway, or the fallback handler's response, and
+/// after open_for a single probe request is let through to test whether the
+/// upstream recovered. Clones share the same state
+/// Example:
+/// let breaker = CircuitBreaker::new()
+///     .failure_ratio(0.5)
+///     .open_for(Duration::from_secs(10))
+///     .fallback(|| Response::ok("text/html", "<p>Back in a moment</p>"));
+/// router.proxy("/", Proxy::new("http://127.0.0.1:3000").circuit_breaker(breaker));
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    window: usize,
+    min_requests: usize,
+    failure_ratio: f64,
+    open_for: Duration,
+    fallback: Option<BoxedHandler>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // Outcomes of the last requests while closed, true for failures
+    outcomes: VecDeque<bool>,
+    phase: Phase,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Closed,
+    // Short-circuiting until the instant
+    Open(Instant),
+    // A probe request is out; another may go once the instant passed
+    Probing(Instant),
+}
+
+impl CircuitBreaker {
+    /// Creates a breaker opening when half of at least 10 of the last 20
+    /// requests failed, for 30 seconds
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            window: 20,
+            min_requests: 10,
+            failure_ratio: 0.5,
+            open_for: Duration::from_secs(30),
+            fallback: None,
+            state: Arc::new(Mutex::new(State {
+                outcomes: VecDeque::new(),
+                phase: Phase::Closed,
+            })),
+        }
+    }
+
+    /// Sets the share of failed requests opening the circuit, between 0
+    /// (excluded) and 1
+    /// Panics on other values
+    pub fn failure_ratio(mut self, ratio: f64) -> CircuitBreaker {
+        assert!(
+            ratio > 0.0 && ratio <= 1.0,
+            "failure ratio must be in (0, 1], got {}",
+            ratio
+        );
+        self.failure_ratio = ratio;
+        self
+    }
+
+    /// Sets how many of the last requests the failure ratio is computed on
+    /// Panics if requests is 0
+    pub fn window(mut self, requests: usize) -> CircuitBreaker {
+        assert!(requests > 0, "circuit breaker window cannot be empty");
+        self.window = requests;
+        self
+    }
+
+    /// Sets how many requests the window needs before the circuit can open,
+    /// at most the window size
+    pub fn min_requests(mut self, requests: usize) -> CircuitBreaker {
+        self.min_requests = requests;
+        self
+    }
+
+    /// Sets how long the circuit stays open before a probe request is sent
+    pub fn open_for(mut self, duration: Duration) -> CircuitBreaker {
+        self.open_for = duration;
+        self
+    }
+
+    /// Answers requests with the handler while the circuit is open
+    pub fn fallback<Args, H: RouteHandler<Args>>(mut self, handler: H) -> CircuitBreaker {
+        self.fallback = Some(Arc::new(move |request: &Request| handler.call(request)));
+        self
+    }
+
+    /// Returns true while requests are short-circuited, probes aside
+    pub fn is_open(&self) -> bool {
+        !matches!(self.state.lock().unwrap().phase, Phase::Closed)
+    }
+
+    // Returns true if a request may be sent upstream, letting a single probe
+    // through once the circuit was open long enough. A probe that never
+    // reported back is replaced after open_for
+    pub(super) fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.phase {
+            Phase::Closed => true,
+            Phase::Open(until) | Phase::Probing(until) if Instant::now() >= until => {
+                state.phase = Phase::Probing(Instant::now() + self.open_for);
+                true
+            }
+            Phase::Open(_) | Phase::Probing(_) => false,
+        }
+    }
+
+    // Records the outcome of an admitted request, opening or closing the
+    // circuit of the named upstreams
+    pub(super) fn record(&self, upstream: &impl fmt::Display, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match state.phase {
+            Phase::Probing(_) if failed => {
+                eprintln!("Circuit to {} stays open: probe failed", upstream);
+                state.phase = Phase::Open(Instant::now() + self.open_for);
+            }
+            Phase::Probing(_) => {
+                eprintln!("Circuit to {} closed", upstream);
+                state.outcomes.clear();
+                state.phase = Phase::Closed;
+            }
+            Phase::Closed => {
+                state.outcomes.push_back(failed);
+                while state.outcomes.len() > self.window {
+                    state.outcomes.pop_front();
+                }
+                let sent = state.outcomes.len();
+                let failures = state.outcomes.iter().filter(|&&failed| failed).count();
+                if sent >= self.min_requests.min(self.window)
+                    && failures as f64 >= self.failure_ratio * sent as f64
+                {
+                    eprintln!(
+                        "Circuit to {} opened: {} of the last {} requests failed",
+                        upstream, failures, sent
+                    );
+                    state.outcomes.clear();
+                    state.phase = Phase::Open(Instant::now() + self.open_for);
+                }
+            }
+            // Sent before the circuit opened
+            Phase::Open(_) => {}
+        }
+    }
+
+    // Answers a request while the circuit is open
+    pub(super) fn short_circuit(&self, request: &Request) -> Response {
+        match &self.fallback {
+            Some(fallback) => fallback(request),
+            None => Response::from_status(StatusCode::BAD_GATEWAY),
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new()
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("window", &self.window)
+            .field("min_requests", &self.min_requests)
+            .field("failure_ratio", &self.failure_ratio)
+            .field("open_for", &self.open_for)
+            .field("fallback", &self.fallback.is_some())
+            .field("phase", &self.state.lock().unwrap().phase)
+            .finish()
+    }
+}
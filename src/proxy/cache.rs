@@ -0,0 +1,422 @@
+// Shared cache of a reverse proxy (RFC 9111)
+// GET responses are stored when a shared cache may keep them: not no-store
+// or private, no "Vary: *" or Set-Cookie, and either an explicit lifetime
+// (s-maxage, max-age or Expires) or a validator to revalidate with. Fresh
+// responses are answered from the cache. Stale ones, those marked no-cache
+// and those a client asks to revalidate are checked with the upstream using
+// If-None-Match or If-Modified-Since, and a 304 refreshes the stored copy.
+// Requests carrying Authorization bypass the cache, and successful unsafe
+// requests drop the responses stored for their path. Bodies are kept in
+// memory or in files of the cache directory, the index in memory, in the
+// same storage as ResponseCache.
+
+use crate::conditional::matches_any;
+use crate::http_date::parse_http_date;
+use crate::response_cache::{
+    Variant, Variants, directive_seconds, has_directive, may_store, vary_of,
+};
+use crate::{Body, Request, Response, StatusCode};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+// Largest body stored by default
+const DEFAULT_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+// Statuses cacheable by default (RFC 9110 section 15.1)
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Storage of a caching reverse proxy (see Proxy::cache)
+/// Responses are stored and reused as the upstream's Cache-Control, Expires,
+/// ETag and Last-Modified headers allow, and answered with X-Cache: HIT or
+/// MISS. Clones share the same entries, so a clone kept outside the router
+/// can invalidate them
+/// Example:
+/// let cache = ProxyCache::new(10_000).dir("/var/cache/site/proxy");
+/// router.proxy("/", Proxy::new("http://127.0.0.1:3000").cache(cache.clone()));
+/// cache.invalidate("/news"); // after publishing
+#[derive(Clone)]
+pub struct ProxyCache {
+    max_entries: usize,
+    max_body_size: usize,
+    // Directory of the stored bodies, None to keep them in memory
+    dir: Option<PathBuf>,
+    // Variants by "path?query"
+    entries: Variants<Arc<Entry>>,
+}
+
+// One stored response and the request header values it was produced for
+struct Entry {
+    vary: Vec<(String, Option<String>)>,
+    status: u16,
+    reason: String,
+    // Without Content-Length and Age, which are set when answering
+    headers: Vec<(String, String)>,
+    body: Arc<Stored>,
+    // When the upstream produced the response, accounting for its Age
+    date: Instant,
+    // How long it is fresh; zero for responses revalidated on every use
+    lifetime: Duration,
+}
+
+// A stored body; files are removed once no entry uses them anymore
+enum Stored {
+    Memory(Vec<u8>),
+    File(PathBuf),
+}
+
+impl ProxyCache {
+    /// Creates a cache keeping at most max_entries responses, in memory
+    pub fn new(max_entries: usize) -> ProxyCache {
+        ProxyCache {
+            max_entries,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            dir: None,
+            entries: Variants::new(),
+        }
+    }
+
+    /// Keeps the bodies in files of dir, created if missing, instead of in
+    /// memory; the files are temporary and only valid while the server runs
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> ProxyCache {
+        let dir = dir.into();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!(
+                "Cannot create proxy cache directory {}: {}",
+                dir.display(),
+                e
+            );
+        }
+        self.dir = Some(dir);
+        self
+    }
+
+    /// Sets the largest body stored, in bytes (8 MiB by default); larger
+    /// responses are passed on without being stored
+    pub fn max_body_size(mut self, bytes: usize) -> ProxyCache {
+        self.max_body_size = bytes;
+        self
+    }
+
+    /// Drops the stored responses for a path, whatever the query
+    pub fn invalidate(&self, path: &str) {
+        self.entries
+            .retain(|key| key.split_once('?').map_or(key, |(path, _)| path) != path);
+    }
+
+    /// Drops all stored responses
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Returns the number of stored responses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no responses are stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Answers a request from the cache or with fetch, which forwards it
+    // upstream with extra headers
+    pub(super) fn handle(
+        &self,
+        request: &Request,
+        fetch: impl Fn(&[(&str, &str)]) -> Response,
+    ) -> Response {
+        let bypass = request.method != "GET"
+            || request.header("Authorization").is_some()
+            || has_directive(request.header("Cache-Control"), "no-store");
+        if bypass {
+            let response = fetch(&[]);
+            let safe = matches!(
+                request.method.as_str(),
+                "GET" | "HEAD" | "OPTIONS" | "TRACE"
+            );
+            if !safe && response.status < 400 {
+                self.invalidate(&request.path);
+            }
+            return response;
+        }
+
+        let key = match request.query.as_str() {
+            "" => request.path.clone(),
+            query => format!("{}?{}", request.path, query),
+        };
+        let stored = self.entries.find(&key, request);
+        let Some(stored) = stored else {
+            return self.store(key, request, fetch(&[]));
+        };
+        if stored.is_fresh()
+            && !wants_revalidation(request)
+            && let Some(response) = stored.answer(request)
+        {
+            return response;
+        }
+
+        let mut conditions = Vec::new();
+        if let Some(etag) = stored.header("ETag") {
+            conditions.push(("If-None-Match", etag));
+        }
+        if let Some(modified) = stored.header("Last-Modified") {
+            conditions.push(("If-Modified-Since", modified));
+        }
+        let response = fetch(&conditions);
+        if response.status == 304 && !conditions.is_empty() {
+            let refreshed = Arc::new(stored.refresh(&response));
+            self.entries
+                .insert(key.clone(), Arc::clone(&refreshed), self.max_entries);
+            if let Some(response) = refreshed.answer(request) {
+                return response;
+            }
+            return self.store(key, request, fetch(&[]));
+        }
+        self.store(key, request, response)
+    }
+
+    // Stores a response if it may be, and returns it marked as a miss
+    fn store(&self, key: String, request: &Request, mut response: Response) -> Response {
+        let lifetime = match lifetime(&response) {
+            Some(lifetime) if self.max_entries > 0 => lifetime,
+            _ => return response.with_header("X-Cache", "MISS"),
+        };
+        let Some(body) = self.buffer(&mut response) else {
+            return response.with_header("X-Cache", "MISS");
+        };
+        let stored = match &self.dir {
+            None => Stored::Memory(body.clone()),
+            Some(dir) => {
+                let path = dir.join(file_name(&key));
+                if let Err(e) = fs::write(&path, &body) {
+                    eprintln!("Cannot write proxy cache file {}: {}", path.display(), e);
+                    response.body = Body::Full(body);
+                    return response.with_header("X-Cache", "MISS");
+                }
+                Stored::File(path)
+            }
+        };
+        let entry = Arc::new(Entry {
+            vary: vary_of(request, &response),
+            status: response.status,
+            reason: response.reason.clone(),
+            headers: response
+                .headers
+                .iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("Age"))
+                .cloned()
+                .collect(),
+            body: Arc::new(stored),
+            date: Instant::now()
+                .checked_sub(age_of(&response))
+                .unwrap_or_else(Instant::now),
+            lifetime,
+        });
+        // The responses that went stale first make room
+        self.entries.insert(key, entry, self.max_entries);
+
+        response.body = Body::Full(body);
+        response.with_header("X-Cache", "MISS")
+    }
+
+    // Reads the whole body of a response into memory, if it is small enough
+    // to be stored; larger bodies are put back to be streamed
+    fn buffer(&self, response: &mut Response) -> Option<Vec<u8>> {
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        if length.is_some_and(|length| length > self.max_body_size as u64) {
+            return None;
+        }
+        let reader = std::mem::take(&mut response.body).into_reader();
+        let mut body = Vec::new();
+        let mut limited = reader.take(self.max_body_size as u64 + 1);
+        if let Err(e) = limited.read_to_end(&mut body) {
+            eprintln!("Cannot read upstream response: {}", e);
+            response.status = 502;
+            response.reason = StatusCode::BAD_GATEWAY.reason().to_string();
+            response.headers.clear();
+            return None;
+        }
+        if body.len() > self.max_body_size {
+            let rest = limited.into_inner();
+            response.body = Body::from_reader(Cursor::new(body).chain(rest));
+            return None;
+        }
+        response
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        Some(body)
+    }
+}
+
+impl fmt::Debug for ProxyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyCache")
+            .field("max_entries", &self.max_entries)
+            .field("max_body_size", &self.max_body_size)
+            .field("dir", &self.dir)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl Entry {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.date.elapsed() < self.lifetime
+    }
+
+    // Builds the response to a request from the stored copy, a 304 if the
+    // client already has it; None if the body file is gone
+    fn answer(&self, request: &Request) -> Option<Response> {
+        let age = self.date.elapsed().as_secs().to_string();
+        if let (Some(tags), Some(etag)) = (request.header("If-None-Match"), self.header("ETag"))
+            && matches_any(tags, etag, false)
+        {
+            let mut response = Response::from_status(StatusCode::NOT_MODIFIED);
+            for name in ["ETag", "Cache-Control", "Expires", "Last-Modified", "Vary"] {
+                if let Some(value) = self.header(name) {
+                    response = response.with_header(name, value);
+                }
+            }
+            return Some(
+                response
+                    .with_header("Age", &age)
+                    .with_header("X-Cache", "HIT"),
+            );
+        }
+        let mut response = Response::new(self.status, &self.reason);
+        response.headers = self.headers.clone();
+        match &*self.body {
+            Stored::Memory(bytes) => response.body = Body::Full(bytes.clone()),
+            Stored::File(path) => {
+                let file = File::open(path).ok()?;
+                let length = file.metadata().ok()?.len();
+                response.body = Body::from_reader(file);
+                response = response.with_header("Content-Length", &length.to_string());
+            }
+        }
+        Some(
+            response
+                .with_header("Age", &age)
+                .with_header("X-Cache", "HIT"),
+        )
+    }
+
+    // Returns a copy updated with the headers of a 304 revalidating it
+    fn refresh(&self, not_modified: &Response) -> Entry {
+        let mut headers = self.headers.clone();
+        for (name, value) in &not_modified.headers {
+            if ["Content-Length", "Age", "X-Cache"]
+                .iter()
+                .any(|skipped| name.eq_ignore_ascii_case(skipped))
+            {
+                continue;
+            }
+            headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+            headers.push((name.clone(), value.clone()));
+        }
+        let mut refreshed = Response::new(self.status, &self.reason);
+        refreshed.headers = headers;
+        Entry {
+            vary: self.vary.clone(),
+            status: self.status,
+            reason: self.reason.clone(),
+            lifetime: lifetime(&refreshed).unwrap_or_default(),
+            headers: refreshed.headers,
+            body: Arc::clone(&self.body),
+            date: Instant::now()
+                .checked_sub(age_of(not_modified))
+                .unwrap_or_else(Instant::now),
+        }
+    }
+}
+
+impl Variant for Entry {
+    fn vary(&self) -> &[(String, Option<String>)] {
+        &self.vary
+    }
+
+    fn expires(&self) -> Instant {
+        self.date + self.lifetime
+    }
+}
+
+impl Drop for Stored {
+    fn drop(&mut self) {
+        if let Stored::File(path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Returns how long a response stays fresh, or None if it must not be stored
+fn lifetime(response: &Response) -> Option<Duration> {
+    if !CACHEABLE_STATUSES.contains(&response.status) || !may_store(response) {
+        return None;
+    }
+    let cache_control = response.header("Cache-Control");
+    let explicit = directive_seconds(cache_control, "s-maxage")
+        .or_else(|| directive_seconds(cache_control, "max-age"))
+        .or_else(|| {
+            let expires =
+                parse_http_date(response.header("Expires")?).unwrap_or(SystemTime::UNIX_EPOCH);
+            let date = response
+                .header("Date")
+                .and_then(parse_http_date)
+                .unwrap_or_else(SystemTime::now);
+            Some(expires.duration_since(date).unwrap_or_default())
+        });
+    let validated = response.header("ETag").is_some() || response.header("Last-Modified").is_some();
+    let lifetime = match explicit {
+        Some(lifetime) if !has_directive(cache_control, "no-cache") => lifetime,
+        _ => Duration::ZERO,
+    };
+    (lifetime > Duration::ZERO || validated).then_some(lifetime)
+}
+
+// Returns the Age the upstream reported
+fn age_of(response: &Response) -> Duration {
+    let seconds = response
+        .header("Age")
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(0);
+    Duration::from_secs(seconds)
+}
+
+// Returns true if the client asks for the stored copy to be revalidated
+fn wants_revalidation(request: &Request) -> bool {
+    let cache_control = request.header("Cache-Control");
+    has_directive(cache_control, "no-cache")
+        || has_directive(cache_control, "max-age=0")
+        || request
+            .header("Pragma")
+            .is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"))
+}
+
+// Names the file of a stored body, unique for every response stored
+fn file_name(key: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!(
+        "{:016x}-{}-{}",
+        hasher.finish(),
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
@@ -0,0 +1,258 @@
+// Forward proxy for CONNECT tunnels
+// A client asks for a tunnel with "CONNECT host:port"; if the destination is
+// allowed, the proxy connects to it, answers 200 and copies bytes both ways
+// until either side is done, so TLS and any other protocol go through it
+// unchanged. Destinations are refused unless they match the allowlist:
+// names are checked as the client sent them, without a DNS lookup, and
+// address ranges against the addresses the name resolves to, connecting
+// only to those in range. Requests in absolute form (GET http://...) are
+// not forwarded.
+
+use super::{DEFAULT_IDLE_TIMEOUT, DEFAULT_TIMEOUT, tunnel};
+use crate::credentials::basic_credentials;
+use crate::forwarded::IpRange;
+use crate::{Request, Response, RouteHandler, StatusCode};
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Checks a user name and password
+type Authenticate = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// A forward proxy opening CONNECT tunnels to allowed destinations (see
+/// Router::forward_proxy)
+/// Destinations are "host:port" patterns: the host is a name, "*.name" for
+/// its subdomains, an address or CIDR range, or "*" for any, and the port a
+/// number or "*"
+/// Example:
+/// let proxy = ForwardProxy::new()
+///     .allow("*.example.com:443")
+///     .allow("10.0.0.0/8:*")
+///     .authenticate(|user, password| user == "ci" && password == "secret");
+/// router.forward_proxy(proxy);
+#[derive(Clone)]
+pub struct ForwardProxy {
+    allowed: Vec<Destination>,
+    authenticate: Option<Authenticate>,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+// An allowlist entry
+#[derive(Clone, Debug)]
+struct Destination {
+    host: HostPattern,
+    // None for any port
+    port: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+enum HostPattern {
+    Any,
+    // A lowercase name or address, matched exactly
+    Name(String),
+    // Subdomains of a name, stored with the leading dot (".example.com")
+    Subdomains(String),
+    Range(IpRange),
+}
+
+impl ForwardProxy {
+    /// Creates a proxy refusing every destination until some are allowed
+    pub fn new() -> ForwardProxy {
+        ForwardProxy {
+            allowed: Vec::new(),
+            authenticate: None,
+            connect_timeout: DEFAULT_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Allows tunnels to destinations matching a "host:port" pattern
+    /// Example: proxy.allow("api.example.com:443").allow("[::1]:*")
+    /// Panics on invalid patterns
+    pub fn allow(mut self, pattern: &str) -> ForwardProxy {
+        let destination = Destination::parse(pattern)
+            .unwrap_or_else(|| panic!("invalid destination pattern {:?}", pattern));
+        self.allowed.push(destination);
+        self
+    }
+
+    /// Requires HTTP Basic credentials in Proxy-Authorization that
+    /// authenticate accepts, answering 407 otherwise
+    pub fn authenticate(
+        mut self,
+        authenticate: impl Fn(&str, &str) -> bool + Send + Sync + 'static,
+    ) -> ForwardProxy {
+        self.authenticate = Some(Arc::new(authenticate));
+        self
+    }
+
+    /// Sets how long connecting to a destination may take before the
+    /// request is answered with 504
+    pub fn connect_timeout(mut self, timeout: Duration) -> ForwardProxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a tunnel may go without traffic either way before it
+    /// is closed (5 minutes by default)
+    pub fn idle_timeout(mut self, timeout: Duration) -> ForwardProxy {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    // Returns the addresses a tunnel to the destination may connect to,
+    // resolving the name only when an address range could allow it
+    fn allowed_addresses(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let candidates: Vec<&Destination> = self
+            .allowed
+            .iter()
+            .filter(|destination| destination.port.is_none_or(|allowed| allowed == port))
+            .collect();
+        let by_name = candidates
+            .iter()
+            .any(|destination| destination.host.matches_name(host));
+        let ranges: Vec<&IpRange> = candidates
+            .iter()
+            .filter_map(|destination| match &destination.host {
+                HostPattern::Range(range) => Some(range),
+                _ => None,
+            })
+            .collect();
+        if !by_name && ranges.is_empty() {
+            return Ok(Vec::new());
+        }
+        let addrs = (host, port).to_socket_addrs()?;
+        Ok(addrs
+            .filter(|addr| by_name || ranges.iter().any(|range| range.contains(addr.ip())))
+            .collect())
+    }
+
+    // Connects to the first address that answers
+    fn connect(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(ErrorKind::NotFound, "no address for the destination");
+        for addr in addrs {
+            match TcpStream::connect_timeout(addr, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl Default for ForwardProxy {
+    fn default() -> ForwardProxy {
+        ForwardProxy::new()
+    }
+}
+
+impl RouteHandler<()> for ForwardProxy {
+    fn call(&self, request: &Request) -> Response {
+        if let Some(authenticate) = &self.authenticate {
+            match basic_credentials(request, "Proxy-Authorization") {
+                Some((user, password)) if authenticate(&user, &password) => {}
+                _ => {
+                    return Response::from_status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                        .with_header("Proxy-Authenticate", "Basic realm=\"proxy\"");
+                }
+            }
+        }
+        let Some((host, port)) = parse_target(&request.path) else {
+            return Response::bad_request("CONNECT needs a host:port target");
+        };
+        let addrs = match self.allowed_addresses(&host, port) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                eprintln!("Cannot resolve {}: {}", host, e);
+                return Response::from_status(StatusCode::BAD_GATEWAY);
+            }
+        };
+        if addrs.is_empty() {
+            return Response::from_status(StatusCode::FORBIDDEN);
+        }
+        let mut target = match self.connect(&addrs) {
+            Ok(target) => target,
+            Err(e) => {
+                eprintln!("Tunnel to {}:{} failed: {}", host, port, e);
+                let status = match e.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => StatusCode::GATEWAY_TIMEOUT,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                return Response::from_status(status);
+            }
+        };
+        let idle_timeout = self.idle_timeout;
+        Response::new(200, "Connection Established").with_upgrade(move |upgraded| {
+            // Bytes the client sent right after the request go first
+            if target.write_all(&upgraded.leftover).is_ok()
+                && let Err(e) = tunnel::tunnel(upgraded.socket, target, Some(idle_timeout))
+            {
+                eprintln!("Tunnel failed: {}", e);
+            }
+        })
+    }
+}
+
+impl fmt::Debug for ForwardProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForwardProxy")
+            .field("allowed", &self.allowed)
+            .field("authenticate", &self.authenticate.is_some())
+            .field("connect_timeout", &self.connect_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl Destination {
+    // Parses a "host:port" pattern
+    fn parse(pattern: &str) -> Option<Destination> {
+        let (host, port) = pattern.rsplit_once(':')?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().ok()?),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(name) = host.strip_prefix("*.") {
+            HostPattern::Subdomains(format!(".{}", name.to_ascii_lowercase()))
+        } else if host.contains('/') {
+            HostPattern::Range(IpRange::parse(host)?)
+        } else if !host.is_empty() && !host.contains('*') {
+            HostPattern::Name(host.to_ascii_lowercase())
+        } else {
+            return None;
+        };
+        Some(Destination { host, port })
+    }
+}
+
+impl HostPattern {
+    // Returns true if the name the client asked for matches
+    fn matches_name(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Name(name) => *name == host,
+            HostPattern::Subdomains(suffix) => host.ends_with(suffix.as_str()),
+            HostPattern::Range(_) => false,
+        }
+    }
+}
+
+// Splits a CONNECT target into its host and port
+fn parse_target(target: &str) -> Option<(String, u16)> {
+    let (host, port) = target.trim_start_matches('/').rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
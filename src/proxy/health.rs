@@ -0,0 +1,139 @@
+// Active health checks of a reverse proxy's upstreams
+// A background thread sends a GET for the configured path to every
+// upstream in turn, then sleeps for the interval. An upstream answering
+// with an error status, or not in time, for enough probes in a row is
+// taken out of rotation, and put back after enough successful ones. The
+// thread starts with the proxy's first request and stops once the proxy
+// and all its clones are dropped.
+
+use super::pool::Upstream;
+use super::read_head;
+use std::io::{self, BufReader, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Once, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Periodic probes of a proxy's upstreams (see Proxy::health_check)
+/// An upstream is considered up while it answers GET requests for the path
+/// with a 2xx or 3xx status. By default it is probed every 10 seconds with
+/// a 2 second timeout, taken out of rotation after 3 failed probes in a row
+/// and put back after 2 successful ones
+/// Example:
+/// let check = HealthCheck::new("/healthz").interval(Duration::from_secs(5));
+/// let proxy = Proxy::new("http://10.0.0.1:3000")
+///     .upstream("http://10.0.0.2:3000")
+///     .health_check(check);
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+    healthy_after: u32,
+    unhealthy_after: u32,
+}
+
+impl HealthCheck {
+    /// Creates checks requesting the path, e.g. "/health"
+    /// Panics if the path does not start with a slash
+    pub fn new(path: &str) -> HealthCheck {
+        assert!(
+            path.starts_with('/'),
+            "health check path must start with '/', got {:?}",
+            path
+        );
+        HealthCheck {
+            path: path.to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            healthy_after: 2,
+            unhealthy_after: 3,
+        }
+    }
+
+    /// Sets the time between two rounds of probes
+    pub fn interval(mut self, interval: Duration) -> HealthCheck {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long connecting and answering a probe may take
+    pub fn timeout(mut self, timeout: Duration) -> HealthCheck {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many successful probes in a row put an upstream back
+    pub fn healthy_after(mut self, probes: u32) -> HealthCheck {
+        self.healthy_after = probes.max(1);
+        self
+    }
+
+    /// Sets how many failed probes in a row take an upstream out
+    pub fn unhealthy_after(mut self, probes: u32) -> HealthCheck {
+        self.unhealthy_after = probes.max(1);
+        self
+    }
+
+    // Starts probing the upstreams until the token is dropped
+    pub(super) fn spawn(&self, upstreams: Vec<Upstream>, token: Weak<Once>) {
+        let check = self.clone();
+        let spawned = thread::Builder::new()
+            .name("health-check".to_string())
+            .spawn(move || {
+                // Successful and failed probes in a row, per upstream
+                let mut streaks = vec![(0, 0); upstreams.len()];
+                while token.strong_count() > 0 {
+                    for (upstream, streak) in upstreams.iter().zip(&mut streaks) {
+                        check.update(upstream, streak, check.probe(upstream));
+                    }
+                    thread::sleep(check.interval);
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to spawn health check thread: {}", e);
+        }
+    }
+
+    // Counts a probe's outcome, taking the upstream out of rotation or
+    // putting it back once the streak is long enough
+    fn update(&self, upstream: &Upstream, streak: &mut (u32, u32), outcome: io::Result<()>) {
+        let (successes, failures) = streak;
+        match outcome {
+            Ok(()) => {
+                *successes += 1;
+                *failures = 0;
+                if !upstream.is_up() && *successes >= self.healthy_after {
+                    eprintln!("Upstream {} is back in rotation", upstream.authority);
+                    upstream.up.store(true, Ordering::Relaxed);
+                }
+            }
+            Err(e) => {
+                *successes = 0;
+                *failures += 1;
+                if upstream.is_up() && *failures >= self.unhealthy_after {
+                    eprintln!(
+                        "Upstream {} taken out of rotation: {}",
+                        upstream.authority, e
+                    );
+                    upstream.up.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    // Sends one probe, failing unless the answer has a 2xx or 3xx status
+    fn probe(&self, upstream: &Upstream) -> io::Result<()> {
+        let mut stream = upstream.connect(self.timeout)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            self.path, upstream.authority
+        )?;
+        let head = read_head(&mut BufReader::new(stream))?;
+        if !(200..400).contains(&head.status) {
+            return Err(io::Error::other(format!("answered {}", head.status)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+// Upstream servers of a reverse proxy
+// A proxy forwards to one upstream or spreads requests over several, in
+// turn or keeping each client on the same one (see Balance). Weights make
+// some upstreams get proportionately more requests. Upstreams
+// found down by health checks (see the health module) are skipped until
+// they recover; when none is up, all of them are tried rather than failing
+// every request. Sticky clients whose upstream went down move to another.
+//
+// Consistent hashing places every upstream at many points of a ring of
+// hashes; a request goes to the first upstream up at or after its key's
+// hash. Adding or removing an upstream only moves the keys of the ring
+// segments it gains or loses, so caches on the upstreams stay mostly warm.
+// An upstream gets as many points per unit of weight.
+
+use crate::Request;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+// Points of the hash ring per upstream and unit of weight
+const VIRTUAL_NODES: usize = 160;
+
+// Address of an upstream server and whether it is in rotation
+#[derive(Clone, Debug)]
+pub(super) struct Upstream {
+    pub host: String,
+    pub port: u16,
+    // Host header value, the host with the port if it is not 80
+    pub authority: String,
+    // Shared with the health checks and the proxy's clones
+    pub up: Arc<AtomicBool>,
+    // Share of the requests relative to the other upstreams
+    pub weight: u32,
+}
+
+/// How a Proxy with several upstreams picks the one for a request (see
+/// Proxy::balance)
+/// Example:
+/// Proxy::new("http://10.0.0.1:3000")
+///     .upstream("http://10.0.0.2:3000")
+///     .balance(Balance::Cookie("backend".to_string()))
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Balance {
+    // Each upstream in turn
+    #[default]
+    RoundRobin,
+    // Each in turn for a client's first request; its response sets the
+    // cookie with this name to the upstream's id, and later requests
+    // carrying it go to the same upstream
+    Cookie(String),
+    // The upstream the client's address (see Request::client_ip) hashes to
+    ClientIp,
+    // The upstream owning the key's segment of a hash ring; requests
+    // without the key go to each upstream in turn
+    ConsistentHash(HashKey),
+}
+
+/// The request attribute Balance::ConsistentHash maps to upstreams
+/// Example: Balance::ConsistentHash(HashKey::Header("X-Tenant".to_string()))
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashKey {
+    // The path with the query, so each URL sticks to one upstream cache
+    Path,
+    // The value of a request header
+    Header(String),
+    // The value of a cookie
+    Cookie(String),
+}
+
+// The upstreams requests are spread over
+#[derive(Clone, Debug)]
+pub(super) struct Pool {
+    pub upstreams: Vec<Upstream>,
+    pub balance: Balance,
+    // Weighted round-robin credit of each upstream, shared by the proxy's
+    // clones
+    credits: Arc<Mutex<Vec<i64>>>,
+    // Ring positions and upstream indexes sorted by position, built on
+    // first use for consistent hashing
+    ring: OnceLock<Vec<(u64, usize)>>,
+}
+
+impl Upstream {
+    // Parses an http:// URL without a path
+    // Panics on other URLs
+    pub fn parse(url: &str) -> Upstream {
+        let authority = url
+            .strip_prefix("http://")
+            .map(|rest| rest.trim_end_matches('/'))
+            .filter(|authority| !authority.is_empty() && !authority.contains('/'))
+            .unwrap_or_else(|| {
+                panic!(
+                    "invalid upstream URL {:?}, expected http://host[:port]",
+                    url
+                )
+            });
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .unwrap_or_else(|_| panic!("invalid port in upstream URL {:?}", url)),
+            ),
+            _ => (authority, 80),
+        };
+        Upstream {
+            host: host.trim_matches(['[', ']']).to_string(),
+            port,
+            authority: authority.to_string(),
+            up: Arc::new(AtomicBool::new(true)),
+            weight: 1,
+        }
+    }
+
+    // Opens a connection, trying each of the upstream's addresses
+    pub fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(ErrorKind::NotFound, "no address for the upstream");
+        for addr in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    // Identifies the upstream in affinity cookies, the same across restarts
+    fn id(&self) -> String {
+        format!("{:016x}", fnv1a(self.authority.as_bytes()))
+    }
+}
+
+impl Pool {
+    pub fn new(first: Upstream) -> Pool {
+        Pool {
+            upstreams: vec![first],
+            balance: Balance::RoundRobin,
+            credits: Arc::new(Mutex::new(vec![0])),
+            ring: OnceLock::new(),
+        }
+    }
+
+    pub fn add(&mut self, upstream: Upstream) {
+        self.upstreams.push(upstream);
+        self.reset();
+    }
+
+    // Sets the weight of the upstream added last
+    pub fn set_weight(&mut self, weight: u32) {
+        assert!(weight > 0, "upstream weight must be at least 1");
+        if let Some(upstream) = self.upstreams.last_mut() {
+            upstream.weight = weight;
+        }
+        self.reset();
+    }
+
+    // Starts balancing afresh after the upstreams changed
+    fn reset(&mut self) {
+        self.credits = Arc::new(Mutex::new(vec![0; self.upstreams.len()]));
+        self.ring = OnceLock::new();
+    }
+
+    // Returns the upstream the request goes to
+    pub fn select(&self, request: &Request) -> &Upstream {
+        let all = 0..self.upstreams.len();
+        let up: Vec<usize> = all.clone().filter(|&i| self.upstreams[i].is_up()).collect();
+        let candidates = match up.is_empty() {
+            true => all.collect(),
+            false => up,
+        };
+        let sticky = match &self.balance {
+            Balance::RoundRobin => None,
+            Balance::Cookie(name) => cookie(request, name).and_then(|id| {
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|&index| self.upstreams[index].id() == id)
+            }),
+            Balance::ClientIp => request.client_ip().map(|ip| {
+                let hash = fnv1a(ip.to_string().as_bytes());
+                candidates[(hash % candidates.len() as u64) as usize]
+            }),
+            Balance::ConsistentHash(key) => {
+                let key = match key {
+                    HashKey::Path if request.query.is_empty() => Some(request.path.clone()),
+                    HashKey::Path => Some(format!("{}?{}", request.path, request.query)),
+                    HashKey::Header(name) => request.header(name).map(str::to_string),
+                    HashKey::Cookie(name) => cookie(request, name).map(str::to_string),
+                };
+                key.map(|key| self.on_ring(&key, &candidates))
+            }
+        };
+        &self.upstreams[sticky.unwrap_or_else(|| self.next_in_turn(&candidates))]
+    }
+
+    // Smooth weighted round-robin: every candidate earns its weight in
+    // credit and the richest one goes, paying the total, so an upstream of
+    // weight 3 next to one of weight 1 gets 3 requests out of 4, spread out
+    // rather than in a row
+    fn next_in_turn(&self, candidates: &[usize]) -> usize {
+        let mut credits = self.credits.lock().unwrap();
+        let mut chosen = candidates[0];
+        for &index in candidates {
+            credits[index] += i64::from(self.upstreams[index].weight);
+            if credits[index] > credits[chosen] {
+                chosen = index;
+            }
+        }
+        let total: i64 = candidates
+            .iter()
+            .map(|&index| i64::from(self.upstreams[index].weight))
+            .sum();
+        credits[chosen] -= total;
+        chosen
+    }
+
+    // Returns the first candidate at or after the key's position on the ring
+    fn on_ring(&self, key: &str, candidates: &[usize]) -> usize {
+        let ring = self.ring.get_or_init(|| {
+            let mut ring = Vec::new();
+            for (index, upstream) in self.upstreams.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES * upstream.weight as usize {
+                    let point = format!("{}#{}", upstream.authority, node);
+                    ring.push((ring_hash(point.as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+            ring
+        });
+        let start = ring.partition_point(|&(point, _)| point < ring_hash(key.as_bytes()));
+        ring[start..]
+            .iter()
+            .chain(&ring[..start])
+            .map(|&(_, index)| index)
+            .find(|index| candidates.contains(index))
+            .unwrap_or(candidates[0])
+    }
+
+    // Returns the Set-Cookie value tying the client to the upstream, if
+    // its request did not name that one already
+    pub fn affinity_cookie(&self, request: &Request, upstream: &Upstream) -> Option<String> {
+        let Balance::Cookie(name) = &self.balance else {
+            return None;
+        };
+        let id = upstream.id();
+        (cookie(request, name) != Some(&id)).then(|| format!("{}={}; Path=/; HttpOnly", name, id))
+    }
+}
+
+// Names the upstreams in log messages
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(&upstream.authority)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the value of a cookie sent with the request
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .header("Cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// FNV-1a hash, stable across runs unlike the standard library's
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// FNV-1a followed by MurmurHash3's finalizer, so that similar keys spread
+// over the whole ring
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
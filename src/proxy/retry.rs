@@ -0,0 +1,75 @@
+// Retries of a reverse proxy
+// A request is only sent again when doing so cannot change the outcome on
+// the upstream: its method must be idempotent (RFC 9110 section 9.2.2) and
+// its body buffered, so it can be replayed byte for byte. Requests whose
+// body is streamed (see Router::stream_body) are sent once. Connection
+// errors, timeouts and the configured statuses are retried, waiting twice
+// as long before each new attempt.
+
+use crate::{Request, Response};
+use std::io;
+use std::time::Duration;
+
+// Methods that can be repeated without changing their effect
+const IDEMPOTENT: [&str; 6] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+/// When a reverse proxy sends a failed request again (see Proxy::retry)
+/// Idempotent requests with a buffered body are retried after connection
+/// errors, timeouts and 502, 503 or 504 responses, up to max_attempts
+/// attempts in all, after a backoff doubling from 100 ms each time
+/// Example:
+/// let retry = RetryPolicy::new(3)
+///     .backoff(Duration::from_millis(50))
+///     .retry_on(&[502, 503]);
+/// router.proxy("/", Proxy::new("http://127.0.0.1:3000").retry(retry));
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    statuses: Vec<u16>,
+}
+
+impl RetryPolicy {
+    /// Creates a policy sending a request at most max_attempts times,
+    /// the first one included
+    /// Panics if max_attempts is 0
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        assert!(max_attempts > 0, "RetryPolicy needs at least one attempt");
+        RetryPolicy {
+            max_attempts,
+            backoff: Duration::from_millis(100),
+            statuses: vec![502, 503, 504],
+        }
+    }
+
+    /// Sets the wait before the first retry, doubled before each next one
+    pub fn backoff(mut self, delay: Duration) -> RetryPolicy {
+        self.backoff = delay;
+        self
+    }
+
+    /// Replaces the upstream response statuses that are retried
+    pub fn retry_on(mut self, statuses: &[u16]) -> RetryPolicy {
+        self.statuses = statuses.to_vec();
+        self
+    }
+
+    // Returns true if the request can safely be sent again
+    pub(super) fn applies_to(&self, request: &Request) -> bool {
+        request.body_stream.is_none()
+            && IDEMPOTENT
+                .iter()
+                .any(|method| request.method.eq_ignore_ascii_case(method))
+    }
+
+    // Returns how long to wait before the attempt following the given one
+    // (counted from 1), or None if it should not be retried
+    pub(super) fn delay(&self, attempt: u32, result: &io::Result<Response>) -> Option<Duration> {
+        let retried = match result {
+            Ok(response) => self.statuses.contains(&response.status),
+            Err(_) => true,
+        };
+        (retried && attempt < self.max_attempts)
+            .then(|| self.backoff.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
@@ -0,0 +1,89 @@
+// Byte tunnels between two sockets
+// Used once a connection is handed over by a CONNECT request or an upgrade
+// to another protocol: each direction is copied on its own thread, the end
+// of one side's input is passed on as a half-close, and the idle timeout
+// counts traffic in both directions, so a long download with nothing sent
+// back keeps the tunnel open.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Copies bytes both ways between two sockets until both sides are done
+// sending, or nothing went either way for the idle timeout
+pub fn tunnel(client: TcpStream, target: TcpStream, idle: Option<Duration>) -> io::Result<()> {
+    // Reads wake up periodically to check the other direction's activity
+    let poll = idle.map(|idle| idle.min(Duration::from_secs(1)));
+    for socket in [&client, &target] {
+        socket.set_read_timeout(poll)?;
+        socket.set_write_timeout(idle)?;
+    }
+    let activity = Arc::new(Activity::new());
+    let forward = {
+        let (from, to) = (client.try_clone()?, target.try_clone()?);
+        let activity = Arc::clone(&activity);
+        std::thread::Builder::new()
+            .name("tunnel".to_string())
+            .spawn(move || pipe(from, to, &activity, idle))?
+    };
+    pipe(target, client, &activity, idle);
+    let _ = forward.join();
+    Ok(())
+}
+
+// Copies one direction of a tunnel, passing the end of input on as a
+// half-close and closing both sockets on errors or idle timeouts
+fn pipe(mut from: TcpStream, mut to: TcpStream, activity: &Activity, idle: Option<Duration>) {
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => {
+                let _ = to.shutdown(Shutdown::Write);
+                return;
+            }
+            Ok(bytes_read) => {
+                activity.touch();
+                if to.write_all(&buffer[..bytes_read]).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) && idle.is_some_and(|idle| activity.idle_for() < idle) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+// When bytes last went through a tunnel, in either direction
+struct Activity {
+    start: Instant,
+    // Milliseconds since start
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
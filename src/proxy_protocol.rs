@@ -0,0 +1,158 @@
+// PROXY protocol (haproxy.org/download/2.9/doc/proxy-protocol.txt)
+// Load balancers such as haproxy and AWS NLB/ELB can open each connection
+// with a header naming the client they accepted it from, either as a text
+// line (v1) or a binary block (v2). With ServerConfig::proxy_protocol set,
+// every connection must start with one; the address it carries replaces the
+// balancer's as the request's remote address.
+
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Starts every v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest v1 line allowed by the specification, CRLF included
+const V1_MAX_LEN: usize = 107;
+// Shortest header of either version ("PROXY UNKNOWN\r\n"), safe to read
+// before knowing which one the connection uses
+const MIN_LEN: usize = 15;
+
+// Outcome of looking for a header at the start of a connection's input
+#[derive(Debug, PartialEq)]
+pub enum Preamble {
+    // At least this many more bytes are needed
+    Incomplete(usize),
+    // The input does not start with a valid header
+    Invalid,
+    // A header of len bytes, with the client address if it announces one
+    // (None for health checks from the balancer itself or unknown families)
+    Header {
+        len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+// Parses the header at the start of input
+pub fn parse(input: &[u8]) -> Preamble {
+    if input.is_empty() {
+        return Preamble::Incomplete(MIN_LEN);
+    }
+    let probe = &input[..input.len().min(V2_SIGNATURE.len())];
+    if V2_SIGNATURE.starts_with(probe) {
+        return parse_v2(input);
+    }
+    let probe = &input[..input.len().min(6)];
+    if !b"PROXY ".starts_with(probe) {
+        return Preamble::Invalid;
+    }
+    if input.len() < MIN_LEN {
+        return Preamble::Incomplete(MIN_LEN - input.len());
+    }
+    parse_v1(input)
+}
+
+// Reads the header off a blocking stream, without reading past it
+// Returns the client address it announces
+pub fn read_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut input = Vec::new();
+    loop {
+        match parse(&input) {
+            Preamble::Incomplete(needed) => {
+                let filled = input.len();
+                input.resize(filled + needed, 0);
+                stream.read_exact(&mut input[filled..])?;
+            }
+            Preamble::Invalid => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "missing or invalid PROXY protocol header",
+                ));
+            }
+            Preamble::Header { source, .. } => return Ok(source),
+        }
+    }
+}
+
+// Parses a v1 line, e.g. "PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n"
+fn parse_v1(input: &[u8]) -> Preamble {
+    let Some(end) = input.windows(2).position(|w| w == b"\r\n") else {
+        // The line can only end with the next byte
+        return if input.len() >= V1_MAX_LEN {
+            Preamble::Invalid
+        } else {
+            Preamble::Incomplete(1)
+        };
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Preamble::Invalid;
+    }
+    let Ok(line) = std::str::from_utf8(&input[..end]) else {
+        return Preamble::Invalid;
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        [
+            "PROXY",
+            family @ ("TCP4" | "TCP6"),
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let source = source.parse::<IpAddr>();
+            let valid = destination.parse::<IpAddr>().is_ok()
+                && destination_port.parse::<u16>().is_ok()
+                && source
+                    .as_ref()
+                    .is_ok_and(|ip| ip.is_ipv4() == (family == "TCP4"));
+            match (source, source_port.parse::<u16>()) {
+                (Ok(ip), Ok(port)) if valid => Some(SocketAddr::new(ip, port)),
+                _ => return Preamble::Invalid,
+            }
+        }
+        _ => return Preamble::Invalid,
+    };
+    Preamble::Header { len, source }
+}
+
+// Parses a v2 block: signature, version and command, address family,
+// address length, then the addresses and optional TLVs (ignored)
+fn parse_v2(input: &[u8]) -> Preamble {
+    if input.len() < 16 {
+        return Preamble::Incomplete(16 - input.len());
+    }
+    let version = input[12] >> 4;
+    let command = input[12] & 0x0f;
+    if version != 2 || command > 1 {
+        return Preamble::Invalid;
+    }
+    let len = 16 + u16::from_be_bytes([input[14], input[15]]) as usize;
+    if input.len() < len {
+        return Preamble::Incomplete(len - input.len());
+    }
+
+    // LOCAL connections come from the balancer itself
+    if command == 0 {
+        return Preamble::Header { len, source: None };
+    }
+    let addresses = &input[16..len];
+    let source = match input[13] >> 4 {
+        // AF_INET: source and destination addresses, then ports
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(ip), port))
+        }
+        1 | 2 => return Preamble::Invalid,
+        // AF_UNSPEC and AF_UNIX carry no usable client address
+        _ => None,
+    };
+    Preamble::Header { len, source }
+}
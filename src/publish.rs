@@ -0,0 +1,342 @@
+// Writable static root
+// With Router::publish, PUT requests that match no route store their body
+// as the file the same path is served from, and DELETE requests remove it.
+// Bodies are streamed into a temporary file next to the target (see Upload)
+// and renamed over it once complete, so readers never see a partially
+// written file. Quotas cap the bytes stored under a directory; usage is
+// measured by walking it before each upload, so files changed by other
+// means are accounted for too. Paths are decoded like those of static
+// files, and writes never go through a link leading out of the base
+// directory nor touch hidden files, override files least of all.
+
+use crate::file_rules::links_allowed;
+use crate::middleware::{BoxedHandler, run_chain};
+use crate::static_files::relative_path;
+use crate::{Middleware, Request, Response, Router, StatusCode, Upload};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Settings for publishing files with PUT and removing them with DELETE
+/// (see Router::publish)
+/// Requests run through the router's middleware and then the ones added
+/// with layer, which is where authentication goes; settings without a layer
+/// must say so with unauthenticated
+/// Example:
+/// router.publish(
+///     Publish::new()
+///         .max_size(512 * 1024 * 1024)
+///         .writable("/releases")
+///         .quota("/releases", 20 * 1024 * 1024 * 1024)
+///         .layer(|request: &Request, next: &Next| match request.header("Authorization") {
+///             Some("Bearer deploy-token") => next.run(request),
+///             _ => Response::new(401, "Unauthorized"),
+///         }),
+/// );
+#[derive(Clone, Default)]
+pub struct Publish {
+    max_size: Option<u64>,
+    sync: bool,
+    // Path prefixes files may be written under, without a trailing slash;
+    // empty when the whole root is writable
+    writable: Vec<String>,
+    // Byte limits by path prefix, without a trailing slash
+    quotas: Vec<(String, u64)>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    unauthenticated: bool,
+}
+
+impl Publish {
+    /// Creates the settings, limiting files to the router's max_body_size
+    pub fn new() -> Publish {
+        Publish::default()
+    }
+
+    /// Sets the largest file accepted, in bytes
+    pub fn max_size(mut self, bytes: u64) -> Publish {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Flushes published files to disk before answering (see Upload::sync)
+    pub fn sync(mut self, sync: bool) -> Publish {
+        self.sync = sync;
+        self
+    }
+
+    /// Allows writing under a path prefix, e.g. "/releases" for
+    /// "/releases/v1.2/app.tar.gz"; calls add prefixes. Without any, the
+    /// whole base directory is writable. Other paths are refused with 403
+    pub fn writable(mut self, prefix: &str) -> Publish {
+        self.writable.push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Limits the bytes stored under a path prefix, "/" for the whole base
+    /// directory. Uploads that would exceed it are refused with 507
+    /// Insufficient Storage; a file being replaced does not count against
+    /// its replacement. Calls add quotas, which all apply to nested paths
+    pub fn quota(mut self, prefix: &str, bytes: u64) -> Publish {
+        self.quotas
+            .push((prefix.trim_end_matches('/').to_string(), bytes));
+        self
+    }
+
+    /// Adds a middleware run before files are written, e.g. to authenticate
+    /// Middleware added first runs first
+    pub fn layer(mut self, middleware: impl Middleware) -> Publish {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Lets the settings go without a layer, for servers only trusted
+    /// clients reach or routers authenticating in Router::layer; anyone
+    /// reaching the server can then write to the base directory
+    pub fn unauthenticated(mut self) -> Publish {
+        self.unauthenticated = true;
+        self
+    }
+
+    // Tells whether requests are checked by a layer, or may go unchecked
+    pub(crate) fn is_guarded(&self) -> bool {
+        !self.middleware.is_empty() || self.unauthenticated
+    }
+
+    // Runs the request through the router's middleware, then this one's,
+    // and finally stores the file
+    pub(crate) fn handle(
+        self: &Arc<Self>,
+        router: &Router,
+        router_middleware: &[Arc<dyn Middleware>],
+        request: &Request,
+        base_dir: &str,
+    ) -> Response {
+        let publish = Arc::clone(self);
+        let base_dir = base_dir.to_string();
+        let default_max_size = router.body_size_limit() as u64;
+        let target = self.target(router, &base_dir, &request.path);
+        let handler: BoxedHandler = Arc::new(move |request: &Request| {
+            let Some((target, path)) = &target else {
+                return Response::from_status(StatusCode::FORBIDDEN);
+            };
+            match request.method.as_str() {
+                "DELETE" => delete(target),
+                _ => publish.put(&base_dir, target, path, request, default_max_size),
+            }
+        });
+        let stack: Vec<&Arc<dyn Middleware>> =
+            router_middleware.iter().chain(&self.middleware).collect();
+        run_chain(&stack, &handler, request)
+    }
+
+    // Returns the file a request path writes to and the decoded path, or
+    // None if it is outside the writable prefixes or base_dir, goes through
+    // a link the router doesn't follow or is hidden, which override files
+    // always are (see Router::allow_hidden)
+    fn target(&self, router: &Router, base_dir: &str, path: &str) -> Option<(PathBuf, String)> {
+        if path.ends_with('/') {
+            return None;
+        }
+        let relative = relative_path(path)?;
+        if relative.as_os_str().is_empty() || router.hides(&relative) {
+            return None;
+        }
+        let decoded = relative.iter().fold(String::new(), |path, name| {
+            path + "/" + &*name.to_string_lossy()
+        });
+        let writable = self.writable.is_empty()
+            || self
+                .writable
+                .iter()
+                .any(|prefix| is_under(&decoded, prefix));
+        let base_dir = Path::new(base_dir);
+        let inside = links_allowed(router.symlink_policy(), base_dir, &relative)
+            && stays_inside(base_dir, &relative);
+        (writable && inside).then(|| (base_dir.join(&relative), decoded))
+    }
+
+    // Stores the body as the target file
+    fn put(
+        &self,
+        base_dir: &str,
+        target: &Path,
+        path: &str,
+        request: &Request,
+        default_max_size: u64,
+    ) -> Response {
+        if target.is_dir() {
+            return Response::from_status(StatusCode::CONFLICT);
+        }
+        let max_size = self.max_size.unwrap_or(default_max_size);
+        let replaced = fs::metadata(target).map_or(0, |metadata| metadata.len());
+        let room = self.room(base_dir, path, replaced);
+        let too_large = |length: u64| match room {
+            Some(room) if length > room && room < max_size => {
+                Some(Response::from_status(StatusCode::INSUFFICIENT_STORAGE))
+            }
+            _ if length > max_size => Some(Response::from_status(StatusCode::PAYLOAD_TOO_LARGE)),
+            _ => None,
+        };
+        let announced = request
+            .header("Content-Length")
+            .and_then(|value| value.parse().ok());
+        if let Some(response) = announced.and_then(too_large) {
+            return response;
+        }
+        let Some(parent) = target.parent() else {
+            return Response::from_status(StatusCode::FORBIDDEN);
+        };
+        if let Err(e) = fs::create_dir_all(parent) {
+            return error_response(&e);
+        }
+
+        let limit = room.map_or(max_size, |room| room.min(max_size));
+        let upload = Upload::new().dir(parent).max_size(limit).sync(self.sync);
+        let file = match upload.save(request.body_reader()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                return too_large(limit.saturating_add(1))
+                    .unwrap_or_else(|| Response::from_status(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+                return Response::bad_request(&e.to_string());
+            }
+            Err(e) => return error_response(&e),
+        };
+        let existed = target.exists();
+        if let Err(e) = file.persist(target) {
+            return error_response(&e);
+        }
+        match existed {
+            true => Response::from_status(StatusCode::NO_CONTENT),
+            false => {
+                Response::from_status(StatusCode::CREATED).with_header("Location", &request.path)
+            }
+        }
+    }
+
+    // Returns the bytes that can still be stored at a path under the
+    // tightest quota covering it, counting the replaced bytes as free
+    fn room(&self, base_dir: &str, path: &str, replaced: u64) -> Option<u64> {
+        self.quotas
+            .iter()
+            .filter(|(prefix, _)| is_under(path, prefix))
+            .map(|(prefix, limit)| {
+                let used = directory(base_dir, prefix).map_or(0, |dir| disk_usage(&dir));
+                limit.saturating_sub(used.saturating_sub(replaced))
+            })
+            .min()
+    }
+
+    // Measures the space used under each quota
+    pub(crate) fn usage(&self, base_dir: &str) -> Vec<QuotaUsage> {
+        self.quotas
+            .iter()
+            .map(|(prefix, limit)| QuotaUsage {
+                prefix: format!("{}/", prefix),
+                used: directory(base_dir, prefix).map_or(0, |dir| disk_usage(&dir)),
+                limit: *limit,
+            })
+            .collect()
+    }
+}
+
+/// Bytes stored under a directory with a quota (see Server::storage_usage)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaUsage {
+    // Path prefix of the quota, ending with "/"
+    pub prefix: String,
+    pub used: u64,
+    pub limit: u64,
+}
+
+// Returns true if the path is the prefix or below it
+// Example: ("/releases/v1", "/releases") -> true, ("/releases-old", "/releases") -> false
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Maps a quota prefix to its directory, base_dir itself for the root
+fn directory(base_dir: &str, prefix: &str) -> Option<PathBuf> {
+    match prefix {
+        "" => Some(PathBuf::from(base_dir)),
+        _ => resolve(base_dir, prefix),
+    }
+}
+
+// Adds up the sizes of the files under a directory, not following symlinks
+fn disk_usage(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => disk_usage(&entry.path()),
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+// Removes the target file
+fn delete(target: &Path) -> Response {
+    if target.is_dir() {
+        return Response::from_status(StatusCode::CONFLICT);
+    }
+    match fs::remove_file(target) {
+        Ok(()) => Response::from_status(StatusCode::NO_CONTENT),
+        Err(e) if e.kind() == ErrorKind::NotFound => Response::from_status(StatusCode::NOT_FOUND),
+        Err(e) => error_response(&e),
+    }
+}
+
+// Maps a quota prefix to a directory under base_dir
+// Returns None for paths that would leave base_dir
+fn resolve(base_dir: &str, prefix: &str) -> Option<PathBuf> {
+    let relative = Path::new(prefix.trim_start_matches('/'));
+    let escapes = relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)));
+    match escapes {
+        true => None,
+        false => Some(Path::new(base_dir).join(relative)),
+    }
+}
+
+// Tells whether the file at relative would be written inside base_dir:
+// the deepest of its directories that exists must resolve to one under it,
+// which links leading elsewhere don't
+fn stays_inside(base_dir: &Path, relative: &Path) -> bool {
+    let Ok(base) = fs::canonicalize(base_dir) else {
+        return false;
+    };
+    let target = base_dir.join(relative);
+    for dir in target.ancestors().skip(1) {
+        match fs::canonicalize(dir) {
+            Ok(real) => return real.starts_with(&base),
+            // Missing directories are created inside the existing one, but
+            // a dangling link could be created anywhere
+            Err(e) if e.kind() == ErrorKind::NotFound && fs::symlink_metadata(dir).is_err() => {}
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+// Turns a file system error into a response
+fn error_response(error: &std::io::Error) -> Response {
+    match error.kind() {
+        ErrorKind::PermissionDenied => Response::from_status(StatusCode::FORBIDDEN),
+        // A parent of the target is a file
+        ErrorKind::AlreadyExists | ErrorKind::NotADirectory => {
+            Response::from_status(StatusCode::CONFLICT)
+        }
+        _ => {
+            eprintln!("Publishing a file failed: {}", error);
+            Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Byte range requests for static files (RFC 9110 section 14)
+// A single range is answered with 206 and the slice of the file, several
+// ranges with a multipart/byteranges body. Ranges the file cannot satisfy
+// get 416; malformed or excessive Range headers are ignored and the whole
+// file is sent. Overlapping and adjacent ranges are merged, and a request
+// whose ranges add up to more than the file is excessive.
+
+use crate::conditional::etag;
+use crate::http_date::format_http_date;
+use crate::{Body, Request, Response, StatusCode};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// More ranges than this are more likely an attack than a real client
+const MAX_RANGES: usize = 16;
+
+// Answers a GET with a Range header for the file
+// Returns None if the whole file should be sent instead
+pub fn range_response(request: &Request, file_path: &Path, content_type: &str) -> Option<Response> {
+    let header = request.header("Range")?;
+    let metadata = std::fs::metadata(file_path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let len = metadata.len();
+
+    // If-Range: only send a part if the client's copy is still current
+    if let Some(validator) = request.header("If-Range") {
+        let modified = metadata.modified().ok()?;
+        if !if_range_matches(validator.trim(), modified, len) {
+            return None;
+        }
+    }
+
+    let ranges = match parse_ranges(header, len)? {
+        ranges if ranges.is_empty() => {
+            return Some(
+                Response::from_status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .with_header("Content-Range", &format!("bytes */{}", len)),
+            );
+        }
+        ranges => ranges,
+    };
+
+    if let [(start, end)] = ranges[..] {
+        let reader = open_slice(file_path, start, end)?;
+        return Some(
+            Response::from_status(StatusCode::PARTIAL_CONTENT)
+                .with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len))
+                .with_header("Content-Length", &(end - start + 1).to_string())
+                .with_body(content_type, Body::from_reader(reader)),
+        );
+    }
+
+    // Every part gets its own header block, the body ends with a closing delimiter
+    let boundary = boundary();
+    let mut body: Box<dyn Read + Send> = Box::new(std::io::empty());
+    let mut body_len = 0;
+    for (start, end) in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, len
+        );
+        body_len += head.len() as u64 + end - start + 1;
+        let slice = open_slice(file_path, start, end)?;
+        body = Box::new(body.chain(Cursor::new(head)).chain(slice));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    body_len += closing.len() as u64;
+    let body = body.chain(Cursor::new(closing));
+
+    Some(
+        Response::from_status(StatusCode::PARTIAL_CONTENT)
+            .with_header("Content-Length", &body_len.to_string())
+            .with_body(
+                &format!("multipart/byteranges; boundary={}", boundary),
+                Body::from_reader(body),
+            ),
+    )
+}
+
+// Parses "bytes=0-99, 200-, -50" into inclusive (start, end) offsets within len
+// Unsatisfiable ranges are dropped; returns None if the header is malformed,
+// uses another unit or asks for too many ranges
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim) {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first, last) {
+            // Suffix range: the last n bytes
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && len > 0).then(|| (len - suffix.min(len), len - 1))
+            }
+            (first, "") => {
+                let first: u64 = first.parse().ok()?;
+                (first < len).then(|| (first, len - 1))
+            }
+            (first, last) => {
+                let first: u64 = first.parse().ok()?;
+                let last: u64 = last.parse().ok()?;
+                if last < first {
+                    return None;
+                }
+                (first < len).then(|| (first, last.min(len - 1)))
+            }
+        };
+        ranges.extend(range);
+        if ranges.len() > MAX_RANGES {
+            return None;
+        }
+    }
+    // Overlapping ranges adding up to more than the file would make us send
+    // it several times over
+    if ranges
+        .iter()
+        .map(|(start, end)| end - start + 1)
+        .sum::<u64>()
+        > len
+    {
+        return None;
+    }
+    Some(merge_ranges(ranges))
+}
+
+// Sorts the ranges and merges those that overlap or are adjacent
+fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last)) if start <= *last + 1 => *last = (*last).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Tells whether the If-Range validator matches the file, which requires a
+// strong one (RFC 9110 section 13.1.5): a weak ETag never matches, and a date
+// only if the file was last modified at least a second ago, since it could
+// otherwise have changed again within the same second
+fn if_range_matches(validator: &str, modified: SystemTime, len: u64) -> bool {
+    if validator.starts_with("W/") {
+        return false;
+    }
+    if validator.starts_with('"') {
+        return validator == etag(modified, len as usize);
+    }
+    let settled = SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age >= Duration::from_secs(1));
+    settled && validator == format_http_date(modified)
+}
+
+// Opens the file positioned at start, limited to the bytes up to end
+fn open_slice(file_path: &Path, start: u64, end: u64) -> Option<impl Read + Send + 'static> {
+    let mut file = File::open(file_path).ok()?;
+    file.seek(SeekFrom::Start(start)).ok()?;
+    Some(file.take(end - start + 1))
+}
+
+// Returns a multipart boundary unlikely to occur in the file
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "byteranges-{:x}-{:x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
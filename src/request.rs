@@ -0,0 +1,194 @@
+use crate::body_reader::{BodyReader, BodyStream};
+use crate::vary::Varied;
+use crate::{parse_request, parse_request_line};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// A parsed HTTP request as seen by route handlers
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    // Request path without the query string (e.g. "/users/42")
+    pub path: String,
+    // Raw query string without the leading '?' (e.g. "page=2&sort=asc")
+    pub query: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    // Raw body bytes, never decoded (see the Bytes and Text extractors)
+    pub body: Vec<u8>,
+    // Path parameters captured by the matched route, in pattern order
+    pub params: Vec<(String, String)>,
+    // Pattern of the matched route as registered, scope prefix included
+    // (e.g. "/users/{id}"); None until the router matched one
+    pub route: Option<String>,
+    // Address of the client: the TCP peer, or the one announced in a PROXY
+    // protocol header; None for connections that are not sockets
+    pub remote_addr: Option<SocketAddr>,
+    // Client address and scheme reported by trusted proxies (see
+    // Router::trusted_proxies); None when the peer is not one of them
+    pub forwarded_ip: Option<IpAddr>,
+    pub forwarded_proto: Option<String>,
+    // Certificate (DER) a TLS client authenticated with, and its subject as
+    // an RFC 4514 string, e.g. "CN=alice,O=Example Corp" (see
+    // TlsConfig::require_client_cert); None without client authentication
+    pub client_certificate: Option<Vec<u8>>,
+    pub client_subject: Option<String>,
+    // Connection the body is read from on routes streaming it (see
+    // Router::stream_body), in which case body stays empty
+    pub(crate) body_stream: Option<BodyStream>,
+    // Nonce SecurityHeaders generated for the response, if its policy has one
+    pub(crate) csp_nonce: Option<String>,
+    // Headers read with vary_header, shared by clones
+    pub(crate) varied: Varied,
+}
+
+impl Request {
+    /// Parses raw request bytes into a Request
+    /// Only the head is decoded as text (invalid UTF-8 is replaced), everything
+    /// after the blank line is kept byte for byte as the body
+    pub fn parse(raw: &[u8]) -> Request {
+        let (head, body) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(index) => (&raw[..index + 4], &raw[index + 4..]),
+            None => (raw, &[][..]),
+        };
+        let head = String::from_utf8_lossy(head);
+        let (method, target, headers) = parse_request(&head);
+        let (_, _, version) = parse_request_line(head.lines().next().unwrap_or(""));
+
+        // Split the request target into path and query string
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (target, String::new()),
+        };
+
+        Request {
+            method,
+            path,
+            query,
+            version: version.to_string(),
+            headers,
+            body: body.to_vec(),
+            params: Vec::new(),
+            route: None,
+            remote_addr: None,
+            forwarded_ip: None,
+            forwarded_proto: None,
+            client_certificate: None,
+            client_subject: None,
+            body_stream: None,
+            csp_nonce: None,
+            varied: Varied::default(),
+        }
+    }
+
+    /// Returns a reader over the body, which reads it from the connection as
+    /// it arrives on routes registered with Router::stream_body
+    /// The body can only be read once: readers of a request and its clones
+    /// share the connection
+    pub fn body_reader(&self) -> BodyReader {
+        BodyReader::new(self)
+    }
+
+    /// Returns the value of a header, comparing names case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of a header that selects the representation sent,
+    /// such as Accept-Encoding, Accept-Language or Origin
+    /// Unlike header, the name is also added to the Vary header of the
+    /// response returned by the calling handler or middleware, whether or not
+    /// the request carries it, so caches don't serve the wrong variant
+    pub fn vary_header(&self, name: &str) -> Option<&str> {
+        self.varied.record(name);
+        self.header(name)
+    }
+
+    /// Returns the IP address of the client
+    /// Behind trusted proxies this is the address they forwarded the request
+    /// for, otherwise the address of the peer (None for in-memory streams)
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded_ip.or(self.remote_addr.map(|addr| addr.ip()))
+    }
+
+    /// Returns the scheme the client used, "https" if a trusted proxy
+    /// terminated TLS for it and "http" otherwise
+    pub fn scheme(&self) -> &str {
+        self.forwarded_proto.as_deref().unwrap_or("http")
+    }
+
+    /// Returns the value of a path parameter captured by the matched route
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the nonce SecurityHeaders generated for the response, for the
+    /// nonce attribute of inline scripts and styles; None unless its
+    /// Content-Security-Policy allows CspSource::Nonce
+    /// Example: format!("<script nonce=\"{}\">", request.csp_nonce().unwrap_or(""))
+    pub fn csp_nonce(&self) -> Option<&str> {
+        self.csp_nonce.as_deref()
+    }
+
+    /// Returns the decoded key/value pairs of the query string
+    /// Example: "name=John+Doe&tag=a%26b" -> [("name", "John Doe"), ("tag", "a&b")]
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_query_component(key), decode_query_component(value))
+            })
+            .collect()
+    }
+}
+
+/// Decodes %XX escapes in a URL component
+/// Returns None if an escape is malformed or the result is not valid UTF-8
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Escapes %, reserved and non-ASCII bytes of a path segment
+#[cfg(any(feature = "static", feature = "webdav"))]
+pub(crate) fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Decodes a query string component, where '+' stands for a space
+// Malformed escapes are kept verbatim rather than rejecting the whole query
+fn decode_query_component(input: &str) -> String {
+    let input = input.replace('+', " ");
+    percent_decode(&input).unwrap_or(input)
+}
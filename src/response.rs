@@ -0,0 +1,316 @@
+use crate::mime::content_type_for;
+use crate::pool;
+use crate::upgrade::{Upgrade, Upgraded};
+use crate::{Body, Request, StatusCode};
+use std::fs::File;
+use std::io::Write;
+
+/// An HTTP response produced by a route handler
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    // Headers in the order they are sent, Content-Length or
+    // Transfer-Encoding is added when writing
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    // Takes over the connection once the head is sent
+    upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    /// Creates an empty response with the given status line
+    /// Example: Response::new(204, "No Content")
+    pub fn new(status: u16, reason: &str) -> Response {
+        Response {
+            status,
+            reason: reason.to_string(),
+            headers: Vec::new(),
+            body: Body::default(),
+            upgrade: None,
+        }
+    }
+
+    /// Creates an empty response with the standard reason phrase for the status
+    /// Example: Response::from_status(StatusCode::NO_CONTENT)
+    pub fn from_status(status: StatusCode) -> Response {
+        Response::new(status.as_u16(), status.reason())
+    }
+
+    /// Replaces the status, with its standard reason phrase
+    /// Example: Response::ok("application/json", body).with_status(StatusCode::CREATED)
+    pub fn with_status(mut self, status: StatusCode) -> Response {
+        self.status = status.as_u16();
+        self.reason = status.reason().to_string();
+        self
+    }
+
+    /// Creates a 200 OK response with the given content type and body
+    pub fn ok(content_type: &str, body: impl Into<Body>) -> Response {
+        Response::new(200, "OK").with_body(content_type, body)
+    }
+
+    /// Creates a 400 Bad Request response with a plain text explanation
+    pub fn bad_request(message: &str) -> Response {
+        Response::new(400, "Bad Request").with_body("text/plain", message)
+    }
+
+    /// Creates a 200 OK response streaming a file as an attachment
+    /// The browser is asked to save it under filename; Content-Type is derived
+    /// from the filename and Content-Length from the file size
+    /// Example: Response::download("exports/report.csv", "report.csv")?.with_rate_limit(64 * 1024)
+    ///
+    /// Returns an error if the file cannot be opened
+    pub fn download(path: &str, filename: &str) -> std::io::Result<Response> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(Response::new(200, "OK")
+            .with_body(content_type_for(filename), Body::from_reader(file))
+            .with_header("Content-Length", &length.to_string())
+            .with_header("Content-Disposition", &content_disposition(filename)))
+    }
+
+    /// Limits how fast the body is sent, in bytes per second
+    pub fn with_rate_limit(mut self, bytes_per_second: u64) -> Response {
+        // Keep the length of buffered bodies, the throttled body is a stream
+        if let Body::Full(bytes) = &self.body
+            && self.header("Content-Length").is_none()
+        {
+            let length = bytes.len().to_string();
+            self = self.with_header("Content-Length", &length);
+        }
+        let body = std::mem::take(&mut self.body);
+        self.body = body.throttle(bytes_per_second);
+        self
+    }
+
+    /// Adds a header to the response
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a request header name to the Vary header
+    /// Names already listed are skipped and the response keeps a single Vary
+    /// header; "*" replaces the list since it already covers every header
+    /// Example: response.with_vary("Accept-Encoding")
+    pub fn with_vary(mut self, name: &str) -> Response {
+        let vary = self
+            .headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Vary"));
+        match vary {
+            Some((_, value)) => {
+                let listed = value
+                    .split(',')
+                    .map(str::trim)
+                    .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(name));
+                if name == "*" {
+                    *value = name.to_string();
+                } else if !listed {
+                    value.push_str(", ");
+                    value.push_str(name);
+                }
+            }
+            None => self.headers.push(("Vary".to_string(), name.to_string())),
+        }
+        self
+    }
+
+    /// Sets the body and its Content-Type header
+    /// Accepts bytes, strings or a streamed Body
+    pub fn with_body(self, content_type: &str, body: impl Into<Body>) -> Response {
+        let mut response = self.with_header("Content-Type", content_type);
+        response.body = body.into();
+        response
+    }
+
+    /// Returns the value of a header, comparing names case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Hands the connection to hook once the head is sent, for another
+    // protocol to continue on it; the response is sent without a body
+    #[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+    pub(crate) fn with_upgrade(mut self, hook: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.upgrade = Some(Upgrade::new(hook));
+        self
+    }
+
+    // Removes the hook set by with_upgrade
+    pub(crate) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    // Writes the status line and headers alone
+    pub(crate) fn write_head(self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut head = pool::shared().get();
+        write!(head, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        for (name, value) in &self.headers {
+            write!(head, "{}: {}\r\n", name, value)?;
+        }
+        head.extend_from_slice(b"\r\n");
+        writer.write_all(&head)
+    }
+
+    /// Writes the status line, headers and body to the writer
+    /// Full bodies get a Content-Length header; streamed bodies are sent
+    /// chunked unless the handler set Content-Length itself. 304 responses
+    /// are sent without a body, 1xx and 204 ones without a body or any
+    /// Content-Type, Content-Length or Transfer-Encoding header
+    pub fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        let bodiless = self.status == 204 || (100..200).contains(&self.status);
+        let mut head = pool::shared().get();
+        write!(head, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        for (name, value) in &self.headers {
+            let framing = ["Content-Type", "Content-Length", "Transfer-Encoding"]
+                .iter()
+                .any(|framing| name.eq_ignore_ascii_case(framing));
+            if !(bodiless && framing) {
+                write!(head, "{}: {}\r\n", name, value)?;
+            }
+        }
+        // A 304 only carries the validators of the body the client already has
+        let chunked = match &self.body {
+            _ if self.status == 304 || bodiless => false,
+            Body::Full(bytes) => {
+                write!(head, "Content-Length: {}\r\n", bytes.len())?;
+                false
+            }
+            _ => {
+                let sized = self.header("Content-Length").is_some();
+                if !sized {
+                    head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+                }
+                !sized
+            }
+        };
+        head.extend_from_slice(b"\r\n");
+
+        writer.write_all(&head)?;
+        if self.status == 304 || bodiless {
+            Ok(())
+        } else if chunked {
+            self.body.write_chunked(writer)
+        } else {
+            self.body.write_to(writer)
+        }
+    }
+}
+
+/// Values route handlers can return, converted into the response sent
+/// Strings are sent as text/plain, byte vectors as application/octet-stream,
+/// a StatusCode as an empty response, and a (StatusCode, value) pair as the
+/// value's response with that status. Results send either side
+/// Example:
+/// router.get("/health", || "ok");
+/// router.post("/items", || (StatusCode::CREATED, "created"));
+/// router.get("/items/{id}", |Path(id): Path<u32>| match find(id) {
+///     Some(item) => Ok(Json(item)),
+///     None => Err(StatusCode::NOT_FOUND),
+/// });
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+
+    /// Converts the value answering the request, for values reporting
+    /// something about it (Error logs the requests it fails)
+    fn into_response_to(self, request: &Request) -> Response
+    where
+        Self: Sized,
+    {
+        let _ = request;
+        self.into_response()
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::ok("text/plain", self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::ok("text/plain", self)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Response::ok("application/octet-stream", self)
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        Response::from_status(self)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        self.1.into_response().with_status(self.0)
+    }
+
+    fn into_response_to(self, request: &Request) -> Response {
+        self.1.into_response_to(request).with_status(self.0)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+
+    fn into_response_to(self, request: &Request) -> Response {
+        match self {
+            Ok(value) => value.into_response_to(request),
+            Err(error) => error.into_response_to(request),
+        }
+    }
+}
+
+// Builds an attachment Content-Disposition value for a filename
+// Quotes, backslashes, control and non-ASCII characters are replaced in the
+// plain filename parameter; non-ASCII names are also sent percent-encoded in
+// filename* (RFC 6266) so clients that support it get the exact name
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    if filename.is_ascii() && fallback == filename {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
@@ -0,0 +1,311 @@
+// Response caching middleware for route handlers
+
+use crate::{Body, Middleware, Next, Request, Response};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Memoizes handler responses for a while
+/// GET and HEAD responses with status 200 and a fully buffered body are
+/// stored per method, path and query, and per value of the request headers
+/// the response lists in Vary. A max-age in the response's Cache-Control
+/// overrides the default TTL; no-store, private and "Vary: *" responses,
+/// responses setting a cookie and requests carrying Authorization or Cookie
+/// are never cached, as they are likely meant for one client only. Hits get
+/// an Age header.
+/// Clones share the same entries, so a clone kept outside the router can
+/// invalidate them
+/// Example:
+/// let cache = ResponseCache::new(Duration::from_secs(60), 1000);
+/// router.scope("/reports", |reports| {
+///     reports.layer(cache.clone());
+///     reports.get("/daily", daily_report);
+/// });
+/// cache.invalidate("/reports/daily"); // after the data changed
+#[derive(Clone)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    // Variants by "METHOD path?query"
+    entries: Variants<Arc<Stored>>,
+}
+
+// One stored response and the request header values it was produced for
+struct Stored {
+    vary: Vec<(String, Option<String>)>,
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+// Stored responses by key, in variants for the values of the request headers
+// their Vary lists; the storage of both ResponseCache and ProxyCache
+// Clones share the same variants
+pub(crate) struct Variants<V> {
+    entries: Arc<RwLock<HashMap<String, Vec<V>>>>,
+}
+
+// A response kept in Variants
+pub(crate) trait Variant {
+    // The request header values it was produced for
+    fn vary(&self) -> &[(String, Option<String>)];
+    // When it stops being fresh
+    fn expires(&self) -> Instant;
+}
+
+impl ResponseCache {
+    /// Creates a cache keeping responses for ttl, with at most max_entries
+    /// responses stored at once
+    pub fn new(ttl: Duration, max_entries: usize) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            max_entries,
+            entries: Variants::new(),
+        }
+    }
+
+    /// Drops the stored responses for a path, whatever the method and query
+    pub fn invalidate(&self, path: &str) {
+        self.entries.retain(|key| key_path(key) != path);
+    }
+
+    /// Drops the stored responses for every path starting with prefix
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.entries
+            .retain(|key| !key_path(key).starts_with(prefix));
+    }
+
+    /// Drops all stored responses
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Returns the number of stored responses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no responses are stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns a fresh stored response for the request
+    fn lookup(&self, key: &str, request: &Request) -> Option<Response> {
+        let now = Instant::now();
+        let stored = self
+            .entries
+            .find(key, request)
+            .filter(|stored| stored.expires > now)?;
+
+        let mut response = Response::new(stored.status, &stored.reason);
+        response.headers = stored.headers.clone();
+        response.body = Body::Full(stored.body.clone());
+        let age = now.duration_since(stored.stored).as_secs();
+        Some(response.with_header("Age", &age.to_string()))
+    }
+
+    // Stores a response if it may be cached
+    fn store(&self, key: String, request: &Request, response: &Response) {
+        let Some(ttl) = self.ttl_for(response) else {
+            return;
+        };
+        let Some(body) = response.body.as_bytes() else {
+            return;
+        };
+        let now = Instant::now();
+        let stored = Stored {
+            vary: vary_of(request, response),
+            status: response.status,
+            reason: response.reason.clone(),
+            headers: response.headers.clone(),
+            body: body.to_vec(),
+            stored: now,
+            expires: now + ttl,
+        };
+        self.entries.insert(key, Arc::new(stored), self.max_entries);
+    }
+
+    // Returns how long a response may be kept, or None if it must not be stored
+    fn ttl_for(&self, response: &Response) -> Option<Duration> {
+        if response.status != 200 || !may_store(response) {
+            return None;
+        }
+        let max_age = directive_seconds(response.header("Cache-Control"), "max-age");
+        Some(max_age.unwrap_or(self.ttl))
+    }
+}
+
+impl<V> Clone for Variants<V> {
+    fn clone(&self) -> Self {
+        Variants {
+            entries: Arc::clone(&self.entries),
+        }
+    }
+}
+
+impl<V: Variant + Clone> Variants<V> {
+    pub(crate) fn new() -> Variants<V> {
+        Variants {
+            entries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // Returns the variant stored under key for the request's header values,
+    // fresh or not
+    pub(crate) fn find(&self, key: &str, request: &Request) -> Option<V> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(key)?
+            .iter()
+            .find(|variant| {
+                variant
+                    .vary()
+                    .iter()
+                    .all(|(name, value)| request.header(name) == value.as_deref())
+            })
+            .cloned()
+    }
+
+    // Stores a variant under key, in place of the one for the same header
+    // values; once max_entries are stored, the variants expiring first
+    // (expired ones first of all) make room
+    pub(crate) fn insert(&self, key: String, variant: V, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        if let Some(variants) = entries.get_mut(&key) {
+            variants.retain(|stored| stored.vary() != variant.vary());
+        }
+        while len_of(&entries) >= max_entries {
+            let soonest = entries
+                .iter()
+                .flat_map(|(key, variants)| variants.iter().map(move |v| (key, v.expires())))
+                .min_by_key(|(_, expires)| *expires)
+                .map(|(key, _)| key.clone());
+            let Some(soonest) = soonest else {
+                break;
+            };
+            entries.remove(&soonest);
+        }
+        entries.entry(key).or_default().push(variant);
+    }
+
+    // Keeps only the variants whose key passes keep
+    pub(crate) fn retain(&self, keep: impl Fn(&str) -> bool) {
+        self.entries.write().unwrap().retain(|key, _| keep(key));
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        len_of(&self.entries.read().unwrap())
+    }
+}
+
+impl Variant for Stored {
+    fn vary(&self) -> &[(String, Option<String>)] {
+        &self.vary
+    }
+
+    fn expires(&self) -> Instant {
+        self.expires
+    }
+}
+
+impl<V: Variant> Variant for Arc<V> {
+    fn vary(&self) -> &[(String, Option<String>)] {
+        (**self).vary()
+    }
+
+    fn expires(&self) -> Instant {
+        (**self).expires()
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let cacheable = matches!(request.method.as_str(), "GET" | "HEAD")
+            && request.header("Authorization").is_none()
+            && request.header("Cookie").is_none();
+        if !cacheable {
+            return next.run(request);
+        }
+
+        let key = match request.query.as_str() {
+            "" => format!("{} {}", request.method, request.path),
+            query => format!("{} {}?{}", request.method, request.path, query),
+        };
+        if let Some(response) = self.lookup(&key, request) {
+            return response;
+        }
+        let response = next.run(request);
+        self.store(key, request, &response);
+        response
+    }
+}
+
+// Returns false if no cache may store the response: no-store, private,
+// "Vary: *" and responses setting a cookie, which is likely meant for one
+// client only
+pub(crate) fn may_store(response: &Response) -> bool {
+    let cache_control = response.header("Cache-Control");
+    !has_directive(cache_control, "no-store")
+        && !has_directive(cache_control, "private")
+        && !response
+            .header("Vary")
+            .is_some_and(|vary| vary.contains('*'))
+        && response.header("Set-Cookie").is_none()
+}
+
+// Returns the values of the request headers the response's Vary lists
+pub(crate) fn vary_of(request: &Request, response: &Response) -> Vec<(String, Option<String>)> {
+    response
+        .header("Vary")
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| (name.to_string(), request.header(name).map(str::to_string)))
+        .collect()
+}
+
+// Returns true if a Cache-Control value contains the directive
+pub(crate) fn has_directive(cache_control: Option<&str>, directive: &str) -> bool {
+    cache_control.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|entry| entry.trim().eq_ignore_ascii_case(directive))
+    })
+}
+
+// Returns the number of seconds a Cache-Control directive such as max-age
+// sets, if it is there and valid
+pub(crate) fn directive_seconds(cache_control: Option<&str>, name: &str) -> Option<Duration> {
+    cache_control?
+        .split(',')
+        .find_map(|directive| {
+            let (key, value) = directive.trim().split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().trim_matches('"').parse::<u64>().ok())?
+        })
+        .map(Duration::from_secs)
+}
+
+// Counts stored variants while the lock is held
+fn len_of<V>(entries: &HashMap<String, Vec<V>>) -> usize {
+    entries.values().map(Vec::len).sum()
+}
+
+// Extracts the path from a "METHOD path?query" key
+fn key_path(key: &str) -> &str {
+    let target = key.split_once(' ').map_or(key, |(_, target)| target);
+    target.split_once('?').map_or(target, |(path, _)| path)
+}
@@ -0,0 +1,190 @@
+// Compiled route tree used by the Router
+// Patterns are stored segment by segment, so matching walks the request path
+// once instead of trying every route. Precedence is deterministic:
+// static segments beat constrained parameters, which beat plain parameters,
+// which beat wildcards; since the tree is searched depth-first the longest
+// matching static prefix wins.
+
+#[cfg(feature = "router")]
+use regex::Regex;
+use std::collections::HashMap;
+
+// One segment of a route pattern
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Static(String), // Must match exactly, e.g. "users"
+    // "{id}" matches any single non-empty segment,
+    // "{id:[0-9]+}" only segments fully matching the regex
+    Param(String, Option<String>),
+    Wildcard(String), // "{*rest}" matches the remainder of the path
+}
+
+// Splits a pattern like "/users/{id}" into segments
+// Anything after a wildcard segment is ignored
+pub fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for segment in pattern.trim_start_matches('/').split('/') {
+        let segment = match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(name) => Segment::Wildcard(name.to_string()),
+                None => match name.split_once(':') {
+                    Some((name, constraint)) => {
+                        Segment::Param(name.to_string(), Some(constraint.to_string()))
+                    }
+                    None => Segment::Param(name.to_string(), None),
+                },
+            },
+            None => Segment::Static(segment.to_string()),
+        };
+        let is_wildcard = matches!(segment, Segment::Wildcard(_));
+        segments.push(segment);
+        if is_wildcard {
+            break;
+        }
+    }
+    segments
+}
+
+/// Tree of route patterns, mapping paths to route ids
+#[derive(Clone, Debug, Default)]
+pub struct RouteTree {
+    statics: HashMap<String, RouteTree>,
+    // Parameter children, constrained ones first
+    params: Vec<ParamChild>,
+    // Routes whose pattern ends with a wildcard at this node
+    wildcard: Vec<usize>,
+    // Routes whose pattern ends exactly at this node
+    routes: Vec<usize>,
+}
+
+impl RouteTree {
+    /// Adds a route id under the given pattern segments
+    pub fn insert(&mut self, segments: &[Segment], id: usize) {
+        match segments.split_first() {
+            None => self.routes.push(id),
+            Some((Segment::Static(name), rest)) => self
+                .statics
+                .entry(name.clone())
+                .or_default()
+                .insert(rest, id),
+            Some((Segment::Param(_, constraint), rest)) => {
+                self.param_child(constraint).node.insert(rest, id)
+            }
+            Some((Segment::Wildcard(_), _)) => self.wildcard.push(id),
+        }
+    }
+
+    // Returns the parameter child for a constraint, creating it if needed
+    fn param_child(&mut self, constraint: &Option<String>) -> &mut ParamChild {
+        let index = match self
+            .params
+            .iter()
+            .position(|child| &child.constraint == constraint)
+        {
+            Some(index) => index,
+            None => {
+                let child = ParamChild::new(constraint.clone());
+                // Keep constrained children ahead of the unconstrained one
+                let index = match constraint {
+                    Some(_) => self
+                        .params
+                        .iter()
+                        .take_while(|c| c.constraint.is_some())
+                        .count(),
+                    None => self.params.len(),
+                };
+                self.params.insert(index, child);
+                index
+            }
+        };
+        &mut self.params[index]
+    }
+
+    /// Finds the highest-precedence route for a path accepted by the filter
+    /// Returns the route id and the raw (still percent-encoded) parameter values
+    /// in pattern order, the wildcard value being the rest of the path
+    pub fn lookup(
+        &self,
+        path: &str,
+        accept: &dyn Fn(usize) -> bool,
+    ) -> Option<(usize, Vec<String>)> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut values = Vec::new();
+        let id = self.search(&parts, &mut values, accept)?;
+        Some((id, values))
+    }
+
+    // Depth-first search trying static children, then parameters, then wildcards
+    fn search(
+        &self,
+        parts: &[&str],
+        values: &mut Vec<String>,
+        accept: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        if let Some((part, rest)) = parts.split_first() {
+            if let Some(child) = self.statics.get(*part)
+                && let Some(id) = child.search(rest, values, accept)
+            {
+                return Some(id);
+            }
+            for child in self.params.iter().filter(|child| child.accepts(part)) {
+                values.push(part.to_string());
+                if let Some(id) = child.node.search(rest, values, accept) {
+                    return Some(id);
+                }
+                values.pop();
+            }
+        } else if let Some(&id) = self.routes.iter().find(|&&id| accept(id)) {
+            return Some(id);
+        }
+
+        // The wildcard takes everything that is left, including slashes
+        let id = *self.wildcard.iter().find(|&&id| accept(id))?;
+        values.push(parts.join("/"));
+        Some(id)
+    }
+}
+
+// A parameter position in the tree, with an optional regex constraint
+#[derive(Clone, Debug)]
+struct ParamChild {
+    constraint: Option<String>,
+    #[cfg(feature = "router")]
+    regex: Option<Regex>,
+    node: RouteTree,
+}
+
+impl ParamChild {
+    // Compiles the constraint so it has to match the whole segment
+    // Panics on invalid regexes, like other malformed route patterns
+    fn new(constraint: Option<String>) -> ParamChild {
+        #[cfg(feature = "router")]
+        let regex = constraint.as_ref().map(|constraint| {
+            Regex::new(&format!("^(?:{})$", constraint))
+                .unwrap_or_else(|e| panic!("invalid route constraint {:?}: {}", constraint, e))
+        });
+        #[cfg(not(feature = "router"))]
+        if let Some(constraint) = &constraint {
+            panic!(
+                "route constraint {:?} requires the router feature",
+                constraint
+            );
+        }
+
+        ParamChild {
+            constraint,
+            #[cfg(feature = "router")]
+            regex,
+            node: RouteTree::default(),
+        }
+    }
+
+    // Returns true if a path segment can be captured by this parameter
+    fn accepts(&self, part: &str) -> bool {
+        #[cfg(feature = "router")]
+        if let Some(regex) = &self.regex {
+            return regex.is_match(part);
+        }
+        !part.is_empty()
+    }
+}
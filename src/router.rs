@@ -0,0 +1,988 @@
+#[cfg(feature = "static")]
+use crate::cache::FileCache;
+use crate::extract::FromRequest;
+#[cfg(any(feature = "static", feature = "webdav"))]
+use crate::file_rules::{FileRules, SymlinkPolicy};
+use crate::forwarded::IpRange;
+#[cfg(feature = "static")]
+use crate::listing::DirectoryListing;
+#[cfg(feature = "static")]
+use crate::live_reload::{LIVE_RELOAD_PATH, LiveReload};
+use crate::middleware::{BoxedHandler, Middleware, run_chain};
+#[cfg(feature = "static")]
+use crate::overrides::OverrideFiles;
+#[cfg(feature = "static")]
+use crate::publish::Publish;
+use crate::request::percent_decode;
+use crate::route_tree::{RouteTree, Segment, parse_pattern};
+#[cfg(feature = "static")]
+use crate::static_files::RobotsPolicy;
+use crate::stats::Counters;
+use crate::{AccessLog, Handler, IntoResponse, Request, Response};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+#[cfg(feature = "webdav")]
+use std::sync::RwLock;
+
+/// Functions usable as route handlers
+/// Implemented for functions and closures taking up to four extractors
+/// (see the extract module) and returning a Response or another IntoResponse
+/// value
+/// Example: fn show_user(Path(id): Path<u32>, Query(page): Query<Page>) -> Response
+pub trait RouteHandler<Args>: Send + Sync + 'static {
+    fn call(&self, request: &Request) -> Response;
+}
+
+// Implements RouteHandler for functions with the given extractor arguments
+// Each argument is extracted in order, the first failure is returned as the response
+macro_rules! impl_route_handler {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> RouteHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, request: &Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(request) {
+                        Ok(value) => value,
+                        Err(rejection) => return rejection,
+                    };
+                )*
+                self($($arg),*).into_response_to(request)
+            }
+        }
+    };
+}
+
+impl_route_handler!();
+impl_route_handler!(A);
+impl_route_handler!(A, B);
+impl_route_handler!(A, B, C);
+impl_route_handler!(A, B, C, D);
+
+// Hook called with every request before routing
+type RequestHook = Arc<dyn Fn(&Request) + Send + Sync>;
+// Hook called with every response the router produces
+type ResponseHook = Arc<dyn Fn(&Request, &mut Response) + Send + Sync>;
+
+#[derive(Clone)]
+struct Route {
+    method: String,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: BoxedHandler,
+    // Middleware inherited from enclosing scopes, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+    // The handler reads the body from the connection (see stream_body)
+    stream_body: bool,
+}
+
+/// How paths differing only by a trailing slash ("/foo" vs "/foo/") are routed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    // Only the exact registered form matches, the other one falls through
+    #[default]
+    Strict,
+    // Requests for the other form are redirected to the registered one
+    Redirect,
+    // Both forms are served by the same route
+    Equivalent,
+}
+
+/// Routing table mapping method + path patterns to handlers
+/// Patterns may contain parameters ("/users/{id}") and a trailing
+/// wildcard ("/files/{*path}"). When several patterns match a path,
+/// static segments win over parameters and parameters over wildcards,
+/// regardless of registration order; for identical patterns the first wins
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    // Compiled patterns pointing into routes
+    tree: RouteTree,
+    // Middleware wrapped around every route of this router, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+    // Cache for small static files served when no route matches
+    #[cfg(feature = "static")]
+    file_cache: Option<Arc<FileCache>>,
+    // Development mode reloading pages when static files change
+    #[cfg(feature = "static")]
+    live_reload: Option<Arc<LiveReload>>,
+    // Writable static root, taking PUTs and DELETEs no route matches
+    #[cfg(feature = "static")]
+    publish: Option<Arc<Publish>>,
+    // Extensions of static files whose server-side includes are expanded
+    #[cfg(feature = "static")]
+    include_extensions: Vec<String>,
+    // Cache-Control values for static files by path pattern, first match wins
+    #[cfg(feature = "static")]
+    cache_rules: Vec<(String, String)>,
+    // Symbolic links files may be served through, and the patterns of
+    // hidden files that are served nonetheless
+    #[cfg(any(feature = "static", feature = "webdav"))]
+    file_rules: FileRules,
+    // Copies of file_rules held by the WebDAV shares mounted on the router
+    #[cfg(feature = "webdav")]
+    shares: Vec<Arc<RwLock<FileRules>>>,
+    // Override files of static directories, when they are read
+    #[cfg(feature = "static")]
+    override_files: Option<Arc<OverrideFiles>>,
+    // Pages listing static directories without an index file
+    #[cfg(feature = "static")]
+    listing: Option<Arc<DirectoryListing>>,
+    // Served for /favicon.ico and /robots.txt when the static root has none
+    #[cfg(feature = "static")]
+    favicon: Option<Arc<[u8]>>,
+    #[cfg(feature = "static")]
+    robots: Option<RobotsPolicy>,
+    // Headers added to responses by path pattern: pattern, name, value
+    header_rules: Vec<(String, String, String)>,
+    // Page template Markdown files are rendered into
+    #[cfg(feature = "markdown")]
+    markdown_template: Option<String>,
+    // Static files of at least this many bytes are served from memory maps
+    #[cfg(feature = "mmap")]
+    mmap_threshold: Option<u64>,
+    trailing_slash: TrailingSlash,
+    // Peers whose Forwarded/X-Forwarded-* headers are believed
+    trusted_proxies: Vec<IpRange>,
+    // Sizes for reading requests, None for the defaults
+    read_buffer_size: Option<usize>,
+    max_head_size: Option<usize>,
+    body_chunk_size: Option<usize>,
+    max_body_size: Option<usize>,
+    // Hooks run for every request and response, in registration order
+    request_hooks: Vec<RequestHook>,
+    response_hooks: Vec<ResponseHook>,
+    // Indices of the routes added by the last route or scope call
+    last_registered: Range<usize>,
+    // Answers requests no route and no static file matches
+    not_found: Option<BoxedHandler>,
+    // Statistics of the server serving the router, set by Server
+    counters: Option<Arc<Counters>>,
+    // Where the server serving the router logs requests, set by Server
+    access_log: Option<AccessLog>,
+}
+
+impl Router {
+    /// Creates an empty router
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers a handler for the given method and path pattern
+    /// The method may be a Method or a name like "PATCH"
+    pub fn route<Args, H: RouteHandler<Args>>(
+        &mut self,
+        method: impl AsRef<str>,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Router {
+        self.add(Route {
+            method: method.as_ref().to_string(),
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Arc::new(move |request: &Request| handler.call(request)),
+            middleware: Vec::new(),
+            stream_body: false,
+        });
+        self.last_registered = self.routes.len() - 1..self.routes.len();
+        self
+    }
+
+    // Stores a route and compiles its pattern into the tree
+    fn add(&mut self, route: Route) {
+        self.tree.insert(&route.segments, self.routes.len());
+        self.routes.push(route);
+    }
+
+    /// Removes the routes registered for the method and pattern, as given
+    /// when registering them (with the scope prefix, if any)
+    /// Returns false if there was none
+    /// Example: router.remove_route(Method::Get, "/beta/{id}");
+    pub fn remove_route(&mut self, method: impl AsRef<str>, pattern: &str) -> bool {
+        let count = self.routes.len();
+        self.routes
+            .retain(|route| route.method != method.as_ref() || route.pattern != pattern);
+        if self.routes.len() == count {
+            return false;
+        }
+        // Route ids are positions, so the tree is compiled again
+        self.tree = RouteTree::default();
+        for (id, route) in self.routes.iter().enumerate() {
+            self.tree.insert(&route.segments, id);
+        }
+        self.last_registered = 0..0;
+        true
+    }
+
+    /// Adds a middleware around all routes of this router
+    /// Middleware added first runs first
+    pub fn layer(&mut self, middleware: impl Middleware) -> &mut Router {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Registers a hook called with every request before it is routed, e.g.
+    /// for logging. GETs answered from the FileCache fast path skip it
+    /// Hooks run in registration order, also when added inside scope
+    /// Example: router.on_request(|request| eprintln!("{} {}", request.method, request.path));
+    pub fn on_request(&mut self, hook: impl Fn(&Request) + Send + Sync + 'static) -> &mut Router {
+        self.request_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook called with every response produced by routes,
+    /// including 405, trailing slash redirects and the not_found handler,
+    /// after all middleware ran. Static files are sent without one
+    /// Hooks run in registration order, also when added inside scope
+    /// Example:
+    /// router.on_response(|_, response| {
+    ///     response.headers.push(("X-Frame-Options".to_string(), "DENY".to_string()));
+    /// });
+    pub fn on_response(
+        &mut self,
+        hook: impl Fn(&Request, &mut Response) + Send + Sync + 'static,
+    ) -> &mut Router {
+        self.response_hooks.push(Arc::new(hook));
+        self
+    }
+
+    // Passes a response produced for the request through the response hooks
+    fn finish(&self, request: &Request, mut response: Response) -> Response {
+        for (name, value) in self.headers_for(&request.path) {
+            if response.header(name).is_none() {
+                response.headers.push((name.to_string(), value.to_string()));
+            }
+        }
+        for hook in &self.response_hooks {
+            hook(request, &mut response);
+        }
+        response
+    }
+
+    /// Adds a middleware around the route registered last, or every route of
+    /// the group registered last with scope, after the router's own layers.
+    /// Calls can be chained; middleware added first runs first
+    /// Panics if no route was registered yet
+    /// Example:
+    /// router
+    ///     .post("/upload", upload)
+    ///     .with(require_auth)
+    ///     .with(Timeout::new(Duration::from_secs(30)));
+    pub fn with(&mut self, middleware: impl Middleware) -> &mut Router {
+        assert!(
+            !self.last_registered.is_empty(),
+            "Router::with called before registering a route"
+        );
+        let middleware: Arc<dyn Middleware> = Arc::new(middleware);
+        for route in &mut self.routes[self.last_registered.clone()] {
+            route.middleware.push(Arc::clone(&middleware));
+        }
+        self
+    }
+
+    /// Lets the route registered last, or every route of the group registered
+    /// last with scope, read the request body while it arrives instead of
+    /// after the whole body was buffered. The handler gets an empty
+    /// request.body and reads the body through a BodyReader, so uploads can
+    /// be written to disk as they come in. Chunked request bodies are
+    /// accepted, and max_body_size does not apply: the handler stops reading
+    /// when it has had enough. Only the threaded backend streams bodies, the
+    /// others hand the buffered body to the BodyReader
+    /// Panics if no route was registered yet
+    /// Example: router.put("/files/{name}", upload).stream_body();
+    pub fn stream_body(&mut self) -> &mut Router {
+        assert!(
+            !self.last_registered.is_empty(),
+            "Router::stream_body called before registering a route"
+        );
+        for route in &mut self.routes[self.last_registered.clone()] {
+            route.stream_body = true;
+        }
+        self
+    }
+
+    // Returns true if the request goes to a route streaming its body
+    pub(crate) fn streams_body(&self, request: &Request) -> bool {
+        self.find(&request.method, &request.path)
+            .is_some_and(|(route, _)| route.stream_body)
+    }
+
+    /// Keeps small static files in memory and serves them through the fast path
+    #[cfg(feature = "static")]
+    pub fn cache_static_files(&mut self, cache: FileCache) -> &mut Router {
+        self.file_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Development mode: reloads open pages whenever a static file changes
+    /// Served HTML gets a script listening to server-sent events at
+    /// "/__live-reload", and a thread polls the base directory for changes.
+    /// Each open page holds a connection, so use the threaded backend
+    #[cfg(feature = "static")]
+    pub fn live_reload(&mut self) -> &mut Router {
+        let live = Arc::new(LiveReload::default());
+        self.live_reload = Some(Arc::clone(&live));
+        self.get(LIVE_RELOAD_PATH, move || live.events())
+    }
+
+    // Returns the live reload state, if enabled
+    #[cfg(feature = "static")]
+    pub(crate) fn live_reloader(&self) -> Option<&Arc<LiveReload>> {
+        self.live_reload.as_ref()
+    }
+
+    /// Lets PUT requests matching no route create or overwrite the file
+    /// served at their path, and DELETE requests remove it, turning the base
+    /// directory into a simple artifact store (see Publish::writable to
+    /// restrict where). Paths are decoded like those of static files; ones
+    /// with "..", or going through links out of the base directory or not
+    /// followed (see symlinks), are refused with 403. Bodies are streamed to
+    /// disk and limited like max_body_size unless the settings say otherwise
+    /// Panics unless the settings have a layer, which should authenticate,
+    /// or were made Publish::unauthenticated
+    /// Example: router.publish(Publish::new().layer(require_deploy_token));
+    #[cfg(feature = "static")]
+    pub fn publish(&mut self, publish: Publish) -> &mut Router {
+        assert!(
+            publish.is_guarded(),
+            "Publish needs an authenticating layer, or Publish::unauthenticated"
+        );
+        self.publish = Some(Arc::new(publish));
+        self
+    }
+
+    // Returns the settings for writing to the static root, if enabled
+    #[cfg(feature = "static")]
+    pub(crate) fn publisher(&self) -> Option<&Publish> {
+        self.publish.as_deref()
+    }
+
+    // Counts the requests served with the router in the server's statistics
+    pub(crate) fn count_into(&mut self, counters: &Arc<Counters>) {
+        self.counters = Some(Arc::clone(counters));
+    }
+
+    // Returns the statistics requests are counted in, if a server serves
+    // the router
+    pub(crate) fn counters(&self) -> Option<&Counters> {
+        self.counters.as_deref()
+    }
+
+    // Logs the requests served with the router, or stops logging them
+    pub(crate) fn log_into(&mut self, access_log: Option<AccessLog>) {
+        self.access_log = access_log;
+    }
+
+    pub(crate) fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    // Returns true if the request writes to the static root (see publish)
+    #[cfg(feature = "static")]
+    pub(crate) fn publishes(&self, request: &Request) -> bool {
+        self.publish.is_some()
+            && matches!(request.method.as_str(), "PUT" | "DELETE")
+            && self.allowed_methods(&request.path).is_empty()
+    }
+
+    // Answers a request writing to the static root, going through the
+    // hooks like routed requests
+    #[cfg(feature = "static")]
+    pub(crate) fn handle_publish(&self, request: &Request, base_dir: &str) -> Option<Response> {
+        let publish = self.publish.as_ref()?;
+        for hook in &self.request_hooks {
+            hook(request);
+        }
+        let response = publish.handle(self, &self.middleware, request, base_dir);
+        if response.status < 300
+            && let Some(cache) = self.file_cache()
+        {
+            cache.forget(&request.path);
+        }
+        Some(self.finish(request, response))
+    }
+
+    /// Sends a Cache-Control header with static files matching the pattern
+    /// Patterns starting with "/" match the request path, others the file
+    /// name; "*" matches within a segment and "**" across segments.
+    /// Rules are tried in the order they were added
+    /// Example:
+    /// router.cache_control("/assets/**", "public, max-age=31536000, immutable");
+    /// router.cache_control("*.html", "no-cache");
+    #[cfg(feature = "static")]
+    pub fn cache_control(&mut self, pattern: &str, value: &str) -> &mut Router {
+        self.cache_rules
+            .push((pattern.to_string(), value.to_string()));
+        self
+    }
+
+    // Returns the Cache-Control value of the first rule matching the path
+    #[cfg(feature = "static")]
+    pub(crate) fn cache_control_for(&self, path: &str) -> Option<&str> {
+        self.cache_rules
+            .iter()
+            .find(|(pattern, _)| crate::glob::pattern_matches(pattern, path))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Adds a header to responses for paths matching the pattern, static
+    /// files and routed responses alike, unless the response already has
+    /// one by that name. Patterns are those of cache_control; when several
+    /// rules for a header match, the first one added wins
+    /// Example:
+    /// router.response_header("*.html", "X-Frame-Options", "DENY");
+    /// router.response_header("/fonts/**", "Access-Control-Allow-Origin", "*");
+    pub fn response_header(&mut self, pattern: &str, name: &str, value: &str) -> &mut Router {
+        self.header_rules
+            .push((pattern.to_string(), name.to_string(), value.to_string()));
+        self
+    }
+
+    // Returns the headers configured for the path, by the first matching
+    // rule for each name
+    pub(crate) fn headers_for(&self, path: &str) -> Vec<(&str, &str)> {
+        let mut headers: Vec<(&str, &str)> = Vec::new();
+        for (pattern, name, value) in &self.header_rules {
+            if !headers
+                .iter()
+                .any(|(added, _)| added.eq_ignore_ascii_case(name))
+                && crate::glob::pattern_matches(pattern, path)
+            {
+                headers.push((name, value));
+            }
+        }
+        headers
+    }
+
+    /// Believes the Forwarded or X-Forwarded-For/X-Forwarded-Proto headers of
+    /// requests coming from these addresses or CIDR ranges, so that
+    /// Request::client_ip and Request::scheme report the client behind them.
+    /// Requests from other peers keep their own address whatever they send
+    /// Panics on entries that are not addresses or ranges
+    /// Example: router.trusted_proxies(&["127.0.0.1", "10.0.0.0/8", "fd00::/8"]);
+    pub fn trusted_proxies(&mut self, ranges: &[&str]) -> &mut Router {
+        for range in ranges {
+            let parsed = IpRange::parse(range)
+                .unwrap_or_else(|| panic!("invalid trusted proxy range {:?}", range));
+            self.trusted_proxies.push(parsed);
+        }
+        self
+    }
+
+    // Returns the ranges of trusted proxies
+    pub(crate) fn proxy_ranges(&self) -> &[IpRange] {
+        &self.trusted_proxies
+    }
+
+    /// Sets how many bytes the first read of a request head asks for (1 KiB
+    /// by default); later reads of the same head ask for twice as much each
+    /// time, up to the head size limit
+    /// Panics on zero
+    pub fn read_buffer_size(&mut self, bytes: usize) -> &mut Router {
+        assert!(bytes > 0, "read buffer size must not be zero");
+        self.read_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Sets the largest request head (request line and headers) accepted, in
+    /// bytes; longer ones are answered with 431. Defaults to MAX_HEAD_SIZE
+    /// Example: router.max_head_size(32 * 1024);
+    pub fn max_head_size(&mut self, bytes: usize) -> &mut Router {
+        self.max_head_size = Some(bytes);
+        self
+    }
+
+    /// Sets how many bytes each read of a request body asks for (64 KiB by
+    /// default). The body grows as data arrives rather than being allocated
+    /// at its announced length up front
+    /// Panics on zero
+    pub fn body_chunk_size(&mut self, bytes: usize) -> &mut Router {
+        assert!(bytes > 0, "body chunk size must not be zero");
+        self.body_chunk_size = Some(bytes);
+        self
+    }
+
+    /// Sets the largest request body accepted, in bytes; requests announcing
+    /// a longer one are answered with 413. Defaults to MAX_BODY_SIZE
+    /// Example: router.max_body_size(100 * 1024 * 1024);
+    pub fn max_body_size(&mut self, bytes: usize) -> &mut Router {
+        self.max_body_size = Some(bytes);
+        self
+    }
+
+    // Returns the size of the first read of a request head
+    pub(crate) fn head_read_size(&self) -> usize {
+        self.read_buffer_size.unwrap_or(crate::READ_CHUNK_SIZE)
+    }
+
+    // Returns the largest request head accepted
+    pub(crate) fn head_size_limit(&self) -> usize {
+        self.max_head_size.unwrap_or(crate::MAX_HEAD_SIZE)
+    }
+
+    // Returns the size of each read of a request body
+    pub(crate) fn body_read_size(&self) -> usize {
+        self.body_chunk_size.unwrap_or(crate::BODY_CHUNK_SIZE)
+    }
+
+    // Returns the largest request body accepted
+    pub(crate) fn body_size_limit(&self) -> usize {
+        self.max_body_size.unwrap_or(crate::MAX_BODY_SIZE)
+    }
+
+    /// Expands server-side includes in static files with the given extensions
+    /// Example: router.server_side_includes(&["shtml"]);
+    /// <!--#include file="header.html" --> includes a file next to the page,
+    /// <!--#include virtual="/parts/nav.html" --> one under the base directory
+    #[cfg(feature = "static")]
+    pub fn server_side_includes(&mut self, extensions: &[&str]) -> &mut Router {
+        self.include_extensions = extensions.iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    // Returns the extensions server-side includes are expanded for
+    #[cfg(feature = "static")]
+    pub(crate) fn include_extensions(&self) -> &[String] {
+        &self.include_extensions
+    }
+
+    /// Sets which symbolic links under the base directory static files are
+    /// served through (followed by default); requests going through a
+    /// refused link get 403 Forbidden, and so do server-side includes. The
+    /// policy applies to WebDAV shares under their root too
+    /// Example: router.symlinks(SymlinkPolicy::FollowIfSameOwner);
+    #[cfg(any(feature = "static", feature = "webdav"))]
+    pub fn symlinks(&mut self, policy: SymlinkPolicy) -> &mut Router {
+        self.file_rules.symlinks = policy;
+        self.update_shares();
+        self
+    }
+
+    // Returns the policy for symbolic links under the base directory
+    #[cfg(feature = "static")]
+    pub(crate) fn symlink_policy(&self) -> SymlinkPolicy {
+        self.file_rules.symlinks
+    }
+
+    /// Serves hidden static files matching the pattern, with the patterns of
+    /// cache_control matched against the decoded path; may be called for
+    /// several patterns. Files and directories whose names start with "."
+    /// (.env, .git/config, .htpasswd) are otherwise answered as not found.
+    /// WebDAV shares match the patterns against paths under their root
+    /// Example: router.allow_hidden("/.well-known/**");
+    #[cfg(any(feature = "static", feature = "webdav"))]
+    pub fn allow_hidden(&mut self, pattern: &str) -> &mut Router {
+        self.file_rules.hidden_allowed.push(pattern.to_string());
+        self.update_shares();
+        self
+    }
+
+    // Tells whether a static file, by its path relative to the base
+    // directory, is hidden and not allowed
+    #[cfg(feature = "static")]
+    pub(crate) fn hides(&self, relative: &std::path::Path) -> bool {
+        self.file_rules.hides(relative)
+    }
+
+    // Returns the file rules a WebDAV share follows, kept up to date with
+    // those of the router
+    #[cfg(feature = "webdav")]
+    pub(crate) fn share_file_rules(&mut self) -> Arc<RwLock<FileRules>> {
+        let rules = Arc::new(RwLock::new(self.file_rules.clone()));
+        self.shares.push(Arc::clone(&rules));
+        rules
+    }
+
+    // Passes the file rules on to the WebDAV shares mounted so far
+    #[cfg(any(feature = "static", feature = "webdav"))]
+    fn update_shares(&self) {
+        #[cfg(feature = "webdav")]
+        for share in &self.shares {
+            *share.write().unwrap() = self.file_rules.clone();
+        }
+    }
+
+    /// Reads a .webserver.toml file in any directory of the static root,
+    /// setting index files, redirects, Basic authentication and headers for
+    /// the files under it (see the README for the format). Files are read
+    /// again once they change. Requests for static files then check every
+    /// directory on their way, and skip the FileCache
+    /// Example: router.directory_overrides();
+    #[cfg(feature = "static")]
+    pub fn directory_overrides(&mut self) -> &mut Router {
+        self.override_files = Some(Arc::default());
+        self
+    }
+
+    // Returns the override files read so far, if they are read at all
+    #[cfg(feature = "static")]
+    pub(crate) fn override_files(&self) -> Option<&OverrideFiles> {
+        self.override_files.as_deref()
+    }
+
+    /// Answers GET requests for static directories without an index file
+    /// with a page listing their entries, laid out by the settings
+    /// Directories requested without a trailing slash are redirected to it
+    /// Example: router.directory_listing(DirectoryListing::new().json(true));
+    #[cfg(feature = "static")]
+    pub fn directory_listing(&mut self, listing: DirectoryListing) -> &mut Router {
+        self.listing = Some(Arc::new(listing));
+        self
+    }
+
+    // Returns the directory listing settings, if directories are listed
+    #[cfg(feature = "static")]
+    pub(crate) fn listing(&self) -> Option<&DirectoryListing> {
+        self.listing.as_deref()
+    }
+
+    /// Answers GET /favicon.ico with the icon when the static root has no
+    /// favicon.ico, rather than with 404 to every browser visiting
+    /// DEFAULT_FAVICON is a transparent icon
+    /// Example: router.favicon_fallback(DEFAULT_FAVICON);
+    #[cfg(feature = "static")]
+    pub fn favicon_fallback(&mut self, icon: &[u8]) -> &mut Router {
+        self.favicon = Some(icon.into());
+        self
+    }
+
+    // Returns the icon served for a missing favicon.ico, if any
+    #[cfg(feature = "static")]
+    pub(crate) fn favicon(&self) -> Option<&[u8]> {
+        self.favicon.as_deref()
+    }
+
+    /// Answers GET /robots.txt following the policy when the static root
+    /// has no robots.txt, rather than with 404 to every crawler
+    /// Example: router.robots_fallback(RobotsPolicy::DenyAll);
+    #[cfg(feature = "static")]
+    pub fn robots_fallback(&mut self, policy: RobotsPolicy) -> &mut Router {
+        self.robots = Some(policy);
+        self
+    }
+
+    // Returns the policy served for a missing robots.txt, if any
+    #[cfg(feature = "static")]
+    pub(crate) fn robots(&self) -> Option<&RobotsPolicy> {
+        self.robots.as_ref()
+    }
+
+    /// Renders static .md files to HTML pages using the default template
+    #[cfg(feature = "markdown")]
+    pub fn render_markdown(&mut self) -> &mut Router {
+        self.markdown_template(crate::markdown::DEFAULT_TEMPLATE)
+    }
+
+    /// Renders static .md files to HTML pages wrapped in the template
+    /// "{title}" is replaced with the first heading (or the file name),
+    /// "{content}" with the rendered document
+    /// Example: router.markdown_template("<html><body>{content}</body></html>");
+    #[cfg(feature = "markdown")]
+    pub fn markdown_template(&mut self, template: &str) -> &mut Router {
+        self.markdown_template = Some(template.to_string());
+        self
+    }
+
+    // Returns the Markdown page template, if rendering is enabled
+    #[cfg(feature = "markdown")]
+    pub(crate) fn markdown_wrapper(&self) -> Option<&str> {
+        self.markdown_template.as_deref()
+    }
+
+    /// Serves static files of at least min_size bytes from read-only memory maps
+    /// Saves copying large files through a userspace buffer; on platforms
+    /// without mmap files are read as usual. Files must not be truncated
+    /// while they are being served (replace them by rename instead)
+    #[cfg(feature = "mmap")]
+    pub fn mmap_static_files(&mut self, min_size: u64) -> &mut Router {
+        self.mmap_threshold = Some(min_size);
+        self
+    }
+
+    /// Returns the size from which static files are memory mapped, if enabled
+    #[cfg(feature = "mmap")]
+    pub fn mmap_threshold(&self) -> Option<u64> {
+        self.mmap_threshold
+    }
+
+    /// Sets how "/foo" and "/foo/" are matched (strict by default)
+    pub fn trailing_slash(&mut self, policy: TrailingSlash) -> &mut Router {
+        self.trailing_slash = policy;
+        self
+    }
+
+    /// Registers the handler for requests matching no route and no static file
+    /// It receives the full request and runs inside the router's middleware,
+    /// replacing the default empty text/plain 404
+    /// Example:
+    /// router.not_found(|request: Request| {
+    ///     let body = format!(r#"{{"error": "no such path: {}"}}"#, request.path);
+    ///     Response::new(404, "Not Found").with_body("application/json", body)
+    /// });
+    pub fn not_found<Args, H: RouteHandler<Args>>(&mut self, handler: H) -> &mut Router {
+        self.not_found = Some(Arc::new(move |request: &Request| handler.call(request)));
+        self
+    }
+
+    /// Runs the not-found handler, if one is registered
+    pub fn handle_not_found(&self, request: &Request) -> Option<Response> {
+        let handler = self.not_found.as_ref()?;
+        let stack: Vec<&Arc<dyn Middleware>> = self.middleware.iter().collect();
+        Some(self.finish(request, run_chain(&stack, handler, request)))
+    }
+
+    /// Returns the static file cache, if enabled
+    #[cfg(feature = "static")]
+    pub fn file_cache(&self) -> Option<&FileCache> {
+        self.file_cache.as_deref()
+    }
+
+    /// Registers a group of routes sharing a path prefix and middleware stack
+    /// Middleware layered inside the closure only applies to the group
+    /// Example:
+    /// router.scope("/admin", |admin| {
+    ///     admin.layer(require_auth);
+    ///     admin.get("/stats", stats); // served at /admin/stats
+    /// });
+    pub fn scope(&mut self, prefix: &str, build: impl FnOnce(&mut Router)) -> &mut Router {
+        let mut group = Router::new();
+        build(&mut group);
+        self.request_hooks.extend(group.request_hooks);
+        self.response_hooks.extend(group.response_hooks);
+
+        let first = self.routes.len();
+        for route in group.routes {
+            let pattern = join_prefix(prefix, &route.pattern);
+            let mut middleware = group.middleware.clone();
+            middleware.extend(route.middleware);
+            self.add(Route {
+                segments: parse_pattern(&pattern),
+                pattern,
+                middleware,
+                ..route
+            });
+        }
+        self.last_registered = first..self.routes.len();
+        self
+    }
+
+    /// Shares the directory root over WebDAV under the path prefix
+    /// Every request must carry HTTP Basic credentials that authenticate
+    /// accepts; serve it over TLS or a trusted network only, as Basic
+    /// credentials are sent in clear text
+    /// Example: router.webdav("/dav", "files", |user, password| user == "me" && password == "secret");
+    #[cfg(feature = "webdav")]
+    pub fn webdav(
+        &mut self,
+        prefix: &str,
+        root: &str,
+        authenticate: impl Fn(&str, &str) -> bool + Send + Sync + 'static,
+    ) -> &mut Router {
+        crate::webdav::mount(self, prefix, root, authenticate);
+        self
+    }
+
+    /// Accepts resumable uploads over the tus protocol under the path prefix
+    /// Uploads are created with POST on the prefix, and appended to with
+    /// PATCH on the URL returned in Location. Add authentication with with,
+    /// which applies to all the endpoint's routes
+    /// Example: router.tus("/files", Tus::new("uploads")).with(require_auth);
+    #[cfg(feature = "tus")]
+    pub fn tus(&mut self, prefix: &str, tus: crate::Tus) -> &mut Router {
+        crate::tus::mount(self, prefix, tus);
+        self
+    }
+
+    /// Forwards every request under the path prefix to an upstream server,
+    /// keeping the path as it is
+    /// Example: router.proxy("/api", Proxy::new("http://127.0.0.1:3000"));
+    #[cfg(feature = "proxy")]
+    pub fn proxy(&mut self, prefix: &str, proxy: crate::Proxy) -> &mut Router {
+        crate::proxy::mount(self, prefix, proxy);
+        self
+    }
+
+    /// Tunnels CONNECT requests to the destinations the proxy allows
+    /// Clients use the server as their HTTP proxy (e.g. HTTPS_PROXY), other
+    /// requests are routed as usual. Tunnels only open on plain TCP
+    /// connections, elsewhere CONNECT is answered with 501
+    /// Example: router.forward_proxy(ForwardProxy::new().allow("*.example.com:443"));
+    #[cfg(feature = "proxy")]
+    pub fn forward_proxy(&mut self, proxy: crate::ForwardProxy) -> &mut Router {
+        self.route("CONNECT", "/{target}", proxy)
+    }
+
+    /// Serves files held in memory under the path prefix, e.g. compiled
+    /// into the binary with embed_assets!; other paths under it get 404
+    /// Example: router.assets("/", embed_assets!("static", ["index.html", "app.js"]));
+    #[cfg(feature = "static")]
+    pub fn assets(&mut self, prefix: &str, assets: crate::Assets) -> &mut Router {
+        crate::assets::mount(self, prefix, assets);
+        self
+    }
+
+    /// Answers requests under the path prefix with the Rhai scripts of a
+    /// directory, recompiled when their file changes (see Scripts)
+    /// Example: router.scripts("/api", Scripts::new("scripts"));
+    #[cfg(feature = "scripting")]
+    pub fn scripts(&mut self, prefix: &str, scripts: crate::Scripts) -> &mut Router {
+        crate::scripts::mount(self, prefix, scripts);
+        self
+    }
+
+    /// Answers the HTTP-01 challenges of an Acme certificate order under
+    /// /.well-known/acme-challenge/, on the router listening on port 80
+    /// Example: http.acme_challenges(&acme);
+    #[cfg(feature = "acme")]
+    pub fn acme_challenges(&mut self, acme: &crate::Acme) -> &mut Router {
+        crate::acme::mount(self, acme);
+        self
+    }
+
+    /// Registers a handler for GET requests
+    pub fn get<Args, H: RouteHandler<Args>>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Registers a handler for POST requests
+    pub fn post<Args, H: RouteHandler<Args>>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Registers a handler for PUT requests
+    pub fn put<Args, H: RouteHandler<Args>>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Registers a handler for DELETE requests
+    pub fn delete<Args, H: RouteHandler<Args>>(
+        &mut self,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Router {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Dispatches the request to the first matching route
+    /// Captured path parameters are stored in request.params
+    /// Returns 405 Method Not Allowed with an Allow header if the path only
+    /// matches routes of other methods, and None if no route matches the path
+    pub fn handle(&self, request: &mut Request) -> Option<Response> {
+        for hook in &self.request_hooks {
+            hook(request);
+        }
+        let response = self.route_request(request)?;
+        Some(self.finish(request, response))
+    }
+
+    // Finds the response of the route matching the request, if any
+    fn route_request(&self, request: &mut Request) -> Option<Response> {
+        if let Some((route, params)) = self.find(&request.method, &request.path) {
+            request.params = params;
+            request.route = Some(route.pattern.clone());
+            return Some(self.dispatch(route, request));
+        }
+
+        let allowed = self.allowed_methods(&request.path);
+        if !allowed.is_empty() {
+            return Some(
+                Response::new(405, "Method Not Allowed").with_header("Allow", &allowed.join(", ")),
+            );
+        }
+
+        // Try the path with the trailing slash toggled, depending on the policy
+        if self.trailing_slash == TrailingSlash::Strict || request.path == "/" {
+            return None;
+        }
+        let alternate = match request.path.strip_suffix('/') {
+            Some(path) => path.to_string(),
+            None => format!("{}/", request.path),
+        };
+        let (route, params) = self.find(&request.method, &alternate)?;
+        match self.trailing_slash {
+            TrailingSlash::Redirect => {
+                // 301 may turn other methods into GET, 308 keeps the method and body
+                let (status, reason) = match request.method.as_str() {
+                    "GET" | "HEAD" => (301, "Moved Permanently"),
+                    _ => (308, "Permanent Redirect"),
+                };
+                let location = match request.query.as_str() {
+                    "" => alternate,
+                    query => format!("{}?{}", alternate, query),
+                };
+                Some(Response::new(status, reason).with_header("Location", &location))
+            }
+            _ => {
+                request.params = params;
+                request.route = Some(route.pattern.clone());
+                Some(self.dispatch(route, request))
+            }
+        }
+    }
+
+    /// Returns the methods of the routes matching a path, in registration order
+    pub fn allowed_methods(&self, path: &str) -> Vec<String> {
+        let mut methods: Vec<String> = Vec::new();
+        for route in &self.routes {
+            if !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        methods.retain(|method| self.find(method, path).is_some());
+        methods
+    }
+
+    // Returns the route matching the method and path, with its decoded parameters
+    fn find(&self, method: &str, path: &str) -> Option<(&Route, Vec<(String, String)>)> {
+        let (id, values) = self
+            .tree
+            .lookup(path, &|id| self.routes[id].method == method)?;
+        let route = &self.routes[id];
+        let names = route.segments.iter().filter_map(|segment| match segment {
+            Segment::Param(name, _) | Segment::Wildcard(name) => Some(name.clone()),
+            Segment::Static(_) => None,
+        });
+        let mut params = Vec::new();
+        for (name, value) in names.zip(values) {
+            params.push((name, percent_decode(&value)?));
+        }
+        Some((route, params))
+    }
+
+    // Runs the route's handler inside the router and route middleware
+    fn dispatch(&self, route: &Route, request: &Request) -> Response {
+        let stack: Vec<&Arc<dyn Middleware>> =
+            self.middleware.iter().chain(&route.middleware).collect();
+        run_chain(&stack, &route.handler, request)
+    }
+}
+
+// Registers legacy handlers as GET routes with exact paths
+impl From<&HashMap<String, Handler>> for Router {
+    fn from(routes: &HashMap<String, Handler>) -> Router {
+        let mut router = Router::new();
+        for (path, handler) in routes {
+            let handler = *handler;
+            router.get(path, move || {
+                let (body, content_type) = handler();
+                Response::ok(&content_type, body)
+            });
+        }
+        router
+    }
+}
+
+// Prepends a scope prefix to a route pattern
+// Example: ("/api/", "/users") -> "/api/users", ("/api", "/") -> "/api"
+fn join_prefix(prefix: &str, pattern: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match pattern {
+        "" | "/" if !prefix.is_empty() => prefix.to_string(),
+        _ => format!("{}/{}", prefix, pattern.trim_start_matches('/')),
+    }
+}
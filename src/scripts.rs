@@ -0,0 +1,259 @@
+// Route handlers written in Rhai (https://rhai.rs)
+// Requests under the mount prefix run the script their path names in the
+// scripts directory: /api/users runs users.rhai, and /api/users/42 runs it
+// too when there is no users/42.rhai, with the rest of the path in
+// request.rest. Compiled scripts are cached and recompiled when their file
+// changes, so edits take effect on the next request without a restart.
+//
+// A script sees the request as the object map `request` and answers with
+// its value: a string is sent as text/plain, an integer as a bare status,
+// () as 204 No Content, and a map describes the whole response with the
+// keys status, headers, content_type and body (a string, a blob, or a map
+// sent as JSON). Scripts get no file or network functions, import loads
+// modules relative to the scripts directory and parse_json decodes JSON
+// request bodies.
+
+use crate::{Request, Response, RouteHandler, Router, StatusCode};
+use rhai::module_resolvers::FileModuleResolver;
+use rhai::{AST, Blob, Dynamic, Engine, Map, Scope};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Extension of script files
+const EXTENSION: &str = "rhai";
+
+// Operations a request may run by default
+const DEFAULT_MAX_OPERATIONS: u64 = 10_000_000;
+
+const METHODS: [&str; 5] = ["GET", "POST", "PUT", "PATCH", "DELETE"];
+
+/// Settings of a scripted endpoint (see Router::scripts)
+/// Example:
+/// router.scripts("/api", Scripts::new("scripts").max_operations(1_000_000));
+/// // scripts/users.rhai, answering GET /api/users and /api/users/42
+/// if request.rest == "" {
+///     #{ body: #{ users: ["alice", "bob"] } }
+/// } else {
+///     `user ${request.rest}`
+/// }
+#[derive(Clone)]
+pub struct Scripts {
+    dir: PathBuf,
+    max_operations: u64,
+}
+
+impl Scripts {
+    /// Creates the settings for scripts in dir
+    pub fn new(dir: impl Into<PathBuf>) -> Scripts {
+        Scripts {
+            dir: dir.into(),
+            max_operations: DEFAULT_MAX_OPERATIONS,
+        }
+    }
+
+    /// Sets the operations a request may run before it is answered with 500,
+    /// bounding scripts stuck in a loop
+    pub fn max_operations(mut self, operations: u64) -> Scripts {
+        self.max_operations = operations;
+        self
+    }
+}
+
+impl fmt::Debug for Scripts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scripts")
+            .field("dir", &self.dir)
+            .field("max_operations", &self.max_operations)
+            .finish()
+    }
+}
+
+// A scripted endpoint and its compiled scripts
+struct Endpoint {
+    dir: PathBuf,
+    engine: Engine,
+    // Compiled scripts by file, with the modification time they were read at
+    cache: Mutex<HashMap<PathBuf, (SystemTime, Arc<AST>)>>,
+}
+
+// Registers the endpoint's routes on the router
+pub fn mount(router: &mut Router, prefix: &str, scripts: Scripts) {
+    let mut engine = Engine::new();
+    engine.set_max_operations(scripts.max_operations);
+    // import statements load modules from the scripts directory
+    engine.set_module_resolver(FileModuleResolver::new_with_path(&scripts.dir));
+    engine.on_print(|text| eprintln!("{}", text));
+    let endpoint = Arc::new(Endpoint {
+        dir: scripts.dir,
+        engine,
+        cache: Mutex::new(HashMap::new()),
+    });
+    router.scope(prefix, |api| {
+        for method in METHODS {
+            api.route(method, "/{*path}", ScriptHandler(Arc::clone(&endpoint)));
+        }
+    });
+}
+
+struct ScriptHandler(Arc<Endpoint>);
+
+impl RouteHandler<()> for ScriptHandler {
+    fn call(&self, request: &Request) -> Response {
+        self.0.handle(request)
+    }
+}
+
+impl Endpoint {
+    // Runs the script a request names
+    fn handle(&self, request: &Request) -> Response {
+        let path = request.param("path").unwrap_or("");
+        let Some((script, rest)) = self.find(path) else {
+            return Response::from_status(StatusCode::NOT_FOUND);
+        };
+        let ast = match self.compiled(&script) {
+            Ok(ast) => ast,
+            Err(e) => {
+                eprintln!("Cannot compile script {}: {}", script.display(), e);
+                return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let mut scope = Scope::new();
+        scope.push_constant("request", request_map(request, rest));
+        match self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast) {
+            Ok(value) => into_response(value).unwrap_or_else(|| {
+                eprintln!("Script {} returned an invalid response", script.display());
+                Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }),
+            Err(e) => {
+                eprintln!("Script {} failed: {}", script.display(), e);
+                Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    // Finds the script for a path: the file it names, else the closest one
+    // above it, returned with the rest of the path
+    fn find(&self, path: &str) -> Option<(PathBuf, String)> {
+        let path = path.trim_matches('/');
+        let escapes = Path::new(path)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            return None;
+        }
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        for end in (0..=segments.len()).rev() {
+            let name = match end {
+                0 => "index".to_string(),
+                _ => segments[..end].join("/"),
+            };
+            let script = self.dir.join(format!("{}.{}", name, EXTENSION));
+            if script.is_file() {
+                return Some((script, segments[end..].join("/")));
+            }
+        }
+        None
+    }
+
+    // Returns the compiled script, compiling it again if the file changed
+    fn compiled(&self, script: &Path) -> Result<Arc<AST>, String> {
+        let modified = fs::metadata(script)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| e.to_string())?;
+        if let Some((at, ast)) = self.cache.lock().unwrap().get(script)
+            && *at == modified
+        {
+            return Ok(Arc::clone(ast));
+        }
+        let source = fs::read_to_string(script).map_err(|e| e.to_string())?;
+        let ast = Arc::new(self.engine.compile(source).map_err(|e| e.to_string())?);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(script.to_path_buf(), (modified, Arc::clone(&ast)));
+        Ok(ast)
+    }
+}
+
+// Exposes the request to scripts
+fn request_map(request: &Request, rest: String) -> Map {
+    let mut headers = Map::new();
+    for (name, value) in &request.headers {
+        headers.insert(name.to_ascii_lowercase().into(), value.clone().into());
+    }
+    let mut query = Map::new();
+    for (name, value) in request.query_pairs() {
+        query.insert(name.into(), value.into());
+    }
+    let mut map = Map::new();
+    map.insert("method".into(), request.method.clone().into());
+    map.insert("path".into(), request.path.clone().into());
+    map.insert("rest".into(), rest.into());
+    map.insert("query".into(), query.into());
+    map.insert("headers".into(), headers.into());
+    map.insert(
+        "body".into(),
+        String::from_utf8_lossy(&request.body).into_owned().into(),
+    );
+    map
+}
+
+// Builds the response a script's value describes
+fn into_response(value: Dynamic) -> Option<Response> {
+    if value.is_unit() {
+        return Some(Response::from_status(StatusCode::NO_CONTENT));
+    }
+    if value.is_int() {
+        let status = u16::try_from(value.as_int().ok()?).ok()?;
+        return status_response(status);
+    }
+    if value.is_string() {
+        return Some(Response::ok("text/plain", value.into_string().ok()?));
+    }
+    let mut map = value.try_cast::<Map>()?;
+    let status = match map.remove("status") {
+        Some(status) => u16::try_from(status.as_int().ok()?).ok()?,
+        None => 200,
+    };
+    let mut response = status_response(status)?;
+    let content_type = match map.remove("content_type") {
+        Some(content_type) => Some(content_type.into_string().ok()?),
+        None => None,
+    };
+    match map.remove("body") {
+        None => {}
+        Some(body) if body.is_string() => {
+            let content_type = content_type.as_deref().unwrap_or("text/plain");
+            response = response.with_body(content_type, body.into_string().ok()?);
+        }
+        Some(body) if body.is_blob() => {
+            let content_type = content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream");
+            response = response.with_body(content_type, body.cast::<Blob>());
+        }
+        Some(body) => {
+            let json = rhai::format_map_as_json(&body.try_cast::<Map>()?);
+            let content_type = content_type.as_deref().unwrap_or("application/json");
+            response = response.with_body(content_type, json);
+        }
+    }
+    if let Some(headers) = map.remove("headers") {
+        for (name, value) in headers.try_cast::<Map>()? {
+            response = response.with_header(&name, &value.to_string());
+        }
+    }
+    Some(response)
+}
+
+// Returns an empty response with a status in range
+fn status_response(status: u16) -> Option<Response> {
+    if !(100..600).contains(&status) {
+        return None;
+    }
+    StatusCode::new(status).map(Response::from_status)
+}
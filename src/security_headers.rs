@@ -0,0 +1,315 @@
+// Security headers
+// SecurityHeaders adds the response headers browsers read to restrict what a
+// page may do: no MIME sniffing, no framing by other sites, a referrer policy
+// and, when given, HSTS and a Content-Security-Policy. Policies allowing
+// CspSource::Nonce get a fresh nonce from the system's secure random source
+// for every response. The handler reads it with Request::csp_nonce and puts
+// it in the nonce attribute of the inline scripts and styles its templates
+// render; bodies are never rewritten, as user content in them could carry a
+// placeholder too. Headers the handler set itself are kept.
+
+use crate::{Middleware, Next, Request, Response, StatusCode};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::Write as _;
+use std::time::Duration;
+
+/// A directive of a Content-Security-Policy, naming what its sources allow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CspDirective {
+    // Fallback for the fetch directives not given
+    DefaultSrc,
+    ScriptSrc,
+    StyleSrc,
+    ImgSrc,
+    ConnectSrc,
+    FontSrc,
+    ObjectSrc,
+    MediaSrc,
+    FrameSrc,
+    WorkerSrc,
+    ManifestSrc,
+    // Pages allowed to frame this one
+    FrameAncestors,
+    // URLs allowed in <base>
+    BaseUri,
+    // URLs forms may be submitted to
+    FormAction,
+}
+
+/// A source allowed by a Content-Security-Policy directive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CspSource {
+    // 'self', the origin of the page
+    SelfOrigin,
+    // 'none', nothing at all; the directive's other sources are ignored
+    None,
+    // 'unsafe-inline'
+    UnsafeInline,
+    // 'unsafe-eval'
+    UnsafeEval,
+    // 'strict-dynamic', trusting scripts loaded by trusted scripts
+    StrictDynamic,
+    // 'nonce-...', with the nonce generated for each response
+    Nonce,
+    // A host, with optional scheme, port and path, e.g. "https://*.example.com"
+    Host(String),
+    // A scheme, without the colon, e.g. "data"
+    Scheme(String),
+    // A hash of an inline script or style, e.g. "sha256-B2yPHKaXnvFWtRChIbabYmUBFZdVfKKXHbWtWidDVF8="
+    Hash(String),
+}
+
+/// A typed Content-Security-Policy, sent by SecurityHeaders
+/// Example:
+/// let policy = ContentSecurityPolicy::new()
+///     .directive(CspDirective::DefaultSrc, &[CspSource::SelfOrigin])
+///     .directive(CspDirective::ScriptSrc, &[CspSource::SelfOrigin, CspSource::Nonce])
+///     .directive(CspDirective::ImgSrc, &[CspSource::SelfOrigin, CspSource::Scheme("data".into())])
+///     .report_uri("/csp-reports");
+/// router.layer(SecurityHeaders::new().content_security_policy(policy));
+#[derive(Clone, Debug, Default)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(CspDirective, Vec<CspSource>)>,
+    upgrade_insecure_requests: bool,
+    report_uri: Option<String>,
+    report_only: bool,
+}
+
+impl ContentSecurityPolicy {
+    /// Creates a policy without directives, which allows everything
+    pub fn new() -> ContentSecurityPolicy {
+        ContentSecurityPolicy::default()
+    }
+
+    /// Allows the sources for the directive, in addition to those given by
+    /// earlier calls for the same directive
+    pub fn directive(
+        mut self,
+        directive: CspDirective,
+        sources: &[CspSource],
+    ) -> ContentSecurityPolicy {
+        match self
+            .directives
+            .iter_mut()
+            .find(|(known, _)| *known == directive)
+        {
+            Some((_, known)) => known.extend_from_slice(sources),
+            None => self.directives.push((directive, sources.to_vec())),
+        }
+        self
+    }
+
+    /// Has browsers load the page's http:// resources over https://
+    pub fn upgrade_insecure_requests(mut self) -> ContentSecurityPolicy {
+        self.upgrade_insecure_requests = true;
+        self
+    }
+
+    /// Sets the URL browsers POST violation reports to
+    pub fn report_uri(mut self, uri: &str) -> ContentSecurityPolicy {
+        self.report_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Only reports violations instead of blocking, sending the policy as
+    /// Content-Security-Policy-Report-Only, to try it on a live site
+    pub fn report_only(mut self, enabled: bool) -> ContentSecurityPolicy {
+        self.report_only = enabled;
+        self
+    }
+
+    fn uses_nonce(&self) -> bool {
+        self.directives
+            .iter()
+            .any(|(_, sources)| sources.contains(&CspSource::Nonce))
+    }
+
+    fn header_name(&self) -> &'static str {
+        match self.report_only {
+            true => "Content-Security-Policy-Report-Only",
+            false => "Content-Security-Policy",
+        }
+    }
+
+    // Renders the header value, with the nonce of the response if any
+    fn header_value(&self, nonce: Option<&str>) -> String {
+        let mut parts: Vec<String> = self
+            .directives
+            .iter()
+            .map(|(directive, sources)| {
+                let sources: Vec<String> = match sources.contains(&CspSource::None) {
+                    true => vec!["'none'".to_string()],
+                    false => sources
+                        .iter()
+                        .filter_map(|source| source_text(source, nonce))
+                        .collect(),
+                };
+                match sources.is_empty() {
+                    true => directive_name(*directive).to_string(),
+                    false => format!("{} {}", directive_name(*directive), sources.join(" ")),
+                }
+            })
+            .collect();
+        if self.upgrade_insecure_requests {
+            parts.push("upgrade-insecure-requests".to_string());
+        }
+        if let Some(uri) = &self.report_uri {
+            parts.push(format!("report-uri {}", uri));
+        }
+        parts.join("; ")
+    }
+}
+
+fn directive_name(directive: CspDirective) -> &'static str {
+    match directive {
+        CspDirective::DefaultSrc => "default-src",
+        CspDirective::ScriptSrc => "script-src",
+        CspDirective::StyleSrc => "style-src",
+        CspDirective::ImgSrc => "img-src",
+        CspDirective::ConnectSrc => "connect-src",
+        CspDirective::FontSrc => "font-src",
+        CspDirective::ObjectSrc => "object-src",
+        CspDirective::MediaSrc => "media-src",
+        CspDirective::FrameSrc => "frame-src",
+        CspDirective::WorkerSrc => "worker-src",
+        CspDirective::ManifestSrc => "manifest-src",
+        CspDirective::FrameAncestors => "frame-ancestors",
+        CspDirective::BaseUri => "base-uri",
+        CspDirective::FormAction => "form-action",
+    }
+}
+
+// Renders a source; a nonce source is left out when there is no nonce
+fn source_text(source: &CspSource, nonce: Option<&str>) -> Option<String> {
+    Some(match source {
+        CspSource::SelfOrigin => "'self'".to_string(),
+        CspSource::None => "'none'".to_string(),
+        CspSource::UnsafeInline => "'unsafe-inline'".to_string(),
+        CspSource::UnsafeEval => "'unsafe-eval'".to_string(),
+        CspSource::StrictDynamic => "'strict-dynamic'".to_string(),
+        CspSource::Nonce => format!("'nonce-{}'", nonce?),
+        CspSource::Host(host) => host.clone(),
+        CspSource::Scheme(scheme) => format!("{}:", scheme),
+        CspSource::Hash(hash) => format!("'{}'", hash),
+    })
+}
+
+/// Middleware adding security headers to route responses
+/// By default it sends X-Content-Type-Options: nosniff, X-Frame-Options:
+/// SAMEORIGIN and Referrer-Policy: strict-origin-when-cross-origin. Headers
+/// the handler set are kept. Static files are sent without them
+/// With a policy allowing CspSource::Nonce, every response gets a new nonce,
+/// which the handler gets from Request::csp_nonce for its inline scripts
+/// Example:
+/// router.layer(
+///     SecurityHeaders::new()
+///         .strict_transport_security(Duration::from_secs(365 * 86400))
+///         .content_security_policy(policy),
+/// );
+/// router.get("/", |request: Request| {
+///     let nonce = request.csp_nonce().unwrap_or("");
+///     Response::ok("text/html", format!("<script nonce=\"{}\">init()</script>", nonce))
+/// });
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+    policy: Option<ContentSecurityPolicy>,
+    random: SystemRandom,
+}
+
+impl SecurityHeaders {
+    /// Creates the middleware with the default headers and no policy
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            headers: vec![
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+                ("X-Frame-Options".to_string(), "SAMEORIGIN".to_string()),
+                (
+                    "Referrer-Policy".to_string(),
+                    "strict-origin-when-cross-origin".to_string(),
+                ),
+            ],
+            policy: None,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// Sends the header, replacing a default of the same name
+    /// Example: SecurityHeaders::new().header("Permissions-Policy", "camera=()")
+    pub fn header(self, name: &str, value: &str) -> SecurityHeaders {
+        let mut headers = self.without(name);
+        headers.headers.push((name.to_string(), value.to_string()));
+        headers
+    }
+
+    /// Stops sending the header
+    /// Example: SecurityHeaders::new().without("X-Frame-Options")
+    pub fn without(mut self, name: &str) -> SecurityHeaders {
+        self.headers
+            .retain(|(known, _)| !known.eq_ignore_ascii_case(name));
+        self
+    }
+
+    /// Tells browsers to only reach the site over HTTPS for max_age
+    /// Only send it from sites served over HTTPS for good
+    pub fn strict_transport_security(self, max_age: Duration) -> SecurityHeaders {
+        let value = format!("max-age={}", max_age.as_secs());
+        self.header("Strict-Transport-Security", &value)
+    }
+
+    /// Sends the Content-Security-Policy
+    pub fn content_security_policy(mut self, policy: ContentSecurityPolicy) -> SecurityHeaders {
+        self.policy = Some(policy);
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let nonce = match &self.policy {
+            Some(policy) if policy.uses_nonce() => match nonce(&self.random) {
+                Some(nonce) => Some(nonce),
+                None => return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR),
+            },
+            _ => None,
+        };
+        let mut response = match &nonce {
+            Some(nonce) => {
+                let mut request = request.clone();
+                request.csp_nonce = Some(nonce.clone());
+                next.run(&request)
+            }
+            None => next.run(request),
+        };
+
+        if let Some(policy) = &self.policy
+            && response.header(policy.header_name()).is_none()
+        {
+            let value = policy.header_value(nonce.as_deref());
+            response = response.with_header(policy.header_name(), &value);
+        }
+        for (name, value) in &self.headers {
+            if response.header(name).is_none() {
+                response = response.with_header(name, value);
+            }
+        }
+        response
+    }
+}
+
+// Returns a new nonce, 128 random bits in hex, or None if the system has
+// no random bytes to give
+fn nonce(random: &SystemRandom) -> Option<String> {
+    let mut bytes = [0; 16];
+    random.fill(&mut bytes).ok()?;
+    Some(bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    }))
+}
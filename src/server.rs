@@ -0,0 +1,1022 @@
+#[cfg(feature = "metrics")]
+use crate::Response;
+use crate::access_log::AccessLog;
+use crate::admin::{self, AdminConfig};
+#[cfg(feature = "event-loop")]
+use crate::event_loop;
+use crate::keep_alive::KeepAlive;
+use crate::plugin::{Plugin, Registry};
+#[cfg(feature = "static")]
+use crate::publish::QuotaUsage;
+#[cfg(unix)]
+use crate::signal;
+#[cfg(feature = "metrics")]
+use crate::stats::Stats;
+use crate::stats::{Counters, DEFAULT_LATENCY_BUCKETS, OpenConnection};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConfig};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring;
+use crate::{
+    IDLE_PROBE_INTERVAL, MAX_IDLE_PROBES, Peer, Router, proxy_protocol, serve_tcp_stream, socket,
+};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Content type of the Prometheus text format
+#[cfg(feature = "metrics")]
+pub(crate) const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// How often acceptors look up from the listener to check for a hand over
+pub(crate) const ACCEPT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server settings
+/// Every option defaults to the operating system's behavior
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Enable TCP Fast Open with the given pending request queue length (Linux only)
+    pub tcp_fast_open: Option<u32>,
+    /// Only wake the acceptor once the client has sent data (Linux only)
+    pub defer_accept: Option<Duration>,
+    /// Disable Nagle's algorithm on accepted connections, so small responses
+    /// go out without delay
+    pub tcp_nodelay: bool,
+    /// Set SO_LINGER on accepted connections: closing waits this long for
+    /// unsent data, and a zero duration resets the connection instead (unix only)
+    pub linger: Option<Duration>,
+    /// Enable TCP keepalive on accepted connections, probing after they were
+    /// idle this long (unix only)
+    pub tcp_keepalive: Option<Duration>,
+    /// Kernel send buffer size in bytes, set on the listener and inherited by
+    /// the connections it accepts (unix only)
+    pub send_buffer_size: Option<usize>,
+    /// Kernel receive buffer size in bytes, set on the listener and inherited
+    /// by the connections it accepts (unix only)
+    pub recv_buffer_size: Option<usize>,
+    /// Stack size in bytes for worker threads (std default is 2 MiB)
+    pub worker_stack_size: Option<usize>,
+    /// Accept connections on this many threads instead of one; extra
+    /// acceptors are named "acceptor-N"
+    pub acceptor_threads: Option<usize>,
+    /// Serve connections on a fixed pool of this many worker threads instead
+    /// of a new thread per connection. A connection keeps its worker until it
+    /// closes, and new ones wait in a queue while every worker is busy
+    pub worker_threads: Option<usize>,
+    /// Give each pooled worker a queue of its own, filled in turn, instead of
+    /// one queue shared by all of them
+    pub per_worker_queues: bool,
+    /// Pin the threads the server spawns (acceptors, workers, event loops) to
+    /// these CPUs in turn (Linux only)
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// Keep connections open for further requests, closing them after this
+    /// long without one. Only the threaded backend keeps connections alive;
+    /// by default every connection closes after one response
+    pub keep_alive_timeout: Option<Duration>,
+    /// Close connections after this many requests, announced with
+    /// Connection: close on the last response (enables keep-alive, with a
+    /// timeout of IDLE_PROBE_INTERVAL * MAX_IDLE_PROBES unless set)
+    pub max_requests_per_connection: Option<usize>,
+    /// Expect a PROXY protocol v1 or v2 header at the start of every connection
+    /// and take the client address from it; connections without one are closed.
+    /// Only enable behind a load balancer that sends it (haproxy, AWS NLB/ELB)
+    pub proxy_protocol: bool,
+    /// Bind with SO_REUSEPORT, so another process (such as a new version of
+    /// the server) can bind the same address and share its connections (Unix only)
+    pub reuse_port: bool,
+    /// Serve connections from this many event loop threads instead of one
+    /// thread per connection
+    #[cfg(feature = "event-loop")]
+    pub event_loop_threads: Option<usize>,
+    /// Serve connections from an io_uring with this many submission entries
+    /// on the calling thread (experimental, Linux only)
+    #[cfg(feature = "io-uring")]
+    pub io_uring_entries: Option<u32>,
+    /// Serve HTTPS with this certificate, and authenticate clients if it says
+    /// so; connections are always served on worker threads
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Serve the administration endpoints under /_admin (see AdminConfig)
+    pub admin: Option<AdminConfig>,
+    /// Append a line per request to this file (see AccessLog)
+    pub access_log: Option<AccessLog>,
+    /// Upper bounds of the request latency histogram's buckets, increasing
+    /// (DEFAULT_LATENCY_BUCKETS unless set; see Stats::latency)
+    #[cfg(feature = "metrics")]
+    pub latency_buckets: Option<Vec<Duration>>,
+    /// Abort the connections still open this long after drain was called
+    pub drain_timeout: Option<Duration>,
+}
+
+/// A multi-threaded HTTP server
+/// Each accepted connection is handled on its own thread named "worker-N"
+/// (or on a pooled one, see ServerConfig::worker_threads), connections are
+/// accepted on a thread named "acceptor"
+/// Example:
+/// Server::new(router, "static").run("127.0.0.1:8080").unwrap();
+#[derive(Clone)]
+pub struct Server {
+    // Shared with clones, so a reload reaches every running acceptor
+    site: Arc<RwLock<Site>>,
+    config: ServerConfig,
+    // Number of worker threads spawned so far, used for thread names
+    workers_spawned: Arc<AtomicU64>,
+    // Worker threads still serving a connection
+    busy_workers: Arc<AtomicUsize>,
+    // Number of threads pinned to a CPU so far, to pick the next CPU in turn
+    threads_pinned: Arc<AtomicUsize>,
+    // Listeners being served, passed on by hand_over
+    listeners: Arc<Mutex<Vec<TcpListener>>>,
+    // Cleared by hand_over and drain so acceptors stop taking connections
+    accepting: Arc<AtomicBool>,
+    started: Instant,
+    // Shared with the routers served, see Server::stats
+    counters: Arc<Counters>,
+    plugins: Arc<Registry>,
+}
+
+// A handle on a server that doesn't keep its router alive, held by the
+// routes of the administration endpoints
+pub(crate) struct WeakServer {
+    site: Weak<RwLock<Site>>,
+    config: ServerConfig,
+    workers_spawned: Arc<AtomicU64>,
+    busy_workers: Arc<AtomicUsize>,
+    threads_pinned: Arc<AtomicUsize>,
+    listeners: Arc<Mutex<Vec<TcpListener>>>,
+    accepting: Arc<AtomicBool>,
+    started: Instant,
+    counters: Arc<Counters>,
+    plugins: Arc<Registry>,
+}
+
+impl WeakServer {
+    // Returns the server, unless it was dropped
+    pub(crate) fn upgrade(&self) -> Option<Server> {
+        Some(Server {
+            site: self.site.upgrade()?,
+            config: self.config.clone(),
+            workers_spawned: Arc::clone(&self.workers_spawned),
+            busy_workers: Arc::clone(&self.busy_workers),
+            threads_pinned: Arc::clone(&self.threads_pinned),
+            listeners: Arc::clone(&self.listeners),
+            accepting: Arc::clone(&self.accepting),
+            started: self.started,
+            counters: Arc::clone(&self.counters),
+            plugins: Arc::clone(&self.plugins),
+        })
+    }
+}
+
+// The router and static file directory, replaced together on reload
+#[derive(Clone)]
+pub(crate) struct Site {
+    pub router: Arc<Router>,
+    pub base_dir: Arc<str>,
+}
+
+impl Server {
+    /// Creates a server dispatching to the router, with static files under base_dir
+    pub fn new(mut router: Router, base_dir: &str) -> Server {
+        let counters = Arc::new(Counters::new(&DEFAULT_LATENCY_BUCKETS));
+        router.count_into(&counters);
+        Server {
+            site: Arc::new(RwLock::new(Site {
+                router: Arc::new(router),
+                base_dir: Arc::from(base_dir),
+            })),
+            config: ServerConfig::default(),
+            workers_spawned: Arc::new(AtomicU64::new(0)),
+            busy_workers: Arc::new(AtomicUsize::new(0)),
+            threads_pinned: Arc::new(AtomicUsize::new(0)),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            accepting: Arc::new(AtomicBool::new(true)),
+            started: Instant::now(),
+            counters,
+            plugins: Arc::new(Registry::default()),
+        }
+    }
+
+    /// Replaces the server settings
+    pub fn config(mut self, config: ServerConfig) -> Server {
+        let mounted = self.config.admin.is_some();
+        self.config = config;
+        // Nothing was counted yet, so the counters start over with the buckets
+        #[cfg(feature = "metrics")]
+        if let Some(bounds) = &self.config.latency_buckets {
+            let counters = Arc::new(Counters::new(bounds));
+            self.update_router(|router| router.count_into(&counters));
+            self.counters = counters;
+        }
+        // The status page lists the last requests
+        let recent = match &self.config.admin {
+            Some(admin) if admin.serves_status_page() => admin::RECENT_REQUESTS,
+            _ => 0,
+        };
+        self.counters.keep_recent(recent);
+        let access_log = self.config.access_log.clone();
+        self.update_router(|router| router.log_into(access_log));
+        // Mount the administration endpoints on the router passed to new
+        if let Some(admin) = &self.config.admin
+            && !mounted
+        {
+            let server = self.downgrade();
+            self.update_router(|router| admin::mount(router, admin, server));
+        }
+        self
+    }
+
+    /// Replaces the router and static file directory without a restart
+    /// New connections use them right away; connections already being
+    /// served finish with the previous ones. Settings of the ServerConfig,
+    /// the access log included, are kept
+    pub fn reload(&self, mut router: Router, base_dir: &str) {
+        router.count_into(&self.counters);
+        router.log_into(self.config.access_log.clone());
+        self.plugins.register(&mut router);
+        if let Some(admin) = &self.config.admin {
+            admin::mount(&mut router, admin, self.downgrade());
+        }
+        #[cfg(feature = "static")]
+        forget_cached_files(&router);
+        *self.site.write().unwrap() = Site {
+            router: Arc::new(router),
+            base_dir: Arc::from(base_dir),
+        };
+    }
+
+    /// Adds a plugin: starts it with Plugin::init, then lets it register on
+    /// the router, and on the router of every later reload
+    /// Returns the error init failed with, leaving the plugin out
+    /// Example: let server = Server::new(router, "static").plugin(Metrics::new())?;
+    pub fn plugin(self, plugin: impl Plugin) -> io::Result<Server> {
+        if let Err(e) = plugin.init(&self) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("plugin {} failed to start: {}", plugin.name(), e),
+            ));
+        }
+        self.update_router(|router| plugin.register(router));
+        self.plugins.add(Arc::new(plugin));
+        Ok(self)
+    }
+
+    /// Stops the plugins, last added first, once serving is over
+    /// run does so when it returns; callers of serve or start call it after
+    /// the acceptors stopped. Later calls do nothing
+    pub fn shutdown_plugins(&self) {
+        self.plugins.shutdown();
+    }
+
+    /// Changes the routes of the running server, e.g. from an admin route
+    /// or a plugin. update gets a copy of the current router, which replaces
+    /// it once update returns, so requests see all of the changes or none.
+    /// Connections already being served finish with the previous router.
+    /// Concurrent updates are applied one after the other, so update must
+    /// not call reload or update_router itself
+    /// Example:
+    /// server.update_router(|router| {
+    ///     router.remove_route(Method::Get, "/beta");
+    ///     router.get("/v2", v2);
+    /// });
+    pub fn update_router(&self, update: impl FnOnce(&mut Router)) {
+        let mut site = self.site.write().unwrap();
+        let mut router = Router::clone(&site.router);
+        update(&mut router);
+        #[cfg(feature = "static")]
+        forget_cached_files(&router);
+        site.router = Arc::new(router);
+    }
+
+    /// Stops accepting connections; serve (or run) returns once the
+    /// connections being served are finished
+    /// Kept-alive connections close after their current response, sent
+    /// with Connection: close, and idle ones right away. With
+    /// ServerConfig::drain_timeout set, the connections still open once it
+    /// passed are aborted (see drain_within). Stats::drain follows progress
+    pub fn drain(&self) {
+        match self.config.drain_timeout {
+            Some(timeout) => self.drain_within(timeout),
+            None => {
+                self.accepting.store(false, Ordering::SeqCst);
+                self.counters.start_drain(None);
+            }
+        }
+    }
+
+    /// Drains like drain, then aborts the connections still open after
+    /// timeout, by shutting their sockets down
+    /// Example:
+    /// server.drain_within(Duration::from_secs(30));
+    /// acceptor.join().unwrap();
+    pub fn drain_within(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.accepting.store(false, Ordering::SeqCst);
+        self.counters.start_drain(Some(deadline));
+        let counters = Arc::clone(&self.counters);
+        let spawned = thread::Builder::new()
+            .name("drain-deadline".to_string())
+            .spawn(move || {
+                while counters.active_connections() > 0 {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        let aborted = counters.abort_connections();
+                        eprintln!("Drain deadline passed, aborted {} connections", aborted);
+                        return;
+                    }
+                    thread::sleep((deadline - now).min(Duration::from_millis(50)));
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to spawn drain deadline thread: {}", e);
+        }
+    }
+
+    /// Returns the time since the server was created
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the live counters: connections, requests, bytes and
+    /// responses by status since the server was created
+    /// Example: println!("{} requests", server.stats().total_requests);
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot(self.uptime())
+    }
+
+    /// Returns a handler serving stats() as JSON, to register on a router
+    /// passed to reload
+    /// Example:
+    /// router.get("/stats", server.stats_handler());
+    /// server.reload(router, "static");
+    #[cfg(feature = "metrics")]
+    pub fn stats_handler(&self) -> impl Fn() -> Response + Send + Sync + 'static {
+        let counters = Arc::clone(&self.counters);
+        let started = self.started;
+        move || {
+            let stats = counters.snapshot(started.elapsed());
+            Response::ok("application/json", stats.to_json())
+        }
+    }
+
+    /// Returns a handler serving stats() in the Prometheus text format, to
+    /// register on a router passed to reload
+    /// Example:
+    /// router.get("/metrics", server.metrics_handler());
+    /// server.reload(router, "static");
+    #[cfg(feature = "metrics")]
+    pub fn metrics_handler(&self) -> impl Fn() -> Response + Send + Sync + 'static {
+        let counters = Arc::clone(&self.counters);
+        let started = self.started;
+        move || {
+            let stats = counters.snapshot(started.elapsed());
+            Response::ok(PROMETHEUS_TYPE, stats.to_prometheus())
+        }
+    }
+
+    // Counts a connection as active in the statistics until dropped,
+    // keeping a duplicate of its socket to abort it at a drain deadline
+    pub(crate) fn open_connection(&self, socket: Option<TcpStream>) -> OpenConnection {
+        OpenConnection::new(&self.counters, socket)
+    }
+
+    /// Returns the addresses of the listeners being served
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let listeners = self.listeners.lock().unwrap();
+        listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    // Returns the server settings
+    pub(crate) fn settings(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // Returns a handle that doesn't keep the server alive
+    fn downgrade(&self) -> WeakServer {
+        WeakServer {
+            site: Arc::downgrade(&self.site),
+            config: self.config.clone(),
+            workers_spawned: Arc::clone(&self.workers_spawned),
+            busy_workers: Arc::clone(&self.busy_workers),
+            threads_pinned: Arc::clone(&self.threads_pinned),
+            listeners: Arc::clone(&self.listeners),
+            accepting: Arc::clone(&self.accepting),
+            started: self.started,
+            counters: Arc::clone(&self.counters),
+            plugins: Arc::clone(&self.plugins),
+        }
+    }
+
+    /// Returns the space used under each directory with a quota, in the
+    /// order the quotas were set (see Publish::quota)
+    #[cfg(feature = "static")]
+    pub fn storage_usage(&self) -> Vec<QuotaUsage> {
+        let site = self.site();
+        site.router
+            .publisher()
+            .map(|publish| publish.usage(&site.base_dir))
+            .unwrap_or_default()
+    }
+
+    /// Calls reload on a new thread named "reloader" every time the process
+    /// receives SIGHUP, typically to re-read the application's configuration
+    /// and pass the result to Server::reload, which is all a reload changes.
+    /// Errors are printed and the current configuration is kept
+    /// Example:
+    /// server.reload_on_sighup(|server| {
+    ///     let config = fs::read_to_string("site.conf")?;
+    ///     server.reload(build_router(&config), static_dir(&config));
+    ///     Ok(())
+    /// })?;
+    #[cfg(unix)]
+    pub fn reload_on_sighup(
+        &self,
+        reload: impl Fn(&Server) -> io::Result<()> + Send + 'static,
+    ) -> io::Result<JoinHandle<()>> {
+        let mut hangups = signal::SignalPipe::hangup()?;
+        let server = self.clone();
+        thread::Builder::new()
+            .name("reloader".to_string())
+            .spawn(move || {
+                while hangups.wait().is_ok() {
+                    match reload(&server) {
+                        Ok(()) => eprintln!("Configuration reloaded"),
+                        Err(e) => eprintln!("Failed to reload configuration: {}", e),
+                    }
+                }
+            })
+    }
+
+    /// Drains the server (see drain) when the process receives SIGTERM or
+    /// SIGINT (Ctrl-C), as sent by service managers and container runtimes
+    /// to stop it; serve (or run) returns once the connections are finished
+    /// A second signal aborts the connections still open right away
+    /// Example:
+    /// server.drain_on_sigterm()?;
+    /// server.run("0.0.0.0:8080")?;
+    #[cfg(unix)]
+    pub fn drain_on_sigterm(&self) -> io::Result<JoinHandle<()>> {
+        let mut signals = signal::SignalPipe::terminate()?;
+        let server = self.clone();
+        thread::Builder::new()
+            .name("terminator".to_string())
+            .spawn(move || {
+                if signals.wait().is_err() {
+                    return;
+                }
+                eprintln!("Shutting down, draining connections");
+                server.drain();
+                if signals.wait().is_ok() {
+                    eprintln!("Shutting down now");
+                    server.drain_within(Duration::ZERO);
+                }
+            })
+    }
+
+    /// Starts command as the successor of this server, then stops accepting
+    /// connections once it is running
+    /// The successor inherits every listener being served as fds 3, 4, ...
+    /// announced in LISTEN_FDS (as with systemd socket activation), and
+    /// Server::bind picks them up for the same addresses, so no connection is
+    /// refused in between. Connections already accepted here are served to
+    /// the end, and serve (or run) returns once they are all finished, after
+    /// which the process can exit
+    /// Example:
+    /// let mut command = Command::new(env::current_exe()?);
+    /// command.args(env::args_os().skip(1));
+    /// server.hand_over(&mut command)?;
+    #[cfg(unix)]
+    pub fn hand_over(&self, command: &mut Command) -> io::Result<Child> {
+        let listeners = self.listeners.lock().unwrap();
+        if listeners.is_empty() {
+            return Err(io::Error::other("no listener to hand over"));
+        }
+        socket::pass_listeners(command, &listeners);
+        let child = command.spawn()?;
+        self.accepting.store(false, Ordering::SeqCst);
+        // Kept-alive clients reconnect to the successor
+        self.counters.start_drain(None);
+        Ok(child)
+    }
+
+    // Returns false once the listeners were handed over to another process,
+    // or the server drains
+    pub(crate) fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    // Applies the configured options to an accepted connection
+    // Options the platform refuses are skipped, with a warning the first time
+    pub(crate) fn configure_connection(&self, stream: &impl socket::Socket) {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        let mut result = Ok(());
+        if self.config.tcp_nodelay {
+            result = result.and(socket::set_nodelay(stream));
+        }
+        if let Some(timeout) = self.config.linger {
+            result = result.and(socket::set_linger(stream, timeout));
+        }
+        if let Some(idle) = self.config.tcp_keepalive {
+            result = result.and(socket::set_tcp_keepalive(stream, idle));
+        }
+        if let Err(e) = result
+            && !WARNED.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("Connection options not applied: {}", e);
+        }
+    }
+
+    // Returns true if connections start with a PROXY protocol header
+    #[cfg(any(feature = "event-loop", all(feature = "io-uring", target_os = "linux")))]
+    pub(crate) fn expects_proxy_header(&self) -> bool {
+        self.config.proxy_protocol
+    }
+
+    // Returns the router and static file directory to serve the next connection with
+    pub(crate) fn site(&self) -> Site {
+        self.site.read().unwrap().clone()
+    }
+
+    /// Binds a listener and applies the configured listener options
+    /// A listener for the address inherited from a previous server (see
+    /// hand_over) or from systemd socket activation is used as it is.
+    /// Options the platform does not support are skipped with a warning
+    pub fn bind(&self, addr: &str) -> io::Result<TcpListener> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        #[cfg(unix)]
+        if let Some(listener) = socket::take_inherited_listener(&addrs) {
+            return Ok(listener);
+        }
+        let listener = if self.config.reuse_port {
+            self.bind_reuse_port(&addrs)?
+        } else {
+            TcpListener::bind(&addrs[..])?
+        };
+
+        if let Some(queue_len) = self.config.tcp_fast_open
+            && let Err(e) = socket::set_tcp_fast_open(&listener, queue_len)
+        {
+            eprintln!("TCP Fast Open not enabled: {}", e);
+        }
+        if let Some(timeout) = self.config.defer_accept
+            && let Err(e) = socket::set_defer_accept(&listener, timeout)
+        {
+            eprintln!("TCP defer accept not enabled: {}", e);
+        }
+        if let Some(bytes) = self.config.send_buffer_size
+            && let Err(e) = socket::set_send_buffer_size(&listener, bytes)
+        {
+            eprintln!("Send buffer size not set: {}", e);
+        }
+        if let Some(bytes) = self.config.recv_buffer_size
+            && let Err(e) = socket::set_recv_buffer_size(&listener, bytes)
+        {
+            eprintln!("Receive buffer size not set: {}", e);
+        }
+
+        Ok(listener)
+    }
+
+    // Binds the first address that works with SO_REUSEPORT, falling back to
+    // a plain bind where the platform lacks it
+    fn bind_reuse_port(&self, addrs: &[SocketAddr]) -> io::Result<TcpListener> {
+        let mut last_error = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        );
+        for addr in addrs {
+            match socket::bind_reuse_port(*addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    eprintln!("SO_REUSEPORT not enabled: {}", e);
+                    return TcpListener::bind(addrs);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Accepts connections on the listener until it fails or is handed over
+    /// With event_loop_threads set, connections are multiplexed on that many
+    /// "event-loop-N" threads instead, and with io_uring_entries set they are
+    /// served from an io_uring on the calling thread (falling back to threads
+    /// where io_uring is unavailable). TLS connections always use threads
+    pub fn serve(&self, listener: TcpListener) {
+        match listener.try_clone() {
+            Ok(clone) => self.listeners.lock().unwrap().push(clone),
+            Err(e) => eprintln!("Failed to clone listener, it cannot be handed over: {}", e),
+        }
+
+        #[cfg(feature = "tls")]
+        if self.config.tls.is_some() {
+            #[cfg(feature = "io-uring")]
+            if self.config.io_uring_entries.is_some() {
+                eprintln!("io_uring does not serve TLS, using threads");
+            }
+            #[cfg(feature = "event-loop")]
+            if self.config.event_loop_threads.is_some() {
+                eprintln!("Event loops do not serve TLS, using threads");
+            }
+            return self.serve_threads(listener);
+        }
+
+        #[cfg(feature = "io-uring")]
+        if let Some(entries) = self.config.io_uring_entries {
+            #[cfg(target_os = "linux")]
+            match uring::Backend::new(entries) {
+                Ok(backend) => {
+                    if let Err(e) = backend.run(&listener, self) {
+                        eprintln!("io_uring backend stopped: {}", e);
+                    }
+                    return;
+                }
+                Err(e) => eprintln!("io_uring unavailable, using threads: {}", e),
+            }
+            #[cfg(not(target_os = "linux"))]
+            eprintln!(
+                "io_uring is only available on Linux, using threads ({entries} entries ignored)"
+            );
+        }
+
+        #[cfg(feature = "event-loop")]
+        if let Some(threads) = self.config.event_loop_threads {
+            return self.serve_event_loops(listener, threads);
+        }
+
+        self.serve_threads(listener);
+    }
+
+    // Accepts connections on the configured number of acceptor threads and
+    // serves each on a worker thread, spawned for it or taken from the pool
+    fn serve_threads(&self, listener: TcpListener) {
+        #[cfg(unix)]
+        if let Err(e) = listener.set_nonblocking(true) {
+            eprintln!("Failed to make listener non-blocking: {}", e);
+            return;
+        }
+        let (pool, workers) = match self.config.worker_threads {
+            Some(threads) => {
+                let (pool, workers) = self.start_worker_pool(threads);
+                (Some(Arc::new(pool)), workers)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let mut acceptors = Vec::new();
+        for id in 1..self.config.acceptor_threads.unwrap_or(1).max(1) {
+            let listener = match listener.try_clone() {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to clone listener: {}", e);
+                    break;
+                }
+            };
+            let server = self.clone();
+            let pool = pool.clone();
+            let cpu = self.next_cpu();
+            let spawned = thread::Builder::new()
+                .name(format!("acceptor-{}", id))
+                .spawn(move || {
+                    pin_current_thread(cpu);
+                    server.accept_connections(&listener, pool.as_deref());
+                });
+            match spawned {
+                Ok(handle) => acceptors.push(handle),
+                Err(e) => eprintln!("Failed to spawn acceptor thread: {}", e),
+            }
+        }
+        self.accept_connections(&listener, pool.as_deref());
+        for handle in acceptors {
+            let _ = handle.join();
+        }
+
+        // Handed over: wait for the connections being served. Pooled workers
+        // stop once their queue is closed and empty
+        drop(pool);
+        for handle in workers {
+            let _ = handle.join();
+        }
+        while self.busy_workers.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // Accepts connections until the listener is handed over, passing each to
+    // the pool or to a new worker thread
+    fn accept_connections(&self, listener: &TcpListener, pool: Option<&WorkerPool>) {
+        while self.is_accepting() {
+            let stream = match accept(listener) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Wake up periodically on idle connections so dead peers get detected
+            if let Err(e) = stream.set_read_timeout(Some(IDLE_PROBE_INTERVAL)) {
+                eprintln!("Failed to set read timeout: {}", e);
+                continue;
+            }
+            self.configure_connection(&stream);
+
+            let site = self.site();
+            if let Some(pool) = pool {
+                pool.dispatch(stream, site);
+                continue;
+            }
+            let server = self.clone();
+            let cpu = self.next_cpu();
+            let busy = BusyWorker::new(&self.busy_workers);
+            let spawned = self.worker_builder().spawn(move || {
+                let _busy = busy;
+                pin_current_thread(cpu);
+                server.serve_accepted(stream, site);
+            });
+            if let Err(e) = spawned {
+                eprintln!("Failed to spawn worker thread: {}", e);
+            }
+        }
+    }
+
+    // Spawns the pooled worker threads, each taking connections from its own
+    // queue or from the one they share
+    fn start_worker_pool(&self, threads: usize) -> (WorkerPool, Vec<JoinHandle<()>>) {
+        let threads = threads.max(1);
+        let queue_count = if self.config.per_worker_queues {
+            threads
+        } else {
+            1
+        };
+        let mut queues = Vec::new();
+        let mut receivers = Vec::new();
+        for _ in 0..queue_count {
+            let (sender, receiver) = mpsc::channel();
+            queues.push(sender);
+            receivers.push(Arc::new(Mutex::new(receiver)));
+        }
+
+        let mut workers = Vec::new();
+        for id in 0..threads {
+            let receiver = Arc::clone(&receivers[id % queue_count]);
+            let server = self.clone();
+            let cpu = self.next_cpu();
+            let spawned = self.worker_builder().spawn(move || {
+                pin_current_thread(cpu);
+                loop {
+                    // The lock is released before serving
+                    let next = receiver.lock().unwrap().recv();
+                    let Ok((stream, site)) = next else {
+                        return;
+                    };
+                    // A panicking handler only loses its connection, the
+                    // worker stays in the pool; the hook already printed it
+                    let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                        server.serve_accepted(stream, site)
+                    }));
+                    if served.is_err() {
+                        eprintln!("Worker recovered from a panic, connection dropped");
+                    }
+                }
+            });
+            match spawned {
+                Ok(handle) => workers.push(handle),
+                Err(e) => eprintln!("Failed to spawn worker thread: {}", e),
+            }
+        }
+        let pool = WorkerPool {
+            queues,
+            next: AtomicUsize::new(0),
+        };
+        (pool, workers)
+    }
+
+    // Serves an accepted connection on the current thread
+    fn serve_accepted(&self, mut stream: TcpStream, site: Site) {
+        let _open = self.open_connection(stream.try_clone().ok());
+        let mut peer = Peer {
+            addr: stream.peer_addr().ok(),
+            ..Peer::default()
+        };
+        if self.config.proxy_protocol {
+            match proxy_protocol::read_header(&mut stream) {
+                Ok(source) => peer.addr = source.or(peer.addr),
+                Err(_) => return,
+            }
+        }
+        let keep_alive = self.keep_alive();
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.config.tls {
+            return tls::serve(stream, tls, &site.base_dir, &site.router, peer, keep_alive);
+        }
+        serve_tcp_stream(stream, &site.base_dir, &site.router, peer, keep_alive);
+    }
+
+    // Returns the limits of persistent connections, if they are enabled
+    fn keep_alive(&self) -> Option<KeepAlive> {
+        let timeout = self.config.keep_alive_timeout;
+        let max_requests = self.config.max_requests_per_connection;
+        if timeout.is_none() && max_requests.is_none() {
+            return None;
+        }
+        Some(KeepAlive {
+            timeout: timeout.unwrap_or(IDLE_PROBE_INTERVAL * MAX_IDLE_PROBES),
+            max_requests,
+        })
+    }
+
+    // Returns the CPU to pin the next spawned thread to, if any
+    fn next_cpu(&self) -> Option<usize> {
+        let cpus = self
+            .config
+            .cpu_affinity
+            .as_ref()
+            .filter(|cpus| !cpus.is_empty())?;
+        let index = self.threads_pinned.fetch_add(1, Ordering::Relaxed);
+        Some(cpus[index % cpus.len()])
+    }
+
+    // Runs event loops sharing the listener and waits for them to stop
+    #[cfg(feature = "event-loop")]
+    fn serve_event_loops(&self, listener: TcpListener, threads: usize) {
+        let mut loops = Vec::new();
+        for id in 0..threads.max(1) {
+            let listener = match listener.try_clone() {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to clone listener: {}", e);
+                    break;
+                }
+            };
+            let server = self.clone();
+            let cpu = self.next_cpu();
+            let spawned = thread::Builder::new()
+                .name(format!("event-loop-{}", id))
+                .spawn(move || {
+                    pin_current_thread(cpu);
+                    if let Err(e) = event_loop::run(listener, &server) {
+                        eprintln!("Event loop stopped: {}", e);
+                    }
+                });
+            match spawned {
+                Ok(handle) => loops.push(handle),
+                Err(e) => eprintln!("Failed to spawn event loop thread: {}", e),
+            }
+        }
+        for handle in loops {
+            let _ = handle.join();
+        }
+    }
+
+    /// Serves connections from the listener on a new thread named "acceptor"
+    pub fn start(&self, listener: TcpListener) -> io::Result<JoinHandle<()>> {
+        let server = self.clone();
+        thread::Builder::new()
+            .name("acceptor".to_string())
+            .spawn(move || server.serve(listener))
+    }
+
+    /// Binds to addr and serves connections until the acceptor stops
+    pub fn run(&self, addr: &str) -> io::Result<()> {
+        let listener = self.bind(addr)?;
+        let acceptor = self.start(listener)?;
+        let result = acceptor
+            .join()
+            .map_err(|_| io::Error::other("acceptor thread panicked"));
+        self.shutdown_plugins();
+        result
+    }
+
+    // Builds the next worker thread with its name and configured stack size
+    fn worker_builder(&self) -> thread::Builder {
+        let id = self.workers_spawned.fetch_add(1, Ordering::Relaxed);
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        match self.config.worker_stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+}
+
+// Queues feeding pooled worker threads, filled in turn
+struct WorkerPool {
+    queues: Vec<mpsc::Sender<(TcpStream, Site)>>,
+    next: AtomicUsize,
+}
+
+impl WorkerPool {
+    fn dispatch(&self, stream: TcpStream, site: Site) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.queues.len();
+        if self.queues[index].send((stream, site)).is_err() {
+            eprintln!("Worker pool stopped, connection dropped");
+        }
+    }
+}
+
+// Pins the current thread to the CPU, warning once if the platform refuses
+fn pin_current_thread(cpu: Option<usize>) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let Some(cpu) = cpu else {
+        return;
+    };
+    if let Err(e) = set_cpu_affinity(cpu)
+        && !WARNED.swap(true, Ordering::Relaxed)
+    {
+        eprintln!("Threads not pinned to CPUs: {}", e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("CPU {} out of range", cpu),
+        ));
+    }
+    // SAFETY: an all-zero cpu_set_t is an empty set, and cpu is within its size
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    // SAFETY: set is valid for the duration of the call; pid 0 is this thread
+    let result =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu_affinity(_cpu: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Counts a worker thread as busy for as long as it lives
+struct BusyWorker(Arc<AtomicUsize>);
+
+impl BusyWorker {
+    fn new(count: &Arc<AtomicUsize>) -> BusyWorker {
+        count.fetch_add(1, Ordering::SeqCst);
+        BusyWorker(Arc::clone(count))
+    }
+}
+
+impl Drop for BusyWorker {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Accepts the next connection on a non-blocking listener, waiting at most
+// ACCEPT_CHECK_INTERVAL for one. Returns None when none came, or when another
+// process sharing the listener took it first
+#[cfg(unix)]
+fn accept(listener: &TcpListener) -> io::Result<Option<TcpStream>> {
+    if !socket::wait_readable(listener, ACCEPT_CHECK_INTERVAL)? {
+        return Ok(None);
+    }
+    match listener.accept() {
+        Ok((stream, _)) => {
+            // Sockets accepted on BSDs inherit the listener's non-blocking mode
+            stream.set_nonblocking(false)?;
+            Ok(Some(stream))
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn accept(listener: &TcpListener) -> io::Result<Option<TcpStream>> {
+    listener.accept().map(|(stream, _)| Some(stream))
+}
+
+// Empties the router's static file cache, whose files may now be shadowed by
+// routes: the fast path answers from it without looking at the routes, and
+// only files no route matches are cached again
+#[cfg(feature = "static")]
+fn forget_cached_files(router: &Router) {
+    if let Some(cache) = router.file_cache() {
+        cache.clear();
+    }
+}
@@ -0,0 +1,164 @@
+// Windows services
+// The service control manager starts a service by running its executable,
+// which hands the calling thread over to the dispatcher; the dispatcher then
+// calls a plain function, ffi_service_main, on a thread of its own. That
+// function can't capture anything, so run_as_service leaves the server and
+// its listener in SERVICE for it to pick up. Stop and shutdown requests
+// drain the server, and the service reports stopped once serve returns.
+
+use crate::Server;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+// How long the service control manager is told stopping may take, without
+// ServerConfig::drain_timeout
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+// Name, server and listener of the service run_as_service started
+static SERVICE: Mutex<Option<(String, Server, TcpListener)>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+impl Server {
+    /// Runs the server as the Windows service name, serving the listener
+    /// until the service is stopped; returns once it stopped
+    /// Stop requests, and the system shutting down, drain the server (see
+    /// drain). Fails if the process was not started by the service control
+    /// manager, e.g. from a console. Services have no console, so messages
+    /// printed with eprintln! are lost: log to a file instead
+    /// Example:
+    /// let listener = server.bind("0.0.0.0:80")?;
+    /// server.run_as_service("shop", listener)?;
+    pub fn run_as_service(&self, name: &str, listener: TcpListener) -> io::Result<()> {
+        *SERVICE.lock().unwrap() = Some((name.to_string(), self.clone(), listener));
+        service_dispatcher::start(name, ffi_service_main).map_err(io_error)
+    }
+}
+
+// Runs on the dispatcher's thread once the service started
+fn service_main(_arguments: Vec<OsString>) {
+    let Some((name, server, listener)) = SERVICE.lock().unwrap().take() else {
+        return;
+    };
+    if let Err(e) = run(&name, server, listener) {
+        eprintln!("Service {} failed: {}", name, e);
+    }
+}
+
+fn run(name: &str, server: Server, listener: TcpListener) -> windows_service::Result<()> {
+    let wait_hint = server.settings().drain_timeout.unwrap_or(STOP_WAIT_HINT);
+    // Set once registered, which is before any request can arrive
+    let registered = Arc::new(OnceLock::new());
+    let handler = {
+        let server = server.clone();
+        let registered = Arc::clone(&registered);
+        move |control: ServiceControl| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                // Reported first, so that it can't come after Stopped
+                if let Some(&status) = registered.get() {
+                    let _ = report(status, ServiceState::StopPending, wait_hint);
+                }
+                server.drain();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let status = service_control_handler::register(name, handler)?;
+    let _ = registered.set(status);
+    report(status, ServiceState::Running, Duration::ZERO)?;
+    server.serve(listener);
+    report(status, ServiceState::Stopped, Duration::ZERO)
+}
+
+// Tells the service control manager the state of the service, which only
+// takes stop requests while running
+fn report(
+    status: ServiceStatusHandle,
+    state: ServiceState,
+    wait_hint: Duration,
+) -> windows_service::Result<()> {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    })
+}
+
+/// Registers the running executable as the Windows service name, started
+/// with the system and given arguments, e.g. the one telling main to call
+/// Server::run_as_service; requires administrator rights
+/// The service runs as LocalSystem from C:\Windows\System32, so relative
+/// paths such as the static directory should be made absolute
+/// Example: install_service("shop", "Shop web server", &["--service"])?;
+pub fn install_service(name: &str, display_name: &str, arguments: &[&str]) -> io::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(io_error)?;
+    let info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from(display_name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: arguments.iter().map(OsString::from).collect(),
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    manager
+        .create_service(&info, ServiceAccess::QUERY_STATUS)
+        .map_err(io_error)?;
+    Ok(())
+}
+
+/// Stops the Windows service name if it runs and removes it; requires
+/// administrator rights
+/// Example: uninstall_service("shop")?;
+pub fn uninstall_service(name: &str) -> io::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(io_error)?;
+    let service = manager
+        .open_service(
+            OsStr::new(name),
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(io_error)?;
+    // Deleting only marks it; it goes once stopped and its handles closed
+    service.delete().map_err(io_error)?;
+    if service.query_status().map_err(io_error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(io_error)?;
+    }
+    Ok(())
+}
+
+fn io_error(error: windows_service::Error) -> io::Error {
+    match error {
+        windows_service::Error::Winapi(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidInput, e),
+    }
+}
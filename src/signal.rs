@@ -0,0 +1,150 @@
+// Delivery of POSIX signals to ordinary threads
+// A signal handler may only do async-signal-safe work, so it just writes a
+// byte to a pipe; a thread blocked reading the other end does the real work.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Write ends of the SIGHUP, SIGUSR1 and SIGTERM/SIGINT pipes, -1 until a
+// handler is installed
+static HANGUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+static USER1_PIPE: AtomicI32 = AtomicI32::new(-1);
+static TERMINATE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// Returns the write end of the pipe the signal goes to
+fn pipe_of(signal: libc::c_int) -> &'static AtomicI32 {
+    match signal {
+        libc::SIGUSR1 => &USER1_PIPE,
+        libc::SIGTERM | libc::SIGINT => &TERMINATE_PIPE,
+        _ => &HANGUP_PIPE,
+    }
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = pipe_of(signal).load(Ordering::Relaxed);
+    if fd >= 0 {
+        // The interrupted code may be about to read errno, which write sets
+        // SAFETY: errno_location points to this thread's errno; write(2) is
+        // async-signal-safe and the byte outlives the call
+        unsafe {
+            let saved = *errno_location();
+            // The write end is non-blocking, so a full pipe fails with EAGAIN
+            // instead of hanging the interrupted thread; its unread bytes
+            // wake the waiting thread anyway
+            libc::write(fd, b"h".as_ptr() as *const libc::c_void, 1);
+            *errno_location() = saved;
+        }
+    }
+}
+
+// Returns the address of the calling thread's errno
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "emscripten"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    // SAFETY: always callable, it returns the current thread's errno
+    unsafe { libc::__errno_location() }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    // SAFETY: always callable, it returns the current thread's errno
+    unsafe { libc::__error() }
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    // SAFETY: always callable, it returns the current thread's errno
+    unsafe { libc::__errno() }
+}
+
+/// Receives a signal as a readable pipe
+pub struct SignalPipe {
+    read: File,
+}
+
+impl SignalPipe {
+    /// Installs the SIGHUP handler, which can only be done once per process
+    pub fn hangup() -> io::Result<SignalPipe> {
+        SignalPipe::install(&[libc::SIGHUP], "SIGHUP")
+    }
+
+    /// Installs the SIGUSR1 handler, which can only be done once per process
+    pub fn user1() -> io::Result<SignalPipe> {
+        SignalPipe::install(&[libc::SIGUSR1], "SIGUSR1")
+    }
+
+    /// Installs the handler of both SIGTERM and SIGINT (Ctrl-C), which can
+    /// only be done once per process
+    pub fn terminate() -> io::Result<SignalPipe> {
+        SignalPipe::install(&[libc::SIGTERM, libc::SIGINT], "SIGTERM")
+    }
+
+    // Routes the signals to a new pipe, shared by signals of the same pipe_of;
+    // the handler is installed once
+    fn install(signals: &[libc::c_int], name: &str) -> io::Result<SignalPipe> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors pipe writes
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            // SAFETY: fd is one of the descriptors just created
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // The handler must never block writing (see on_signal)
+        // SAFETY: fds[1] is the write end just created
+        unsafe {
+            let flags = libc::fcntl(fds[1], libc::F_GETFL);
+            libc::fcntl(fds[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        // SAFETY: both descriptors are open and owned by nobody else
+        let read = unsafe { File::from_raw_fd(fds[0]) };
+        // The write end stays open for the lifetime of the process
+        if pipe_of(signals[0])
+            .compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // SAFETY: the write end was not published, so nothing else uses it
+            unsafe { libc::close(fds[1]) };
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("a {} handler is already installed", name),
+            ));
+        }
+
+        for &signal in signals {
+            // SAFETY: sigaction is zeroable and the handler only does async-signal-safe work
+            let result = unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut())
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(SignalPipe { read })
+    }
+
+    /// Blocks until the signal arrives
+    /// Signals arriving in quick succession may be reported once
+    pub fn wait(&mut self) -> io::Result<()> {
+        let mut byte = [0; 1];
+        loop {
+            match self.read.read(&mut byte) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
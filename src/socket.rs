@@ -0,0 +1,413 @@
+// Low-level socket options that std does not expose
+// Options are applied on a best-effort basis: on platforms without support
+// the functions return an Unsupported error so callers can fall back
+
+#[cfg(feature = "static")]
+use std::fs::File;
+use std::io;
+#[cfg(feature = "static")]
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::process::Command;
+#[cfg(unix)]
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A socket the options below can be set on
+#[cfg(unix)]
+pub trait Socket: AsRawFd {}
+#[cfg(unix)]
+impl<T: AsRawFd> Socket for T {}
+#[cfg(not(unix))]
+pub trait Socket {}
+#[cfg(not(unix))]
+impl<T> Socket for T {}
+
+// First file descriptor of listeners passed to a new process, as with
+// systemd socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// Listeners passed by the parent process and not claimed by a bind yet
+#[cfg(unix)]
+static INHERITED: Mutex<Option<Vec<TcpListener>>> = Mutex::new(None);
+
+/// Enables TCP Fast Open on a listening socket
+/// queue_len bounds the number of pending Fast Open requests
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fast_open(listener: &TcpListener, queue_len: u32) -> io::Result<()> {
+    set_int_option(
+        listener,
+        libc::IPPROTO_TCP,
+        libc::TCP_FASTOPEN,
+        queue_len as i32,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fast_open(_listener: &TcpListener, _queue_len: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Delays accept() until the client has sent data, or the timeout expires
+/// The kernel works with whole seconds, shorter timeouts round up to one second
+#[cfg(target_os = "linux")]
+pub fn set_defer_accept(listener: &TcpListener, timeout: Duration) -> io::Result<()> {
+    let seconds = timeout.as_secs().max(1).min(i32::MAX as u64) as i32;
+    set_int_option(listener, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, seconds)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_defer_accept(_listener: &TcpListener, _timeout: Duration) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Disables Nagle's algorithm, so small writes are sent without waiting for
+/// the acknowledgement of earlier ones
+#[cfg(unix)]
+pub fn set_nodelay(socket: &impl Socket) -> io::Result<()> {
+    set_int_option(socket, libc::IPPROTO_TCP, libc::TCP_NODELAY, 1)
+}
+
+#[cfg(not(unix))]
+pub fn set_nodelay(_socket: &impl Socket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sets SO_LINGER: closing the socket waits up to timeout for unsent data to
+/// be delivered. A zero timeout resets the connection instead of closing it
+/// gracefully. The kernel works with whole seconds, rounding up
+#[cfg(unix)]
+pub fn set_linger(socket: &impl Socket, timeout: Duration) -> io::Result<()> {
+    let seconds = (timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0)).min(i32::MAX as u64);
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: seconds as libc::c_int,
+    };
+    // SAFETY: the fd is a valid open socket and linger outlives the call
+    check(unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    })
+    .map(drop)
+}
+
+#[cfg(not(unix))]
+pub fn set_linger(_socket: &impl Socket, _timeout: Duration) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the size of the kernel send buffer (SO_SNDBUF), in bytes
+/// Linux doubles the value for bookkeeping and caps it at net.core.wmem_max
+#[cfg(unix)]
+pub fn set_send_buffer_size(socket: &impl Socket, bytes: usize) -> io::Result<()> {
+    let bytes = bytes.min(i32::MAX as usize) as libc::c_int;
+    set_int_option(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, bytes)
+}
+
+#[cfg(not(unix))]
+pub fn set_send_buffer_size(_socket: &impl Socket, _bytes: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the size of the kernel receive buffer (SO_RCVBUF), in bytes
+/// Set on a listener, it applies to the connections it accepts and sizes
+/// the TCP window they announce; Linux caps it at net.core.rmem_max
+#[cfg(unix)]
+pub fn set_recv_buffer_size(socket: &impl Socket, bytes: usize) -> io::Result<()> {
+    let bytes = bytes.min(i32::MAX as usize) as libc::c_int;
+    set_int_option(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, bytes)
+}
+
+#[cfg(not(unix))]
+pub fn set_recv_buffer_size(_socket: &impl Socket, _bytes: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Enables TCP keepalive, sending the first probe after the connection was
+/// idle for the given time (whole seconds, at least one); later probes
+/// follow the system's interval and count
+#[cfg(unix)]
+pub fn set_tcp_keepalive(socket: &impl Socket, idle: Duration) -> io::Result<()> {
+    set_int_option(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    let seconds = idle.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let idle_option = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let idle_option = libc::TCP_KEEPIDLE;
+    set_int_option(socket, libc::IPPROTO_TCP, idle_option, seconds)
+}
+
+#[cfg(not(unix))]
+pub fn set_tcp_keepalive(_socket: &impl Socket, _idle: Duration) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Binds a listener with SO_REUSEADDR and SO_REUSEPORT set, so other
+/// sockets with the same options (in this process or another one) can bind
+/// the same address and share its incoming connections
+#[cfg(unix)]
+pub fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: plain system call without pointers
+    let fd = check(unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) })?;
+    // SAFETY: the socket was just created and nothing else owns it; it is
+    // closed when the listener drops, including on the error paths below
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // SAFETY: plain system call on an open fd
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    set_int_option(&listener, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    set_int_option(&listener, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+    // SAFETY: an all-zero sockaddr_storage is valid and large enough for
+    // both address families
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: see above
+            let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            // SAFETY: sockaddr_in fits in sockaddr_storage
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: see above
+            let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            // SAFETY: sockaddr_in6 fits in sockaddr_storage
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    // SAFETY: storage holds a valid address of len bytes
+    check(unsafe {
+        libc::bind(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    })?;
+    // SAFETY: plain system call on an open fd
+    check(unsafe { libc::listen(fd, libc::SOMAXCONN) })?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+pub fn bind_reuse_port(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Takes the listener passed by the parent process (see pass_listeners)
+/// that is bound to one of the addresses, if any
+/// Listeners are found through the LISTEN_FDS environment variable, so
+/// sockets from systemd socket activation are picked up as well. A port 0
+/// address matches any port. Each listener is handed out once
+#[cfg(unix)]
+pub fn take_inherited_listener(addrs: &[SocketAddr]) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let listeners = inherited.get_or_insert_with(inherited_listeners);
+    let position = listeners.iter().position(|listener| {
+        listener.local_addr().is_ok_and(|local| {
+            addrs.iter().any(|addr| {
+                addr.ip() == local.ip() && (addr.port() == 0 || addr.port() == local.port())
+            })
+        })
+    })?;
+    Some(listeners.remove(position))
+}
+
+// Adopts the sockets announced in LISTEN_FDS, unless LISTEN_PID names
+// another process
+#[cfg(unix)]
+fn inherited_listeners() -> Vec<TcpListener> {
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    if !for_us {
+        return Vec::new();
+    }
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count.max(0)) {
+        // SAFETY: an all-zero stat is valid, and fstat only writes to it
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        // SAFETY: plain system call, failing on closed fds
+        let is_socket = unsafe { libc::fstat(fd, &mut stat) } == 0
+            && stat.st_mode & libc::S_IFMT == libc::S_IFSOCK;
+        if !is_socket {
+            eprintln!("Inherited fd {} is not a socket, ignored", fd);
+            continue;
+        }
+        // SAFETY: the fd was passed to this process for it to own; keep it
+        // from leaking into processes started later
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        // SAFETY: see above
+        listeners.push(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+    listeners
+}
+
+/// Makes command inherit the listeners as fds 3, 4, ... and announces them
+/// with LISTEN_FDS, the way take_inherited_listener expects
+#[cfg(unix)]
+pub fn pass_listeners(command: &mut Command, listeners: &[TcpListener]) {
+    use std::os::unix::process::CommandExt;
+
+    let fds: Vec<i32> = listeners.iter().map(AsRawFd::as_raw_fd).collect();
+    let count = fds.len() as i32;
+    // Allocated here, since the child must not allocate
+    let mut moved = vec![0; fds.len()];
+    command
+        .env("LISTEN_FDS", count.to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDNAMES");
+    // SAFETY: the closure runs between fork and exec, and only makes
+    // async-signal-safe system calls without allocating
+    unsafe {
+        command.pre_exec(move || {
+            // Move every fd out of the target range first, so none of them is
+            // overwritten before it was copied; the copies close on exec
+            for (source, fd) in moved.iter_mut().zip(&fds) {
+                *source = check(libc::fcntl(
+                    *fd,
+                    libc::F_DUPFD_CLOEXEC,
+                    LISTEN_FDS_START + count,
+                ))?;
+            }
+            for (target, source) in (LISTEN_FDS_START..).zip(&moved) {
+                // dup2 clears close-on-exec on the target
+                check(libc::dup2(*source, target))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Waits up to timeout for a connection to accept
+/// Returns false on timeout or when interrupted by a signal
+#[cfg(unix)]
+pub fn wait_readable(listener: &TcpListener, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+    // SAFETY: pollfd is valid for the duration of the call
+    match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+        -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => Ok(false),
+        -1 => Err(io::Error::last_os_error()),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Sends len bytes of a file to a socket with sendfile(2)
+/// The data goes from the page cache to the socket without a userspace copy.
+/// If the kernel refuses (e.g. a file system without sendfile support) before
+/// anything was sent, the file is copied through writer instead
+#[cfg(all(feature = "static", target_os = "linux"))]
+pub fn send_file(
+    socket: i32,
+    writer: &mut impl Write,
+    file: &mut File,
+    len: u64,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+    while remaining > 0 {
+        // Linux transfers at most 0x7ffff000 bytes per call
+        let count = remaining.min(0x7fff_f000) as usize;
+        // SAFETY: both fds are open for the duration of the call and offset is a valid pointer
+        let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut offset, count) };
+        if sent > 0 {
+            remaining -= sent as u64;
+            continue;
+        }
+        if sent == 0 {
+            // The file shrank after its length was sent
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EINVAL | libc::ENOSYS) if offset == 0 => {
+                io::copy(&mut file.take(len), writer)?;
+                return Ok(());
+            }
+            _ => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "static", not(target_os = "linux")))]
+pub fn send_file(
+    _socket: i32,
+    writer: &mut impl Write,
+    file: &mut File,
+    len: u64,
+) -> io::Result<()> {
+    io::copy(&mut file.take(len), writer)?;
+    Ok(())
+}
+
+// Sets an integer socket option through setsockopt(2)
+#[cfg(unix)]
+fn set_int_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the fd is a valid open socket and value outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Turns the -1 a system call returns on failure into the error it set
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
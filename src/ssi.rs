@@ -0,0 +1,102 @@
+// Server-side includes
+// Only the include directive is supported: <!--#include file="header.html" -->
+// resolves relative to the including file, <!--#include virtual="/parts/nav.html" -->
+// relative to the base directory. Paths may not climb out with "..", nor go
+// through links the router's SymlinkPolicy refuses or hidden files it
+// doesn't serve, nor reach files an override file puts behind [auth], and
+// includes nest at most MAX_INCLUDE_DEPTH levels deep.
+
+use crate::Router;
+use crate::file_rules::links_allowed;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+// Replaces directives that could not be processed, as classic SSI does
+const ERROR_MARKER: &[u8] = b"[an error occurred while processing this directive]";
+
+// Renders a file with its includes expanded
+// Returns None if the file itself cannot be read
+pub fn render(router: &Router, base_dir: &str, file_path: &Path) -> Option<Vec<u8>> {
+    let mut page = Vec::new();
+    render_into(router, Path::new(base_dir), file_path, 0, &mut page).ok()?;
+    Some(page)
+}
+
+// Appends the expanded file to page
+fn render_into(
+    router: &Router,
+    base_dir: &Path,
+    file: &Path,
+    depth: usize,
+    page: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    let content = fs::read(file)?;
+    let mut rest = &content[..];
+    while let Some(start) = find(rest, b"<!--#") {
+        page.extend_from_slice(&rest[..start]);
+        let Some(length) = find(&rest[start..], b"-->") else {
+            break;
+        };
+        let directive = String::from_utf8_lossy(&rest[start + 5..start + length]);
+        rest = &rest[start + length + 3..];
+
+        let included = match include_target(router, base_dir, file, &directive) {
+            Some(_) if depth >= MAX_INCLUDE_DEPTH => {
+                eprintln!("Includes nested too deeply in {}", file.display());
+                false
+            }
+            Some(target) => render_into(router, base_dir, &target, depth + 1, page).is_ok(),
+            None => false,
+        };
+        if !included {
+            page.extend_from_slice(ERROR_MARKER);
+        }
+    }
+    page.extend_from_slice(rest);
+    Ok(())
+}
+
+// Resolves an include directive to the file it names
+// Returns None for other directives, malformed ones, paths leaving the root
+// and paths the router doesn't serve without credentials
+fn include_target(
+    router: &Router,
+    base_dir: &Path,
+    file: &Path,
+    directive: &str,
+) -> Option<PathBuf> {
+    let attribute = directive.trim().strip_prefix("include")?.trim_start();
+    let (name, value) = attribute.split_once('=')?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let climbs = Path::new(value)
+        .components()
+        .any(|component| component == Component::ParentDir);
+    if climbs {
+        return None;
+    }
+    let target = match name.trim() {
+        "file" if !value.starts_with('/') => file.parent()?.join(value),
+        "virtual" => base_dir.join(value.trim_start_matches('/')),
+        _ => return None,
+    };
+    let relative = target.strip_prefix(base_dir).ok()?;
+    let served =
+        !router.hides(relative) && links_allowed(router.symlink_policy(), base_dir, relative);
+    if !served {
+        return None;
+    }
+    // The including page would show a protected file to anyone allowed to
+    // read it; invalid override files refuse too, as they do requests
+    if let Some(files) = router.override_files()
+        && files.resolve(base_dir, relative).ok()?.requires_auth()
+    {
+        return None;
+    }
+    Some(target)
+}
+
+// Position of the first occurrence of needle in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,328 @@
+// Static file serving from the base directory
+
+use crate::conditional::{self, Validators};
+use crate::file_rules::links_allowed;
+use crate::live_reload::inject_script;
+use crate::mime::content_type_for;
+use crate::overrides::{Overrides, redirect};
+use crate::request::percent_decode;
+use crate::request::percent_encode;
+use crate::{Request, Response, Router, StatusCode, StreamFn, ssi};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufReader, copy};
+use std::path::{Component, Path, PathBuf};
+
+// Maps a request path to a file under base_dir
+// Returns the file path and its content type, or None if the file does not
+// exist or the path names no file (see relative_path)
+pub fn static_file(base_dir: &str, path: &str) -> Option<(PathBuf, &'static str)> {
+    let relative = relative_path(path)?;
+    let file_path = Path::new(base_dir).join(&relative);
+    if !file_path.exists() {
+        return None;
+    }
+
+    // Determine content type based on file extension
+    let content_type = content_type_for(&relative.to_string_lossy());
+    Some((file_path, content_type))
+}
+
+// Decodes a request path into the path of a file relative to the base
+// directory, index.html for the root
+// Segments are decoded one by one and pushed as file names, so the result is
+// the same with any platform's separators. Returns None when the path can't
+// name a file under the base directory: malformed escapes, bytes that are
+// not UTF-8, NUL bytes, "..", or segments that decode to more than a file
+// name, such as "%2F" or, on Windows, a backslash or drive prefix
+pub fn relative_path(path: &str) -> Option<PathBuf> {
+    if path == "/" {
+        return Some(PathBuf::from("index.html"));
+    }
+    // Request heads are decoded lossily, so this stands for raw bytes that
+    // were not UTF-8
+    if path.contains(char::REPLACEMENT_CHARACTER) {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        let segment = percent_decode(segment)?;
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        let mut components = Path::new(&segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None)
+                if name == OsStr::new(&segment) && !segment.contains('\0') =>
+            {
+                relative.push(name)
+            }
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// A transparent 1x1 icon, for Router::favicon_fallback
+// An icon directory holding one 32-bit image, then the image: its bitmap
+// header (twice as high, to cover the mask), the pixel and the mask's row
+pub const DEFAULT_FAVICON: &[u8] = b"\
+    \x00\x00\x01\x00\x01\x00\x01\x01\x00\x00\x01\x00\x20\x00\x30\x00\x00\x00\x16\x00\x00\x00\
+    \x28\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x01\x00\x20\x00\x00\x00\x00\x00\
+    \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+    \x00\x00\x00\x00\
+    \x00\x00\x00\x00";
+
+/// The robots.txt served when the static root has none (see
+/// Router::robots_fallback)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RobotsPolicy {
+    // Crawlers may fetch every path
+    AllowAll,
+    // Crawlers are asked to fetch nothing
+    DenyAll,
+    // The robots.txt given
+    Custom(String),
+}
+
+impl RobotsPolicy {
+    // Returns the robots.txt of the policy
+    fn text(&self) -> &str {
+        match self {
+            RobotsPolicy::AllowAll => "User-agent: *\nDisallow:\n",
+            RobotsPolicy::DenyAll => "User-agent: *\nDisallow: /\n",
+            RobotsPolicy::Custom(text) => text,
+        }
+    }
+}
+
+// Checks that a static file may be served for the request, answering it
+// otherwise: 400 for paths naming no file under base_dir (see
+// relative_path), hidden files as missing ones (see Router::allow_hidden),
+// 403 for refused links (see Router::symlinks), and whatever the override
+// files decide (see Router::directory_overrides). Missing favicons and
+// robots.txt get the fallbacks of the router, if any. Directories are served
+// their index file, listed (see Router::directory_listing) or not found
+// Returns the headers the override files add to the file
+pub fn admit(
+    router: &Router,
+    base_dir: &str,
+    request: &mut Request,
+) -> Result<Vec<(String, String)>, Response> {
+    let base_dir = Path::new(base_dir);
+    let Some(relative) = relative_path(&request.path) else {
+        return Err(Response::from_status(StatusCode::BAD_REQUEST));
+    };
+    if router.hides(&relative) {
+        return Err(not_found(router, request));
+    }
+    if !links_allowed(router.symlink_policy(), base_dir, &relative) {
+        return Err(Response::from_status(StatusCode::FORBIDDEN));
+    }
+    let overrides = match router.override_files() {
+        Some(files) => files.resolve(base_dir, &relative).map_err(|e| {
+            eprintln!("Not serving {}: {}", request.path, e);
+            Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+        })?,
+        None => Overrides::default(),
+    };
+    if let Some(response) = overrides.answer(request) {
+        return Err(response);
+    }
+    if let Some(response) = fallback(router, base_dir, request) {
+        return Err(response);
+    }
+
+    // Directories are served under a path ending in "/", so that the links
+    // in their page resolve
+    let (directory, relative) = match request.path.as_str() {
+        "/" => (base_dir.to_path_buf(), PathBuf::new()),
+        _ => (base_dir.join(&relative), relative),
+    };
+    if !directory.is_dir() {
+        return Ok(overrides.into_headers());
+    }
+    let index = overrides.index_file(&directory);
+    let listing = router
+        .listing()
+        .filter(|_| index.is_none() && request.method == "GET");
+    if (index.is_some() || listing.is_some()) && !request.path.ends_with('/') {
+        let location = match request.query.as_str() {
+            "" => format!("{}/", request.path),
+            query => format!("{}/?{}", request.path, query),
+        };
+        return Err(redirect(request, &location));
+    }
+    if let Some(name) = index {
+        let path = relative.join(name);
+        if router.hides(&path) || !links_allowed(router.symlink_policy(), base_dir, &path) {
+            return Err(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        request.path.push_str(&percent_encode(name));
+    } else if let Some(listing) = listing {
+        return Err(listing.render(router, base_dir, &relative, request));
+    } else {
+        return Err(not_found(router, request));
+    }
+    Ok(overrides.into_headers())
+}
+
+// Answers GETs for /favicon.ico and /robots.txt with the router's fallbacks
+// when the base directory has no such file
+fn fallback(router: &Router, base_dir: &Path, request: &Request) -> Option<Response> {
+    if request.method != "GET" {
+        return None;
+    }
+    let response = match request.path.as_str() {
+        "/favicon.ico" => Response::ok("image/x-icon", router.favicon()?.to_vec()),
+        "/robots.txt" => Response::ok("text/plain", router.robots()?.text().to_string()),
+        _ => return None,
+    };
+    if base_dir.join(&request.path[1..]).exists() {
+        return None;
+    }
+    Some(with_path_headers(router, &request.path, &[], response))
+}
+
+// Answers like for a file that doesn't exist
+fn not_found(router: &Router, request: &Request) -> Response {
+    router
+        .handle_not_found(request)
+        .unwrap_or_else(|| Response::from_status(StatusCode::NOT_FOUND))
+}
+
+// Returns a writer function streaming the file into the response
+pub fn file_writer(file_path: PathBuf) -> StreamFn {
+    Box::new(move |writer| {
+        let file = File::open(&file_path)?;
+        let mut reader = BufReader::new(file);
+        copy(&mut reader, writer)?;
+        Ok(())
+    })
+}
+
+// Builds pages that are not sent byte for byte: files with server-side
+// includes, rendered Markdown and, in live reload mode, HTML pages with the
+// reload script
+// Returns None for files to be served as they are
+pub fn render_page(router: &Router, base_dir: &str, request: &Request) -> Option<Response> {
+    if request.method != "GET" {
+        return None;
+    }
+    let live_reload = router.live_reloader().is_some();
+    #[cfg(feature = "markdown")]
+    let markdown = router.markdown_wrapper().is_some();
+    #[cfg(not(feature = "markdown"))]
+    let markdown = false;
+    if !live_reload && !markdown && router.include_extensions().is_empty() {
+        return None;
+    }
+    let (file_path, content_type) = static_file(base_dir, &request.path)?;
+    let extension = file_path.extension().and_then(|s| s.to_str());
+
+    #[cfg(feature = "markdown")]
+    if extension == Some("md")
+        && let Some(template) = router.markdown_wrapper()
+    {
+        let source = std::fs::read_to_string(&file_path).ok()?;
+        let page = crate::markdown::render(&source, template, &file_path);
+        return Some(finish_page(live_reload, "text/html", page.into_bytes()));
+    }
+
+    let includes = extension.is_some_and(|extension| {
+        router
+            .include_extensions()
+            .iter()
+            .any(|included| included == extension)
+    });
+    let page = if includes {
+        ssi::render(router, base_dir, &file_path)?
+    } else if live_reload && content_type == "text/html" {
+        std::fs::read(&file_path).ok()?
+    } else {
+        return None;
+    };
+    Some(finish_page(live_reload, content_type, page))
+}
+
+// Wraps a rendered page in a response, with the reload script in live reload mode
+fn finish_page(live_reload: bool, content_type: &str, page: Vec<u8>) -> Response {
+    if live_reload && content_type == "text/html" {
+        let page = inject_script(page);
+        return Response::ok(content_type, page).with_header("Cache-Control", "no-store");
+    }
+    Response::ok(content_type, page)
+}
+
+// Answers a conditional request for a file with 304 or 412
+// Returns None if the file should be sent
+pub fn preconditions(request: &Request, file_path: &Path) -> Option<Response> {
+    if !conditional::is_conditional(request) {
+        return None;
+    }
+    let validators = std::fs::metadata(file_path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .and_then(|metadata| Validators::of(&metadata));
+    conditional::evaluate(request, validators.as_ref())
+}
+
+// Returns the ETag and Last-Modified header lines sent with a file
+pub fn validator_lines(file_path: &Path) -> String {
+    std::fs::metadata(file_path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .and_then(|metadata| Validators::of(&metadata))
+        .map(|validators| validators.header_lines())
+        .unwrap_or_default()
+}
+
+// Adds the headers of override files, then the caching policy and headers
+// configured for the path, unless the response has them
+pub fn with_path_headers(
+    router: &Router,
+    path: &str,
+    overrides: &[(String, String)],
+    mut response: Response,
+) -> Response {
+    let cache_control = router
+        .cache_control_for(path)
+        .map(|value| ("Cache-Control", value));
+    let overrides = overrides
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    for (name, value) in overrides
+        .chain(cache_control)
+        .chain(router.headers_for(path))
+    {
+        if response.header(name).is_none() {
+            response = response.with_header(name, value);
+        }
+    }
+    response
+}
+
+// Formats the same headers as with_path_headers as header lines, for static
+// files whose head is written directly
+pub fn path_header_lines(router: &Router, path: &str, overrides: &[(String, String)]) -> String {
+    let mut lines = String::new();
+    for (name, value) in overrides {
+        lines.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let overridden = |name: &str| {
+        overrides
+            .iter()
+            .any(|(set, _)| set.eq_ignore_ascii_case(name))
+    };
+    if let Some(value) = router.cache_control_for(path)
+        && !overridden("Cache-Control")
+    {
+        lines.push_str(&format!("Cache-Control: {}\r\n", value));
+    }
+    for (name, value) in router.headers_for(path) {
+        if !overridden(name) {
+            lines.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    lines
+}
@@ -0,0 +1,977 @@
+// Runtime statistics
+// A server keeps one set of counters, shared with every router it serves so
+// they survive reloads. serve_stream counts requests and bytes through a
+// Metered stream, which reads the status of each response from its status
+// line, and the backends hold an OpenConnection for every connection they
+// serve. HTTP/2 is counted per stream, in the HTTP/1 form requests and
+// responses take inside the pipeline.
+//
+// Latencies go into a histogram of fixed buckets, counted lock-free like
+// the rest; percentiles are estimated from it by interpolating within the
+// bucket they fall in, as Prometheus' histogram_quantile does, so they are
+// only as precise as the buckets are narrow.
+//
+// Requests the router answers are also counted under the route they
+// matched: its method and pattern, never the path requested, so the number
+// of series stays that of the routes however many paths clients try.
+//
+// The last requests answered can be kept too, for the status page; off by
+// default, as it takes a lock per request.
+//
+// Reading the counters back (Stats, its JSON and Prometheus formats) needs
+// the metrics feature; without it requests are not measured at all, and the
+// counters only keep the open connections for drains.
+//
+// The counters also follow a drain (see Server::drain): whether one
+// started, and the sockets of the open connections, so that those still
+// open at its deadline can be aborted. Shutting a socket down wakes up
+// whatever waits on it, on any backend, which then closes it as usual.
+
+use crate::Request;
+use crate::access_log::Pending;
+#[cfg(feature = "metrics")]
+use crate::escape::json_string;
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "metrics")]
+use std::collections::BTreeMap;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "metrics")]
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(feature = "metrics")]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, Instant, SystemTime};
+
+// Status codes counted individually, others are not counted by status
+const STATUS_RANGE: std::ops::Range<u16> = 100..600;
+
+// Longest response start searched for the status line
+const MAX_STATUS_SEARCH: usize = 8 * 1024;
+
+/// Upper bounds of the latency histogram's buckets unless
+/// ServerConfig::latency_buckets sets others
+pub const DEFAULT_LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+
+// Percentiles reported in the JSON and Prometheus formats
+#[cfg(feature = "metrics")]
+const PERCENTILES: [(f64, &str); 3] = [(0.5, "p50"), (0.95, "p95"), (0.99, "p99")];
+
+/// Snapshot of a server's counters (see Server::stats)
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    // Connections being served
+    pub active_connections: usize,
+    // Connections accepted since the server was created
+    pub total_connections: u64,
+    // Requests answered
+    pub total_requests: u64,
+    // Bytes read from and written to clients
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Responses by status code
+    pub statuses: BTreeMap<u16, u64>,
+    pub uptime: Duration,
+    // Time from reading a request head to the end of its response
+    pub latency: Histogram,
+    // Requests answered by router routes, by method and route pattern,
+    // sorted by pattern then method
+    pub routes: Vec<RouteStats>,
+    // The last requests answered, newest first, when the server keeps them
+    // (see AdminConfig::status_page)
+    pub recent: Vec<RecentRequest>,
+    // Requests read and not answered in full yet
+    pub in_flight_requests: usize,
+    // Progress of the drain, once it started
+    pub drain: Option<DrainStatus>,
+}
+
+/// Progress of a server's drain (see Stats::drain and Server::drain)
+/// Done once Stats::active_connections reaches 0
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrainStatus {
+    // Time since the drain started
+    pub elapsed: Duration,
+    // Time left before the connections still open are aborted, None
+    // without a deadline
+    pub time_left: Option<Duration>,
+    // Connections aborted at the deadline
+    pub aborted_connections: u64,
+}
+
+/// Counts of request latencies by bucket (see Stats::latency)
+/// Example: let p95 = server.stats().latency.quantile(0.95);
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // Upper bound of each bucket and how many requests took at most that
+    // long, the counts adding up from one bucket to the next
+    pub buckets: Vec<(Duration, u64)>,
+    // Requests measured, including those slower than the last bound
+    pub count: u64,
+    // Total time of the requests measured
+    pub sum: Duration,
+}
+
+/// Counters of the requests a route answered (see Stats::routes)
+/// The route is the pattern it was registered with, so requests for
+/// "/users/1" and "/users/2" both count under "/users/{id}"
+/// Example:
+/// for route in server.stats().routes {
+///     println!("{} {}: p99 {:?}", route.method, route.route, route.latency.quantile(0.99));
+/// }
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RouteStats {
+    pub method: String,
+    pub route: String,
+    // Responses by status code
+    pub statuses: BTreeMap<u16, u64>,
+    // Latencies of the route's requests, count being how many it answered
+    pub latency: Histogram,
+}
+
+/// A request answered lately (see Stats::recent)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecentRequest {
+    // When the response was done
+    pub at: SystemTime,
+    pub method: String,
+    // Path without the query, which may carry secrets
+    pub path: String,
+    pub status: u16,
+    pub latency: Duration,
+    pub client_ip: Option<IpAddr>,
+}
+
+#[cfg(feature = "metrics")]
+impl Stats {
+    /// Formats the counters as a JSON object, latencies in milliseconds
+    /// Example: {"active_connections":2,...,"latency":{"count":9,"sum_ms":41.2,"p50_ms":2.5,...},"routes":[{"method":"GET","route":"/users/{id}",...}],"statuses":{"200":8,"404":1}}
+    pub fn to_json(&self) -> String {
+        let routes: Vec<String> = self
+            .routes
+            .iter()
+            .map(|route| {
+                format!(
+                    "{{\"method\":{},\"route\":{},\"latency\":{},\"statuses\":{{{}}}}}",
+                    json_string(&route.method),
+                    json_string(&route.route),
+                    route.latency.to_json(),
+                    statuses_json(&route.statuses)
+                )
+            })
+            .collect();
+        let recent: Vec<String> = self
+            .recent
+            .iter()
+            .map(|request| {
+                format!(
+                    "{{\"unix_ms\":{},\"method\":{},\"path\":{},\"status\":{},\"latency_ms\":{:.3},\"client_ip\":{}}}",
+                    request
+                        .at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis(),
+                    json_string(&request.method),
+                    json_string(&request.path),
+                    request.status,
+                    request.latency.as_secs_f64() * 1000.0,
+                    request
+                        .client_ip
+                        .map_or("null".to_string(), |ip| json_string(&ip.to_string()))
+                )
+            })
+            .collect();
+        let drain = match &self.drain {
+            None => "null".to_string(),
+            Some(drain) => format!(
+                "{{\"elapsed_ms\":{},\"time_left_ms\":{},\"aborted_connections\":{}}}",
+                drain.elapsed.as_millis(),
+                drain
+                    .time_left
+                    .map_or("null".to_string(), |left| left.as_millis().to_string()),
+                drain.aborted_connections
+            ),
+        };
+        format!(
+            "{{\"active_connections\":{},\"total_connections\":{},\"total_requests\":{},\
+             \"in_flight_requests\":{},\"bytes_in\":{},\"bytes_out\":{},\"uptime_secs\":{},\
+             \"drain\":{},\"latency\":{},\"routes\":[{}],\"recent\":[{}],\"statuses\":{{{}}}}}",
+            self.active_connections,
+            self.total_connections,
+            self.total_requests,
+            self.in_flight_requests,
+            self.bytes_in,
+            self.bytes_out,
+            self.uptime.as_secs(),
+            drain,
+            self.latency.to_json(),
+            routes.join(","),
+            recent.join(","),
+            statuses_json(&self.statuses)
+        )
+    }
+
+    /// Formats the counters in the Prometheus text format, with the latency
+    /// histograms and their estimated percentiles, overall and by route
+    /// Example: router.get("/metrics", server.metrics_handler());
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str| {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        };
+        metric(
+            "http_connections_active",
+            "gauge",
+            "Connections being served",
+        );
+        metric("http_connections_total", "counter", "Connections accepted");
+        metric("http_requests_total", "counter", "Requests answered");
+        metric(
+            "http_responses_total",
+            "counter",
+            "Responses by status code",
+        );
+        metric(
+            "http_received_bytes_total",
+            "counter",
+            "Bytes read from clients",
+        );
+        metric(
+            "http_sent_bytes_total",
+            "counter",
+            "Bytes written to clients",
+        );
+        metric(
+            "http_uptime_seconds",
+            "gauge",
+            "Time since the server was created",
+        );
+        metric(
+            "http_requests_in_flight",
+            "gauge",
+            "Requests read and not answered in full yet",
+        );
+        metric(
+            "http_draining",
+            "gauge",
+            "1 once the server drains its connections",
+        );
+        let _ = write!(
+            text,
+            "http_connections_active {}\nhttp_connections_total {}\nhttp_requests_total {}\n",
+            self.active_connections, self.total_connections, self.total_requests
+        );
+        for (status, count) in &self.statuses {
+            let _ = writeln!(
+                text,
+                "http_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+        let _ = write!(
+            text,
+            "http_received_bytes_total {}\nhttp_sent_bytes_total {}\nhttp_uptime_seconds {}\n\
+             http_requests_in_flight {}\nhttp_draining {}\n",
+            self.bytes_in,
+            self.bytes_out,
+            self.uptime.as_secs_f64(),
+            self.in_flight_requests,
+            u8::from(self.drain.is_some())
+        );
+        write_histogram(
+            &mut text,
+            "http_request_duration_seconds",
+            "Time from reading a request head to the end of its response",
+            &[("", &self.latency)],
+        );
+        if self.routes.is_empty() {
+            return text;
+        }
+
+        // Labels of each route, ending with a comma for the next label
+        let labels: Vec<String> = self
+            .routes
+            .iter()
+            .map(|route| {
+                format!(
+                    "method=\"{}\",route=\"{}\",",
+                    label_value(&route.method),
+                    label_value(&route.route)
+                )
+            })
+            .collect();
+        let name = "http_route_responses_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Responses of routed requests by route and status code\n\
+             # TYPE {} counter",
+            name, name
+        );
+        for (labels, route) in labels.iter().zip(&self.routes) {
+            for (status, count) in &route.statuses {
+                let _ = writeln!(
+                    text,
+                    "{}{{{}status=\"{}\"}} {}",
+                    name, labels, status, count
+                );
+            }
+        }
+        let histograms: Vec<(&str, &Histogram)> = labels
+            .iter()
+            .zip(&self.routes)
+            .map(|(labels, route)| (labels.as_str(), &route.latency))
+            .collect();
+        write_histogram(
+            &mut text,
+            "http_route_request_duration_seconds",
+            "Time from reading a routed request head to the end of its response",
+            &histograms,
+        );
+        text
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Histogram {
+    /// Estimates the latency under which the given share of requests (0.0
+    /// to 1.0) were answered, or None before any request
+    /// Requests past the last bucket are taken as lasting its bound
+    /// Example: histogram.quantile(0.99)
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = quantile.clamp(0.0, 1.0) * self.count as f64;
+        let mut lower = (Duration::ZERO, 0);
+        for &(bound, count) in &self.buckets {
+            if count as f64 >= rank && count > lower.1 {
+                let share = (rank - lower.1 as f64) / (count - lower.1) as f64;
+                return Some(lower.0 + (bound - lower.0).mul_f64(share.max(0.0)));
+            }
+            lower = (bound, count);
+        }
+        Some(lower.0)
+    }
+
+    // Formats the count, sum and percentiles as a JSON object
+    fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"count\":{},\"sum_ms\":{:.3}",
+            self.count,
+            self.sum.as_secs_f64() * 1000.0
+        );
+        for (quantile, name) in PERCENTILES {
+            match self.quantile(quantile) {
+                Some(latency) => {
+                    let _ = write!(
+                        json,
+                        ",\"{}_ms\":{:.3}",
+                        name,
+                        latency.as_secs_f64() * 1000.0
+                    );
+                }
+                None => {
+                    let _ = write!(json, ",\"{}_ms\":null", name);
+                }
+            }
+        }
+        json.push('}');
+        json
+    }
+}
+
+// Writes histograms of one metric, each with its labels (ending with a
+// comma, or empty), then their percentiles as a gauge named after it
+#[cfg(feature = "metrics")]
+fn write_histogram(text: &mut String, name: &str, help: &str, histograms: &[(&str, &Histogram)]) {
+    let _ = writeln!(text, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    for (labels, histogram) in histograms {
+        for (bound, count) in &histogram.buckets {
+            let _ = writeln!(
+                text,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                labels,
+                bound.as_secs_f64(),
+                count
+            );
+        }
+        let labels = labels.trim_end_matches(',');
+        let braced = match labels.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", labels),
+        };
+        let _ = write!(
+            text,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}\n{}_sum{} {}\n{}_count{} {}\n",
+            name,
+            labels,
+            if labels.is_empty() { "" } else { "," },
+            histogram.count,
+            name,
+            braced,
+            histogram.sum.as_secs_f64(),
+            name,
+            braced,
+            histogram.count
+        );
+    }
+    let gauge = name.replace("_seconds", "_quantile_seconds");
+    let _ = writeln!(
+        text,
+        "# HELP {} Percentiles estimated from {}\n# TYPE {} gauge",
+        gauge, name, gauge
+    );
+    for (labels, histogram) in histograms {
+        for (quantile, _) in PERCENTILES {
+            let value = histogram
+                .quantile(quantile)
+                .map_or("NaN".to_string(), |latency| {
+                    latency.as_secs_f64().to_string()
+                });
+            let _ = writeln!(
+                text,
+                "{}{{{}quantile=\"{}\"}} {}",
+                gauge, labels, quantile, value
+            );
+        }
+    }
+}
+
+// Formats counts by status as the members of a JSON object
+#[cfg(feature = "metrics")]
+fn statuses_json(statuses: &BTreeMap<u16, u64>) -> String {
+    let mut json = String::new();
+    for (status, count) in statuses {
+        if !json.is_empty() {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\":{}", status, count);
+    }
+    json
+}
+
+// Escapes a Prometheus label value
+#[cfg(feature = "metrics")]
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Responses by status code, counted lock-free
+struct StatusCounts(Vec<AtomicU64>);
+
+impl StatusCounts {
+    fn new() -> StatusCounts {
+        StatusCounts(STATUS_RANGE.map(|_| AtomicU64::new(0)).collect())
+    }
+
+    fn record(&self, status: u16) {
+        if STATUS_RANGE.contains(&status) {
+            self.0[(status - STATUS_RANGE.start) as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn snapshot(&self) -> BTreeMap<u16, u64> {
+        STATUS_RANGE
+            .zip(&self.0)
+            .map(|(status, count)| (status, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+// Requests in each latency bucket, with one more bucket for those past the
+// last bound, and their total time
+struct Latencies {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Latencies {
+    fn new(bounds: &[Duration]) -> Latencies {
+        Latencies {
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, bounds: &[Duration], latency: Duration) {
+        let bucket = bounds.partition_point(|bound| *bound < latency);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn snapshot(&self, bounds: &[Duration]) -> Histogram {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(bounds.len());
+        for (bound, requests) in bounds.iter().zip(&self.buckets) {
+            count += requests.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.buckets[bounds.len()].load(Ordering::Relaxed);
+        Histogram {
+            buckets,
+            count,
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// The counters of a route
+struct RouteCounters {
+    method: String,
+    route: String,
+    statuses: StatusCounts,
+    latencies: Latencies,
+}
+
+// The live counters behind Stats
+pub(crate) struct Counters {
+    active_connections: AtomicUsize,
+    total_connections: AtomicU64,
+    total_requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    statuses: StatusCounts,
+    // Upper bounds of the latency buckets
+    latency_bounds: Vec<Duration>,
+    latencies: Latencies,
+    // By "METHOD pattern", added as routes first answer; patterns are
+    // registered by the application, so they don't grow with the paths
+    // clients request
+    routes: RwLock<HashMap<String, RouteCounters>>,
+    // The last requests answered, newest last, up to recent_limit
+    recent: Mutex<VecDeque<RecentRequest>>,
+    recent_limit: AtomicUsize,
+    in_flight_requests: AtomicUsize,
+    // Set with the drain's start and deadline once the server drains
+    draining: AtomicBool,
+    drain: Mutex<Option<(Instant, Option<Instant>)>>,
+    aborted_connections: AtomicU64,
+    // Duplicates of the sockets of open connections by id, to abort them
+    sockets: Mutex<HashMap<u64, TcpStream>>,
+    next_socket: AtomicU64,
+}
+
+impl Counters {
+    // Panics unless the bounds are increasing and above zero
+    pub(crate) fn new(latency_bounds: &[Duration]) -> Counters {
+        assert!(
+            !latency_bounds.is_empty()
+                && latency_bounds[0] > Duration::ZERO
+                && latency_bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "latency buckets must be increasing and above zero, got {:?}",
+            latency_bounds
+        );
+        Counters {
+            active_connections: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            total_requests: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            statuses: StatusCounts::new(),
+            latency_bounds: latency_bounds.to_vec(),
+            latencies: Latencies::new(latency_bounds),
+            routes: RwLock::new(HashMap::new()),
+            recent: Mutex::new(VecDeque::new()),
+            recent_limit: AtomicUsize::new(0),
+            in_flight_requests: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            drain: Mutex::new(None),
+            aborted_connections: AtomicU64::new(0),
+            sockets: Mutex::new(HashMap::new()),
+            next_socket: AtomicU64::new(0),
+        }
+    }
+
+    // Notes that the server drains, with a deadline to abort the
+    // connections still open, keeping the earliest deadline if several
+    // drains overlap
+    pub(crate) fn start_drain(&self, deadline: Option<Instant>) {
+        let mut drain = self.drain.lock().unwrap();
+        let (started, previous) = drain.unwrap_or((Instant::now(), None));
+        let deadline = match (previous, deadline) {
+            (Some(previous), Some(deadline)) => Some(previous.min(deadline)),
+            (previous, deadline) => previous.or(deadline),
+        };
+        *drain = Some((started, deadline));
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    // Returns true once the server drains, so that kept-alive connections
+    // close after their current response
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    // Returns the number of connections being served
+    pub(crate) fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    // Shuts the sockets of the open connections down, returning how many
+    pub(crate) fn abort_connections(&self) -> usize {
+        let sockets = self.sockets.lock().unwrap();
+        for socket in sockets.values() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        self.aborted_connections
+            .fetch_add(sockets.len() as u64, Ordering::Relaxed);
+        sockets.len()
+    }
+
+    // Keeps the last requests answered, up to limit (0 keeps none)
+    pub(crate) fn keep_recent(&self, limit: usize) {
+        self.recent_limit.store(limit, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        while recent.len() > limit {
+            recent.pop_front();
+        }
+    }
+
+    fn keeps_recent(&self) -> bool {
+        self.recent_limit.load(Ordering::Relaxed) > 0
+    }
+
+    // Reads the counters
+    #[cfg(feature = "metrics")]
+    pub(crate) fn snapshot(&self, uptime: Duration) -> Stats {
+        let mut routes: Vec<RouteStats> = self
+            .routes
+            .read()
+            .unwrap()
+            .values()
+            .map(|counters| RouteStats {
+                method: counters.method.clone(),
+                route: counters.route.clone(),
+                statuses: counters.statuses.snapshot(),
+                latency: counters.latencies.snapshot(&self.latency_bounds),
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.route, &a.method).cmp(&(&b.route, &b.method)));
+        Stats {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            total_requests: self.total_requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            statuses: self.statuses.snapshot(),
+            uptime,
+            latency: self.latencies.snapshot(&self.latency_bounds),
+            routes,
+            recent: self.recent.lock().unwrap().iter().rev().cloned().collect(),
+            in_flight_requests: self.in_flight_requests.load(Ordering::Relaxed),
+            drain: self
+                .drain
+                .lock()
+                .unwrap()
+                .map(|(started, deadline)| DrainStatus {
+                    elapsed: started.elapsed(),
+                    time_left: deadline
+                        .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+                    aborted_connections: self.aborted_connections.load(Ordering::Relaxed),
+                }),
+        }
+    }
+
+    // Takes back bytes counted twice, e.g. read ahead with one request and
+    // replayed to the next
+    pub(crate) fn uncount_in(&self, len: usize) {
+        self.bytes_in.fetch_sub(len as u64, Ordering::Relaxed);
+    }
+
+    // Counts a response with the given status
+    fn record(&self, status: u16) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        self.statuses.record(status);
+    }
+
+    // Counts an answered request of a route
+    fn record_route(&self, method: &str, pattern: &str, status: u16, latency: Duration) {
+        let key = format!("{} {}", method, pattern);
+        let routes = self.routes.read().unwrap();
+        if let Some(route) = routes.get(&key) {
+            route.statuses.record(status);
+            route.latencies.record(&self.latency_bounds, latency);
+            return;
+        }
+        drop(routes);
+        let mut routes = self.routes.write().unwrap();
+        let route = routes.entry(key).or_insert_with(|| RouteCounters {
+            method: method.to_string(),
+            route: pattern.to_string(),
+            statuses: StatusCounts::new(),
+            latencies: Latencies::new(&self.latency_bounds),
+        });
+        route.statuses.record(status);
+        route.latencies.record(&self.latency_bounds, latency);
+    }
+
+    // Adds a request to the last ones answered
+    fn record_recent(&self, request: RecentRequest) {
+        let limit = self.recent_limit.load(Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        while recent.len() >= limit.max(1) {
+            recent.pop_front();
+        }
+        if limit > 0 {
+            recent.push_back(request);
+        }
+    }
+}
+
+// A request being counted: its status once answered, and its latency when
+// dropped, after the response went out, under its route if it had one
+pub(crate) struct Measured<'a> {
+    counters: &'a Counters,
+    started: Cell<Instant>,
+    // Status of the response, 0 until it was sent
+    status: Cell<u16>,
+    // Method and pattern of the route the router matched
+    route: RefCell<Option<(String, String)>>,
+    // Method, path and client of the request, when the last requests are
+    // kept
+    described: RefCell<Option<(String, String, Option<IpAddr>)>>,
+    // Counted in flight
+    begun: Cell<bool>,
+}
+
+impl<'a> Measured<'a> {
+    pub(crate) fn new(counters: &'a Counters) -> Measured<'a> {
+        Measured {
+            counters,
+            started: Cell::new(Instant::now()),
+            status: Cell::new(0),
+            route: RefCell::new(None),
+            described: RefCell::new(None),
+            begun: Cell::new(false),
+        }
+    }
+
+    // Returns true if the request should be described once parsed
+    pub(crate) fn keeps_recent(&self) -> bool {
+        self.counters.keeps_recent()
+    }
+
+    // Notes what the request was, for the last requests answered
+    pub(crate) fn describe(&self, request: &Request) {
+        if self.keeps_recent() {
+            *self.described.borrow_mut() = Some((
+                request.method.clone(),
+                request.path.clone(),
+                request.client_ip(),
+            ));
+        }
+    }
+
+    // Starts the clock once the request head was read, so waiting for the
+    // client to send it is not counted, and counts the request in flight
+    pub(crate) fn begin(&self) {
+        self.started.set(Instant::now());
+        self.begun.set(true);
+        self.counters
+            .in_flight_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Counts the request under the route the router matched, if any
+    pub(crate) fn route(&self, method: &str, pattern: Option<&str>) {
+        if let Some(pattern) = pattern {
+            *self.route.borrow_mut() = Some((method.to_string(), pattern.to_string()));
+        }
+    }
+
+    // Counts the response's status
+    fn respond(&self, status: u16) {
+        self.counters.record(status);
+        self.status.set(status);
+    }
+}
+
+impl Drop for Measured<'_> {
+    fn drop(&mut self) {
+        if self.begun.get() {
+            self.counters
+                .in_flight_requests
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        let status = self.status.get();
+        if status == 0 {
+            return;
+        }
+        let latency = self.started.get().elapsed();
+        self.counters
+            .latencies
+            .record(&self.counters.latency_bounds, latency);
+        if let Some((method, pattern)) = self.route.get_mut() {
+            self.counters.record_route(method, pattern, status, latency);
+        }
+        if let Some((method, path, client_ip)) = self.described.take() {
+            self.counters.record_recent(RecentRequest {
+                at: SystemTime::now(),
+                method,
+                path,
+                status,
+                latency,
+                client_ip,
+            });
+        }
+    }
+}
+
+// Counts a connection as active until dropped, keeping a duplicate of its
+// socket, if given, to abort it at a drain deadline
+pub(crate) struct OpenConnection(Arc<Counters>, Option<u64>);
+
+impl OpenConnection {
+    pub(crate) fn new(counters: &Arc<Counters>, socket: Option<TcpStream>) -> OpenConnection {
+        counters.total_connections.fetch_add(1, Ordering::Relaxed);
+        counters.active_connections.fetch_add(1, Ordering::SeqCst);
+        let id = socket.map(|socket| {
+            let id = counters.next_socket.fetch_add(1, Ordering::Relaxed);
+            counters.sockets.lock().unwrap().insert(id, socket);
+            id
+        });
+        OpenConnection(Arc::clone(counters), id)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        if let Some(id) = self.1 {
+            self.0.sockets.lock().unwrap().remove(&id);
+        }
+        self.0.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A stream counting the bytes of one request and its response, and the
+// status of the response once its status line went through, for the
+// statistics and the access log
+pub(crate) struct Metered<'a, S: Read + Write> {
+    pub inner: S,
+    measured: Option<&'a Measured<'a>>,
+    logged: Option<&'a Pending<'a>>,
+    // Start of the response, kept until the final status line is complete
+    head: Vec<u8>,
+    counted: bool,
+}
+
+impl<'a, S: Read + Write> Metered<'a, S> {
+    pub(crate) fn new(
+        inner: S,
+        measured: Option<&'a Measured<'a>>,
+        logged: Option<&'a Pending<'a>>,
+    ) -> Metered<'a, S> {
+        Metered {
+            inner,
+            measured,
+            logged,
+            head: Vec::new(),
+            counted: measured.is_none() && logged.is_none(),
+        }
+    }
+
+    // Counts bytes written around the stream, e.g. with sendfile(2)
+    pub(crate) fn count_out(&self, len: u64) {
+        if let Some(measured) = self.measured {
+            measured
+                .counters
+                .bytes_out
+                .fetch_add(len, Ordering::Relaxed);
+        }
+        if let Some(logged) = self.logged {
+            logged.count(len);
+        }
+    }
+
+    // Looks for the status line of the final response, skipping interim
+    // responses such as 100 Continue
+    fn observe(&mut self, data: &[u8]) {
+        self.head.extend_from_slice(data);
+        while !self.counted && self.head.len() >= 12 {
+            if !self.head.starts_with(b"HTTP/1.") || self.head.len() > MAX_STATUS_SEARCH {
+                // Not an HTTP/1 response, e.g. HTTP/2 frames
+                self.counted = true;
+                break;
+            }
+            let status = std::str::from_utf8(&self.head[9..12])
+                .ok()
+                .and_then(|status| status.parse::<u16>().ok());
+            match status {
+                Some(status @ 100..=199) if status != 101 => {
+                    match self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(end) => drop(self.head.drain(..end + 4)),
+                        None => return,
+                    }
+                }
+                Some(status) => {
+                    if let Some(measured) = self.measured {
+                        measured.respond(status);
+                    }
+                    if let Some(logged) = self.logged {
+                        logged.respond(status);
+                    }
+                    self.counted = true;
+                }
+                None => self.counted = true,
+            }
+        }
+        if self.counted {
+            self.head = Vec::new();
+        }
+    }
+}
+
+impl<S: Read + Write> Read for Metered<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if let Some(measured) = self.measured {
+            measured
+                .counters
+                .bytes_in
+                .fetch_add(bytes_read as u64, Ordering::Relaxed);
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl<S: Read + Write> Write for Metered<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if !self.counted {
+            self.observe(&buf[..written]);
+        }
+        self.count_out(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,356 @@
+// Helpers for testing applications built on this crate
+// TestClient sends requests through a Router over an in-memory stream, and
+// responses can be rendered into stable, human-readable strings that are
+// compared against stored snapshots
+
+use crate::{Router, serve_connection};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+
+/// Headers whose values change between runs and are left out of snapshots
+pub const VOLATILE_HEADERS: &[&str] = &["Date", "Last-Modified", "ETag", "Expires", "Age"];
+
+/// Serializes a raw HTTP response into a snapshot-friendly string
+/// Parameters:
+/// - raw: The bytes written to the client (status line, headers and body)
+///
+/// Returns the status line, the headers sorted by name (without the ones in
+/// VOLATILE_HEADERS), a blank line and the body
+pub fn snapshot_response(raw: &[u8]) -> String {
+    snapshot_response_ignoring(raw, &[])
+}
+
+/// Same as snapshot_response, but also drops the given headers
+/// Header names are compared case-insensitively
+/// Example: snapshot_response_ignoring(&raw, &["X-Request-Id"])
+pub fn snapshot_response_ignoring(raw: &[u8], ignored: &[&str]) -> String {
+    // Split the head from the body at the first empty line
+    let (head, body) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(index) => (&raw[..index], &raw[index + 4..]),
+        None => (raw, &[][..]),
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+
+    // Collect the remaining headers, skipping volatile and ignored ones
+    let mut headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, _)| {
+            !VOLATILE_HEADERS
+                .iter()
+                .chain(ignored)
+                .any(|skip| skip.eq_ignore_ascii_case(name))
+        })
+        .collect();
+    headers.sort_by_key(|(name, value)| (name.to_ascii_lowercase(), *value));
+
+    let mut snapshot = String::new();
+    snapshot.push_str(status_line);
+    snapshot.push('\n');
+    for (name, value) in headers {
+        snapshot.push_str(&format!("{}: {}\n", name, value));
+    }
+    snapshot.push('\n');
+    snapshot.push_str(&snapshot_body(body));
+    snapshot
+}
+
+// Renders a body for a snapshot
+// Text bodies are kept as they are, binary ones are summarised by their size
+fn snapshot_body(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("<{} bytes of binary data>", body.len()),
+    }
+}
+
+/// One step of the data a MemoryStream delivers to the server
+#[derive(Clone, Debug)]
+pub enum ReadStep {
+    // Bytes returned by one or more reads
+    Data(Vec<u8>),
+    // A single read failing with this error kind (e.g. WouldBlock for an idle client)
+    Error(ErrorKind),
+}
+
+/// An in-memory connection for simulating clients
+/// Reads follow a script of data and errors, then report end of file;
+/// writes are recorded and can be made to fail
+/// Example: a slow client that idles once, then trickles its request
+/// MemoryStream::default()
+///     .then_error(ErrorKind::WouldBlock)
+///     .then_data("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+///     .max_read(3)
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStream {
+    script: VecDeque<ReadStep>,
+    // Upper bound on the bytes returned by a single read, 0 for no limit
+    max_read: usize,
+    output: Vec<u8>,
+    // Writes fail with this error once output holds write_limit bytes
+    write_failure: Option<(usize, ErrorKind)>,
+    empty_writes: usize,
+}
+
+impl MemoryStream {
+    /// Creates a stream whose reads return input, then end of file
+    pub fn new(input: impl Into<Vec<u8>>) -> MemoryStream {
+        MemoryStream::default().then_data(input)
+    }
+
+    /// Appends data to the read script
+    pub fn then_data(mut self, data: impl Into<Vec<u8>>) -> MemoryStream {
+        self.script.push_back(ReadStep::Data(data.into()));
+        self
+    }
+
+    /// Appends a failing read to the read script
+    pub fn then_error(mut self, kind: ErrorKind) -> MemoryStream {
+        self.script.push_back(ReadStep::Error(kind));
+        self
+    }
+
+    /// Returns at most max bytes per read, simulating short reads
+    pub fn max_read(mut self, max: usize) -> MemoryStream {
+        self.max_read = max;
+        self
+    }
+
+    /// Fails every write with the given error once limit bytes were written
+    /// Example: fail_writes_after(0, ErrorKind::BrokenPipe) simulates a vanished peer
+    pub fn fail_writes_after(mut self, limit: usize, kind: ErrorKind) -> MemoryStream {
+        self.write_failure = Some((limit, kind));
+        self
+    }
+
+    /// Returns everything written to the stream so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Returns the number of zero-byte writes, e.g. idle connection probes
+    pub fn empty_writes(&self) -> usize {
+        self.empty_writes
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.script.front_mut() {
+                None => return Ok(0),
+                Some(ReadStep::Error(kind)) => {
+                    let kind = *kind;
+                    self.script.pop_front();
+                    return Err(kind.into());
+                }
+                Some(ReadStep::Data(data)) if data.is_empty() => {
+                    self.script.pop_front();
+                }
+                Some(ReadStep::Data(data)) => {
+                    let mut len = buf.len().min(data.len());
+                    if self.max_read > 0 {
+                        len = len.min(self.max_read);
+                    }
+                    buf[..len].copy_from_slice(&data[..len]);
+                    data.drain(..len);
+                    return Ok(len);
+                }
+            }
+        }
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            self.empty_writes += 1;
+        }
+        if let Some((limit, kind)) = self.write_failure {
+            let room = limit.saturating_sub(self.output.len());
+            if room == 0 {
+                return Err(kind.into());
+            }
+            let len = buf.len().min(room);
+            self.output.extend_from_slice(&buf[..len]);
+            return Ok(len);
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.write_failure {
+            Some((limit, kind)) if self.output.len() >= limit => Err(kind.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sends requests through a Router without binding a socket
+/// Every request runs the full connection pipeline (parsing, routing,
+/// middleware, static files) over a MemoryStream
+/// Example:
+/// let client = TestClient::new(router);
+/// let response = client.get("/api/hello");
+/// assert_eq!(response.status, 200);
+pub struct TestClient {
+    router: Router,
+    base_dir: String,
+}
+
+impl TestClient {
+    /// Creates a client for the router, without static files
+    pub fn new(router: Router) -> TestClient {
+        TestClient {
+            router,
+            base_dir: String::new(),
+        }
+    }
+
+    /// Serves static files from base_dir when no route matches
+    pub fn static_dir(mut self, base_dir: &str) -> TestClient {
+        self.base_dir = base_dir.to_string();
+        self
+    }
+
+    /// Sends a GET request
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], b"")
+    }
+
+    /// Sends a POST request with the given body
+    pub fn post(&self, path: &str, content_type: &str, body: impl AsRef<[u8]>) -> TestResponse {
+        self.request(
+            "POST",
+            path,
+            &[("Content-Type", content_type)],
+            body.as_ref(),
+        )
+    }
+
+    /// Sends a request with extra headers and a body
+    /// Host and, for non-empty bodies, Content-Length are added automatically
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> TestResponse {
+        let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !body.is_empty() {
+            raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        raw.push_str("\r\n");
+
+        let mut raw = raw.into_bytes();
+        raw.extend_from_slice(body);
+        self.send_raw(&raw)
+    }
+
+    /// Sends raw request bytes as they are
+    pub fn send_raw(&self, raw: &[u8]) -> TestResponse {
+        let mut stream = MemoryStream::new(raw);
+        serve_connection(&mut stream, &self.base_dir, &self.router);
+        TestResponse::parse(stream.output())
+    }
+}
+
+/// A response received by a TestClient
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub reason: String,
+    // Headers in the order they were sent
+    pub headers: Vec<(String, String)>,
+    // Body with any chunked transfer encoding removed
+    pub body: Vec<u8>,
+    // Bytes exactly as written by the server
+    pub raw: Vec<u8>,
+}
+
+impl TestResponse {
+    /// Parses the bytes written by the server
+    /// Interim 1xx responses are skipped and chunked bodies are decoded;
+    /// a connection closed without a response gives status 0
+    pub fn parse(raw: &[u8]) -> TestResponse {
+        let mut rest = raw;
+        loop {
+            let (head, body) = match rest.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(index) => (&rest[..index], &rest[index + 4..]),
+                None => (rest, &[][..]),
+            };
+            let head = String::from_utf8_lossy(head);
+            let mut lines = head.split("\r\n");
+            let mut status_line = lines.next().unwrap_or("").splitn(3, ' ').skip(1);
+            let status = status_line.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+            let reason = status_line.next().unwrap_or("").to_string();
+            if (100..200).contains(&status) {
+                rest = body;
+                continue;
+            }
+
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect();
+            let chunked = headers.iter().any(|(name, value)| {
+                name.eq_ignore_ascii_case("Transfer-Encoding") && value == "chunked"
+            });
+            let body = if chunked {
+                decode_chunked(body)
+            } else {
+                body.to_vec()
+            };
+            return TestResponse {
+                status,
+                reason,
+                headers,
+                body,
+                raw: raw.to_vec(),
+            };
+        }
+    }
+
+    /// Returns the value of a header, comparing names case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the body as text, replacing invalid UTF-8
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    /// Returns the snapshot of the raw response (see snapshot_response)
+    pub fn snapshot(&self) -> String {
+        snapshot_response(&self.raw)
+    }
+}
+
+// Joins the chunks of a chunked body, stopping at the last chunk
+fn decode_chunked(mut raw: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(line_end) = raw.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&raw[..line_end])
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .unwrap_or(0);
+        let data = &raw[line_end + 2..];
+        if size == 0 || data.len() < size {
+            break;
+        }
+        body.extend_from_slice(&data[..size]);
+        raw = data[size..].strip_prefix(b"\r\n").unwrap_or(&data[size..]);
+    }
+    body
+}
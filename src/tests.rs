@@ -1,10 +1,28 @@
+// Some helpers are only used by tests of optional features
+#[cfg_attr(not(feature = "static"), allow(unused_imports, dead_code))]
+#[allow(clippy::module_inception)]
 #[cfg(test)]
 mod tests {
     // Import necessary modules and types for testing
-    use crate::{Handler, handle_connection, handle_request, parse_request, parse_request_line};
+    #[cfg(feature = "static")]
+    use crate::FileCache;
+    use crate::extract::{Bytes, Text};
+    #[cfg(feature = "router")]
+    use crate::extract::{Headers, Json, Path, Query};
+    use crate::pool::BufferPool;
+    use crate::route_tree::{RouteTree, parse_pattern};
+    use crate::testing::{MemoryStream, TestClient, snapshot_response, snapshot_response_ignoring};
+    use crate::{
+        AdminConfig, Body, BodyReader, Handler, MAX_BODY_SIZE, MAX_HEAD_SIZE, MAX_IDLE_PROBES,
+        Next, Request, Response, Router, Server, ServerConfig, TrailingSlash, Upload,
+        handle_connection, handle_request, parse_request, parse_request_line, read_or_probe,
+        serve_connection, serve_tcp_connection,
+    };
+    #[cfg(feature = "router")]
+    use serde::Deserialize;
     use std::collections::HashMap;
     use std::fs::File;
-    use std::io::Cursor;
+    use std::io::{Cursor, ErrorKind};
     use std::io::{Read, Write};
     use tempfile::TempDir;
 
@@ -45,6 +63,7 @@ mod tests {
     // Test handling of a request for the index file
     // Creates a temporary directory with an index.html file and verifies correct response
     #[test]
+    #[cfg(feature = "static")]
     fn test_handle_request_index_file() {
         let temp_dir = TempDir::new().unwrap();
         let file_path = temp_dir.path().join("index.html");
@@ -99,33 +118,6 @@ mod tests {
         assert_eq!(content_type, "application/json");
     }
 
-    // Mock Stream implementation for testing
-    // Simulates a TCP stream for testing connection handling
-    struct MockStream {
-        read_data: Cursor<Vec<u8>>, // Simulates incoming data
-        write_data: Vec<u8>,        // Captures outgoing data
-    }
-
-    // Implementation of Read trait for MockStream
-    // Allows reading from the mock stream
-    impl Read for MockStream {
-        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            self.read_data.read(buf)
-        }
-    }
-
-    // Implementation of Write trait for MockStream
-    // Allows writing to the mock stream and captures written data
-    impl Write for MockStream {
-        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            self.write_data.extend_from_slice(buf);
-            Ok(buf.len())
-        }
-        fn flush(&mut self) -> std::io::Result<()> {
-            Ok(())
-        }
-    }
-
     // Test handling of a complete connection with valid request
     // Verifies that the server responds correctly to a valid API request
     #[test]
@@ -139,14 +131,11 @@ mod tests {
         });
 
         let request = b"GET /api/hello HTTP/1.1\r\nHost: localhost\r\n\r\n";
-        let mut stream = MockStream {
-            read_data: Cursor::new(request.to_vec()),
-            write_data: Vec::new(),
-        };
+        let mut stream = MemoryStream::new(request);
 
         handle_connection(&mut stream, "", &routes);
 
-        let response = String::from_utf8_lossy(&stream.write_data);
+        let response = String::from_utf8_lossy(stream.output());
 
         assert!(response.contains("HTTP/1.1 200 OK"));
         assert!(response.contains(r#"{"message": "Hello"}"#));
@@ -155,6 +144,7 @@ mod tests {
     // Test file streaming functionality
     // Verifies that files are correctly streamed in the response
     #[test]
+    #[cfg(feature = "static")]
     fn test_handle_request_stream_file() {
         let temp_dit = TempDir::new().unwrap();
         let file_path = temp_dit.path().join("index.html");
@@ -176,4 +166,6241 @@ mod tests {
         stream_fn(&mut output).unwrap();
         assert_eq!(output, b"<h1>Hello</h1>");
     }
+
+    // Sends a raw request through handle_connection using a MemoryStream
+    // Returns everything the server wrote back
+    fn send_request(request: &[u8], base_dir: &str, routes: &HashMap<String, Handler>) -> Vec<u8> {
+        let mut stream = MemoryStream::new(request);
+        handle_connection(&mut stream, base_dir, routes);
+        stream.output().to_vec()
+    }
+
+    // Test snapshot serialization of a raw response
+    // Verifies that headers are sorted and volatile headers are dropped
+    #[test]
+    fn test_snapshot_response_sorts_and_filters_headers() {
+        let raw = b"HTTP/1.1 200 OK\r\nX-Custom: 1\r\nDate: Mon, 01 Jan 2024 00:00:00 GMT\r\nContent-Type: text/plain\r\nX-Request-Id: abc\r\n\r\nhello";
+
+        assert_eq!(
+            snapshot_response(raw),
+            "HTTP/1.1 200 OK\nContent-Type: text/plain\nX-Custom: 1\nX-Request-Id: abc\n\nhello"
+        );
+        assert_eq!(
+            snapshot_response_ignoring(raw, &["x-request-id"]),
+            "HTTP/1.1 200 OK\nContent-Type: text/plain\nX-Custom: 1\n\nhello"
+        );
+    }
+
+    // Test snapshot serialization of a binary body
+    // Verifies that non UTF-8 bodies are summarised instead of dumped
+    #[test]
+    fn test_snapshot_response_binary_body() {
+        let raw = b"HTTP/1.1 200 OK\r\n\r\n\xff\xfe\x00";
+        assert_eq!(
+            snapshot_response(raw),
+            "HTTP/1.1 200 OK\n\n<3 bytes of binary data>"
+        );
+    }
+
+    // Snapshot of the built-in error pages
+    // Guards the exact bytes sent for 400, 404 and 405 responses
+    #[test]
+    fn test_snapshot_builtin_error_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let routes = HashMap::new();
+
+        let bad_request = send_request(b"GET / HTTP/1.1\r\n\r\n", base_dir, &routes);
+        assert_eq!(
+            snapshot_response(&bad_request),
+            "HTTP/1.1 400 Bad Request\nContent-Length: 0\n\n"
+        );
+
+        let not_found = send_request(
+            b"GET /missing.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &routes,
+        );
+        assert_eq!(
+            snapshot_response(&not_found),
+            "HTTP/1.1 404 Not Found\nContent-Type: text/plain\n\n"
+        );
+
+        // Non-GET requests for paths that do not exist are a 404, not a 405
+        let missing_post = send_request(
+            b"POST /missing.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &routes,
+        );
+        assert_eq!(
+            snapshot_response(&missing_post),
+            "HTTP/1.1 404 Not Found\nContent-Type: text/plain\n\n"
+        );
+
+        // Existing static files only allow GET
+        #[cfg(feature = "static")]
+        {
+            File::create(temp_dir.path().join("index.html")).unwrap();
+            let not_allowed = send_request(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                base_dir,
+                &routes,
+            );
+            assert_eq!(
+                snapshot_response(&not_allowed),
+                "HTTP/1.1 405 Method Not Allowed\nAllow: GET\nContent-Type: text/plain\n\n"
+            );
+        }
+    }
+
+    // Test 405 responses for routes registered under other methods
+    // The Allow header must list every method registered for the path
+    #[test]
+    fn test_router_method_not_allowed() {
+        let mut router = Router::new();
+        router.get("/items/{id}", || Response::ok("text/plain", "item"));
+        router.delete("/items/{id}", || Response::new(204, "No Content"));
+        router.post("/items", || Response::new(201, "Created"));
+
+        let response = route_request(&router, b"PUT /items/1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, DELETE"));
+        assert_eq!(router.allowed_methods("/items"), ["POST"]);
+
+        // Paths no route knows about are left to the static fallback
+        assert!(route_request(&router, b"PUT /other HTTP/1.1\r\n\r\n").is_none());
+
+        let mut stream =
+            MemoryStream::new(b"PUT /other HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(String::from_utf8_lossy(stream.output()).starts_with("HTTP/1.1 404"));
+    }
+
+    // Test the custom not-found handler
+    // It only answers when neither a route nor a static file matches
+    #[test]
+    fn test_router_not_found_handler() {
+        let temp_dir = TempDir::new().unwrap();
+        File::create(temp_dir.path().join("index.html")).unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+
+        let mut router = Router::new();
+        router.get("/api/hello", || Response::ok("text/plain", "hello"));
+        router.not_found(|request: Request| {
+            let body = format!(r#"{{"error": "no such path: {}"}}"#, request.path);
+            Response::new(404, "Not Found").with_body("application/json", body)
+        });
+
+        let response = serve_request(
+            b"GET /api/missing HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert_eq!(
+            snapshot_response(response.as_bytes()),
+            "HTTP/1.1 404 Not Found\nContent-Length: 39\nContent-Type: application/json\n\n{\"error\": \"no such path: /api/missing\"}"
+        );
+
+        let response = serve_request(
+            b"GET /api/hello HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(response.ends_with("hello"));
+        #[cfg(feature = "static")]
+        {
+            let response = serve_request(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                base_dir,
+                &router,
+            );
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+    }
+
+    // Test streaming response bodies
+    // Unsized streams are sent chunked, sized ones are copied as they are read
+    #[test]
+    fn test_response_streamed_bodies() {
+        let mut router = Router::new();
+        router.get("/lines", || {
+            let lines = (1..=3).map(|n| format!("line {}\n", n));
+            Response::ok("text/plain", Body::from_chunks(lines))
+        });
+        router.get("/reader", || {
+            Response::ok(
+                "text/plain",
+                Body::from_reader(Cursor::new(b"0123456789".to_vec())),
+            )
+            .with_header("Content-Length", "10")
+        });
+
+        let response = serve_request(
+            b"GET /lines HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "",
+            &router,
+        );
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n\
+             7\r\nline 1\n\r\n7\r\nline 2\n\r\n7\r\nline 3\n\r\n0\r\n\r\n"
+        );
+
+        let response = serve_request(
+            b"GET /reader HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "",
+            &router,
+        );
+        assert_eq!(
+            snapshot_response(response.as_bytes()),
+            "HTTP/1.1 200 OK\nContent-Length: 10\nContent-Type: text/plain\n\n0123456789"
+        );
+    }
+
+    // Test file downloads through a route
+    // The file is streamed with its length and an attachment disposition
+    #[test]
+    fn test_response_download() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("report.html");
+        File::create(&file_path)
+            .unwrap()
+            .write_all(b"<p>report</p>")
+            .unwrap();
+        let file_path = file_path.to_str().unwrap().to_string();
+
+        let mut router = Router::new();
+        router.get("/report", move || {
+            Response::download(&file_path, "report.html").unwrap()
+        });
+        let response = serve_request(
+            b"GET /report HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "",
+            &router,
+        );
+        assert_eq!(
+            snapshot_response(response.as_bytes()),
+            "HTTP/1.1 200 OK\nContent-Disposition: attachment; filename=\"report.html\"\n\
+             Content-Length: 13\nContent-Type: text/html\n\n<p>report</p>"
+        );
+
+        let response = Response::download(
+            temp_dir.path().join("report.html").to_str().unwrap(),
+            "r\u{e9}sum\u{e9} \"1\".txt",
+        )
+        .unwrap();
+        assert_eq!(
+            response.header("Content-Disposition"),
+            Some(
+                "attachment; filename=\"r_sum_ _1_.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%221%22.txt"
+            )
+        );
+        assert!(Response::download("/nonexistent/file", "file").is_err());
+    }
+
+    // Test rate limited response bodies
+    // The whole body still arrives, but no faster than the configured rate
+    #[test]
+    fn test_response_rate_limit() {
+        let response = Response::ok("text/plain", vec![b'x'; 100]).with_rate_limit(1000);
+        let started = std::time::Instant::now();
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(90));
+        assert!(output.ends_with(&[b'x'; 100]));
+    }
+
+    // Test handling of a connection closed before sending anything
+    // A zero-byte read must end the connection without writing a response
+    #[test]
+    fn test_handle_connection_zero_byte_read() {
+        let output = send_request(b"", "", &HashMap::new());
+        assert!(output.is_empty());
+    }
+
+    // Test a slow client that stays idle for a while before sending its request
+    // Verifies the connection is probed and the request still gets served
+    #[test]
+    fn test_handle_connection_idle_then_request() {
+        let mut stream = MemoryStream::default()
+            .then_error(ErrorKind::WouldBlock)
+            .then_error(ErrorKind::TimedOut)
+            .then_data("GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        handle_connection(&mut stream, "", &HashMap::new());
+
+        assert_eq!(stream.empty_writes(), 2);
+        assert!(String::from_utf8_lossy(stream.output()).starts_with("HTTP/1.1 404"));
+    }
+
+    // Test detection of half-open connections
+    // A failing probe or too many idle intervals must end the connection
+    #[test]
+    fn test_handle_connection_half_open() {
+        // A client that never sends anything, idling past every probe
+        let idle = (0..=MAX_IDLE_PROBES).fold(MemoryStream::default(), |stream, _| {
+            stream.then_error(ErrorKind::WouldBlock)
+        });
+
+        let mut dead = idle.clone().fail_writes_after(0, ErrorKind::BrokenPipe);
+        handle_connection(&mut dead, "", &HashMap::new());
+        assert_eq!(dead.empty_writes(), 1);
+        assert!(dead.output().is_empty());
+
+        let mut silent = idle;
+        handle_connection(&mut silent, "", &HashMap::new());
+        assert_eq!(silent.empty_writes(), MAX_IDLE_PROBES as usize);
+        assert!(silent.output().is_empty());
+    }
+
+    // Test clients hanging up while a response is written
+    // Failing writes must end the connection, never panic the worker
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_serve_connection_client_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("big.txt"), "x".repeat(100_000)).unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        let mut router = Router::new();
+        router.cache_static_files(crate::FileCache::new(1 << 20, 8));
+        router.get("/hello", || Response::ok("text/plain", "hello"));
+
+        for request in [
+            "GET /big.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /big.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /big.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-9\r\n\r\n",
+            "GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /hello HTTP/1.1\r\n\r\n",
+        ] {
+            let mut stream =
+                MemoryStream::new(request).fail_writes_after(4, ErrorKind::ConnectionReset);
+            crate::serve_connection(&mut stream, base_dir, &router);
+            assert_eq!(stream.output(), b"HTTP");
+        }
+    }
+
+    // Test the scripted MemoryStream
+    // Reads follow the script with short reads, writes fail past their limit
+    #[test]
+    fn test_memory_stream_script() {
+        let mut stream = MemoryStream::new("hello")
+            .then_error(ErrorKind::ConnectionReset)
+            .then_data("!")
+            .max_read(2)
+            .fail_writes_after(3, ErrorKind::BrokenPipe);
+
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(
+            stream.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(stream.write(b"abcd").unwrap(), 3);
+        assert_eq!(
+            stream.write(b"d").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(stream.output(), b"abc");
+
+        // A connection reset ends the connection like end of file does
+        let mut reset = MemoryStream::default().then_error(ErrorKind::ConnectionReset);
+        assert_eq!(read_or_probe(&mut reset, &mut buf), None);
+    }
+
+    // Test reading request heads spread over many reads
+    // Large heads arriving in small pieces must be reassembled before parsing
+    #[test]
+    fn test_read_request_head_across_reads() {
+        let mut router = Router::new();
+        router.get("/echo", |request: Request| {
+            let length = request.header("X-Long").unwrap().len();
+            Response::ok("text/plain", length.to_string())
+        });
+        let raw = format!(
+            "GET /echo HTTP/1.1\r\nHost: localhost\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(3000)
+        );
+
+        let mut stream = MemoryStream::new(raw.into_bytes()).max_read(7);
+        serve_connection(&mut stream, "", &router);
+        assert!(String::from_utf8_lossy(stream.output()).ends_with("\r\n\r\n3000"));
+    }
+
+    // Test requests whose head is too long or never finishes
+    // Oversized heads get a 431, heads cut off by the client a 400
+    #[test]
+    fn test_read_request_head_limits() {
+        let huge = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Huge: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_SIZE)
+        );
+        let response = send_request(huge.as_bytes(), "", &HashMap::new());
+        assert_eq!(
+            snapshot_response(&response),
+            "HTTP/1.1 431 Request Header Fields Too Large\nContent-Length: 0\n\n"
+        );
+
+        let response = send_request(b"GET / HTTP/1.1\r\nHost: loc", "", &HashMap::new());
+        assert_eq!(
+            snapshot_response(&response),
+            "HTTP/1.1 400 Bad Request\nContent-Length: 0\n\n"
+        );
+    }
+
+    // Test read sizes and request limits configured on the router
+    // Heads and bodies must be reassembled from small reads, and the limits
+    // must replace MAX_HEAD_SIZE and MAX_BODY_SIZE in both directions
+    #[test]
+    fn test_read_request_configured_limits() {
+        let mut router = Router::new();
+        router.post("/upload", |request: Request| {
+            let length = request.header("X-Long").map_or(0, str::len);
+            Response::ok("text/plain", format!("{} {}", length, request.body.len()))
+        });
+        router
+            .read_buffer_size(16)
+            .max_head_size(4 * MAX_HEAD_SIZE)
+            .body_chunk_size(7)
+            .max_body_size(100);
+        let request = |header: usize, body: usize| {
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: localhost\r\nX-Long: {}\r\nContent-Length: {}\r\n\r\n{}",
+                "a".repeat(header),
+                body,
+                "b".repeat(body)
+            )
+        };
+
+        let mut stream = MemoryStream::new(request(2 * MAX_HEAD_SIZE, 100).into_bytes());
+        serve_connection(&mut stream, "", &router);
+        let response = String::from_utf8_lossy(stream.output()).to_string();
+        assert!(response.ends_with(&format!("{} 100", 2 * MAX_HEAD_SIZE)));
+
+        let mut stream = MemoryStream::new(request(4 * MAX_HEAD_SIZE, 0).into_bytes());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().starts_with(b"HTTP/1.1 431 "));
+
+        let mut stream = MemoryStream::new(request(10, 101).into_bytes());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().starts_with(b"HTTP/1.1 413 "));
+    }
+
+    // Test handlers streaming the request body through a BodyReader
+    // Chunked and oversized bodies are read as the handler goes, and handlers
+    // that don't read it never trigger 100 Continue
+    #[test]
+    fn test_stream_request_body() {
+        let mut router = Router::new();
+        router
+            .post("/upload", |mut body: BodyReader| {
+                let mut received = Vec::new();
+                match body.read_to_end(&mut received) {
+                    Ok(len) => Response::ok("text/plain", format!("{} {}", len, received[len - 1])),
+                    Err(e) => Response::bad_request(&e.to_string()),
+                }
+            })
+            .stream_body();
+        router
+            .post("/reject", |_: Request| Response::new(403, "Forbidden"))
+            .stream_body();
+        router.max_body_size(10).body_chunk_size(3);
+
+        let raw = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n10\r\n0123456789abcdef\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut stream = MemoryStream::new(raw.to_vec()).max_read(4);
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().ends_with(b"\r\n21 102"));
+
+        let body = "x".repeat(1000);
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\n{}",
+            body
+        );
+        let mut stream = MemoryStream::new(raw.into_bytes());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().ends_with(b"\r\n1000 120"));
+
+        let raw = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX";
+        let mut stream = MemoryStream::new(raw.to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().starts_with(b"HTTP/1.1 400 "));
+
+        let raw = b"POST /reject HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n";
+        let mut stream = MemoryStream::new(raw.to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().starts_with(b"HTTP/1.1 403 "));
+    }
+
+    // Test saving uploads to temporary files
+    // Files are removed unless persisted, also when the size limit is exceeded
+    #[test]
+    fn test_upload_to_temp_file() {
+        let dir = TempDir::new().unwrap();
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+        let upload = Upload::new().dir(dir.path()).max_size(10).sync(true);
+
+        let mut file = upload.save(&b"0123456789"[..]).unwrap();
+        assert_eq!(file.len(), 10);
+        assert!(file.path().starts_with(dir.path()));
+        let mut saved = String::new();
+        file.read_to_string(&mut saved).unwrap();
+        assert_eq!(saved, "0123456789");
+        let target = dir.path().join("saved.txt");
+        file.persist(&target).unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "0123456789");
+        assert_eq!(files(), 1);
+
+        let error = upload.save(&b"0123456789a"[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::FileTooLarge);
+        assert_eq!(files(), 1);
+
+        drop(upload.save(&b"dropped"[..]).unwrap());
+        upload.save(&b"discarded"[..]).unwrap().discard().unwrap();
+        assert_eq!(files(), 1);
+
+        // Streamed request bodies are saved as they arrive
+        let mut router = Router::new();
+        let uploads = upload.clone();
+        router
+            .put("/upload", move |body: BodyReader| {
+                match uploads.save(body) {
+                    Ok(file) => Response::ok("text/plain", file.len().to_string()),
+                    Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                        Response::new(413, "Payload Too Large")
+                    }
+                    Err(e) => Response::bad_request(&e.to_string()),
+                }
+            })
+            .stream_body();
+        let raw = b"PUT /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n0\r\n\r\n";
+        let mut stream = MemoryStream::new(raw.to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().ends_with(b"\r\n4"));
+        let raw =
+            b"PUT /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\n0123456789a";
+        let mut stream = MemoryStream::new(raw.to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().starts_with(b"HTTP/1.1 413 "));
+        assert_eq!(files(), 1);
+    }
+
+    // Test resumable uploads over the tus protocol
+    // Appends resume after a dropped connection, bad checksums roll back
+    #[test]
+    #[cfg(feature = "tus")]
+    fn test_tus_upload() {
+        let dir = TempDir::new().unwrap();
+        let (completed, finished) = std::sync::mpsc::channel();
+        let completed = std::sync::Mutex::new(completed);
+        let tus = crate::Tus::new(dir.path())
+            .max_size(100)
+            .on_complete(move |path, metadata| {
+                let data = std::fs::read_to_string(path).unwrap();
+                let _ = completed.lock().unwrap().send((data, metadata.to_vec()));
+            });
+        let mut router = Router::new();
+        router.tus("/files", tus);
+        let send = |raw: &str| {
+            let mut stream = MemoryStream::new(raw.as_bytes().to_vec());
+            serve_connection(&mut stream, "", &router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+        let patch = |location: &str, offset: usize, extra: &str, body: &str| {
+            send(&format!(
+                "PATCH {} HTTP/1.1\r\nHost: localhost\r\nTus-Resumable: 1.0.0\r\nContent-Type: application/offset+octet-stream\r\nUpload-Offset: {}\r\n{}\r\n{}",
+                location, offset, extra, body
+            ))
+        };
+
+        let response = send("OPTIONS /files HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        assert!(response.contains("Tus-Extension: creation,checksum\r\n"));
+        assert!(response.contains("Tus-Max-Size: 100\r\n"));
+        let response = send("POST /files HTTP/1.1\r\nHost: localhost\r\nUpload-Length: 11\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 412 "));
+        let response = send(
+            "POST /files HTTP/1.1\r\nHost: localhost\r\nTus-Resumable: 1.0.0\r\nUpload-Length: 101\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 413 "));
+
+        let response = send(
+            "POST /files HTTP/1.1\r\nHost: localhost\r\nTus-Resumable: 1.0.0\r\nUpload-Length: 11\r\nUpload-Metadata: filename aGVsbG8udHh0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 201 "));
+        assert!(response.contains("Tus-Resumable: 1.0.0\r\n"));
+        let location = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Location: "))
+            .unwrap()
+            .to_string();
+        assert!(location.starts_with("/files/"));
+
+        let checksum = "Upload-Checksum: sha1 xNhxrROtAP3pp7t/9+0lQ67FQkE=\r\n";
+        let response = patch(
+            &location,
+            0,
+            &format!("{}Content-Length: 6\r\n", checksum),
+            "hello ",
+        );
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        assert!(response.contains("Upload-Offset: 6\r\n"));
+        let response = patch(&location, 0, "Content-Length: 5\r\n", "world");
+        assert!(response.starts_with("HTTP/1.1 409 "));
+        let response = patch(
+            &location,
+            6,
+            &format!("{}Content-Length: 5\r\n", checksum),
+            "world",
+        );
+        assert!(response.starts_with("HTTP/1.1 460 "));
+
+        // The connection drops after three bytes, which are kept
+        let response = patch(&location, 6, "Content-Length: 5\r\n", "wor");
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        let response = send(&format!(
+            "HEAD {} HTTP/1.1\r\nHost: localhost\r\nTus-Resumable: 1.0.0\r\n\r\n",
+            location
+        ));
+        assert!(response.contains("Upload-Offset: 9\r\n"));
+        assert!(response.contains("Upload-Length: 11\r\n"));
+        assert!(finished.try_recv().is_err());
+
+        let response = patch(
+            &location,
+            9,
+            "Transfer-Encoding: chunked\r\n",
+            "2\r\nld\r\n0\r\n\r\n",
+        );
+        assert!(response.contains("Upload-Offset: 11\r\n"));
+        let (data, metadata) = finished.try_recv().unwrap();
+        assert_eq!(data, "hello world");
+        assert_eq!(
+            metadata,
+            vec![("filename".to_string(), "hello.txt".to_string())]
+        );
+    }
+
+    // Test publishing files to the static root with PUT
+    // Writes go through the publish middleware, stay under the base directory
+    // and respect the size limit; routed paths keep answering 405
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_publish_static_files() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        let mut router = Router::new();
+        router.get("/api", || Response::ok("text/plain", "api"));
+        router.publish(crate::Publish::new().max_size(10).layer(
+            |request: &Request, next: &Next| match request.header("Authorization") {
+                Some("Bearer secret") => next.run(request),
+                _ => Response::new(401, "Unauthorized"),
+            },
+        ));
+        let put = |path: &str, headers: &str, body: &str| {
+            let raw = format!(
+                "PUT {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n{}",
+                path, headers, body
+            );
+            let mut stream = MemoryStream::new(raw.into_bytes());
+            serve_connection(&mut stream, base_dir, &router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let response = put("/docs/a.txt", "Content-Length: 5\r\n", "hello");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+        assert!(!dir.path().join("docs").exists());
+
+        let response = put(
+            "/docs/a.txt",
+            &format!("{}Content-Length: 5\r\n", auth),
+            "hello",
+        );
+        assert!(response.starts_with("HTTP/1.1 201 "));
+        assert!(response.contains("Location: /docs/a.txt\r\n"));
+        let chunked = format!("{}Transfer-Encoding: chunked\r\n", auth);
+        let response = put("/docs/a.txt", &chunked, "3\r\nbye\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        let mut stream =
+            MemoryStream::new(b"GET /docs/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+        serve_connection(&mut stream, base_dir, &router);
+        assert!(stream.output().ends_with(b"\r\n\r\nbye"));
+
+        let response = put(
+            "/docs/b.txt",
+            &format!("{}Content-Length: 11\r\n", auth),
+            "01234567890",
+        );
+        assert!(response.starts_with("HTTP/1.1 413 "));
+        let response = put("/docs/b.txt", &chunked, "b\r\n01234567890\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 "));
+        let response = put(
+            "/docs/../../b.txt",
+            &format!("{}Content-Length: 1\r\n", auth),
+            "x",
+        );
+        assert!(response.starts_with("HTTP/1.1 403 "));
+        let response = put("/api", &format!("{}Content-Length: 1\r\n", auth), "x");
+        assert!(response.starts_with("HTTP/1.1 405 "));
+        let entries: Vec<_> = std::fs::read_dir(dir.path().join("docs"))
+            .unwrap()
+            .collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    // Test removing published files with DELETE
+    // Only paths under the writable prefixes can be written or removed
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_publish_delete_and_writable_prefixes() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        std::fs::create_dir_all(dir.path().join("releases/v1")).unwrap();
+        std::fs::write(dir.path().join("releases/v1/app.bin"), "app").unwrap();
+        std::fs::write(dir.path().join("index.html"), "home").unwrap();
+        let mut router = Router::new();
+        router.publish(
+            crate::Publish::new()
+                .writable("/releases/")
+                .writable("/uploads")
+                .unauthenticated(),
+        );
+        let send = |method: &str, path: &str| {
+            let raw = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nx",
+                method, path
+            );
+            let mut stream = MemoryStream::new(raw.into_bytes());
+            serve_connection(&mut stream, base_dir, &router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+
+        assert!(send("DELETE", "/releases/v1/app.bin").starts_with("HTTP/1.1 204 "));
+        assert!(!dir.path().join("releases/v1/app.bin").exists());
+        assert!(send("DELETE", "/releases/v1/app.bin").starts_with("HTTP/1.1 404 "));
+        assert!(send("DELETE", "/releases/v1").starts_with("HTTP/1.1 409 "));
+        assert!(send("DELETE", "/index.html").starts_with("HTTP/1.1 403 "));
+        assert!(dir.path().join("index.html").exists());
+
+        assert!(send("PUT", "/uploads/a.txt").starts_with("HTTP/1.1 201 "));
+        assert!(send("PUT", "/uploadsx/a.txt").starts_with("HTTP/1.1 403 "));
+        assert!(send("PUT", "/index.html").starts_with("HTTP/1.1 403 "));
+    }
+
+    // Test the paths files are published at
+    // Paths are decoded like static files; links can't lead writes outside and
+    // hidden files, override files above all, are out of reach
+    #[test]
+    #[cfg(all(feature = "static", unix))]
+    fn test_publish_paths_and_links() {
+        let dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        std::fs::create_dir(dir.path().join("files")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("files", dir.path().join("inner")).unwrap();
+        let send = |router: &Router, method: &str, path: &str| {
+            let raw = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nx",
+                method, path
+            );
+            let mut stream = MemoryStream::new(raw.into_bytes());
+            serve_connection(&mut stream, base_dir, router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+        let mut router = Router::new();
+        router.publish(
+            crate::Publish::new()
+                .writable("/files/my dir")
+                .unauthenticated(),
+        );
+
+        // Reads and writes map encoded paths the same way
+        let response = send(&router, "PUT", "/files/my%20dir/a%20b.txt");
+        assert!(response.starts_with("HTTP/1.1 201 "), "{}", response);
+        assert!(dir.path().join("files/my dir/a b.txt").exists());
+        let response = send(&router, "GET", "/files/my%20dir/a%20b.txt");
+        assert!(response.ends_with("\r\n\r\nx"), "{}", response);
+        assert!(send(&router, "PUT", "/files/my%2Fdir/a.txt").starts_with("HTTP/1.1 403 "));
+        assert!(send(&router, "PUT", "/files/%2e%2e/x.txt").starts_with("HTTP/1.1 403 "));
+
+        // Hidden files and override files can't be written or removed
+        let mut router = Router::new();
+        router
+            .directory_overrides()
+            .allow_hidden("/uploads/.well-known/**")
+            .publish(crate::Publish::new().writable("/uploads").unauthenticated());
+        std::fs::create_dir(dir.path().join("uploads")).unwrap();
+        std::fs::write(dir.path().join("uploads/.webserver.toml"), "").unwrap();
+        for path in [
+            "/uploads/.webserver.toml",
+            "/uploads/%2Ewebserver.toml",
+            "/uploads/.git/config",
+            "/uploads/.well-known/.webserver.toml",
+        ] {
+            assert!(
+                send(&router, "PUT", path).starts_with("HTTP/1.1 403 "),
+                "{}",
+                path
+            );
+            assert!(
+                send(&router, "DELETE", path).starts_with("HTTP/1.1 403 "),
+                "{}",
+                path
+            );
+        }
+        assert!(dir.path().join("uploads/.webserver.toml").exists());
+        assert!(!dir.path().join("uploads/.git").exists());
+        let response = send(&router, "PUT", "/uploads/.well-known/key.txt");
+        assert!(response.starts_with("HTTP/1.1 201 "), "{}", response);
+
+        // Links leading out of the base directory are never written through
+        let mut router = Router::new();
+        router.publish(crate::Publish::new().unauthenticated());
+        assert!(send(&router, "PUT", "/link/pwned.txt").starts_with("HTTP/1.1 403 "));
+        assert!(send(&router, "PUT", "/link/new/pwned.txt").starts_with("HTTP/1.1 403 "));
+        assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+        assert!(send(&router, "PUT", "/inner/a.txt").starts_with("HTTP/1.1 201 "));
+        router.symlinks(crate::SymlinkPolicy::Deny);
+        assert!(send(&router, "PUT", "/inner/b.txt").starts_with("HTTP/1.1 403 "));
+        assert!(send(&router, "DELETE", "/inner/a.txt").starts_with("HTTP/1.1 403 "));
+
+        // Publishing needs a layer, or saying it goes without
+        let unguarded =
+            std::panic::catch_unwind(|| Router::new().publish(crate::Publish::new()).clone());
+        assert!(unguarded.is_err());
+    }
+
+    // Test storage quotas on published files
+    // Uploads exceeding the tightest quota get 507, replaced files count as free
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_publish_quotas() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        std::fs::create_dir_all(dir.path().join("team/a")).unwrap();
+        std::fs::write(dir.path().join("team/a/old.bin"), "12345").unwrap();
+        std::fs::write(dir.path().join("other.bin"), "123").unwrap();
+        let mut router = Router::new();
+        router.publish(
+            crate::Publish::new()
+                .quota("/", 12)
+                .quota("/team/", 8)
+                .unauthenticated(),
+        );
+        let put = |path: &str, body: &str| {
+            let raw = format!(
+                "PUT {} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                path,
+                body.len(),
+                body
+            );
+            let mut stream = MemoryStream::new(raw.into_bytes());
+            serve_connection(&mut stream, base_dir, &router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+
+        assert!(put("/team/a/new.bin", "1234").starts_with("HTTP/1.1 507 "));
+        assert!(put("/team/a/new.bin", "123").starts_with("HTTP/1.1 201 "));
+        assert!(put("/team/a/old.bin", "12").starts_with("HTTP/1.1 204 "));
+        assert!(put("/more.bin", "12345").starts_with("HTTP/1.1 507 "));
+        assert!(put("/more.bin", "1234").starts_with("HTTP/1.1 201 "));
+
+        let server = Server::new(router.clone(), base_dir);
+        let usage = server.storage_usage();
+        assert_eq!(
+            (usage[0].prefix.as_str(), usage[0].used, usage[0].limit),
+            ("/", 12, 12)
+        );
+        assert_eq!(
+            (usage[1].prefix.as_str(), usage[1].used, usage[1].limit),
+            ("/team/", 5, 8)
+        );
+    }
+
+    // Test the administration endpoints
+    // Requests need an allowed address and the token; drain stops the server
+    #[test]
+    fn test_admin_endpoints() {
+        let reloads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = std::sync::Arc::clone(&reloads);
+        let admin = AdminConfig::new("s3cret").on_reload(move |server| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut router = Router::new();
+            router.get("/", || Response::ok("text/plain", "reloaded"));
+            server.reload(router, "");
+            Ok(())
+        });
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        let server = Server::new(router, "").config(ServerConfig {
+            admin: Some(admin),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = server.start(listener).unwrap();
+        let send = |request: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let auth = "Host: localhost\r\nAuthorization: Bearer s3cret\r\nConnection: close\r\n";
+
+        let response =
+            send("GET /_admin/status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("WWW-Authenticate: Bearer realm=\"admin\"\r\n"));
+        let response = send(&format!("GET /_admin/status HTTP/1.1\r\n{}\r\n", auth));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.contains("\"accepting\":true"));
+        assert!(response.contains(&format!("\"listeners\":[\"{}\"]", addr)));
+        let response = send(&format!("GET /_admin/config HTTP/1.1\r\n{}\r\n", auth));
+        assert!(response.contains("AdminConfig"));
+        assert!(!response.contains("s3cret"));
+
+        // Reloading keeps the endpoints on the new router
+        let response = send(&format!("POST /_admin/reload HTTP/1.1\r\n{}\r\n", auth));
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(reloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(
+            send("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .ends_with("reloaded")
+        );
+        let response = send(&format!("POST /_admin/drain HTTP/1.1\r\n{}\r\n", auth));
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        acceptor.join().unwrap();
+
+        // Other addresses are refused, token or not
+        let server = Server::new(Router::new(), "").config(ServerConfig {
+            admin: Some(AdminConfig::new("s3cret").allow(&["10.0.0.0/8"])),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        write!(client, "GET /_admin/status HTTP/1.1\r\n{}\r\n", auth).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        server.drain();
+    }
+
+    // Test the administration endpoints without the metrics feature
+    // The statistics endpoints are left out, the others are still served
+    #[test]
+    #[cfg(not(feature = "metrics"))]
+    fn test_admin_endpoints_without_metrics() {
+        let server = Server::new(Router::new(), "").config(ServerConfig {
+            admin: Some(AdminConfig::new("s3cret")),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let status_of = |path: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\
+                 Connection: close\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response[9..12].to_string()
+        };
+
+        assert_eq!(status_of("/_admin/status"), "200");
+        assert_eq!(status_of("/_admin/stats"), "404");
+        assert_eq!(status_of("/_admin/metrics"), "404");
+        assert_eq!(status_of("/_status"), "404");
+        server.drain();
+    }
+
+    // Test the runtime statistics
+    // Requests, statuses and bytes are counted, including after a reload
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_server_stats() {
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        let server = Server::new(router, "");
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        router.get("/stats", server.stats_handler());
+        server.reload(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let send = |request: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let home = send("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        send("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        send("GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        send("GET / HTTP/1.1\r\n\r\n");
+        // Workers may still be finishing after the client saw the response
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while server.stats().active_connections > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let stats = server.stats();
+        assert_eq!(stats.total_requests, 4);
+        assert_eq!(stats.total_connections, 4);
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(
+            stats.statuses.into_iter().collect::<Vec<_>>(),
+            vec![(200, 2), (400, 1), (404, 1)]
+        );
+        assert!(stats.bytes_out >= 2 * home.len() as u64);
+        assert!(stats.bytes_in > 100);
+
+        let response = send("GET /stats HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.contains("\"total_requests\":4,"));
+        assert!(response.ends_with("\"statuses\":{\"200\":2,\"400\":1,\"404\":1}}"));
+        server.drain();
+    }
+
+    // Test changing the routes of a running server
+    // Added routes are served right away and removed ones fall through to 404
+    #[test]
+    fn test_server_update_router() {
+        let mut router = Router::new();
+        router.get("/old", || Response::ok("text/plain", "old"));
+        router.scope("/api", |api| {
+            api.get("/items", || Response::ok("text/plain", "items"));
+        });
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let get = |path: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(get("/old").ends_with("old"));
+
+        server.update_router(|router| {
+            assert!(router.remove_route("GET", "/old"));
+            assert!(!router.remove_route("POST", "/api/items"));
+            router.get("/new", || Response::ok("text/plain", "new"));
+        });
+        assert!(get("/old").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("/new").ends_with("new"));
+        assert!(get("/api/items").ends_with("items"));
+
+        server.update_router(|router| {
+            assert!(router.remove_route(crate::Method::Get, "/api/items"));
+        });
+        assert!(get("/api/items").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("/new").ends_with("new"));
+        server.drain();
+    }
+
+    // Test replacing a route handler while it is registered
+    // Swaps bump the version, rollback returns to the handler served before
+    #[test]
+    fn test_hot_swap_handler() {
+        let handler = crate::HotSwap::new(|| Response::ok("text/plain", "blue"));
+        let mut router = Router::new();
+        router.get("/color", handler.clone());
+        let body = |router: &Router| {
+            let response = route_request(router, b"GET /color HTTP/1.1\r\n\r\n").unwrap();
+            String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+        };
+        assert_eq!(handler.version(), 1);
+        assert_eq!(body(&router), "blue");
+
+        assert_eq!(
+            handler.swap(|Text(text): Text| Response::ok("text/plain", format!("green{}", text))),
+            2
+        );
+        assert_eq!(body(&router), "green");
+        assert_eq!(handler.rollback(), Some(1));
+        assert_eq!(body(&router), "blue");
+        assert_eq!(handler.rollback(), Some(2));
+        assert_eq!(body(&router), "green");
+
+        // A request in flight finishes with the version it started with
+        let (started, release) = (
+            std::sync::Arc::new(std::sync::Barrier::new(2)),
+            std::sync::Arc::new(std::sync::Barrier::new(2)),
+        );
+        let (entered, proceed) = (started.clone(), release.clone());
+        handler.swap(move || {
+            entered.wait();
+            proceed.wait();
+            Response::ok("text/plain", "slow")
+        });
+        std::thread::scope(|scope| {
+            let slow = scope.spawn(|| body(&router));
+            started.wait();
+            assert_eq!(handler.swap(|| Response::ok("text/plain", "fast")), 4);
+            release.wait();
+            assert_eq!(slow.join().unwrap(), "slow");
+        });
+        assert_eq!(body(&router), "fast");
+        assert_eq!(format!("{:?}", handler), "HotSwap { version: 4 }");
+    }
+
+    // Test extending a server with a plugin
+    // Its routes and middleware survive reloads, and it is stopped only once
+    #[test]
+    fn test_server_plugin() {
+        struct Greeter {
+            events: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+        }
+        impl crate::Plugin for Greeter {
+            fn name(&self) -> &str {
+                "greeter"
+            }
+            fn init(&self, server: &Server) -> std::io::Result<()> {
+                let uptime = server.uptime();
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("init {}", uptime.as_secs()));
+                Ok(())
+            }
+            fn register(&self, router: &mut Router) {
+                self.events.lock().unwrap().push("register".to_string());
+                router.get("/hello", || Response::ok("text/plain", "hello"));
+                router.layer(|request: &Request, next: &Next| {
+                    next.run(request).with_header("X-Greeter", "1")
+                });
+            }
+            fn shutdown(&self) {
+                self.events.lock().unwrap().push("shutdown".to_string());
+            }
+        }
+        struct Broken;
+        impl crate::Plugin for Broken {
+            fn name(&self) -> &str {
+                "broken"
+            }
+            fn init(&self, _: &Server) -> std::io::Result<()> {
+                Err(std::io::Error::other("no config"))
+            }
+            fn register(&self, _: &mut Router) {
+                panic!("a plugin failing to start must not register");
+            }
+        }
+
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        let server = Server::new(router, "")
+            .plugin(Greeter {
+                events: events.clone(),
+            })
+            .unwrap();
+        let error = server.clone().plugin(Broken).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "plugin broken failed to start: no config"
+        );
+        let get = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            let mut stream = MemoryStream::new(request.as_bytes());
+            serve_connection(&mut stream, "", &server.site().router);
+            String::from_utf8_lossy(stream.output()).to_string()
+        };
+        let response = get("/hello");
+        assert!(response.contains("X-Greeter: 1\r\n"));
+        assert!(response.ends_with("hello"));
+        assert!(get("/").contains("X-Greeter: 1\r\n"));
+
+        server.reload(Router::new(), "");
+        assert!(get("/hello").ends_with("hello"));
+        server.shutdown_plugins();
+        server.shutdown_plugins();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["init 0", "register", "register", "shutdown"]
+        );
+    }
+
+    // Test route handlers implemented in WebAssembly
+    // The module echoes the request; runaway and invalid modules are contained
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_wasm_handler() {
+        // Answers with a fixed head followed by the request it was given
+        let echo = r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "HTTP/1.1 200 OK\0d\0aContent-Type: text/plain\0d\0a\0d\0a")
+            (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "handle") (param $ptr i32) (param $len i32) (result i64)
+                (memory.copy (i32.const 45) (local.get $ptr) (local.get $len))
+                (i64.extend_i32_u (i32.add (i32.const 45) (local.get $len)))))"#;
+        let handler = crate::WasmHandler::from_bytes(echo.as_bytes()).unwrap();
+        let mut router = Router::new();
+        router.post("/echo", handler);
+        let response = route_request(
+            &router,
+            b"POST /echo?x=1 HTTP/1.1\r\nContent-Length: 4\r\n\r\nping",
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        let body = String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(body.starts_with("POST /echo?x=1 HTTP/1.1\r\n"));
+        assert!(body.contains("Content-Length: 4\r\n"));
+        assert!(body.ends_with("\r\n\r\nping"));
+
+        // Running out of fuel fails the request, not the server
+        let spin = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "handle") (param i32 i32) (result i64) (loop (br 0)) (i64.const 0)))"#;
+        let handler = crate::WasmHandler::from_bytes(spin.as_bytes())
+            .unwrap()
+            .fuel(10_000);
+        let mut router = Router::new();
+        router.get("/spin", handler);
+        let response = route_request(&router, b"GET /spin HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 500);
+
+        // Modules importing anything or lacking the ABI are refused
+        let imports = r#"(module (import "env" "f" (func)) (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "handle") (param i32 i32) (result i64) (i64.const 0)))"#;
+        assert!(crate::WasmHandler::from_bytes(imports.as_bytes()).is_err());
+        let error = crate::WasmHandler::from_bytes(b"(module)").unwrap_err();
+        assert_eq!(error.to_string(), "module: missing export \"memory\"");
+        assert!(crate::WasmHandler::from_bytes(b"not wasm").is_err());
+    }
+
+    // Test route handlers written as Rhai scripts
+    // Paths map to script files, which are recompiled once they change
+    #[test]
+    #[cfg(feature = "scripting")]
+    fn test_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("index.rhai"), r#""home""#).unwrap();
+        std::fs::write(
+            dir.join("users.rhai"),
+            r#"
+            if request.method == "POST" {
+                let user = parse_json(request.body);
+                #{ status: 201, headers: #{ "Location": `/api/users/${user.name}` } }
+            } else if request.rest == "" {
+                #{ body: #{ page: request.query.page } }
+            } else {
+                `user ${request.rest}`
+            }
+            "#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("spin.rhai"), "loop {}").unwrap();
+        let mut router = Router::new();
+        router.scripts("/api", crate::Scripts::new(dir).max_operations(10_000));
+
+        let body = |response: &Response| response.body.as_bytes().unwrap().to_vec();
+        let response = route_request(&router, b"GET /api HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(body(&response), b"home");
+        let response = route_request(&router, b"GET /api/users?page=2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(body(&response), br#"{"page":"2"}"#);
+        let response = route_request(&router, b"GET /api/users/42 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(body(&response), b"user 42");
+        let response = route_request(
+            &router,
+            b"POST /api/users HTTP/1.1\r\nContent-Length: 16\r\n\r\n{\"name\":\"alice\"}",
+        )
+        .unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/api/users/alice"));
+
+        // Runaway scripts are stopped, escaping the directory is refused
+        let response = route_request(&router, b"GET /api/spin HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 500);
+        let response = route_request(&router, b"GET /api/%2e%2e/index HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 404);
+
+        // An edited script is picked up by the next request
+        std::fs::write(dir.join("index.rhai"), "418").unwrap();
+        let file = File::options()
+            .write(true)
+            .open(dir.join("index.rhai"))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+        let response = route_request(&router, b"GET /api HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 418);
+    }
+
+    // Test serving assets held in memory
+    // Files answer with content-based validators, index.html serves the prefix
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_embedded_assets() {
+        let assets = crate::embed_assets!("src", ["mime.rs"])
+            .add("index.html", "<h1>Home</h1>".as_bytes())
+            .add("docs/index.html", b"<h1>Docs</h1>".to_vec());
+        assert_eq!(assets.len(), 3);
+        assert_eq!(assets.get("/mime.rs"), Some(&include_bytes!("mime.rs")[..]));
+        let mut router = Router::new();
+        router.assets("/site", assets);
+
+        let body = |response: &Response| response.body.as_bytes().unwrap().to_vec();
+        let response = route_request(&router, b"GET /site HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        assert_eq!(body(&response), b"<h1>Home</h1>");
+        let response = route_request(&router, b"GET /site/docs/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(body(&response), b"<h1>Docs</h1>");
+        let response = route_request(&router, b"GET /site/mime.rs HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(body(&response), include_bytes!("mime.rs"));
+        let response = route_request(&router, b"GET /site/missing.css HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 404);
+
+        // The ETag only depends on the content
+        let response = route_request(&router, b"GET /site/ HTTP/1.1\r\n\r\n").unwrap();
+        let etag = response.header("ETag").unwrap().to_string();
+        let same: crate::Assets = [("index.html", "<h1>Home</h1>".as_bytes())]
+            .into_iter()
+            .collect();
+        let mut other = Router::new();
+        other.assets("/", same);
+        let raw = format!("GET / HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag);
+        let response = route_request(&other, raw.as_bytes()).unwrap();
+        assert_eq!(response.status, 304);
+    }
+
+    // Test caching compressed bodies on disk
+    // A version is compressed once, then served from its cache file
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression_cache() {
+        use std::io::Read;
+        use std::sync::{Arc, Mutex};
+
+        let temp_dir = TempDir::new().unwrap();
+        let cache_dir = temp_dir.path().join("gzip");
+        let version = Arc::new(Mutex::new("\"v1\""));
+        let mut router = Router::new();
+        router.layer(crate::Compression::new().min_size(0).cache_dir(&cache_dir));
+        let current = Arc::clone(&version);
+        router.get("/app.js", move || {
+            let etag = *current.lock().unwrap();
+            Response::ok("application/javascript", format!("let version = {};", etag))
+                .with_header("ETag", etag)
+        });
+        router.get("/live", || Response::ok("text/plain", "no validator"));
+
+        let fetch = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", path);
+            let response = route_request(&router, raw.as_bytes()).unwrap();
+            let length = response.header("Content-Length").map(str::to_string);
+            // Read to the end like the server does, which completes the cache file
+            let mut compressed = Vec::new();
+            response
+                .body
+                .into_reader()
+                .read_to_end(&mut compressed)
+                .unwrap();
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_string(&mut decoded)
+                .unwrap();
+            (decoded, length)
+        };
+        let cache_files = || std::fs::read_dir(&cache_dir).unwrap().count();
+
+        // The first request compresses and fills the cache
+        let (body, length) = fetch("/app.js");
+        assert_eq!(body, "let version = \"v1\";");
+        assert!(length.is_none());
+        assert_eq!(cache_files(), 1);
+
+        // The next one is answered from the file, with its length
+        let (body, length) = fetch("/app.js");
+        assert_eq!(body, "let version = \"v1\";");
+        let entry = std::fs::read_dir(&cache_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let cached_len = entry.metadata().unwrap().len();
+        assert_eq!(length, Some(cached_len.to_string()));
+
+        // A new version gets its own entry, responses without validators none
+        *version.lock().unwrap() = "\"v2\"";
+        assert_eq!(fetch("/app.js").0, "let version = \"v2\";");
+        assert_eq!(fetch("/live").0, "no validator");
+        assert_eq!(cache_files(), 2);
+
+        // Query strings don't make new entries, so they can't fill the disk
+        for query in 0..5 {
+            let (body, _) = fetch(&format!("/app.js?n={}", query));
+            assert_eq!(body, "let version = \"v2\";");
+        }
+        assert_eq!(cache_files(), 2);
+    }
+
+    // Test the caching reverse proxy
+    // Upstream freshness and validators decide what is stored and revalidated
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_caching_proxy() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let mut upstream = Router::new();
+        let count = Arc::clone(&hits);
+        upstream.get("/fresh", move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            Response::ok("text/plain", "fresh").with_header("Cache-Control", "max-age=60")
+        });
+        upstream.post("/fresh", || {
+            Response::from_status(crate::StatusCode::NO_CONTENT)
+        });
+        let count = Arc::clone(&hits);
+        upstream.get("/validated", move |request: Request| {
+            count.fetch_add(1, Ordering::SeqCst);
+            if request.header("If-None-Match") == Some("\"v1\"") {
+                return Response::from_status(crate::StatusCode::NOT_MODIFIED)
+                    .with_header("ETag", "\"v1\"");
+            }
+            Response::ok("text/plain", "validated")
+                .with_header("Cache-Control", "no-cache")
+                .with_header("ETag", "\"v1\"")
+        });
+        upstream.get("/private", || {
+            Response::ok("text/plain", "mine").with_header("Cache-Control", "private, max-age=60")
+        });
+        upstream.get("/headers", |request: Request| {
+            let host = request.header("Host").unwrap_or("").to_string();
+            let forwarded = request.header("X-Forwarded-Host").unwrap_or("").to_string();
+            Response::ok("text/plain", format!("{} {}", host, forwarded))
+        });
+        upstream.get("/stream", || {
+            Response::ok("text/plain", Body::from_chunks(["one ", "two"]))
+        });
+        let server = Server::new(upstream, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let cache = crate::ProxyCache::new(100);
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&format!("http://{}", addr)).cache(cache.clone()),
+        );
+        let fetch = |raw: &str| {
+            let response = route_request(&router, raw.as_bytes()).unwrap();
+            let x_cache = response.header("X-Cache").unwrap_or("").to_string();
+            let mut body = Vec::new();
+            response.body.into_reader().read_to_end(&mut body).unwrap();
+            (response.status, x_cache, String::from_utf8(body).unwrap())
+        };
+        let get = |path: &str| fetch(&format!("GET {} HTTP/1.1\r\nHost: site.test\r\n\r\n", path));
+
+        // Fresh responses are answered from the cache until invalidated
+        assert_eq!(
+            get("/fresh"),
+            (200, "MISS".to_string(), "fresh".to_string())
+        );
+        assert_eq!(get("/fresh"), (200, "HIT".to_string(), "fresh".to_string()));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let (status, _, _) = fetch("POST /fresh HTTP/1.1\r\nHost: site.test\r\n\r\n");
+        assert_eq!(status, 204);
+        assert_eq!(get("/fresh").1, "MISS");
+
+        // no-cache responses are revalidated, a 304 serves the stored body
+        hits.store(0, Ordering::SeqCst);
+        assert_eq!(get("/validated").1, "MISS");
+        assert_eq!(
+            get("/validated"),
+            (200, "HIT".to_string(), "validated".to_string())
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // Private responses are passed on without being stored
+        assert_eq!(get("/private").1, "MISS");
+        assert_eq!(get("/private").1, "MISS");
+        assert_eq!(cache.len(), 2);
+
+        // Host names the upstream, the body framing is decoded
+        let (_, _, body) = get("/headers");
+        assert_eq!(body, format!("{} site.test", addr));
+        assert_eq!(get("/stream").2, "one two");
+
+        // Bodies can be kept on disk instead
+        let temp_dir = TempDir::new().unwrap();
+        let on_disk = crate::ProxyCache::new(100).dir(temp_dir.path());
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&format!("http://{}", addr)).cache(on_disk),
+        );
+        for x_cache in ["MISS", "HIT"] {
+            let response = route_request(&router, b"GET /fresh HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(response.header("X-Cache"), Some(x_cache));
+        }
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+        server.drain();
+
+        // Unreachable upstreams get 502
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let mut router = Router::new();
+        router.proxy("/", crate::Proxy::new(&format!("http://{}", closed_addr)));
+        let response = route_request(&router, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 502);
+    }
+
+    // Test the reverse proxy's circuit breaker
+    // Failures must open the circuit, then a probe must close it again
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_circuit_breaker() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let failing = Arc::new(AtomicBool::new(true));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let mut upstream = Router::new();
+        let (fail, count) = (Arc::clone(&failing), Arc::clone(&hits));
+        upstream.get("/", move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            match fail.load(Ordering::SeqCst) {
+                true => Response::from_status(crate::StatusCode::INTERNAL_SERVER_ERROR),
+                false => Response::ok("text/plain", "up"),
+            }
+        });
+        let server = Server::new(upstream, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let breaker = crate::CircuitBreaker::new()
+            .window(4)
+            .min_requests(4)
+            .open_for(std::time::Duration::from_millis(200))
+            .fallback(|| {
+                Response::new(503, "Service Unavailable").with_body("text/plain", "later")
+            });
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&format!("http://{}", addr)).circuit_breaker(breaker.clone()),
+        );
+        let get = || route_request(&router, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        // Failures are passed on until enough of them open the circuit
+        for _ in 0..4 {
+            assert_eq!(get().status, 500);
+        }
+        assert!(breaker.is_open());
+        let response = get();
+        assert_eq!(response.status, 503);
+        assert_eq!(response.body, b"later");
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        // A failed probe keeps it open
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        assert_eq!(get().status, 500);
+        assert_eq!(get().status, 503);
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+
+        // A successful one closes it
+        failing.store(false, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        assert_eq!(get().status, 200);
+        assert!(!breaker.is_open());
+        assert_eq!(get().status, 200);
+        server.drain();
+    }
+
+    // Test retrying proxied requests
+    // Only idempotent requests must be sent again, on the configured statuses
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_retry() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Every third request succeeds
+        let hits = Arc::new(AtomicUsize::new(0));
+        let mut upstream = Router::new();
+        for method in ["GET", "POST"] {
+            let count = Arc::clone(&hits);
+            upstream.route(method, "/flaky", move || {
+                match count.fetch_add(1, Ordering::SeqCst) % 3 {
+                    2 => Response::ok("text/plain", "finally"),
+                    _ => Response::from_status(crate::StatusCode::SERVICE_UNAVAILABLE),
+                }
+            });
+        }
+        let server = Server::new(upstream, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let proxy = crate::Proxy::new(&format!("http://{}", addr));
+        let retry = crate::RetryPolicy::new(3).backoff(std::time::Duration::from_millis(10));
+        let mut router = Router::new();
+        router.proxy("/", proxy.clone().retry(retry.clone()));
+
+        let response = route_request(&router, b"GET /flaky HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // Not for POST, nor for statuses left out of the policy
+        let response = route_request(&router, b"POST /flaky HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        let mut router = Router::new();
+        router.proxy("/", proxy.retry(retry.retry_on(&[502])));
+        let response = route_request(&router, b"GET /flaky HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+        server.drain();
+
+        // Unreachable upstreams are retried too
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&format!("http://{}", closed_addr))
+                .retry(crate::RetryPolicy::new(3).backoff(std::time::Duration::from_millis(50))),
+        );
+        let started = std::time::Instant::now();
+        let response = route_request(&router, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 502);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(150));
+    }
+
+    // Test balancing over upstreams with health checks
+    // Upstreams failing their probes must leave the rotation until they recover
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_health_checks() {
+        use std::collections::HashSet;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let healthy = Arc::new(AtomicBool::new(true));
+        let mut servers = Vec::new();
+        let mut urls = Vec::new();
+        for name in ["one", "two"] {
+            let mut upstream = Router::new();
+            upstream.get("/who", move || Response::ok("text/plain", name));
+            let up = Arc::clone(&healthy);
+            upstream.get("/health", move || {
+                match name == "one" || up.load(Ordering::SeqCst) {
+                    true => Response::ok("text/plain", "ok"),
+                    false => Response::from_status(crate::StatusCode::SERVICE_UNAVAILABLE),
+                }
+            });
+            let server = Server::new(upstream, "");
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            urls.push(format!("http://{}", listener.local_addr().unwrap()));
+            server.start(listener).unwrap();
+            servers.push(server);
+        }
+
+        let check = crate::HealthCheck::new("/health")
+            .interval(std::time::Duration::from_millis(20))
+            .healthy_after(1)
+            .unhealthy_after(1);
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&urls[0])
+                .upstream(&urls[1])
+                .health_check(check),
+        );
+        let names = |requests: usize| {
+            let mut names = HashSet::new();
+            for _ in 0..requests {
+                let response = route_request(&router, b"GET /who HTTP/1.1\r\n\r\n").unwrap();
+                let mut body = String::new();
+                response
+                    .body
+                    .into_reader()
+                    .read_to_string(&mut body)
+                    .unwrap();
+                names.insert(body);
+            }
+            let mut names: Vec<String> = names.into_iter().collect();
+            names.sort();
+            names
+        };
+
+        // Requests go to each upstream in turn
+        assert_eq!(names(2), ["one", "two"]);
+
+        // Until one fails its health check
+        healthy.store(false, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        assert_eq!(names(4), ["one"]);
+
+        healthy.store(true, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        assert_eq!(names(2), ["one", "two"]);
+        for server in servers {
+            server.drain();
+        }
+    }
+
+    // Test session affinity when balancing over upstreams
+    // Clients must keep reaching the upstream their cookie or address maps to
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_sticky_sessions() {
+        let mut servers = Vec::new();
+        let mut urls = Vec::new();
+        for name in ["one", "two", "three"] {
+            let mut upstream = Router::new();
+            upstream.get("/who", move || Response::ok("text/plain", name));
+            let server = Server::new(upstream, "");
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            urls.push(format!("http://{}", listener.local_addr().unwrap()));
+            server.start(listener).unwrap();
+            servers.push(server);
+        }
+        let proxy = |balance: crate::Balance| {
+            let mut router = Router::new();
+            router.proxy(
+                "/",
+                crate::Proxy::new(&urls[0])
+                    .upstream(&urls[1])
+                    .upstream(&urls[2])
+                    .balance(balance),
+            );
+            router
+        };
+        let fetch = |router: &Router, raw: &str, ip: [u8; 4]| {
+            let mut request = Request::parse(raw.as_bytes());
+            request.remote_addr = Some((ip, 40000).into());
+            let response = router.handle(&mut request).unwrap();
+            let cookie = response.header("Set-Cookie").map(str::to_string);
+            let mut body = String::new();
+            response
+                .body
+                .into_reader()
+                .read_to_string(&mut body)
+                .unwrap();
+            (body, cookie)
+        };
+
+        // The first response names the upstream in a cookie sent back after
+        let router = proxy(crate::Balance::Cookie("backend".to_string()));
+        let (first, cookie) = fetch(&router, "GET /who HTTP/1.1\r\n\r\n", [10, 0, 0, 1]);
+        let cookie = cookie.unwrap();
+        assert!(cookie.starts_with("backend=") && cookie.ends_with("; Path=/; HttpOnly"));
+        let value = cookie.split(';').next().unwrap();
+        let raw = format!("GET /who HTTP/1.1\r\nCookie: theme=dark; {}\r\n\r\n", value);
+        for _ in 0..4 {
+            assert_eq!(fetch(&router, &raw, [10, 0, 0, 1]), (first.clone(), None));
+        }
+        // Unknown ids are replaced
+        let raw = "GET /who HTTP/1.1\r\nCookie: backend=gone\r\n\r\n";
+        assert!(fetch(&router, raw, [10, 0, 0, 1]).1.is_some());
+
+        // Addresses always map to the same upstream, and spread over them
+        let router = proxy(crate::Balance::ClientIp);
+        let mut seen = std::collections::HashSet::new();
+        for client in 1..=20 {
+            let ip = [192, 168, 1, client];
+            let (name, cookie) = fetch(&router, "GET /who HTTP/1.1\r\n\r\n", ip);
+            assert!(cookie.is_none());
+            for _ in 0..3 {
+                assert_eq!(fetch(&router, "GET /who HTTP/1.1\r\n\r\n", ip).0, name);
+            }
+            seen.insert(name);
+        }
+        assert!(seen.len() > 1);
+        for server in servers {
+            server.drain();
+        }
+    }
+
+    // Test consistent hashing over upstreams
+    // Adding an upstream must only move the keys it takes over
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_consistent_hashing() {
+        let mut servers = Vec::new();
+        let mut urls = Vec::new();
+        for name in ["one", "two", "three", "four"] {
+            let mut upstream = Router::new();
+            upstream.get("/{*path}", move || Response::ok("text/plain", name));
+            let server = Server::new(upstream, "");
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            urls.push(format!("http://{}", listener.local_addr().unwrap()));
+            server.start(listener).unwrap();
+            servers.push(server);
+        }
+        let proxy = |count: usize, key: crate::HashKey| {
+            let mut proxy = crate::Proxy::new(&urls[0]);
+            for url in &urls[1..count] {
+                proxy = proxy.upstream(url);
+            }
+            let mut router = Router::new();
+            router.proxy("/", proxy.balance(crate::Balance::ConsistentHash(key)));
+            router
+        };
+        let fetch = |router: &Router, raw: &str| {
+            let response = route_request(router, raw.as_bytes()).unwrap();
+            let mut body = String::new();
+            response
+                .body
+                .into_reader()
+                .read_to_string(&mut body)
+                .unwrap();
+            body
+        };
+        let names = |router: &Router| -> Vec<String> {
+            (0..200)
+                .map(|page| fetch(router, &format!("GET /page/{} HTTP/1.1\r\n\r\n", page)))
+                .collect()
+        };
+
+        // Each path keeps its upstream, and all of them get some
+        let three = proxy(3, crate::HashKey::Path);
+        let before = names(&three);
+        assert_eq!(names(&three), before);
+        for name in ["one", "two", "three"] {
+            assert!(before.iter().filter(|n| *n == name).count() > 30);
+        }
+
+        // A new upstream takes about a quarter of the paths from the others
+        let after = names(&proxy(4, crate::HashKey::Path));
+        let moved: Vec<&String> = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .map(|(_, after)| after)
+            .collect();
+        assert!(moved.iter().all(|name| *name == "four"));
+        assert!((20..=80).contains(&moved.len()));
+
+        // Keys can come from headers or cookies too
+        let router = proxy(4, crate::HashKey::Header("X-Tenant".to_string()));
+        let raw = "GET /a HTTP/1.1\r\nX-Tenant: acme\r\n\r\n";
+        let tenant = fetch(&router, raw);
+        assert_eq!(
+            fetch(&router, "GET /b HTTP/1.1\r\nX-Tenant: acme\r\n\r\n"),
+            tenant
+        );
+        let router = proxy(4, crate::HashKey::Cookie("user".to_string()));
+        let raw = "GET /a HTTP/1.1\r\nCookie: user=42\r\n\r\n";
+        let user = fetch(&router, raw);
+        assert_eq!(
+            fetch(&router, "GET /b HTTP/1.1\r\nCookie: user=42\r\n\r\n"),
+            user
+        );
+        for server in servers {
+            server.drain();
+        }
+    }
+
+    // Test weighted round-robin over upstreams
+    // Upstreams must get requests in proportion to their weight
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_proxy_weighted_round_robin() {
+        let mut servers = Vec::new();
+        let mut urls = Vec::new();
+        for name in ["big", "small"] {
+            let mut upstream = Router::new();
+            upstream.get("/who", move || Response::ok("text/plain", name));
+            let server = Server::new(upstream, "");
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            urls.push(format!("http://{}", listener.local_addr().unwrap()));
+            server.start(listener).unwrap();
+            servers.push(server);
+        }
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&urls[0]).weight(3).upstream(&urls[1]),
+        );
+
+        let names: Vec<String> = (0..8)
+            .map(|_| {
+                let response = route_request(&router, b"GET /who HTTP/1.1\r\n\r\n").unwrap();
+                let mut body = String::new();
+                response
+                    .body
+                    .into_reader()
+                    .read_to_string(&mut body)
+                    .unwrap();
+                body
+            })
+            .collect();
+        assert_eq!(names.iter().filter(|name| *name == "big").count(), 6);
+        // Spread out rather than in a row
+        assert!(
+            names
+                .windows(4)
+                .all(|run| run.contains(&"small".to_string()))
+        );
+        for server in servers {
+            server.drain();
+        }
+    }
+
+    // Test CONNECT tunnels through the forward proxy
+    // Credentials and the allowlist are checked before bytes flow both ways
+    #[test]
+    #[cfg(feature = "proxy")]
+    fn test_forward_proxy() {
+        use std::io::BufRead;
+        use std::net::{Shutdown, TcpListener, TcpStream};
+
+        // A target echoing what it receives
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in target.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = stream.try_clone().unwrap();
+                std::io::copy(&mut reader, &mut stream).unwrap();
+            }
+        });
+
+        let mut router = Router::new();
+        router.forward_proxy(
+            crate::ForwardProxy::new()
+                .allow(&format!("127.0.0.0/8:{}", target_port))
+                .authenticate(|user, password| user == "ci" && password == "secret"),
+        );
+        // Kept-alive connections are handed over as well
+        let server = Server::new(router, "").config(ServerConfig {
+            keep_alive_timeout: Some(std::time::Duration::from_secs(5)),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        // Sends a CONNECT request and returns the connection with the response head
+        let connect = |target: &str, credentials: Option<&str>, early: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+            if let Some(credentials) = credentials {
+                request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+            }
+            request.push_str("\r\n");
+            request.push_str(early);
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                assert!(reader.read_line(&mut head).unwrap() > 0);
+            }
+            (stream, reader, head)
+        };
+        let allowed = format!("127.0.0.1:{}", target_port);
+        // "ci:secret"
+        let credentials = Some("Y2k6c2VjcmV0");
+
+        let (_, _, head) = connect(&allowed, None, "");
+        assert!(head.starts_with("HTTP/1.1 407 "));
+        assert!(head.contains("Proxy-Authenticate: Basic realm=\"proxy\""));
+        let (_, _, head) = connect(&allowed, Some("Y2k6d3Jvbmc="), "");
+        assert!(head.starts_with("HTTP/1.1 407 "));
+
+        // Destinations outside the allowlist are refused
+        let (_, _, head) = connect("127.0.0.1:1", credentials, "");
+        assert!(head.starts_with("HTTP/1.1 403 "));
+        let (_, _, head) = connect("example.com:443", credentials, "");
+        assert!(head.starts_with("HTTP/1.1 403 "));
+        let (_, _, head) = connect("no-port", credentials, "");
+        assert!(head.starts_with("HTTP/1.1 400 "));
+
+        // Bytes sent along with the request reach the target first
+        let (mut stream, mut reader, head) = connect(&allowed, credentials, "early ");
+        assert!(head.starts_with("HTTP/1.1 200 Connection Established\r\n"));
+        assert!(!head.contains("Content-Length"));
+        stream.write_all(b"then").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = String::new();
+        reader.read_to_string(&mut echoed).unwrap();
+        assert_eq!(echoed, "early then");
+
+        server.drain();
+    }
+
+    // Test proxying WebSocket connections
+    // The handshake is forwarded, and bytes are relayed after the upstream 101
+    #[test]
+    #[cfg(feature = "websocket")]
+    fn test_websocket_proxy() {
+        use std::io::BufRead;
+        use std::net::{TcpListener, TcpStream};
+
+        // An upstream accepting the handshake, greeting and echoing
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                let seen = |name: &str| {
+                    head.lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .unwrap_or("")
+                        .to_string()
+                };
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: accepted\r\n\
+                     X-Seen-Connection: {}\r\nX-Seen-Key: {}\r\n\r\nhello ",
+                    seen("Connection: "),
+                    seen("Sec-WebSocket-Key: ")
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+                std::io::copy(&mut reader, &mut stream).unwrap();
+            }
+        });
+
+        let mut router = Router::new();
+        router.proxy(
+            "/",
+            crate::Proxy::new(&format!("http://{}", upstream_addr))
+                .cache(crate::ProxyCache::new(10)),
+        );
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        // A frame sent along with the handshake reaches the upstream after it
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: key\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\nearly",
+            )
+            .unwrap();
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0);
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Upgrade: websocket\r\n"));
+        assert!(head.contains("Connection: Upgrade\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: accepted\r\n"));
+        assert!(head.contains("X-Seen-Connection: Upgrade\r\n"));
+        assert!(head.contains("X-Seen-Key: key\r\n"));
+        assert!(!head.contains("X-Cache"));
+
+        stream.write_all(b" late").unwrap();
+        let mut received = [0; 16];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello early late");
+
+        server.drain();
+    }
+
+    // Test proxying WebSocket handshakes without the websocket feature
+    // They are forwarded as plain requests, without Upgrade and not kept open
+    #[test]
+    #[cfg(all(feature = "proxy", not(feature = "websocket")))]
+    fn test_websocket_proxy_disabled() {
+        let mut upstream = Router::new();
+        upstream.get("/chat", |request: Request| {
+            let seen = format!(
+                "{} {}",
+                request.header("Connection").unwrap_or("-"),
+                request.header("Upgrade").unwrap_or("-")
+            );
+            Response::ok("text/plain", seen)
+        });
+        let server = Server::new(upstream, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let mut router = Router::new();
+        router.proxy("/", crate::Proxy::new(&format!("http://{}", addr)));
+        let response = route_request(
+            &router,
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: key\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        let mut body = Vec::new();
+        response.body.into_reader().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"close -");
+
+        server.drain();
+    }
+
+    // Test handlers choosing the response status
+    // Statuses without a body are sent without Content-Type or framing headers
+    #[test]
+    fn test_response_status_control() {
+        let written = |response: Response| {
+            let mut output = Vec::new();
+            response.write_to(&mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let created = Response::ok("application/json", "{\"id\":7}")
+            .with_status(crate::StatusCode::CREATED)
+            .with_header("Location", "/items/7");
+        assert_eq!(
+            written(created),
+            "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nLocation: /items/7\r\n\
+             Content-Length: 8\r\n\r\n{\"id\":7}"
+        );
+        assert_eq!(
+            written(Response::from_status(crate::StatusCode::NO_CONTENT)),
+            "HTTP/1.1 204 No Content\r\n\r\n"
+        );
+        // A body given to a 204 anyway is dropped with its headers
+        let no_content = Response::ok("text/plain", "ignored")
+            .with_status(crate::StatusCode::NO_CONTENT)
+            .with_header("X-Request-Id", "1");
+        assert_eq!(
+            written(no_content),
+            "HTTP/1.1 204 No Content\r\nX-Request-Id: 1\r\n\r\n"
+        );
+        let missing =
+            Response::ok("text/plain", "no such item").with_status(crate::StatusCode::NOT_FOUND);
+        assert!(
+            written(missing).starts_with("HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n")
+        );
+        let failed = Response::from_status(crate::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            written(failed),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    // Test handlers returning values other than Response
+    // Strings, bytes, statuses, JSON and results are converted by IntoResponse
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_into_response_handlers() {
+        #[derive(serde::Serialize)]
+        struct Item {
+            id: u32,
+        }
+
+        let mut router = Router::new();
+        router
+            .get("/text", || "hello")
+            .get("/owned", || format!("{}-{}", "a", "b"))
+            .get("/bytes", || vec![0u8, 1, 2])
+            .post("/items", || {
+                (crate::StatusCode::CREATED, "created".to_string())
+            })
+            .delete("/items/{id}", || crate::StatusCode::NO_CONTENT)
+            .get("/items/{id}", |Path(id): Path<u32>| match id {
+                7 => Ok(Json(Item { id })),
+                _ => Err((crate::StatusCode::NOT_FOUND, "no such item")),
+            });
+        let client = TestClient::new(router);
+
+        let text = client.get("/text");
+        assert_eq!(text.status, 200);
+        assert_eq!(text.header("Content-Type"), Some("text/plain"));
+        assert_eq!(text.body, b"hello");
+        assert_eq!(client.get("/owned").body, b"a-b");
+        let bytes = client.get("/bytes");
+        assert_eq!(
+            bytes.header("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(bytes.body, [0, 1, 2]);
+        let created = client.post("/items", "text/plain", "");
+        assert_eq!(created.status, 201);
+        assert_eq!(created.body, b"created");
+        assert_eq!(client.request("DELETE", "/items/7", &[], b"").status, 204);
+        let item = client.get("/items/7");
+        assert_eq!(item.header("Content-Type"), Some("application/json"));
+        assert_eq!(item.body, br#"{"id":7}"#);
+        let missing = client.get("/items/8");
+        assert_eq!(missing.status, 404);
+        assert_eq!(missing.body, b"no such item");
+    }
+
+    // Test handlers returning Result with the framework Error
+    // Errors from ? map to statuses without exposing their details
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_fallible_handlers() {
+        use crate::Error;
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        std::fs::write(temp_dir.path().join("7.json"), r#"{"name": "alice"}"#).unwrap();
+        std::fs::write(temp_dir.path().join("8.json"), "not json").unwrap();
+        let mut router = Router::new();
+        router.get(
+            "/users/{id}",
+            move |Path(id): Path<u32>| -> Result<Response, Error> {
+                if id == 0 {
+                    return Err(Error::bad_request("ids start at 1"));
+                }
+                let data = std::fs::read_to_string(format!("{}/{}.json", dir, id))?;
+                let user: serde_json::Value = serde_json::from_str(&data)?;
+                Ok(Response::ok("text/plain", user["name"].to_string()))
+            },
+        );
+        let client = TestClient::new(router);
+
+        assert_eq!(client.get("/users/7").body, b"\"alice\"");
+        let invalid = client.get("/users/0");
+        assert_eq!(invalid.status, 400);
+        assert_eq!(invalid.header("Content-Type"), Some("text/plain"));
+        assert_eq!(invalid.body, b"ids start at 1");
+        let missing = client.get("/users/9");
+        assert_eq!(missing.status, 404);
+        assert_eq!(missing.body, b"Not Found");
+        let broken = client.get("/users/8");
+        assert_eq!(broken.status, 500);
+        assert_eq!(broken.body, b"Internal Server Error");
+
+        // The causes are kept for the log
+        let error = Error::new(crate::StatusCode::BAD_GATEWAY, "inventory unavailable")
+            .with_source(std::io::Error::other("connection refused"));
+        assert_eq!(error.status(), crate::StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            error.to_string(),
+            "502 inventory unavailable: connection refused"
+        );
+    }
+
+    // Test async handlers registered next to plain ones
+    // Futures run on the shared runtime, timers included; a panicking one answers 500
+    #[test]
+    #[cfg(all(feature = "async", feature = "router"))]
+    fn test_async_handlers() {
+        use crate::{Async, Error};
+
+        async fn double(Path(n): Path<u32>) -> Result<String, Error> {
+            tokio::task::yield_now().await;
+            let doubled = tokio::task::spawn_blocking(move || n * 2).await?;
+            Ok(doubled.to_string())
+        }
+
+        let mut router = Router::new();
+        router
+            .get("/double/{n}", Async(double))
+            .get("/sync", || "sync")
+            .get("/status", Async(|| async { crate::StatusCode::ACCEPTED }))
+            .get(
+                "/slow",
+                Async(|| async {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    "done"
+                }),
+            )
+            .get(
+                "/panic",
+                Async(|| async { panic!("handler failed") as &str }),
+            );
+        let client = TestClient::new(router);
+
+        assert_eq!(client.get("/double/21").body, b"42");
+        assert_eq!(client.get("/double/x").status, 400);
+        assert_eq!(client.get("/sync").body, b"sync");
+        assert_eq!(client.get("/status").status, 202);
+        assert_eq!(client.get("/slow").body, b"done");
+        assert_eq!(client.get("/panic").status, 500);
+    }
+
+    // Test per-route concurrency limits
+    // Requests past the limit must get 503 with Retry-After instead of waiting
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_concurrency_limit() {
+        let mut router = Router::new();
+        router
+            .get("/render", || {
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                Response::ok("text/plain", "rendered")
+            })
+            .with(
+                crate::ConcurrencyLimit::new(1).retry_after(std::time::Duration::from_millis(1500)),
+            );
+        router.get("/cheap", || Response::ok("text/plain", "cheap"));
+
+        std::thread::scope(|scope| {
+            let first = scope.spawn(|| route_request(&router, b"GET /render HTTP/1.1\r\n\r\n"));
+            std::thread::sleep(std::time::Duration::from_millis(100));
+
+            let started = std::time::Instant::now();
+            let response = route_request(&router, b"GET /render HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(response.status, 503);
+            assert_eq!(response.header("Retry-After"), Some("2"));
+            assert!(started.elapsed() < std::time::Duration::from_millis(150));
+
+            // Other routes are not limited
+            let response = route_request(&router, b"GET /cheap HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(response.body, b"cheap");
+
+            let response = first.join().unwrap().unwrap();
+            assert_eq!(response.body, b"rendered");
+        });
+
+        // The permit is given back once the request is done
+        let response = route_request(&router, b"GET /render HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 200);
+    }
+
+    // Test the access log, rotated by size and reopened after a rename
+    // Lines use the Combined Log Format and only the newest rotated files are kept
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_access_log_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("access.log");
+        let log = crate::AccessLog::new(&path).unwrap().max_size(300).keep(2);
+        let mut router = Router::new();
+        router.get("/hello", || Response::ok("text/plain", "hello"));
+        let server = Server::new(router, "").config(ServerConfig {
+            access_log: Some(log.clone()),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let lines = |path: &std::path::Path| {
+            std::fs::read_to_string(path)
+                .map(|log| log.lines().count())
+                .unwrap_or(0)
+        };
+        // Sends a request and waits for its line, written after the response
+        let send = |logged: usize| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(
+                    b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\
+                      User-Agent: test \"agent\"\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            for _ in 0..100 {
+                if lines(&path) == logged {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            response
+        };
+
+        let response = send(1);
+        let line = std::fs::read_to_string(&path).unwrap();
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.ends_with(&format!(
+            "+0000] \"GET /hello HTTP/1.1\" 200 {} \"-\" \"test \\\"agent\\\"\"\n",
+            response.len()
+        )));
+
+        // Three lines fit in a file, the tenth one goes to the fourth file
+        for logged in [2, 3, 1, 2, 3, 1, 2, 3, 1] {
+            send(logged);
+        }
+        let rotated: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|rotated| *rotated != path)
+            .collect();
+        assert_eq!(rotated.len(), 2);
+        assert!(rotated.iter().all(|rotated| lines(rotated) == 3));
+        assert_eq!(lines(&path), 1);
+
+        // After another program renamed the file, reopen starts a new one
+        std::fs::rename(&path, temp_dir.path().join("renamed.log")).unwrap();
+        log.reopen().unwrap();
+        send(1);
+        assert_eq!(lines(&temp_dir.path().join("renamed.log")), 1);
+        assert_eq!(lines(&path), 1);
+        server.drain();
+    }
+
+    // Test sending the access log to syslog and to the journal
+    // Lines become RFC 5424 messages over UDP and native journal entries
+    #[test]
+    #[cfg(all(feature = "router", unix))]
+    fn test_access_log_syslog_and_journald() {
+        let mut router = Router::new();
+        router.get("/hello", || Response::ok("text/plain", "hello"));
+        // Serves one request with the access log
+        let request = |log: crate::AccessLog| {
+            let server = Server::new(router.clone(), "").config(ServerConfig {
+                access_log: Some(log),
+                ..ServerConfig::default()
+            });
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            server.start(listener).unwrap();
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            client.read_to_end(&mut Vec::new()).unwrap();
+            server.drain();
+        };
+
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let syslog = crate::Syslog::udp(collector.local_addr().unwrap())
+            .unwrap()
+            .facility(16)
+            .app_name("shop");
+        request(crate::AccessLog::syslog(syslog));
+        let mut datagram = [0; 1024];
+        let len = collector.recv(&mut datagram).unwrap();
+        let message = String::from_utf8_lossy(&datagram[..len]).into_owned();
+        // local0.info, then the version
+        assert!(message.starts_with("<134>1 "));
+        let fields: Vec<&str> = message.splitn(8, ' ').collect();
+        assert_eq!(fields[3], "shop");
+        assert_eq!(fields[4], std::process::id().to_string());
+        assert_eq!(fields[5..7], ["access", "-"]);
+        assert!(fields[7].starts_with("127.0.0.1 - - ["));
+        assert!(fields[7].contains("\"GET /hello HTTP/1.1\" 200 "));
+        assert!(!fields[7].ends_with('\n'));
+
+        let temp_dir = TempDir::new().unwrap();
+        let socket = temp_dir.path().join("journal.socket");
+        let journal = std::os::unix::net::UnixDatagram::bind(&socket).unwrap();
+        journal
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let journald = crate::Journald::at(&socket).unwrap().identifier("shop");
+        request(crate::AccessLog::journald(journald));
+        let len = journal.recv(&mut datagram).unwrap();
+        let entry = String::from_utf8_lossy(&datagram[..len]).into_owned();
+        assert!(entry.starts_with("MESSAGE=127.0.0.1 - - ["));
+        assert!(entry.ends_with("\nPRIORITY=6\nSYSLOG_IDENTIFIER=shop\n"));
+    }
+
+    // Test the JSON access log format
+    // Each line is an object with the request's timing and its X-Request-Id
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_access_log_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("access.json");
+        let log = crate::AccessLog::new(&path)
+            .unwrap()
+            .format(crate::LogFormat::Json);
+        let mut router = Router::new();
+        router.get("/slow", || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            Response::ok("text/plain", "done")
+        });
+        let server = Server::new(router, "").config(ServerConfig {
+            access_log: Some(log),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let send = |request: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            response.len()
+        };
+        let first = send(
+            "GET /slow?page=2 HTTP/1.1\r\nHost: localhost\r\n\
+             X-Request-Id: abc-\"123\"\r\nConnection: close\r\n\r\n",
+        );
+        send("GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .collect();
+            if lines.len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        server.drain();
+
+        assert_eq!(lines.len(), 2);
+        let timestamp = lines[0]["timestamp"].as_str().unwrap();
+        assert_eq!(timestamp.len(), "2026-10-16T13:55:36.123Z".len());
+        assert!(timestamp.ends_with('Z'));
+        assert_eq!(lines[0]["method"], "GET");
+        assert_eq!(lines[0]["path"], "/slow");
+        assert_eq!(lines[0]["status"], 200);
+        assert!(lines[0]["duration_ms"].as_f64().unwrap() >= 50.0);
+        assert_eq!(lines[0]["bytes"], first);
+        assert_eq!(lines[0]["client_ip"], "127.0.0.1");
+        assert_eq!(lines[0]["request_id"], "abc-\"123\"");
+        assert_eq!(lines[1]["status"], 404);
+        assert!(lines[1]["request_id"].is_null());
+    }
+
+    // Test the debug capture middleware
+    // Captured responses, streamed and binary ones included, reach the client unchanged
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_debug_capture() {
+        let capture = crate::DebugCapture::new().path("/api/**").max_body(4);
+        let mut router = Router::new();
+        router.layer(capture.clone());
+        router.post("/api/echo", |request: Request| {
+            Response::ok("application/octet-stream", request.body)
+        });
+        router.get("/api/stream", || {
+            Response::ok("text/plain", crate::Body::from_chunks(["héllo ", "wörld"]))
+        });
+
+        let mut request = Request::parse(b"POST /api/echo HTTP/1.1\r\nCookie: id=1\r\n\r\n");
+        request.body = vec![0, 159, 146, 150, 255, 10];
+        let response = router.handle(&mut request).unwrap();
+        assert_eq!(response.body, vec![0, 159, 146, 150, 255, 10]);
+
+        let response = route_request(&router, b"GET /api/stream HTTP/1.1\r\n\r\n").unwrap();
+        assert!(response.body.is_streamed());
+        let mut body = String::new();
+        response
+            .body
+            .into_reader()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "héllo wörld");
+
+        // Clones share the switch
+        assert!(capture.is_enabled());
+        capture.set_enabled(false);
+        let response = route_request(&router, b"GET /api/stream HTTP/1.1\r\n\r\n").unwrap();
+        assert!(response.body.is_streamed());
+    }
+
+    // Test the security headers middleware and its Content-Security-Policy
+    // Every response gets its own nonce, in the header and the request only
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_security_headers() {
+        use crate::{ContentSecurityPolicy, CspDirective, CspSource, SecurityHeaders};
+        let policy = ContentSecurityPolicy::new()
+            .directive(CspDirective::DefaultSrc, &[CspSource::SelfOrigin])
+            .directive(CspDirective::ScriptSrc, &[CspSource::Nonce])
+            .directive(
+                CspDirective::ScriptSrc,
+                &[CspSource::Host("https://cdn.example.com".into())],
+            )
+            .directive(
+                CspDirective::ObjectSrc,
+                &[CspSource::None, CspSource::SelfOrigin],
+            )
+            .directive(CspDirective::ImgSrc, &[CspSource::Scheme("data".into())])
+            .upgrade_insecure_requests();
+        let mut router = Router::new();
+        router.layer(
+            SecurityHeaders::new()
+                .without("X-Frame-Options")
+                .strict_transport_security(std::time::Duration::from_secs(600))
+                .content_security_policy(policy),
+        );
+        router.get("/page", |request: Request| {
+            let nonce = request.csp_nonce().unwrap_or("");
+            // Reflected input must stay inert, placeholder or not
+            let html = format!("<script nonce=\"{}\">go()</script>{}", nonce, request.query);
+            Response::ok("text/html", html)
+        });
+        router.get("/own", || {
+            Response::ok("text/plain", "own").with_header("Referrer-Policy", "no-referrer")
+        });
+
+        let response = route_request(&router, b"GET /page?{csp_nonce} HTTP/1.1\r\n\r\n").unwrap();
+        let csp = response
+            .header("Content-Security-Policy")
+            .unwrap()
+            .to_string();
+        let nonce = csp
+            .split("'nonce-")
+            .nth(1)
+            .unwrap()
+            .split('\'')
+            .next()
+            .unwrap();
+        assert_eq!(nonce.len(), 32);
+        assert_eq!(
+            csp,
+            format!(
+                "default-src 'self'; script-src 'nonce-{}' https://cdn.example.com; \
+                 object-src 'none'; img-src data:; upgrade-insecure-requests",
+                nonce
+            )
+        );
+        assert_eq!(
+            response.body,
+            format!("<script nonce=\"{}\">go()</script>{{csp_nonce}}", nonce).into_bytes()
+        );
+        assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(response.header("X-Frame-Options"), None);
+        assert_eq!(
+            response.header("Strict-Transport-Security"),
+            Some("max-age=600")
+        );
+
+        // Each response gets a new nonce
+        let response = route_request(&router, b"GET /page HTTP/1.1\r\n\r\n").unwrap();
+        let csp = response.header("Content-Security-Policy").unwrap();
+        assert!(csp.contains("'nonce-"));
+        assert!(!csp.contains(nonce));
+
+        // The handler's headers win
+        let response = route_request(&router, b"GET /own HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Referrer-Policy"), Some("no-referrer"));
+
+        // Report-only policies without a nonce source
+        let mut router = Router::new();
+        router.layer(
+            SecurityHeaders::new().content_security_policy(
+                ContentSecurityPolicy::new()
+                    .directive(CspDirective::FrameAncestors, &[CspSource::None])
+                    .report_uri("/csp")
+                    .report_only(true),
+            ),
+        );
+        router.get("/", |request: Request| {
+            Response::ok("text/plain", format!("{:?}", request.csp_nonce()))
+        });
+        let response = route_request(&router, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Content-Security-Policy"), None);
+        assert_eq!(
+            response.header("Content-Security-Policy-Report-Only"),
+            Some("frame-ancestors 'none'; report-uri /csp")
+        );
+        assert_eq!(response.body, b"None".to_vec());
+    }
+
+    // Test the request latency histogram
+    // Latencies land in the configured buckets, with percentiles in both formats
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_latency_histogram() {
+        let ms = std::time::Duration::from_millis;
+        let histogram = crate::Histogram {
+            buckets: vec![(ms(10), 2), (ms(20), 6), (ms(40), 8)],
+            count: 10,
+            sum: ms(250),
+        };
+        assert_eq!(histogram.quantile(0.1), Some(ms(5)));
+        assert_eq!(histogram.quantile(0.5), Some(ms(17) + ms(1) / 2));
+        assert_eq!(histogram.quantile(0.99), Some(ms(40)));
+        assert_eq!(crate::Histogram::default().quantile(0.5), None);
+
+        let mut router = Router::new();
+        router.get("/fast", || Response::ok("text/plain", "fast"));
+        router.get("/slow", || {
+            std::thread::sleep(std::time::Duration::from_millis(60));
+            Response::ok("text/plain", "slow")
+        });
+        let server = Server::new(router.clone(), "").config(ServerConfig {
+            latency_buckets: Some(vec![ms(50), ms(1000)]),
+            ..ServerConfig::default()
+        });
+        router.get("/metrics", server.metrics_handler());
+        server.reload(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let get = |path: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        get("/fast");
+        get("/fast");
+        get("/slow");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while server.stats().latency.count < 3 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let latency = server.stats().latency;
+        assert_eq!(latency.buckets, vec![(ms(50), 2), (ms(1000), 3)]);
+        assert_eq!(latency.count, 3);
+        assert!(latency.sum >= ms(60));
+        assert!(latency.quantile(0.99).unwrap() > ms(50));
+        assert!(
+            server
+                .stats()
+                .to_json()
+                .contains("\"latency\":{\"count\":3,")
+        );
+
+        let metrics = get("/metrics");
+        assert!(metrics.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(metrics.contains("# TYPE http_request_duration_seconds histogram\n"));
+        assert!(metrics.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(metrics.contains("http_request_duration_seconds_bucket{le=\"1\"} 3\n"));
+        assert!(metrics.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(metrics.contains("http_request_duration_seconds_count 3\n"));
+        assert!(metrics.contains("http_request_duration_quantile_seconds{quantile=\"0.95\"} 0."));
+        assert!(metrics.contains("http_responses_total{status=\"200\"} 3\n"));
+        server.drain();
+    }
+
+    // Test the metrics of each route
+    // Requests count under the pattern they matched, never the raw path
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_route_metrics() {
+        let mut router = Router::new();
+        router.get("/users/{id}", |request: Request| {
+            Response::ok("text/plain", request.param("id").unwrap_or("").to_string())
+        });
+        router.scope("/api", |api| {
+            api.post("/orders", || Response::new(500, "Internal Server Error"));
+        });
+        let mut request = Request::parse(b"GET /users/7 HTTP/1.1\r\n\r\n");
+        router.handle(&mut request).unwrap();
+        assert_eq!(request.route.as_deref(), Some("/users/{id}"));
+
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let send = |method: &str, path: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                method, path
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+        };
+        send("GET", "/users/1");
+        send("GET", "/users/2");
+        send("POST", "/api/orders");
+        send("GET", "/missing");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while server.stats().latency.count < 4 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let stats = server.stats();
+        let routes: Vec<_> = stats
+            .routes
+            .iter()
+            .map(|route| {
+                let statuses: Vec<_> = route.statuses.clone().into_iter().collect();
+                (
+                    route.method.as_str(),
+                    route.route.as_str(),
+                    statuses,
+                    route.latency.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            routes,
+            vec![
+                ("POST", "/api/orders", vec![(500, 1)], 1),
+                ("GET", "/users/{id}", vec![(200, 2)], 2),
+            ]
+        );
+        assert!(stats.to_json().contains(
+            "\"routes\":[{\"method\":\"POST\",\"route\":\"/api/orders\",\"latency\":{\"count\":1,"
+        ));
+        let metrics = stats.to_prometheus();
+        assert!(metrics.contains(
+            "http_route_responses_total{method=\"GET\",route=\"/users/{id}\",status=\"200\"} 2\n"
+        ));
+        assert!(metrics.contains(
+            "http_route_request_duration_seconds_count{method=\"POST\",route=\"/api/orders\"} 1\n"
+        ));
+        assert!(metrics.contains(
+            "http_route_request_duration_seconds_bucket{method=\"GET\",route=\"/users/{id}\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(!metrics.contains("/users/1"));
+        server.drain();
+    }
+
+    // Test the HTML status page
+    // It needs the token, taken as a Basic password, and lists routes and recent requests
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_status_page() {
+        let mut router = Router::new();
+        router.get("/items/{id}", || Response::ok("text/plain", "item"));
+        let server = Server::new(router, "").config(ServerConfig {
+            admin: Some(AdminConfig::new("s3cret").status_page(true)),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let get = |path: &str, auth: &str| {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+                path, auth
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        get("/items/1", "");
+        get("/a<b>", "");
+
+        let response = get("/_status", "");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("WWW-Authenticate: Basic realm=\"status\"\r\n"));
+        // "admin:wrong" and "admin:s3cret"
+        let response = get("/_status", "Authorization: Basic YWRtaW46d3Jvbmc=\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = get("/_status", "Authorization: Basic YWRtaW46czNjcmV0\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("<meta http-equiv=\"refresh\" content=\"5\">"));
+        assert!(response.contains("accepting connections"));
+        assert!(response.contains("<td>GET</td><td>/items/{id}</td><td class=\"n\">1</td>"));
+        assert!(response.contains("<td>/a&lt;b&gt;</td><td class=\"n\">404</td>"));
+        assert!(!response.contains("<td>/a<b>"));
+        // Newest first
+        assert!(
+            response.find("/a&lt;b&gt;").unwrap() < response.find("<td>/items/1</td>").unwrap()
+        );
+        let response = get("/_admin/stats", "Authorization: Bearer s3cret\r\n");
+        assert!(response.contains("\"recent\":[{\"unix_ms\":"));
+        server.drain();
+    }
+
+    // Test draining with a deadline
+    // Kept-alive connections close after their response, and those left are aborted
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_drain_deadline() {
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        router.get("/slow", || {
+            std::thread::sleep(std::time::Duration::from_millis(400));
+            Response::ok("text/plain", "slow")
+        });
+        let server = Server::new(router, "").config(ServerConfig {
+            keep_alive_timeout: Some(std::time::Duration::from_secs(30)),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = server.start(listener).unwrap();
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+
+        // A kept-alive connection, idle after its first response
+        let mut idle = std::net::TcpStream::connect(addr).unwrap();
+        idle.write_all(request("/").as_bytes()).unwrap();
+        let mut buffer = [0; 1024];
+        let mut response = Vec::new();
+        while !response.ends_with(b"home") {
+            let read = idle.read(&mut buffer).unwrap();
+            assert!(read > 0);
+            response.extend_from_slice(&buffer[..read]);
+        }
+        // A connection that never sends its request
+        let silent = std::net::TcpStream::connect(addr).unwrap();
+        let mut busy = std::net::TcpStream::connect(addr).unwrap();
+        busy.write_all(request("/slow").as_bytes()).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while (server.stats().in_flight_requests == 0 || server.stats().active_connections < 3)
+            && std::time::Instant::now() < deadline
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(server.stats().drain.is_none());
+
+        server.drain_within(std::time::Duration::from_millis(1500));
+        let stats = server.stats();
+        assert_eq!(stats.in_flight_requests, 1);
+        assert_eq!(stats.active_connections, 3);
+        let drain = stats.drain.as_ref().unwrap();
+        assert!(drain.time_left.unwrap() > std::time::Duration::from_secs(1));
+        assert!(stats.to_json().contains("\"in_flight_requests\":1,"));
+        assert!(stats.to_prometheus().contains("http_draining 1\n"));
+
+        // The request in flight completes, announcing the close
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("slow"));
+        // The idle one closes without waiting for the deadline
+        idle.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(idle.read(&mut buffer).unwrap(), 0);
+        assert_eq!(server.stats().drain.unwrap().aborted_connections, 0);
+
+        // The silent one is aborted at the deadline, and serving stops
+        acceptor.join().unwrap();
+        let stats = server.stats();
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(stats.in_flight_requests, 0);
+        assert_eq!(stats.drain.unwrap().aborted_connections, 1);
+        drop(silent);
+    }
+
+    // Test reading request bodies by Content-Length
+    // The body may arrive over several reads, extra bytes are not part of it
+    #[test]
+    fn test_read_request_body_content_length() {
+        let mut router = Router::new();
+        router.post("/upload", |request: Request| {
+            Response::ok("text/plain", format!("{} bytes", request.body.len()))
+        });
+        let body = "x".repeat(5000);
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5000\r\nExpect: 100-continue\r\n\r\n{}trailing",
+            body
+        );
+
+        let mut stream = MemoryStream::new(raw.into_bytes()).max_read(333);
+        serve_connection(&mut stream, "", &router);
+        let response = String::from_utf8_lossy(stream.output());
+        assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK"));
+        assert!(response.ends_with("5000 bytes"));
+
+        // Without Content-Length there is no body
+        let mut stream =
+            MemoryStream::new(b"POST /upload HTTP/1.1\r\nHost: localhost\r\n\r\nignored".to_vec());
+        serve_connection(&mut stream, "", &router);
+        assert!(String::from_utf8_lossy(stream.output()).ends_with("0 bytes"));
+    }
+
+    // Test invalid or unacceptable request bodies
+    // Each is rejected before the handler runs
+    #[test]
+    fn test_read_request_body_errors() {
+        let status_of = |raw: &[u8]| {
+            let response = send_request(raw, "", &HashMap::new());
+            String::from_utf8_lossy(&response)
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nshort"),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n"),
+            "HTTP/1.1 400 Bad Request"
+        );
+        let too_large = format!(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(
+            status_of(too_large.as_bytes()),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"),
+            "HTTP/1.1 501 Not Implemented"
+        );
+    }
+
+    // Test binary request bodies
+    // Bodies containing NUL, invalid UTF-8 and CRLFCRLF must reach the handler unchanged
+    #[test]
+    fn test_binary_request_body() {
+        let payload: Vec<u8> = vec![0x00, 0xff, 0xfe, b'\r', b'\n', b'\r', b'\n', 0x80, 0x01];
+        let mut router = Router::new();
+        router.post("/echo", |Bytes(data): Bytes| {
+            Response::ok("application/octet-stream", data)
+        });
+        router.post("/text", |Text(text): Text| Response::ok("text/plain", text));
+
+        let mut raw = format!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            payload.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&payload);
+        let mut stream = MemoryStream::new(raw);
+        serve_connection(&mut stream, "", &router);
+        assert!(stream.output().ends_with(&payload));
+
+        let mut request = Request::parse(b"POST /text HTTP/1.1\r\n\r\n\xff");
+        let response = router.handle(&mut request).unwrap();
+        assert_eq!(response.status, 400);
+    }
+
+    // Test the public TestClient
+    // Requests go through the whole pipeline and responses come back parsed
+    #[test]
+    fn test_test_client() {
+        let mut router = Router::new();
+        router.get("/api/hello", || {
+            Response::ok("application/json", r#"{"hello": true}"#)
+        });
+        router.post("/api/echo", |request: Request| {
+            Response::ok("text/plain", request.body.clone())
+        });
+        router.get("/stream", || {
+            Response::ok("text/plain", Body::from_chunks(["ab", "cd"]))
+        });
+        let client = TestClient::new(router);
+
+        let response = client.get("/api/hello");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(response.text(), r#"{"hello": true}"#);
+
+        let response = client.post("/api/echo", "text/plain", "ping");
+        assert_eq!(response.body, b"ping");
+        assert_eq!(client.get("/stream").body, b"abcd");
+        assert_eq!(
+            client.get("/missing").snapshot(),
+            "HTTP/1.1 404 Not Found\nContent-Type: text/plain\n\n"
+        );
+        assert_eq!(client.send_raw(b"GET / HTTP/1.1\r\n\r\n").status, 400);
+    }
+
+    // Sends a raw request through a Router and returns the response
+    fn route_request(router: &Router, raw: &[u8]) -> Option<Response> {
+        let mut request = Request::parse(raw);
+        router.handle(&mut request)
+    }
+
+    // Test path parameter extraction into single values, tuples and structs
+    // Verifies that parameters are parsed into the requested types
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_extract_path_params() {
+        #[derive(Deserialize)]
+        struct PostPath {
+            user: String,
+            post: u32,
+        }
+
+        let mut router = Router::new();
+        router.get("/users/{id}", |Path(id): Path<u32>| {
+            Response::ok("text/plain", format!("user {}", id + 1))
+        });
+        router.get("/pairs/{a}/{b}", |Path((a, b)): Path<(String, u8)>| {
+            Response::ok("text/plain", format!("{} {}", a, b))
+        });
+        router.get("/users/{user}/posts/{post}", |Path(p): Path<PostPath>| {
+            Response::ok("text/plain", format!("{} {}", p.user, p.post))
+        });
+
+        let response = route_request(&router, b"GET /users/41 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"user 42");
+
+        let response = route_request(&router, b"GET /pairs/x%20y/7 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"x y 7");
+
+        let response = route_request(&router, b"GET /users/ann/posts/3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"ann 3");
+    }
+
+    // Test rejection of path parameters that cannot be parsed
+    // The handler must not run and a 400 must be returned instead
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_extract_path_rejects_invalid_param() {
+        let mut router = Router::new();
+        router.get("/users/{id}", |Path(_id): Path<u32>| -> Response {
+            panic!("handler must not run")
+        });
+
+        let response = route_request(&router, b"GET /users/abc HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 400);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+    }
+
+    // Test query string and header extraction
+    // Verifies decoding, optional fields and case-insensitive header lookup
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_extract_query_and_headers() {
+        #[derive(Deserialize)]
+        struct Search {
+            q: String,
+            page: Option<u32>,
+        }
+
+        let mut router = Router::new();
+        router.get(
+            "/search",
+            |Query(search): Query<Search>, headers: Headers| {
+                let body = format!(
+                    "{} {:?} {}",
+                    search.q,
+                    search.page,
+                    headers.get("user-agent").unwrap_or("-")
+                );
+                Response::ok("text/plain", body)
+            },
+        );
+
+        let response = route_request(
+            &router,
+            b"GET /search?q=hello+world%21 HTTP/1.1\r\nUser-Agent: test\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"hello world! None test");
+
+        let response = route_request(&router, b"GET /search?page=2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 400);
+    }
+
+    // Test JSON body extraction through a full connection
+    // Verifies that valid bodies reach the handler and invalid ones get a 400
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_extract_json_body() {
+        #[derive(Deserialize)]
+        struct NewUser {
+            name: String,
+        }
+
+        let mut router = Router::new();
+        router.post("/users", |Json(user): Json<NewUser>| {
+            Response::new(201, "Created").with_body("text/plain", user.name)
+        });
+
+        let mut stream = MemoryStream::new(b"POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 14\r\n\r\n{\"name\":\"ann\"}"
+                    .to_vec(),);
+        serve_connection(&mut stream, "", &router);
+        assert_eq!(
+            snapshot_response(stream.output()),
+            "HTTP/1.1 201 Created\nContent-Length: 3\nContent-Type: text/plain\n\nann"
+        );
+
+        let response = route_request(&router, b"POST /users HTTP/1.1\r\n\r\n{\"name\":").unwrap();
+        assert_eq!(response.status, 400);
+    }
+
+    // Test route groups sharing a prefix and their own middleware
+    // Verifies that the auth middleware only guards routes under /admin
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_router_scope_prefix_and_middleware() {
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "home"));
+        router.scope("/admin", |admin| {
+            admin.layer(
+                |request: &Request, next: &Next| match request.header("Authorization") {
+                    Some("secret") => next.run(request),
+                    _ => Response::new(401, "Unauthorized"),
+                },
+            );
+            admin.get("/", || Response::ok("text/plain", "dashboard"));
+            admin.get("/users/{id}", |Path(id): Path<u32>| {
+                Response::ok("text/plain", format!("user {}", id))
+            });
+        });
+
+        let response = route_request(&router, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"home");
+
+        let response = route_request(&router, b"GET /admin/users/7 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 401);
+
+        let response = route_request(
+            &router,
+            b"GET /admin/users/7 HTTP/1.1\r\nAuthorization: secret\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"user 7");
+
+        let response = route_request(
+            &router,
+            b"GET /admin HTTP/1.1\r\nAuthorization: secret\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"dashboard");
+    }
+
+    // Test nested scopes
+    // Outer middleware must run before inner middleware, prefixes must stack
+    #[test]
+    fn test_router_nested_scopes() {
+        let mut router = Router::new();
+        router.layer(|request: &Request, next: &Next| {
+            next.run(request).with_header("X-Layer", "root")
+        });
+        router.scope("/api", |api| {
+            api.layer(|request: &Request, next: &Next| {
+                next.run(request).with_header("X-Layer", "api")
+            });
+            api.scope("/v1/", |v1| {
+                v1.layer(|request: &Request, next: &Next| {
+                    next.run(request).with_header("X-Layer", "v1")
+                });
+                v1.get("/ping", || Response::ok("text/plain", "pong"));
+            });
+        });
+
+        let response = route_request(&router, b"GET /api/v1/ping HTTP/1.1\r\n\r\n").unwrap();
+        let layers: Vec<&str> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "X-Layer")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        // Responses travel back out, so the innermost layer tags first
+        assert_eq!(layers, ["v1", "api", "root"]);
+        assert!(route_request(&router, b"GET /ping HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    // Test middleware attached to single routes and whole groups with with
+    // Only the routes registered last must be wrapped, after the router's layers
+    #[test]
+    fn test_router_per_route_middleware() {
+        let tag = |name: &'static str| {
+            move |request: &Request, next: &Next| next.run(request).with_header("X-Layer", name)
+        };
+        let mut router = Router::new();
+        router.layer(tag("root"));
+        router.get("/public", || Response::ok("text/plain", "public"));
+        router
+            .get("/private", || Response::ok("text/plain", "private"))
+            .with(
+                |request: &Request, next: &Next| match request.header("Authorization") {
+                    Some("secret") => next.run(request),
+                    _ => Response::new(401, "Unauthorized"),
+                },
+            )
+            .with(tag("private"));
+        router
+            .scope("/api", |api| {
+                api.layer(tag("api"));
+                api.get("/a", || Response::ok("text/plain", "a"));
+                api.get("/b", || Response::ok("text/plain", "b"))
+                    .with(tag("b"));
+            })
+            .with(tag("group"));
+
+        let layers = |raw: &[u8]| -> Vec<String> {
+            route_request(&router, raw)
+                .unwrap()
+                .headers
+                .into_iter()
+                .filter(|(name, _)| name == "X-Layer")
+                .map(|(_, value)| value)
+                .collect()
+        };
+        assert_eq!(layers(b"GET /public HTTP/1.1\r\n\r\n"), ["root"]);
+
+        let response = route_request(&router, b"GET /private HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            layers(b"GET /private HTTP/1.1\r\nAuthorization: secret\r\n\r\n"),
+            ["private", "root"]
+        );
+        assert_eq!(
+            layers(b"GET /api/a HTTP/1.1\r\n\r\n"),
+            ["group", "api", "root"]
+        );
+        assert_eq!(
+            layers(b"GET /api/b HTTP/1.1\r\n\r\n"),
+            ["group", "b", "api", "root"]
+        );
+    }
+
+    // Test request and response hooks
+    // Hooks must see every routed request and edit responses after middleware
+    #[test]
+    fn test_router_request_and_response_hooks() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut router = Router::new();
+        let log = std::sync::Arc::clone(&seen);
+        router.on_request(move |request| log.lock().unwrap().push(request.path.clone()));
+        router.layer(|request: &Request, next: &Next| {
+            next.run(request).with_header("X-Layer", "root")
+        });
+        router.on_response(|request, response| {
+            response
+                .headers
+                .push(("X-Path".to_string(), request.path.clone()));
+        });
+        router.get("/a", || Response::ok("text/plain", "a"));
+        router.scope("/api", |api| {
+            api.on_response(|_, response| response.status = 202);
+            api.post("/b", || Response::ok("text/plain", "b"));
+        });
+
+        let response = route_request(&router, b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("X-Path"), Some("/a"));
+        assert_eq!(response.header("X-Layer"), Some("root"));
+        assert_eq!(response.status, 202);
+
+        // 405 responses pass through the hooks as well
+        let response = route_request(&router, b"GET /api/b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("X-Path"), Some("/api/b"));
+        assert!(route_request(&router, b"GET /missing HTTP/1.1\r\n\r\n").is_none());
+
+        assert_eq!(*seen.lock().unwrap(), ["/a", "/api/b", "/missing"]);
+    }
+
+    // Test binding with TCP Fast Open and defer accept enabled
+    // Verifies the options are applied on Linux and a request is still served
+    #[test]
+    fn test_server_listener_options() {
+        let mut router = Router::new();
+        router.get("/ping", || Response::ok("text/plain", "pong"));
+        let server = Server::new(router, "").config(ServerConfig {
+            tcp_fast_open: Some(16),
+            defer_accept: Some(std::time::Duration::from_secs(1)),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let read_option = |name| {
+                let mut value: libc::c_int = 0;
+                let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+                let result = unsafe {
+                    libc::getsockopt(
+                        listener.as_raw_fd(),
+                        libc::IPPROTO_TCP,
+                        name,
+                        &mut value as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                assert_eq!(result, 0);
+                value
+            };
+            assert_eq!(read_option(libc::TCP_FASTOPEN), 16);
+            assert!(read_option(libc::TCP_DEFER_ACCEPT) > 0);
+        }
+
+        std::thread::spawn(move || server.serve(listener));
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("pong"));
+    }
+
+    // Sends a raw request through serve_connection using a MemoryStream
+    fn serve_request(request: &[u8], base_dir: &str, router: &Router) -> String {
+        let mut stream = MemoryStream::new(request);
+        serve_connection(&mut stream, base_dir, router);
+        String::from_utf8_lossy(stream.output()).to_string()
+    }
+
+    // Test the fast path for small cached static files
+    // Once cached, the file is served from memory even if it changes on disk
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_file_cache_fast_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let file_path = temp_dir.path().join("style.css");
+        let set_mtime = |secs| {
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            File::options()
+                .write(true)
+                .open(&file_path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        std::fs::write(&file_path, "body {}").unwrap();
+        set_mtime(0x10000);
+
+        let mut router = Router::new();
+        router.cache_static_files(FileCache::new(1024, 16));
+        let request = b"GET /style.css?v=1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let first = serve_request(request, base_dir, &router);
+        assert_eq!(
+            first,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Length: 7\r\n\
+             ETag: \"3b9aca000000-7\"\r\nLast-Modified: Thu, 01 Jan 1970 18:12:16 GMT\r\n\r\nbody {}"
+        );
+        assert!(router.file_cache().unwrap().get("/style.css").is_some());
+        let second = serve_request(request, base_dir, &router);
+        assert_eq!(first, second);
+
+        // A new mtime invalidates the entry, even if the size is unchanged
+        std::fs::write(&file_path, "changed").unwrap();
+        set_mtime(0x20000);
+        let third = serve_request(request, base_dir, &router);
+        assert!(third.ends_with(
+            "ETag: \"773594000000-7\"\r\nLast-Modified: Fri, 02 Jan 1970 12:24:32 GMT\r\n\r\nchanged"
+        ));
+
+        // The fast path must not bypass the Host header check
+        let no_host = serve_request(b"GET /style.css HTTP/1.1\r\n\r\n", base_dir, &router);
+        assert!(no_host.starts_with("HTTP/1.1 400"));
+
+        // Nor range requests for the cached file
+        let ranged = b"GET /style.css HTTP/1.1\r\nHost: localhost\r\nrange: bytes=0-3\r\n\r\n";
+        for _ in 0..2 {
+            let partial = serve_request(ranged, base_dir, &router);
+            assert!(partial.starts_with("HTTP/1.1 206"), "{}", partial);
+            assert!(partial.ends_with("\r\n\r\nchan"), "{}", partial);
+        }
+    }
+
+    // Test which files stay out of the cache
+    // Files above the size limit and paths served by routes are never cached
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_file_cache_skips_large_files_and_routes() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::write(temp_dir.path().join("big.html"), "x".repeat(100)).unwrap();
+        std::fs::write(temp_dir.path().join("api.html"), "file").unwrap();
+
+        let mut router = Router::new();
+        router.cache_static_files(FileCache::new(10, 16));
+        router.get("/api.html", || Response::ok("text/plain", "route"));
+
+        let big = serve_request(
+            b"GET /big.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(big.starts_with("HTTP/1.1 200 OK"));
+        assert!(router.file_cache().unwrap().get("/big.html").is_none());
+
+        let routed = serve_request(
+            b"GET /api.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(routed.ends_with("route"));
+        assert!(router.file_cache().unwrap().get("/api.html").is_none());
+    }
+
+    // Test routes added to a running server over cached files
+    // The fast path must not keep serving a file a new route now answers
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_file_cache_forgets_files_shadowed_by_new_routes() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("news.html"), "file").unwrap();
+        let mut router = Router::new();
+        router.cache_static_files(FileCache::new(1024, 16));
+        let server = Server::new(router, temp_dir.path().to_str().unwrap());
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let news = || {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(
+                    b"GET /news.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(news().ends_with("\r\n\r\nfile"));
+        assert!(news().ends_with("\r\n\r\nfile"));
+
+        server.update_router(|router| {
+            router.get("/news.html", || Response::ok("text/plain", "route"));
+        });
+        assert!(news().ends_with("\r\n\r\nroute"));
+        let mut router = Router::new();
+        router.cache_static_files(FileCache::new(1024, 16));
+        server.reload(router, temp_dir.path().to_str().unwrap());
+        assert!(news().ends_with("\r\n\r\nfile"));
+    }
+
+    // Test eviction from a full file cache
+    // The least recently used file makes room for the new one
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_file_cache_lru_eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let path_of = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+        for name in ["a.css", "b.css", "c.css"] {
+            std::fs::write(path_of(name), name).unwrap();
+        }
+
+        let cache = FileCache::new(1024, 2);
+        cache.load("/a.css", path_of("a.css"), "text/css").unwrap();
+        cache.load("/b.css", path_of("b.css"), "text/css").unwrap();
+        assert!(cache.get("/a.css").is_some());
+        cache.load("/c.css", path_of("c.css"), "text/css").unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("/a.css").is_some());
+        assert!(cache.get("/b.css").is_none());
+        assert!(cache.get("/c.css").is_some());
+
+        // Deleted files are dropped on the next lookup
+        std::fs::remove_file(path_of("c.css")).unwrap();
+        assert!(cache.get("/c.css").is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    // Test serving large static files from memory maps
+    // Files below the threshold keep using the regular path
+    #[test]
+    #[cfg(all(feature = "mmap", unix))]
+    fn test_mmap_static_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::write(temp_dir.path().join("big.html"), "x".repeat(100)).unwrap();
+        std::fs::write(temp_dir.path().join("small.html"), "small").unwrap();
+        for name in ["big.html", "small.html"] {
+            File::options()
+                .write(true)
+                .open(temp_dir.path().join(name))
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(0x10000))
+                .unwrap();
+        }
+
+        let mut router = Router::new();
+        router.mmap_static_files(64);
+
+        let big = serve_request(
+            b"GET /big.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert_eq!(
+            big,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 100\r\n\
+                 ETag: \"3b9aca000000-64\"\r\nLast-Modified: Thu, 01 Jan 1970 18:12:16 GMT\r\n\r\n{}",
+                "x".repeat(100)
+            )
+        );
+        let small = serve_request(
+            b"GET /small.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert_eq!(
+            small,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nETag: \"3b9aca000000-5\"\r\n\
+             Last-Modified: Thu, 01 Jan 1970 18:12:16 GMT\r\n\r\nsmall"
+        );
+    }
+
+    // Test static file transfer over a real TCP connection
+    // On Linux the body is sent with sendfile, elsewhere through the regular copy
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_serve_tcp_connection_static_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let body = "<p>sendfile</p>".repeat(10_000);
+        std::fs::write(temp_dir.path().join("page.html"), &body).unwrap();
+        File::options()
+            .write(true)
+            .open(temp_dir.path().join("page.html"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(0x10000))
+            .unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap().to_string();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_tcp_connection(stream, &base_dir, &Router::new());
+        });
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /page.html HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        let expected_head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\
+             ETag: \"3b9aca000000-{:x}\"\r\nLast-Modified: Thu, 01 Jan 1970 18:12:16 GMT\r\n\r\n",
+            body.len(),
+            body.len()
+        );
+        assert_eq!(response, expected_head + body.as_str());
+    }
+
+    // Test buffer reuse through the pool
+    // Returned buffers come back empty, oversized ones are not kept
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let pool = BufferPool::new(1, 64);
+        {
+            let mut buffer = pool.get();
+            buffer.extend_from_slice(b"hello");
+        }
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 5);
+        drop(buffer);
+
+        pool.get().extend_from_slice(&[0; 100]);
+        let mut first = pool.get();
+        let second = pool.get();
+        assert_eq!(first.capacity(), 0);
+        assert_eq!(second.capacity(), 0);
+
+        // Only max_buffers buffers are kept
+        first.extend_from_slice(b"a");
+        drop(first);
+        drop(second);
+        let reused = pool.get();
+        assert!(reused.capacity() > 0);
+        assert_eq!(pool.get().capacity(), 0);
+    }
+
+    // Test the trailing slash policies
+    // Strict keeps 404s, Redirect points to the registered form, Equivalent serves both
+    #[test]
+    fn test_router_trailing_slash_policy() {
+        let mut router = Router::new();
+        router.get("/docs/", || Response::ok("text/plain", "docs"));
+        router.post("/items", || Response::ok("text/plain", "created"));
+
+        assert!(route_request(&router, b"GET /docs HTTP/1.1\r\n\r\n").is_none());
+
+        router.trailing_slash(TrailingSlash::Redirect);
+        let response = route_request(&router, b"GET /docs?page=2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 301);
+        assert_eq!(response.header("Location"), Some("/docs/?page=2"));
+        let response = route_request(&router, b"POST /items/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 308);
+        assert_eq!(response.header("Location"), Some("/items"));
+
+        router.trailing_slash(TrailingSlash::Equivalent);
+        let response = route_request(&router, b"GET /docs HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"docs");
+        let response = route_request(&router, b"POST /items/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"created");
+        assert!(route_request(&router, b"GET /other/ HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    // Test worker thread naming and custom stack sizes
+    // Handlers must run on threads named "worker-N" spawned by the "acceptor"
+    #[test]
+    fn test_server_thread_names() {
+        let mut router = Router::new();
+        router.get("/thread", || {
+            let name = std::thread::current().name().unwrap_or("").to_string();
+            Response::ok("text/plain", name)
+        });
+        let server = Server::new(router, "").config(ServerConfig {
+            worker_stack_size: Some(4 * 1024 * 1024),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = server.start(listener).unwrap();
+        assert_eq!(acceptor.thread().name(), Some("acceptor"));
+
+        for expected in ["worker-0", "worker-1"] {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /thread HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with(expected));
+        }
+    }
+
+    // Test the event loop backend
+    // A client stalled mid-request must not block others on the same loop thread
+    #[test]
+    #[cfg(feature = "event-loop")]
+    fn test_server_event_loop() {
+        let mut router = Router::new();
+        router.get("/thread", || {
+            let name = std::thread::current().name().unwrap_or("").to_string();
+            Response::ok("text/plain", name)
+        });
+        router.post("/echo", |request: Request| {
+            Response::ok("text/plain", request.body.clone())
+        });
+        let server = Server::new(router, "").config(ServerConfig {
+            event_loop_threads: Some(1),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let mut stalled = std::net::TcpStream::connect(addr).unwrap();
+        stalled
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhe")
+            .unwrap();
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /thread HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("event-loop-0"));
+
+        stalled.write_all(b"llo").unwrap();
+        let mut response = String::new();
+        stalled.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    // Test the io_uring backend
+    // Routes, request bodies and static files spliced in several chunks must all arrive intact
+    #[test]
+    #[cfg(feature = "io-uring")]
+    fn test_server_io_uring() {
+        let temp_dir = TempDir::new().unwrap();
+        let file: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(temp_dir.path().join("large.bin"), &file).unwrap();
+
+        let mut router = Router::new();
+        router.post("/echo", |request: Request| {
+            Response::ok("text/plain", request.body.clone())
+        });
+        let server = Server::new(router, temp_dir.path().to_str().unwrap()).config(ServerConfig {
+            io_uring_entries: Some(64),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhe")
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        client.write_all(b"llo").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(&response[head_len..], &file[..]);
+    }
+
+    // Test reloading the router on SIGHUP
+    // Connections accepted after the signal must be served by the new router,
+    // and bursts of signals must neither block on the full pipe nor clobber errno
+    #[test]
+    #[cfg(unix)]
+    fn test_server_reload_on_sighup() {
+        let mut router = Router::new();
+        router.get("/version", || Response::ok("text/plain", "1"));
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        server
+            .reload_on_sighup(|server| {
+                let mut router = Router::new();
+                router.get("/version", || Response::ok("text/plain", "2"));
+                server.reload(router, "");
+                Ok(())
+            })
+            .unwrap();
+
+        let version = || {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /version HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(version().ends_with("\r\n\r\n1"));
+
+        unsafe { libc::raise(libc::SIGHUP) };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while !version().ends_with("\r\n\r\n2") {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "router was not reloaded"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // More signals than the pipe holds bytes
+        for _ in 0..100_000 {
+            unsafe { libc::raise(libc::SIGHUP) };
+        }
+        unsafe {
+            libc::close(-1);
+            libc::raise(libc::SIGHUP);
+        }
+        let errno = std::io::Error::last_os_error().raw_os_error();
+        assert_eq!(errno, Some(libc::EBADF));
+    }
+
+    // Test draining on SIGTERM
+    // The server must stop accepting, so the acceptor ends, and report the drain
+    #[test]
+    #[cfg(all(unix, feature = "metrics"))]
+    fn test_server_drain_on_sigterm() {
+        let server = Server::new(Router::new(), "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let acceptor = server.start(listener).unwrap();
+        let terminator = server.drain_on_sigterm().unwrap();
+        assert!(server.drain_on_sigterm().is_err());
+        assert!(server.stats().drain.is_none());
+
+        unsafe { libc::raise(libc::SIGTERM) };
+        acceptor.join().unwrap();
+        assert!(!server.is_accepting());
+        assert!(server.stats().drain.is_some());
+        assert!(!terminator.is_finished());
+    }
+
+    // Test PID files
+    // A running process's file must be kept, a stale one replaced, and ours removed on drop
+    #[test]
+    #[cfg(unix)]
+    fn test_pid_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("server.pid");
+        let pid_file = crate::PidFile::create(&path).unwrap();
+        assert_eq!(pid_file.path(), path);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        let mut other = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        std::fs::write(&path, format!("{}\n", other.id())).unwrap();
+        let error = crate::PidFile::create(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(path.exists());
+
+        other.kill().unwrap();
+        other.wait().unwrap();
+        let _pid_file = crate::PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+    }
+
+    // Test live reload mode
+    // HTML pages get the reload script and open event streams hear about file changes
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_live_reload() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::write(
+            temp_dir.path().join("index.html"),
+            "<html><BODY>hi</BODY></html>",
+        )
+        .unwrap();
+        std::fs::write(temp_dir.path().join("style.css"), "body {}").unwrap();
+        let mut router = Router::new();
+        router.live_reload();
+
+        let page = serve_request(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.contains("hi<script>new EventSource(\"/__live-reload\")"));
+        assert!(page.ends_with("</script></BODY></html>"));
+        let css = serve_request(
+            b"GET /style.css HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(css.ends_with("\r\n\r\nbody {}"));
+
+        let server = Server::new(router, base_dir);
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+        let mut events = std::net::TcpStream::connect(addr).unwrap();
+        events
+            .write_all(b"GET /__live-reload HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 1024];
+        while !String::from_utf8_lossy(&received).contains("retry: 1000") {
+            let n = events.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+        assert!(String::from_utf8_lossy(&received).contains("text/event-stream"));
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        std::fs::write(temp_dir.path().join("style.css"), "body { color: red; }").unwrap();
+        events
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        while !String::from_utf8_lossy(&received).contains("event: reload") {
+            let n = events.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    // Test server-side includes
+    // Nested includes expand, while loops, traversal, protected files and other
+    // directives become error markers
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_server_side_includes() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join("parts")).unwrap();
+        std::fs::write(
+            root.join("index.shtml"),
+            r#"<!--#include file="header.html" -->body<!--#include virtual="/parts/footer.html" -->"#,
+        )
+        .unwrap();
+        std::fs::write(root.join("header.html"), "<h1>Site</h1>").unwrap();
+        std::fs::write(
+            root.join("parts/footer.html"),
+            r#"<footer><!--#include file="nav.html" --></footer>"#,
+        )
+        .unwrap();
+        std::fs::write(root.join("parts/nav.html"), "nav").unwrap();
+        std::fs::write(
+            root.join("loop.shtml"),
+            r#"x<!--#include file="loop.shtml" -->"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("bad.shtml"),
+            r#"<!--#include file="../secret" --><!--#exec cmd="ls" --><!--#include file="missing" -->"#,
+        )
+        .unwrap();
+        std::fs::create_dir(root.join("private")).unwrap();
+        std::fs::write(
+            root.join("private/.webserver.toml"),
+            "[auth]\n[auth.users]\nalice = \"secret\"\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("private/notes.txt"), "notes").unwrap();
+        std::fs::write(
+            root.join("leak.shtml"),
+            r#"<!--#include virtual="/private/notes.txt" -->"#,
+        )
+        .unwrap();
+        let mut router = Router::new();
+        router
+            .server_side_includes(&["shtml"])
+            .directory_overrides();
+
+        let page = serve_request(
+            b"GET /index.shtml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.contains("Content-Type: text/html\r\n"));
+        assert!(page.ends_with("\r\n\r\n<h1>Site</h1>body<footer>nav</footer>"));
+
+        let marker = "[an error occurred while processing this directive]";
+        let page = serve_request(
+            b"GET /loop.shtml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.ends_with(&format!("{}{}", "x".repeat(9), marker)));
+        let page = serve_request(
+            b"GET /bad.shtml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.ends_with(&marker.repeat(3)));
+        let page = serve_request(
+            b"GET /leak.shtml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.ends_with(marker));
+
+        // Files with other extensions are served as they are
+        let page = serve_request(
+            b"GET /parts/footer.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.ends_with("<!--#include file=\"nav.html\" --></footer>"));
+    }
+
+    // Test rendering Markdown files
+    // .md files become HTML pages in the template, titled after their first heading
+    #[test]
+    #[cfg(feature = "markdown")]
+    fn test_markdown_rendering() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::write(
+            temp_dir.path().join("guide.md"),
+            "# Install & run\n\nUse `cargo run`.\n\n| a |\n|---|\n| 1 |\n",
+        )
+        .unwrap();
+        std::fs::write(temp_dir.path().join("notes.md"), "no heading").unwrap();
+        let mut router = Router::new();
+        router.markdown_template("<title>{title}</title><main>{content}</main>");
+
+        let page = serve_request(
+            b"GET /guide.md HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.contains("Content-Type: text/html\r\n"));
+        assert!(page.contains("<title>Install &amp; run</title>"));
+        assert!(page.contains("<h1>Install &amp; run</h1>"));
+        assert!(page.contains("<p>Use <code>cargo run</code>.</p>"));
+        assert!(page.contains("<table>"));
+        assert!(page.ends_with("</main>"));
+
+        let page = serve_request(
+            b"GET /notes.md HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &router,
+        );
+        assert!(page.contains("<title>notes.md</title><main><p>no heading</p>"));
+
+        // Without the mode, Markdown is sent as it is
+        let page = serve_request(
+            b"GET /notes.md HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base_dir,
+            &Router::new(),
+        );
+        assert!(page.ends_with("\r\n\r\nno heading"));
+    }
+
+    // Test the WebDAV share
+    // Authenticated clients can create, list, copy, move, lock and delete files
+    #[test]
+    #[cfg(feature = "webdav")]
+    fn test_webdav_share() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let mut router = Router::new();
+        router.webdav("/dav", root.to_str().unwrap(), |user, password| {
+            user == "me" && password == "secret"
+        });
+        let client = TestClient::new(router);
+        let auth = ("Authorization", "Basic bWU6c2VjcmV0");
+
+        let response = client.request("PROPFIND", "/dav/", &[("Depth", "1")], b"");
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            response.header("WWW-Authenticate"),
+            Some("Basic realm=\"WebDAV\"")
+        );
+        let wrong = ("Authorization", "Basic bWU6d3Jvbmc=");
+        assert_eq!(client.request("GET", "/dav/", &[wrong], b"").status, 401);
+
+        assert_eq!(
+            client.request("MKCOL", "/dav/docs", &[auth], b"").status,
+            201
+        );
+        assert_eq!(
+            client.request("MKCOL", "/dav/docs", &[auth], b"").status,
+            405
+        );
+        assert_eq!(
+            client.request("MKCOL", "/dav/a/b", &[auth], b"").status,
+            409
+        );
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/a%20b.txt", &[auth], b"hello")
+                .status,
+            201
+        );
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/a%20b.txt", &[auth], b"hello!")
+                .status,
+            204
+        );
+        assert_eq!(std::fs::read(root.join("docs/a b.txt")).unwrap(), b"hello!");
+        // Writes guarded by a stale ETag or by If-None-Match: * are refused
+        let etag = client
+            .request("GET", "/dav/docs/a%20b.txt", &[auth], b"")
+            .header("ETag")
+            .unwrap()
+            .to_string();
+        let stale = [auth, ("If-Match", "\"stale\"")];
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/a%20b.txt", &stale, b"lost")
+                .status,
+            412
+        );
+        let create_only = [auth, ("If-None-Match", "*")];
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/a%20b.txt", &create_only, b"lost")
+                .status,
+            412
+        );
+        let current = [auth, ("If-Match", etag.as_str())];
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/a%20b.txt", &current, b"hello!")
+                .status,
+            204
+        );
+        assert_eq!(
+            client
+                .request("GET", "/dav/docs/a%20b.txt", &[auth], b"")
+                .body,
+            b"hello!"
+        );
+        assert_eq!(
+            client
+                .request("GET", "/dav/../etc/passwd", &[auth], b"")
+                .status,
+            403
+        );
+
+        let response = client.request("PROPFIND", "/dav/docs/", &[auth, ("Depth", "1")], b"");
+        assert_eq!(response.status, 207);
+        let xml = response.text();
+        assert!(xml.contains("<D:href>/dav/docs/</D:href>"));
+        assert!(xml.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(xml.contains("<D:href>/dav/docs/a%20b.txt</D:href>"));
+        assert!(xml.contains("<D:getcontentlength>6</D:getcontentlength>"));
+        assert!(xml.contains(" GMT</D:getlastmodified>"));
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+        assert_eq!(
+            crate::http_date::format_http_date(date),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        let response = client.request("PROPFIND", "/dav/docs/", &[auth], b"");
+        assert_eq!(response.status, 403);
+
+        let copy = [auth, ("Destination", "http://localhost/dav/docs/copy.txt")];
+        assert_eq!(
+            client
+                .request("COPY", "/dav/docs/a%20b.txt", &copy, b"")
+                .status,
+            201
+        );
+        let no_overwrite = [
+            auth,
+            ("Destination", "/dav/docs/copy.txt"),
+            ("Overwrite", "F"),
+        ];
+        assert_eq!(
+            client
+                .request("COPY", "/dav/docs/a%20b.txt", &no_overwrite, b"")
+                .status,
+            412
+        );
+        let outside = [auth, ("Destination", "/elsewhere/copy.txt")];
+        assert_eq!(
+            client
+                .request("MOVE", "/dav/docs/copy.txt", &outside, b"")
+                .status,
+            502
+        );
+        assert_eq!(
+            client.request("MKCOL", "/dav/archive", &[auth], b"").status,
+            201
+        );
+        let move_docs = [auth, ("Destination", "/dav/archive/docs")];
+        assert_eq!(
+            client.request("MOVE", "/dav/docs", &move_docs, b"").status,
+            201
+        );
+        assert_eq!(
+            std::fs::read(root.join("archive/docs/copy.txt")).unwrap(),
+            b"hello!"
+        );
+        assert!(!root.join("docs").exists());
+
+        let response = client.request("LOCK", "/dav/new.txt", &[auth], b"<lockinfo/>");
+        assert_eq!(response.status, 201);
+        assert!(
+            response
+                .header("Lock-Token")
+                .unwrap()
+                .starts_with("<opaquelocktoken:")
+        );
+        assert!(root.join("new.txt").exists());
+        assert_eq!(
+            client
+                .request("UNLOCK", "/dav/new.txt", &[auth], b"")
+                .status,
+            204
+        );
+
+        assert_eq!(
+            client
+                .request("DELETE", "/dav/archive", &[auth], b"")
+                .status,
+            204
+        );
+        assert!(!root.join("archive").exists());
+        assert_eq!(client.request("DELETE", "/dav/", &[auth], b"").status, 403);
+        assert_eq!(
+            client
+                .request("DELETE", "/dav/archive", &[auth], b"")
+                .status,
+            404
+        );
+    }
+
+    // Test the WebDAV share's limits
+    // Collections can't be copied into themselves nor replace their ancestors,
+    // and the router's hidden file and link rules apply, even set after mounting
+    #[test]
+    #[cfg(all(feature = "webdav", unix))]
+    fn test_webdav_share_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let outside = TempDir::new().unwrap();
+        std::fs::create_dir(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.txt"), b"a").unwrap();
+        std::fs::write(root.join("docs/.env"), b"SECRET=1").unwrap();
+        std::fs::write(outside.path().join("passwd"), b"root").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("docs/link")).unwrap();
+        let mut router = Router::new();
+        router.webdav("/dav", root.to_str().unwrap(), |_, _| true);
+        router.symlinks(crate::SymlinkPolicy::Deny);
+        let client = TestClient::new(router);
+        let auth = ("Authorization", "Basic bWU6c2VjcmV0");
+
+        let into_itself = [auth, ("Destination", "/dav/docs/sub")];
+        assert_eq!(
+            client
+                .request("COPY", "/dav/docs", &into_itself, b"")
+                .status,
+            403
+        );
+        assert!(!root.join("docs/sub").exists());
+        let onto_parent = [auth, ("Destination", "/dav/docs")];
+        assert_eq!(
+            client
+                .request("MOVE", "/dav/docs/a.txt", &onto_parent, b"")
+                .status,
+            403
+        );
+        assert_eq!(std::fs::read(root.join("docs/a.txt")).unwrap(), b"a");
+        let missing_parent = [auth, ("Destination", "/dav/none/docs")];
+        assert_eq!(
+            client
+                .request("MOVE", "/dav/docs", &missing_parent, b"")
+                .status,
+            409
+        );
+
+        assert_eq!(
+            client.request("GET", "/dav/docs/.env", &[auth], b"").status,
+            404
+        );
+        assert_eq!(
+            client
+                .request("PUT", "/dav/docs/.env", &[auth], b"SECRET=2")
+                .status,
+            404
+        );
+        assert_eq!(
+            client
+                .request("GET", "/dav/docs/link/passwd", &[auth], b"")
+                .status,
+            403
+        );
+        let response = client.request("PROPFIND", "/dav/docs/", &[auth, ("Depth", "1")], b"");
+        let xml = response.text();
+        assert!(xml.contains("<D:href>/dav/docs/a.txt</D:href>"));
+        assert!(!xml.contains(".env"));
+        assert!(!xml.contains("link"));
+        let copy = [auth, ("Destination", "/dav/copy")];
+        assert_eq!(client.request("COPY", "/dav/docs", &copy, b"").status, 201);
+        assert!(root.join("copy/a.txt").exists());
+        assert!(!root.join("copy/.env").exists());
+        assert!(!root.join("copy/link").exists());
+    }
+
+    // Test byte range requests for static files
+    // One range gets a plain 206, several a multipart/byteranges body
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_byte_ranges() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("digits.html"), "0123456789").unwrap();
+        let client = TestClient::new(Router::new()).static_dir(temp_dir.path().to_str().unwrap());
+        let range = |value: &str| client.request("GET", "/digits.html", &[("Range", value)], b"");
+
+        let response = range("bytes=2-4");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.body, b"234");
+        assert_eq!(range("bytes=-3").body, b"789");
+        assert_eq!(range("bytes=7-").body, b"789");
+        assert_eq!(range("bytes=8-100").body, b"89");
+
+        let response = range("bytes=0-1, 5-5, 20-30, -2");
+        assert_eq!(response.status, 206);
+        let content_type = response.header("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/html\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/html\r\nContent-Range: bytes 5-5/10\r\n\r\n5\
+             \r\n--{b}\r\nContent-Type: text/html\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(response.text(), expected);
+        assert_eq!(
+            response.header("Content-Length"),
+            Some(expected.len().to_string().as_str())
+        );
+
+        let response = range("bytes=10-20");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+
+        // Malformed headers, other units and stale If-Range validators get the whole file
+        for ignored in ["bytes=5-2", "items=0-1", "bytes=abc"] {
+            assert_eq!(range(ignored).status, 200);
+        }
+        let stale = [("Range", "bytes=0-1"), ("If-Range", "\"stale\"")];
+        assert_eq!(
+            client.request("GET", "/digits.html", &stale, b"").body,
+            b"0123456789"
+        );
+    }
+
+    // Test merging byte ranges and the If-Range strong validator rule
+    // Ranges adding up to more than the file and weak validators get the whole file
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_byte_range_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("digits.html");
+        std::fs::write(&path, "0123456789").unwrap();
+        let client = TestClient::new(Router::new()).static_dir(temp_dir.path().to_str().unwrap());
+        let request =
+            |headers: &[(&str, &str)]| client.request("GET", "/digits.html", headers, b"");
+        let range = |value: &str| request(&[("Range", value)]);
+
+        // Overlapping and adjacent ranges become a single part
+        let response = range("bytes=3-5, 0-4");
+        assert_eq!(response.header("Content-Range"), Some("bytes 0-5/10"));
+        assert_eq!(response.body, b"012345");
+        let response = range("bytes=6-7, 0-1, 2-3");
+        assert_eq!(response.status, 206);
+        assert!(
+            response
+                .text()
+                .contains("Content-Range: bytes 0-3/10\r\n\r\n0123")
+        );
+        assert!(
+            response
+                .text()
+                .contains("Content-Range: bytes 6-7/10\r\n\r\n67")
+        );
+        assert!(!response.text().contains("bytes 2-3/10"));
+
+        // The same bytes asked for over and over
+        for excessive in ["bytes=0-, 0-", "bytes=0-5, 4-9", "bytes=-6, -6"] {
+            assert_eq!(range(excessive).status, 200, "{}", excessive);
+        }
+        assert_eq!(range("bytes=0-4, 5-9").body, b"0123456789");
+
+        // A strong ETag matches, its weak form doesn't
+        let etag = client
+            .get("/digits.html")
+            .header("ETag")
+            .unwrap()
+            .to_string();
+        let response = request(&[("Range", "bytes=0-1"), ("If-Range", &etag)]);
+        assert_eq!(response.body, b"01");
+        let weak = format!("W/{}", etag);
+        let response = request(&[("Range", "bytes=0-1"), ("If-Range", &weak)]);
+        assert_eq!(response.status, 200);
+
+        // A date only matches once the file is a second old
+        use std::time::{Duration, SystemTime};
+        let just_modified = SystemTime::now();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(just_modified).unwrap();
+        let date = crate::http_date::format_http_date(just_modified);
+        let response = request(&[("Range", "bytes=0-1"), ("If-Range", &date)]);
+        assert_eq!(response.status, 200);
+        let modified = just_modified - Duration::from_secs(60);
+        file.set_modified(modified).unwrap();
+        let date = crate::http_date::format_http_date(modified);
+        let response = request(&[("Range", "bytes=0-1"), ("If-Range", &date)]);
+        assert_eq!(response.body, b"01");
+    }
+
+    // Test decoding static file paths
+    // Escaped names must be found, and paths naming no file under the base directory refused
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_path_decoding() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().join("www");
+        std::fs::create_dir_all(base_dir.join("docs")).unwrap();
+        std::fs::write(base_dir.join("docs/my notes.txt"), "notes").unwrap();
+        std::fs::write(base_dir.join("caf\u{e9}.txt"), "menu").unwrap();
+        std::fs::write(temp_dir.path().join("secret.txt"), "secret").unwrap();
+        let client = TestClient::new(Router::new()).static_dir(base_dir.to_str().unwrap());
+
+        assert_eq!(client.get("/docs/my%20notes.txt").body, b"notes");
+        assert_eq!(client.get("/caf%C3%A9.txt").body, b"menu");
+        assert_eq!(client.get("/docs/./my%20notes.txt").body, b"notes");
+        assert_eq!(client.get("/docs/missing.txt").status, 404);
+        for path in [
+            "/../secret.txt",
+            "/docs/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/docs%2F..%2F..%2Fsecret.txt",
+            "/caf%E9.txt",
+            "/bad%zz",
+            "/nul%00.txt",
+        ] {
+            assert_eq!(client.get(path).status, 400, "{}", path);
+        }
+        let response = client.send_raw(b"GET /caf\xe9.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(response.status, 400);
+    }
+
+    // Test the symbolic link policy of static files
+    // Denied links must be refused wherever they are in the path, includes too
+    #[test]
+    #[cfg(all(feature = "static", unix))]
+    fn test_static_symlink_policy() {
+        use crate::SymlinkPolicy;
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().join("www");
+        std::fs::create_dir_all(temp_dir.path().join("outside")).unwrap();
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::write(temp_dir.path().join("outside/secret.txt"), "secret").unwrap();
+        std::fs::write(base_dir.join("page.txt"), "page").unwrap();
+        std::fs::write(
+            base_dir.join("index.shtml"),
+            r#"<!--#include virtual="/linked/secret.txt" -->"#,
+        )
+        .unwrap();
+        symlink(base_dir.join("page.txt"), base_dir.join("alias.txt")).unwrap();
+        symlink(temp_dir.path().join("outside"), base_dir.join("linked")).unwrap();
+
+        let client = |policy| {
+            let mut router = Router::new();
+            router.symlinks(policy).server_side_includes(&["shtml"]);
+            TestClient::new(router).static_dir(base_dir.to_str().unwrap())
+        };
+        // Every link here has the same owner as its target
+        for policy in [SymlinkPolicy::Follow, SymlinkPolicy::FollowIfSameOwner] {
+            let client = client(policy);
+            assert_eq!(client.get("/alias.txt").body, b"page");
+            assert_eq!(client.get("/linked/secret.txt").body, b"secret");
+            assert_eq!(client.get("/index.shtml").body, b"secret");
+        }
+
+        let denied = client(SymlinkPolicy::Deny);
+        assert_eq!(denied.get("/page.txt").body, b"page");
+        assert_eq!(denied.get("/alias.txt").status, 403);
+        assert_eq!(denied.get("/linked/secret.txt").status, 403);
+        assert_eq!(denied.get("/linked/missing.txt").status, 403);
+        assert_eq!(denied.get("/missing.txt").status, 404);
+        let page = denied.get("/index.shtml").body;
+        assert!(String::from_utf8_lossy(&page).contains("an error occurred"));
+
+        // Handing a link to another user takes root
+        if std::os::unix::fs::lchown(base_dir.join("alias.txt"), Some(65534), None).is_ok() {
+            let client = client(SymlinkPolicy::FollowIfSameOwner);
+            assert_eq!(client.get("/alias.txt").status, 403);
+            assert_eq!(client.get("/linked/secret.txt").body, b"secret");
+        }
+    }
+
+    // Test that hidden static files are not served
+    // Dotfiles and dot directories must look missing unless allowed, includes too
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_hidden_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path();
+        std::fs::create_dir_all(base_dir.join(".git")).unwrap();
+        std::fs::create_dir_all(base_dir.join(".well-known")).unwrap();
+        std::fs::write(base_dir.join(".env"), "SECRET=1").unwrap();
+        std::fs::write(base_dir.join(".git/config"), "[core]").unwrap();
+        std::fs::write(base_dir.join(".well-known/security.txt"), "Contact: x").unwrap();
+        std::fs::write(base_dir.join("page.txt"), "page").unwrap();
+        std::fs::write(
+            base_dir.join("index.shtml"),
+            r#"<!--#include virtual="/.env" -->"#,
+        )
+        .unwrap();
+
+        let mut router = Router::new();
+        router
+            .server_side_includes(&["shtml"])
+            .allow_hidden("/.well-known/**");
+        let client = TestClient::new(router).static_dir(base_dir.to_str().unwrap());
+        assert_eq!(client.get("/page.txt").body, b"page");
+        assert_eq!(client.get("/.env").status, 404);
+        assert_eq!(client.get("/%2Eenv").status, 404);
+        assert_eq!(client.get("/.git/config").status, 404);
+        assert_eq!(client.get("/.well-known/security.txt").body, b"Contact: x");
+        let page = client.get("/index.shtml").body;
+        assert!(!String::from_utf8_lossy(&page).contains("SECRET"));
+
+        let mut router = Router::new();
+        router.not_found(|_request: Request| {
+            Response::new(404, "Not Found").with_body("text/plain", "gone")
+        });
+        let client = TestClient::new(router).static_dir(base_dir.to_str().unwrap());
+        assert_eq!(client.get("/.git/config").body, b"gone");
+    }
+
+    // Test per-directory override files
+    // Index files, redirects, authentication and headers must apply to the subtree
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_directory_overrides() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path();
+        for directory in ["docs", "private", "broken"] {
+            std::fs::create_dir_all(base_dir.join(directory)).unwrap();
+        }
+        let files = [
+            (
+                ".webserver.toml",
+                "[headers]\nX-Site = \"root\"\n\n[redirects]\n\"old.html\" = \"new.html\" # moved\n",
+            ),
+            ("new.html", "new"),
+            (
+                "docs/.webserver.toml",
+                "index = [\"home.html\"]\n[headers]\nX-Site = 'docs'\nCache-Control = \"no-cache\"\n",
+            ),
+            ("docs/home.html", "home"),
+            ("docs/other.html", "other"),
+            (
+                "private/.webserver.toml",
+                "[auth]\nrealm = \"Staff\"\n[auth.users]\nalice = \"secret\"\n",
+            ),
+            ("private/notes.txt", "notes"),
+            ("broken/.webserver.toml", "index = [\"home.html\"\n"),
+            ("broken/page.txt", "page"),
+        ];
+        for (name, content) in files {
+            std::fs::write(base_dir.join(name), content).unwrap();
+        }
+
+        let mut router = Router::new();
+        router.directory_overrides();
+        let client = TestClient::new(router).static_dir(base_dir.to_str().unwrap());
+        assert_eq!(client.get("/new.html").header("X-Site"), Some("root"));
+        let moved = client.get("/old.html?v=2");
+        assert_eq!(moved.status, 301);
+        assert_eq!(moved.header("Location"), Some("/new.html?v=2"));
+
+        let directory = client.get("/docs");
+        assert_eq!(directory.status, 301);
+        assert_eq!(directory.header("Location"), Some("/docs/"));
+        let index = client.get("/docs/");
+        assert_eq!(index.body, b"home");
+        assert_eq!(index.header("X-Site"), Some("docs"));
+        assert_eq!(index.header("Cache-Control"), Some("no-cache"));
+        assert_eq!(client.get("/docs/.webserver.toml").status, 404);
+
+        let refused = client.get("/private/notes.txt");
+        assert_eq!(refused.status, 401);
+        assert!(
+            refused
+                .header("WWW-Authenticate")
+                .unwrap()
+                .contains("Staff")
+        );
+        let credentials = [("Authorization", "Basic YWxpY2U6c2VjcmV0")];
+        let allowed = client.request("GET", "/private/notes.txt", &credentials, b"");
+        assert_eq!(allowed.body, b"notes");
+        assert_eq!(client.get("/broken/page.txt").status, 500);
+
+        // Changed files are read again
+        std::fs::write(
+            base_dir.join("docs/.webserver.toml"),
+            "index = [\"missing.html\", \"other.html\"]\n",
+        )
+        .unwrap();
+        let index = client.get("/docs/");
+        assert_eq!(index.body, b"other");
+        assert_eq!(index.header("X-Site"), Some("root"));
+    }
+
+    // Test listing static directories without an index file
+    // The page must follow the configured template and JSON must list the same entries
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_directory_listing() {
+        use crate::{DirectoryListing, ListingColumn};
+
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path();
+        std::fs::create_dir_all(base_dir.join("sub")).unwrap();
+        std::fs::create_dir_all(base_dir.join("site")).unwrap();
+        std::fs::write(base_dir.join("a.txt"), "hello").unwrap();
+        std::fs::write(base_dir.join("b & c.html"), "<p>b</p>").unwrap();
+        std::fs::write(base_dir.join(".env"), "SECRET=1").unwrap();
+        std::fs::write(base_dir.join("sub/x.txt"), "x").unwrap();
+        std::fs::write(base_dir.join("site/index.html"), "site").unwrap();
+
+        let mut router = Router::new();
+        router.directory_listing(
+            DirectoryListing::new()
+                .header("<h1>Files in {path}</h1>")
+                .footer("<p>end</p>")
+                .style("td{color:red}")
+                .columns(&[ListingColumn::Size, ListingColumn::Type])
+                .json(true),
+        );
+        let client = TestClient::new(router).static_dir(base_dir.to_str().unwrap());
+        let root = client.get("/");
+        assert_eq!(
+            root.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let page = root.text();
+        assert!(page.contains("<h1>Files in /</h1>") && page.contains("<p>end</p>"));
+        assert!(page.contains("td{color:red}"));
+        assert!(page.contains("<a href=\"b%20&amp;%20c.html\">b &amp; c.html</a>"));
+        assert!(page.contains("<a href=\"sub/\">sub/</a>"));
+        assert!(page.contains("5 B") && !page.contains("Modified"));
+        assert!(!page.contains(".env") && !page.contains("../"));
+
+        let directory = client.get("/sub");
+        assert_eq!(directory.status, 301);
+        assert_eq!(directory.header("Location"), Some("/sub/"));
+        assert!(
+            client
+                .get("/sub/")
+                .text()
+                .contains("<a href=\"../\">../</a>")
+        );
+        assert_eq!(client.get("/site/").body, b"site");
+
+        let json = client.request("GET", "/", &[("Accept", "application/json")], b"");
+        assert_eq!(json.header("Content-Type"), Some("application/json"));
+        assert!(
+            json.text().starts_with(
+                r#"[{"name":"site","directory":true,"type":"directory"},{"name":"sub","#
+            )
+        );
+        assert!(json.text().contains(
+            r#"{"name":"a.txt","directory":false,"size":5,"type":"application/octet-stream"}"#
+        ));
+
+        // Without a listing, directories are not found
+        let client = TestClient::new(Router::new()).static_dir(base_dir.to_str().unwrap());
+        assert_eq!(client.get("/sub/").status, 404);
+        assert_eq!(client.get("/site/").body, b"site");
+    }
+
+    // Test the favicon and robots.txt fallbacks
+    // They must only answer while the static root lacks the files
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_fallbacks() {
+        use crate::{DEFAULT_FAVICON, RobotsPolicy};
+
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let client = TestClient::new(Router::new()).static_dir(base_dir);
+        assert_eq!(client.get("/favicon.ico").status, 404);
+        assert_eq!(client.get("/robots.txt").status, 404);
+
+        let mut router = Router::new();
+        router
+            .favicon_fallback(DEFAULT_FAVICON)
+            .robots_fallback(RobotsPolicy::DenyAll);
+        let client = TestClient::new(router).static_dir(base_dir);
+        let icon = client.get("/favicon.ico");
+        assert_eq!(icon.header("Content-Type"), Some("image/x-icon"));
+        assert_eq!(icon.body, DEFAULT_FAVICON);
+        assert_eq!(
+            client.get("/robots.txt").text(),
+            "User-agent: *\nDisallow: /\n"
+        );
+
+        std::fs::write(temp_dir.path().join("robots.txt"), "User-agent: *\n").unwrap();
+        assert_eq!(client.get("/robots.txt").text(), "User-agent: *\n");
+    }
+
+    // Test per-path Cache-Control rules for static files
+    // The first matching rule applies, with and without the file cache
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_static_cache_control() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("assets/js")).unwrap();
+        std::fs::write(temp_dir.path().join("assets/js/app.1a2b.css"), "a {}").unwrap();
+        std::fs::write(temp_dir.path().join("index.html"), "<p>home</p>").unwrap();
+        std::fs::write(temp_dir.path().join("other.css"), "b {}").unwrap();
+        let mut router = Router::new();
+        router
+            .cache_control("/assets/**", "public, max-age=31536000, immutable")
+            .cache_control("*.html", "no-cache");
+        let mut cached = router.clone();
+        cached.cache_static_files(FileCache::new(1024, 16));
+
+        for router in [&router, &cached] {
+            let client = TestClient::new(router.clone()).static_dir(base_dir);
+            for _ in 0..2 {
+                let response = client.get("/assets/js/app.1a2b.css");
+                assert_eq!(
+                    response.header("Cache-Control"),
+                    Some("public, max-age=31536000, immutable")
+                );
+                assert_eq!(client.get("/").header("Cache-Control"), Some("no-cache"));
+                assert_eq!(client.get("/other.css").header("Cache-Control"), None);
+                assert_eq!(client.get("/missing.html").header("Cache-Control"), None);
+            }
+        }
+    }
+
+    // Test headers configured by path pattern
+    // Static files and routes get them, headers the handler set are kept
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_response_headers() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("fonts")).unwrap();
+        std::fs::write(temp_dir.path().join("fonts/sans.woff2"), "font").unwrap();
+        std::fs::write(temp_dir.path().join("index.html"), "<p>home</p>").unwrap();
+        let mut router = Router::new();
+        router
+            .get("/api/status", || Response::ok("text/plain", "up"))
+            .get("/api/embed", || {
+                Response::ok("text/plain", "embed").with_header("X-Frame-Options", "SAMEORIGIN")
+            })
+            .response_header("*.html", "X-Frame-Options", "DENY")
+            .response_header("/api/*", "X-Frame-Options", "DENY")
+            .response_header("/fonts/**", "Access-Control-Allow-Origin", "*")
+            .response_header(
+                "/fonts/**",
+                "Access-Control-Allow-Origin",
+                "https://example.com",
+            )
+            .response_header("/api/**", "X-Content-Type-Options", "nosniff");
+        let mut cached = router.clone();
+        cached.cache_static_files(FileCache::new(1024, 16));
+
+        for router in [&router, &cached] {
+            let client = TestClient::new(router.clone()).static_dir(base_dir);
+            for _ in 0..2 {
+                let font = client.get("/fonts/sans.woff2");
+                assert_eq!(font.header("Access-Control-Allow-Origin"), Some("*"));
+                assert_eq!(font.header("X-Frame-Options"), None);
+                assert_eq!(client.get("/").header("X-Frame-Options"), Some("DENY"));
+            }
+            let status = client.get("/api/status");
+            assert_eq!(status.header("X-Frame-Options"), Some("DENY"));
+            assert_eq!(status.header("X-Content-Type-Options"), Some("nosniff"));
+            let embed = client.get("/api/embed");
+            assert_eq!(embed.header("X-Frame-Options"), Some("SAMEORIGIN"));
+        }
+    }
+
+    // Test the response cache middleware
+    // Verifies hits, Vary variants, bypasses, no-store, cookies and invalidation
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_response_cache_memoizes_handler_responses() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = crate::ResponseCache::new(std::time::Duration::from_secs(60), 16);
+        let mut router = Router::new();
+        router.scope("/reports", |reports| {
+            reports.layer(cache.clone());
+            let counter = calls.clone();
+            reports.get("/daily", move |Headers(headers): Headers| {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let language = headers.get("Accept-Language").map_or("en", String::as_str);
+                Response::ok("text/plain", format!("{} {}", language, n))
+                    .with_header("Vary", "Accept-Language")
+            });
+            let counter = calls.clone();
+            reports.get("/live", move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Response::ok("text/plain", "live").with_header("Cache-Control", "no-store")
+            });
+            let counter = calls.clone();
+            reports.get("/session", move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Response::ok("text/plain", "welcome").with_header("Set-Cookie", "session=1")
+            });
+        });
+
+        let response = route_request(&router, b"GET /reports/daily HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"en 1");
+        assert_eq!(response.header("Age"), None);
+        let response = route_request(&router, b"GET /reports/daily HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"en 1");
+        assert_eq!(response.header("Age"), Some("0"));
+
+        // Another Accept-Language is another variant
+        let french = b"GET /reports/daily HTTP/1.1\r\nAccept-Language: fr\r\n\r\n";
+        assert_eq!(route_request(&router, french).unwrap().body, b"fr 2");
+        assert_eq!(route_request(&router, french).unwrap().body, b"fr 2");
+        assert_eq!(cache.len(), 2);
+
+        // Authorization and query strings bypass or split the cache
+        let authorized = b"GET /reports/daily HTTP/1.1\r\nAuthorization: token\r\n\r\n";
+        assert_eq!(route_request(&router, authorized).unwrap().body, b"en 3");
+        let query = b"GET /reports/daily?week=2 HTTP/1.1\r\n\r\n";
+        assert_eq!(route_request(&router, query).unwrap().body, b"en 4");
+        assert_eq!(route_request(&router, query).unwrap().body, b"en 4");
+        // Neither are requests with cookies answered from the cache, nor
+        // responses setting one stored
+        let with_cookie = b"GET /reports/daily HTTP/1.1\r\nCookie: session=1\r\n\r\n";
+        assert_eq!(route_request(&router, with_cookie).unwrap().body, b"en 5");
+        route_request(&router, b"GET /reports/session HTTP/1.1\r\n\r\n").unwrap();
+        route_request(&router, b"GET /reports/session HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(cache.len(), 3);
+
+        // no-store responses are never kept
+        route_request(&router, b"GET /reports/live HTTP/1.1\r\n\r\n").unwrap();
+        route_request(&router, b"GET /reports/live HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 9);
+
+        cache.invalidate("/reports/daily");
+        assert!(cache.is_empty());
+        let response = route_request(&router, b"GET /reports/daily HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"en 10");
+        cache.invalidate_prefix("/reports/");
+        assert!(cache.is_empty());
+    }
+
+    // Test conditional requests for static files
+    // Preconditions are evaluated in RFC 9110 order and answered with 304 or 412
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_conditional_static_requests() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let file_path = temp_dir.path().join("page.html");
+        std::fs::write(&file_path, "page").unwrap();
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(0x10000))
+            .unwrap();
+        let etag = "\"3b9aca000000-4\"";
+        let router = Router::new();
+        let get = |headers: &str| {
+            let request = format!(
+                "GET /page.html HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                headers
+            );
+            serve_request(request.as_bytes(), base_dir, &router)
+        };
+
+        let response = get(&format!("If-None-Match: \"other\", W/{}\r\n", etag));
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 304 Not Modified\r\nETag: {}\r\n\
+                 Last-Modified: Thu, 01 Jan 1970 18:12:16 GMT\r\n\r\n",
+                etag
+            )
+        );
+        assert!(get("If-None-Match: \"other\"\r\n").starts_with("HTTP/1.1 200"));
+        assert!(
+            get("If-Modified-Since: Thu, 01 Jan 1970 18:12:16 GMT\r\n").starts_with("HTTP/1.1 304")
+        );
+        assert!(
+            get("If-Modified-Since: Thursday, 01-Jan-70 18:12:15 GMT\r\n")
+                .starts_with("HTTP/1.1 200")
+        );
+        assert!(get("If-Modified-Since: yesterday\r\n").starts_with("HTTP/1.1 200"));
+        // If-None-Match takes precedence over If-Modified-Since
+        assert!(
+            get("If-None-Match: \"other\"\r\nIf-Modified-Since: Thu Jan  1 18:12:16 1970\r\n")
+                .starts_with("HTTP/1.1 200")
+        );
+
+        assert!(get("If-Match: *\r\n").ends_with("\r\n\r\npage"));
+        assert!(get("If-Match: \"other\"\r\n").starts_with("HTTP/1.1 412"));
+        // Weak tags never match strongly
+        assert!(get(&format!("If-Match: W/{}\r\n", etag)).starts_with("HTTP/1.1 412"));
+        assert!(
+            get("If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n")
+                .starts_with("HTTP/1.1 412")
+        );
+        // If-Match replaces If-Unmodified-Since and is checked before If-None-Match
+        let both = format!(
+            "If-Match: {}\r\nIf-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n",
+            etag
+        );
+        assert!(get(&both).starts_with("HTTP/1.1 200"));
+        let both = format!("If-Match: \"other\"\r\nIf-None-Match: {}\r\n", etag);
+        assert!(get(&both).starts_with("HTTP/1.1 412"));
+    }
+
+    // Test automatic Vary headers
+    // Headers read with vary_header by handlers and middleware end up in Vary once
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_vary_header_management() {
+        let mut router = Router::new();
+        router.layer(|request: &Request, next: &Next| {
+            let response = next.run(request);
+            match request.vary_header("Origin") {
+                Some(origin) => response.with_header("Access-Control-Allow-Origin", origin),
+                None => response,
+            }
+        });
+        router.get("/greeting", |request: Request| {
+            let text = match request.vary_header("Accept-Language") {
+                Some(language) if language.starts_with("fr") => "bonjour",
+                _ => "hello",
+            };
+            Response::ok("text/plain", text).with_vary("accept-language")
+        });
+        router.get("/plain", || Response::ok("text/plain", "plain"));
+
+        let response = route_request(&router, b"GET /greeting HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.header("Vary"), Some("accept-language, Origin"));
+        let response = route_request(
+            &router,
+            b"GET /greeting HTTP/1.1\r\nAccept-Language: fr\r\nOrigin: https://a.example\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"bonjour");
+        assert_eq!(response.header("Vary"), Some("accept-language, Origin"));
+        assert_eq!(
+            response
+                .headers
+                .iter()
+                .filter(|(name, _)| name == "Vary")
+                .count(),
+            1
+        );
+
+        let response = route_request(&router, b"GET /plain HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Vary"), Some("Origin"));
+        let response = Response::ok("text/plain", "")
+            .with_vary("Cookie")
+            .with_vary("*");
+        assert_eq!(response.with_vary("Accept").header("Vary"), Some("*"));
+    }
+
+    // Test Vary headers of handlers run on other threads or panicking
+    // Reads behind Timeout count, and a panic leaves nothing for the next request
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_vary_header_across_threads_and_panics() {
+        let mut router = Router::new();
+        router.scope("/slow", |slow| {
+            slow.layer(crate::Timeout::new(std::time::Duration::from_secs(5)));
+            slow.get("/greeting", |request: Request| {
+                let language = request.vary_header("Accept-Language").unwrap_or("en");
+                Response::ok("text/plain", language.to_string())
+            });
+        });
+        router.get("/broken", |request: Request| -> Response {
+            request.vary_header("Cookie");
+            panic!("broken handler");
+        });
+        router.get("/plain", || Response::ok("text/plain", "plain"));
+
+        let response = route_request(&router, b"GET /slow/greeting HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Vary"), Some("Accept-Language"));
+        let broken = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            route_request(&router, b"GET /broken HTTP/1.1\r\n\r\n")
+        }));
+        assert!(broken.is_err());
+        let response = route_request(&router, b"GET /plain HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    // Test the PROXY protocol
+    // v1 and v2 headers are parsed, and the announced client reaches handlers
+    #[test]
+    fn test_proxy_protocol() {
+        use crate::proxy_protocol::{Preamble, parse};
+
+        let v1 = b"PROXY TCP4 203.0.113.9 192.0.2.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            parse(v1),
+            Preamble::Header {
+                len: 44,
+                source: Some("203.0.113.9:56324".parse().unwrap())
+            }
+        );
+        assert_eq!(
+            parse(b"PROXY UNKNOWN\r\n"),
+            Preamble::Header {
+                len: 15,
+                source: None
+            }
+        );
+        assert_eq!(parse(b""), Preamble::Incomplete(15));
+        assert_eq!(parse(b"PROXY TCP6 ::1 ::1 1 2"), Preamble::Incomplete(1));
+        assert_eq!(parse(b"PROXY TCP4 ::1 ::1 1 2\r\n"), Preamble::Invalid);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Preamble::Invalid);
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        v2.extend_from_slice(&[203, 0, 113, 9, 192, 0, 2, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(parse(&v2[..20]), Preamble::Incomplete(8));
+        assert_eq!(
+            parse(&v2),
+            Preamble::Header {
+                len: 28,
+                source: Some("203.0.113.9:56324".parse().unwrap())
+            }
+        );
+        let local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(
+            parse(local),
+            Preamble::Header {
+                len: 16,
+                source: None
+            }
+        );
+
+        let mut router = Router::new();
+        router.get("/whoami", |request: Request| {
+            Response::ok("text/plain", format!("{:?}", request.remote_addr))
+        });
+        // Every backend strips the header before the request
+        let configs = [
+            ServerConfig {
+                proxy_protocol: true,
+                ..ServerConfig::default()
+            },
+            #[cfg(feature = "event-loop")]
+            ServerConfig {
+                proxy_protocol: true,
+                event_loop_threads: Some(1),
+                ..ServerConfig::default()
+            },
+            #[cfg(feature = "io-uring")]
+            ServerConfig {
+                proxy_protocol: true,
+                io_uring_entries: Some(64),
+                ..ServerConfig::default()
+            },
+        ];
+        for config in configs {
+            let server = Server::new(router.clone(), "").config(config);
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            server.start(listener).unwrap();
+
+            for header in [&v1[..44], &v2[..]] {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.write_all(header).unwrap();
+                client
+                    .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).unwrap();
+                assert!(
+                    response.ends_with("Some(203.0.113.9:56324)"),
+                    "{}",
+                    response
+                );
+            }
+
+            // Connections without a header are closed unanswered (possibly reset)
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            assert_eq!(response, "");
+        }
+    }
+
+    // Test trusted proxies
+    // Forwarding headers only count when the peer is trusted, walking back the chain
+    #[test]
+    fn test_trusted_proxies_client_ip() {
+        let whoami = |request: Request| {
+            let ip = request.client_ip().map(|ip| ip.to_string());
+            Response::ok(
+                "text/plain",
+                format!("{} {}", ip.unwrap_or_default(), request.scheme()),
+            )
+        };
+        let ask = |router: Router, headers: &str| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                serve_tcp_connection(stream, "", &router);
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let request = format!("GET /whoami HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            server.join().unwrap();
+            response.split("\r\n\r\n").nth(1).unwrap().to_string()
+        };
+        let forwarded_for = "X-Forwarded-For: 198.51.100.4, 203.0.113.7, 10.1.2.3\r\n\
+                             X-Forwarded-Proto: https\r\n";
+
+        let mut untrusting = Router::new();
+        untrusting.get("/whoami", whoami);
+        assert_eq!(ask(untrusting, forwarded_for), "127.0.0.1 http");
+
+        let mut router = Router::new();
+        router.trusted_proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        router.get("/whoami", whoami);
+        // The client spoofed 198.51.100.4; 203.0.113.7 is the first untrusted hop
+        assert_eq!(ask(router.clone(), forwarded_for), "203.0.113.7 https");
+        assert_eq!(ask(router.clone(), ""), "127.0.0.1 http");
+        let forwarded = "Forwarded: for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.1\r\n";
+        assert_eq!(ask(router.clone(), forwarded), "2001:db8::17 https");
+        let hidden = "Forwarded: for=_hidden;proto=https\r\n";
+        assert_eq!(ask(router, hidden), "127.0.0.1 https");
+    }
+
+    // Test the timeout middleware
+    // Slow handlers are answered with 503 (or the configured status) on time
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_timeout_middleware() {
+        let mut router = Router::new();
+        router.scope("/slow", |slow| {
+            slow.layer(crate::Timeout::new(std::time::Duration::from_millis(50)));
+            slow.get("/sleep/{ms}", |Path(ms): Path<u64>| {
+                std::thread::sleep(std::time::Duration::from_millis(ms));
+                Response::ok("text/plain", "done")
+            });
+        });
+        router.scope("/upstream", |upstream| {
+            upstream.layer(
+                crate::Timeout::new(std::time::Duration::from_millis(50))
+                    .status(crate::StatusCode::GATEWAY_TIMEOUT),
+            );
+            upstream.get("/", || {
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                Response::ok("text/plain", "late")
+            });
+        });
+
+        let response = route_request(&router, b"GET /slow/sleep/0 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"done");
+
+        let started = std::time::Instant::now();
+        let response = route_request(&router, b"GET /slow/sleep/500 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 503);
+        assert!(started.elapsed() < std::time::Duration::from_millis(400));
+
+        let response = route_request(&router, b"GET /upstream HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 504);
+    }
+
+    // Test the cap on handlers running past their deadline
+    // Once reached, requests are refused without running until the handlers end
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_timeout_max_overdue() {
+        let mut router = Router::new();
+        router.scope("/api", |api| {
+            api.layer(crate::Timeout::new(std::time::Duration::from_millis(20)).max_overdue(1));
+            api.get("/hang", || {
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                Response::ok("text/plain", "late")
+            });
+            api.get("/quick", || Response::ok("text/plain", "quick"));
+        });
+
+        let response = route_request(&router, b"GET /api/hang HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 503);
+        let response = route_request(&router, b"GET /api/quick HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 503);
+
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let response = route_request(&router, b"GET /api/quick HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"quick");
+    }
+
+    // Test response compression settings
+    // Size, content type, exclusions, no-transform and Accept-Encoding must be honored
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression_configuration() {
+        use std::io::Read;
+
+        let page = "<p>hello</p>".repeat(100);
+        let mut router = Router::new();
+        router.layer(
+            crate::Compression::new()
+                .min_size(256)
+                .content_types(&["text/"])
+                .level(9)
+                .exclude("/raw/**"),
+        );
+        let html = page.clone();
+        router.get("/page", move || {
+            Response::ok("text/html; charset=utf-8", html.clone()).with_header("ETag", "\"v1\"")
+        });
+        router.get("/small", || Response::ok("text/plain", "tiny"));
+        let json = page.clone();
+        router.get("/data", move || {
+            Response::ok("application/json", json.clone())
+        });
+        let raw = page.clone();
+        router.get("/raw/page", move || Response::ok("text/html", raw.clone()));
+        let fixed = page.clone();
+        router.get("/fixed", move || {
+            Response::ok("text/html", fixed.clone()).with_header("Cache-Control", "no-transform")
+        });
+
+        let gzip = |path: &str| {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nAccept-Encoding: br, gzip;q=0.8\r\n\r\n",
+                path
+            );
+            route_request(&router, raw.as_bytes()).unwrap()
+        };
+
+        let response = gzip("/page");
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("ETag"), Some("W/\"v1\""));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.header("Content-Length").is_none());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body.into_reader())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page);
+
+        for path in ["/small", "/data", "/raw/page", "/fixed"] {
+            let response = gzip(path);
+            assert!(response.header("Content-Encoding").is_none(), "{}", path);
+        }
+
+        // Clients refusing gzip get the identity body, still marked as varying
+        for accept in ["identity", "gzip;q=0", "*;q=0"] {
+            let raw = format!("GET /page HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept);
+            let response = route_request(&router, raw.as_bytes()).unwrap();
+            assert!(response.header("Content-Encoding").is_none(), "{}", accept);
+            assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+            assert_eq!(response.body.as_bytes(), Some(page.as_bytes()));
+        }
+    }
+
+    // Test HTTP/2 over cleartext connections
+    // Prior knowledge and Upgrade: h2c must both reach the router over frames
+    #[test]
+    #[cfg(feature = "http2")]
+    fn test_http2_cleartext() {
+        use std::net::TcpStream;
+
+        const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        let frame = |kind: u8, flags: u8, stream_id: u32, payload: &[u8]| {
+            let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+            frame.extend_from_slice(&[kind, flags]);
+            frame.extend_from_slice(&stream_id.to_be_bytes());
+            frame.extend_from_slice(payload);
+            frame
+        };
+        // Collects status and body per stream until count streams ended
+        let responses = |client: &mut TcpStream, count: usize| {
+            let mut streams: HashMap<u32, (String, Vec<u8>)> = HashMap::new();
+            let mut ended = 0;
+            while ended < count {
+                let mut header = [0; 9];
+                client.read_exact(&mut header).unwrap();
+                let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                let stream_id = u32::from_be_bytes(header[5..].try_into().unwrap());
+                let mut payload = vec![0; len];
+                client.read_exact(&mut payload).unwrap();
+                let entry = streams.entry(stream_id).or_default();
+                match header[3] {
+                    // The status comes first, as a literal with the :status name index
+                    0x1 => {
+                        assert_eq!(payload[0], 0x08);
+                        let digits = &payload[2..2 + payload[1] as usize];
+                        entry.0 = String::from_utf8(digits.to_vec()).unwrap();
+                    }
+                    0x0 => entry.1.extend_from_slice(&payload),
+                    _ => continue,
+                }
+                if header[3] <= 0x1 && header[4] & 0x1 != 0 {
+                    ended += 1;
+                }
+            }
+            streams
+        };
+
+        let mut router = Router::new();
+        router.get("/hello", |request: Request| {
+            let host = request.header("Host").unwrap_or("").to_string();
+            Response::ok("text/plain", format!("hello {}", host))
+        });
+        router.post("/echo", |request: Request| {
+            Response::ok("text/plain", request.body.clone())
+        });
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        // GET /hello with a Huffman-coded authority added to the dynamic
+        // table (RFC 7541 C.4.1), then a POST reusing it from the table
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut get = b"\x82\x86\x04\x06/hello\x41\x8c".to_vec();
+        get.extend_from_slice(&[
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ]);
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(0x4, 0, 0, &[]));
+        input.extend(frame(0x1, 0x5, 1, &get));
+        input.extend(frame(0x1, 0x4, 3, b"\x83\x86\x04\x05/echo\xbe"));
+        input.extend(frame(0x0, 0x1, 3, b"ping"));
+        client.write_all(&input).unwrap();
+        let streams = responses(&mut client, 2);
+        assert_eq!(
+            streams[&1],
+            ("200".to_string(), b"hello www.example.com".to_vec())
+        );
+        assert_eq!(streams[&3], ("200".to_string(), b"ping".to_vec()));
+
+        // Upgrade, with settings announcing a 100 streams limit and the default window
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                  Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+            )
+            .unwrap();
+        let switching =
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        let mut head = vec![0; switching.len()];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head, switching);
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(0x4, 0, 0, &[]));
+        client.write_all(&input).unwrap();
+        let streams = responses(&mut client, 1);
+        assert_eq!(
+            streams[&1],
+            ("200".to_string(), b"hello localhost".to_vec())
+        );
+    }
+
+    // Test TLS with client certificate authentication
+    // Required certificates reach handlers as a subject, others fail the handshake
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_tls_client_certificates() {
+        use crate::TlsConfig;
+        use rcgen::{
+            BasicConstraints, CertificateParams, CertifiedIssuer, DistinguishedName, DnType,
+            ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        };
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use std::net::{SocketAddr, TcpStream};
+        use std::sync::Arc;
+
+        // A CA issuing the server's certificate and alice's
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(path("ca.pem"), ca.pem()).unwrap();
+
+        let mut server_params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = server_params.signed_by(&server_key, &ca).unwrap();
+        std::fs::write(path("cert.pem"), server_cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), server_key.serialize_pem()).unwrap();
+
+        let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        // Drop the default common name so the names keep the order they are pushed in
+        client_params.distinguished_name = DistinguishedName::new();
+        client_params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example, Corp");
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "alice");
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = client_params.signed_by(&client_key, &ca).unwrap();
+
+        let start = |tls: TlsConfig| {
+            let mut router = Router::new();
+            router.get("/whoami", |request: Request| {
+                let subject = request.client_subject.as_deref().unwrap_or("anonymous");
+                Response::ok("text/plain", subject.to_string())
+            });
+            let server = Server::new(router, "").config(ServerConfig {
+                tls: Some(tls),
+                ..ServerConfig::default()
+            });
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            server.start(listener).unwrap();
+            addr
+        };
+        // Sends GET /whoami, with alice's certificate or without one
+        let whoami = |addr: SocketAddr, authenticated: bool| {
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+            let config = if authenticated {
+                let key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+                builder
+                    .with_client_auth_cert(
+                        vec![client_cert.der().clone()],
+                        PrivateKeyDer::Pkcs8(key),
+                    )
+                    .unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            let name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+            let mut response = Vec::new();
+            stream.write_all(
+                b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )?;
+            stream.read_to_end(&mut response)?;
+            Ok::<String, std::io::Error>(String::from_utf8(response).unwrap())
+        };
+
+        // Required: alice is let in and named, anonymous clients are not
+        let tls = TlsConfig::from_pem_files(&path("cert.pem"), &path("key.pem"))
+            .unwrap()
+            .require_client_cert(&path("ca.pem"))
+            .unwrap();
+        let addr = start(tls);
+        let response = whoami(addr, true).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nCN=alice,O=Example\\, Corp"));
+        assert!(whoami(addr, false).is_err());
+
+        // Requested: both are let in, only alice is named
+        let tls = TlsConfig::from_pem_files(&path("cert.pem"), &path("key.pem"))
+            .unwrap()
+            .request_client_cert(&path("ca.pem"))
+            .unwrap();
+        let addr = start(tls);
+        assert!(
+            whoami(addr, true)
+                .unwrap()
+                .ends_with("CN=alice,O=Example\\, Corp")
+        );
+        assert!(whoami(addr, false).unwrap().ends_with("\r\n\r\nanonymous"));
+
+        // Files without certificates are rejected up front
+        assert!(TlsConfig::from_pem_files(&path("key.pem"), &path("key.pem")).is_err());
+    }
+
+    // Test TLS certificate selection by SNI hostname
+    // Exact names beat wildcards, unknown names get the default certificate
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_tls_sni_certificates() {
+        use crate::TlsConfig;
+        use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+        use rustls::pki_types::ServerName;
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use std::net::TcpStream;
+        use std::sync::Arc;
+
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        // Writes name.pem and name-key.pem, valid for the hostnames
+        let issue = |name: &str, hostnames: &[&str]| {
+            let hostnames = hostnames
+                .iter()
+                .map(|host| host.to_string())
+                .collect::<Vec<_>>();
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(hostnames)
+                .unwrap()
+                .signed_by(&key, &ca)
+                .unwrap();
+            std::fs::write(path(&format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(path(&format!("{}-key.pem", name)), key.serialize_pem()).unwrap();
+            (
+                path(&format!("{}.pem", name)),
+                path(&format!("{}-key.pem", name)),
+            )
+        };
+        let default = issue("default", &["localhost"]);
+        let exact = issue("exact", &["api.example.org"]);
+        let wildcard = issue("wildcard", &["*.example.org"]);
+
+        let tls = TlsConfig::from_pem_files(&default.0, &default.1)
+            .unwrap()
+            .add_host("*.example.org", &wildcard.0, &wildcard.1)
+            .unwrap()
+            .add_host("API.example.org.", &exact.0, &exact.1)
+            .unwrap();
+        let mut router = Router::new();
+        router.get("/", || Response::ok("text/plain", "hello"));
+        let server = Server::new(router, "").config(ServerConfig {
+            tls: Some(tls),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        // Returns the certificate sent for hostname, once verified for it
+        let certificate = |hostname: &str| {
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.der().clone()).unwrap();
+            let config = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            let name = ServerName::try_from(hostname.to_string()).unwrap();
+            let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+            let mut response = Vec::new();
+            let head = format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                hostname
+            );
+            stream.write_all(head.as_bytes())?;
+            stream.read_to_end(&mut response)?;
+            assert!(response.ends_with(b"\r\n\r\nhello"));
+            let chain = stream.conn.peer_certificates().unwrap();
+            Ok::<Vec<u8>, std::io::Error>(chain[0].to_vec())
+        };
+        let der = |pem: &str| {
+            use rustls::pki_types::CertificateDer;
+            use rustls::pki_types::pem::PemObject;
+            CertificateDer::from_pem_file(pem).unwrap().to_vec()
+        };
+
+        assert_eq!(certificate("api.example.org").unwrap(), der(&exact.0));
+        assert_eq!(certificate("www.example.org").unwrap(), der(&wildcard.0));
+        assert_eq!(certificate("localhost").unwrap(), der(&default.0));
+        // The wildcard does not cover the parent domain, so the default
+        // certificate is sent and fails verification
+        assert!(certificate("example.org").is_err());
+    }
+
+    // Test certificates obtained through ACME
+    // A stub CA validates the HTTP-01 challenge and signs the CSR; the certificate
+    // reaches the TLS listener, and a restart serves it from the cache
+    #[test]
+    #[cfg(feature = "acme")]
+    fn test_acme_certificates() {
+        use crate::{Acme, StatusCode, TlsConfig};
+        use rcgen::{
+            BasicConstraints, CertificateParams, CertificateSigningRequestParams, CertifiedIssuer,
+            IsCa, KeyPair,
+        };
+        use rustls::pki_types::ServerName;
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use serde_json::{Value, json};
+        use std::net::{SocketAddr, TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        fn base64url_decode(input: &str) -> Vec<u8> {
+            let mut bits = 0u32;
+            let mut count = 0;
+            let mut decoded = Vec::new();
+            for byte in input.bytes() {
+                let value = match byte {
+                    b'A'..=b'Z' => byte - b'A',
+                    b'a'..=b'z' => byte - b'a' + 26,
+                    b'0'..=b'9' => byte - b'0' + 52,
+                    b'-' => 62,
+                    _ => 63,
+                };
+                bits = bits << 6 | value as u32;
+                count += 6;
+                if count >= 8 {
+                    count -= 8;
+                    decoded.push((bits >> count) as u8);
+                }
+            }
+            decoded
+        }
+        // Payload of a JWS request body
+        fn payload(request: &Request) -> Value {
+            let jws: Value = serde_json::from_slice(&request.body).unwrap();
+            let payload = base64url_decode(jws["payload"].as_str().unwrap());
+            serde_json::from_slice(&payload).unwrap_or(Value::Null)
+        }
+
+        let dir = TempDir::new().unwrap();
+        let cache_dir = dir.path().join("acme").to_str().unwrap().to_string();
+        let acme_for = |directory: &str| Acme::new(&["localhost"], &cache_dir).directory(directory);
+
+        // The CA's directory needs its own address, known once bound
+        let ca_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ca_url = format!("http://{}", ca_listener.local_addr().unwrap());
+        let acme = acme_for(&format!("{}/directory", ca_url));
+        let mut http = Router::new();
+        http.acme_challenges(&acme);
+        let http = Server::new(http, "");
+        let http_listener = http.bind("127.0.0.1:0").unwrap();
+        let http_addr = http_listener.local_addr().unwrap();
+        http.start(http_listener).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Arc::new(
+            CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap(),
+        );
+        let ca_der = ca.der().clone();
+        let certificate = Arc::new(Mutex::new(None::<String>));
+        let validated = Arc::new(Mutex::new(false));
+        let mut stub = Router::new();
+        stub.on_response(|_, response| {
+            response
+                .headers
+                .push(("Replay-Nonce".to_string(), "nonce".to_string()));
+        });
+        let url = ca_url.clone();
+        stub.get("/directory", move || {
+            let directory = json!({
+                "newNonce": format!("{}/nonce", url),
+                "newAccount": format!("{}/account", url),
+                "newOrder": format!("{}/order", url),
+            });
+            Response::ok("application/json", directory.to_string())
+        });
+        stub.route("HEAD", "/nonce", || Response::from_status(StatusCode::OK));
+        let url = ca_url.clone();
+        stub.post("/account", move || {
+            Response::from_status(StatusCode::CREATED)
+                .with_header("Location", &format!("{}/account/1", url))
+                .with_body("application/json", r#"{"status":"valid"}"#)
+        });
+        let url = ca_url.clone();
+        stub.post("/order", move |request: Request| {
+            let order = payload(&request);
+            assert_eq!(order["identifiers"][0]["value"], "localhost");
+            let order = json!({
+                "status": "pending",
+                "authorizations": [format!("{}/authz/1", url)],
+                "finalize": format!("{}/finalize/1", url),
+            });
+            Response::from_status(StatusCode::CREATED)
+                .with_header("Location", &format!("{}/order/1", url))
+                .with_body("application/json", order.to_string())
+        });
+        let url = ca_url.clone();
+        let valid = Arc::clone(&validated);
+        stub.post("/authz/1", move || {
+            let status = if *valid.lock().unwrap() {
+                "valid"
+            } else {
+                "pending"
+            };
+            let authorization = json!({
+                "status": status,
+                "challenges": [
+                    { "type": "dns-01", "url": format!("{}/unused", url), "token": "dns" },
+                    { "type": "http-01", "url": format!("{}/challenge/1", url), "token": "t0ken" },
+                ],
+            });
+            Response::ok("application/json", authorization.to_string())
+        });
+        // Validates like a CA would, fetching the key authorization over HTTP
+        let valid = Arc::clone(&validated);
+        stub.post("/challenge/1", move |request: Request| {
+            assert_eq!(payload(&request), json!({}));
+            let mut client = TcpStream::connect(http_addr).unwrap();
+            client
+                .write_all(
+                    b"GET /.well-known/acme-challenge/t0ken HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\r\n\r\nt0ken."));
+            *valid.lock().unwrap() = true;
+            Response::ok("application/json", r#"{"status":"processing"}"#)
+        });
+        let url = ca_url.clone();
+        let issued = Arc::clone(&certificate);
+        let signer = Arc::clone(&ca);
+        stub.post("/finalize/1", move |request: Request| {
+            let csr = base64url_decode(payload(&request)["csr"].as_str().unwrap());
+            let csr = CertificateSigningRequestParams::from_der(&csr.into()).unwrap();
+            let certificate = csr.signed_by(&signer).unwrap();
+            *issued.lock().unwrap() = Some(certificate.pem());
+            let order = json!({ "status": "valid", "certificate": format!("{}/cert/1", url) });
+            Response::ok("application/json", order.to_string())
+        });
+        let url = ca_url.clone();
+        stub.post("/order/1", move || {
+            let order = json!({ "status": "valid", "certificate": format!("{}/cert/1", url) });
+            Response::ok("application/json", order.to_string())
+        });
+        let issued = Arc::clone(&certificate);
+        stub.post("/cert/1", move || {
+            let chain = issued.lock().unwrap().clone().unwrap();
+            Response::ok("application/pem-certificate-chain", chain)
+        });
+        Server::new(stub, "").start(ca_listener).unwrap();
+        // Starts a TLS server filled in by acme
+        let serve = |acme: &Acme| {
+            let tls = TlsConfig::new().unwrap();
+            acme.start(&tls).unwrap();
+            let mut router = Router::new();
+            router.get("/", || Response::ok("text/plain", "secure"));
+            let server = Server::new(router, "").config(ServerConfig {
+                tls: Some(tls),
+                ..ServerConfig::default()
+            });
+            let listener = server.bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            server.start(listener).unwrap();
+            addr
+        };
+        // Fetches / once the handshake succeeds with a certificate from the CA
+        let fetch = |addr: SocketAddr| {
+            let mut roots = RootCertStore::empty();
+            roots.add(ca_der.clone()).unwrap();
+            let config = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            let started = Instant::now();
+            loop {
+                let name = ServerName::try_from("localhost").unwrap();
+                let connection = ClientConnection::new(Arc::new(config.clone()), name).unwrap();
+                let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+                let mut response = Vec::new();
+                let sent = stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .and_then(|_| stream.read_to_end(&mut response));
+                if sent.is_ok() {
+                    return String::from_utf8(response).unwrap();
+                }
+                assert!(
+                    started.elapsed() < Duration::from_secs(10),
+                    "no certificate"
+                );
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        let addr = serve(&acme);
+        assert!(fetch(addr).ends_with("\r\n\r\nsecure"));
+        let cached = dir.path().join("acme");
+        assert!(cached.join("localhost.pem").exists());
+        assert!(cached.join("localhost-key.pem").exists());
+        assert!(cached.join("account-key.der").exists());
+
+        // A restart serves the cached certificate without reaching the CA
+        let addr = serve(&acme_for("http://127.0.0.1:1/directory"));
+        assert!(fetch(addr).ends_with("\r\n\r\nsecure"));
+    }
+
+    // Test the socket options applied to listeners and accepted connections
+    // Each configured option must be readable back from the socket
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_server_socket_options() {
+        use std::os::fd::AsRawFd;
+        use std::time::Duration;
+
+        fn read_option(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> [i32; 2] {
+            let mut value = [0i32; 2];
+            let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    value.as_mut_ptr() as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(result, 0);
+            value
+        }
+
+        let server = Server::new(Router::new(), "").config(ServerConfig {
+            tcp_nodelay: true,
+            linger: Some(Duration::from_millis(1500)),
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        // Linux doubles buffer sizes for its own bookkeeping
+        assert_eq!(
+            read_option(&listener, libc::SOL_SOCKET, libc::SO_SNDBUF)[0],
+            128 * 1024
+        );
+        assert_eq!(
+            read_option(&listener, libc::SOL_SOCKET, libc::SO_RCVBUF)[0],
+            128 * 1024
+        );
+
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        server.configure_connection(&stream);
+        assert_eq!(
+            read_option(&stream, libc::IPPROTO_TCP, libc::TCP_NODELAY)[0],
+            1
+        );
+        assert_eq!(
+            read_option(&stream, libc::SOL_SOCKET, libc::SO_LINGER),
+            [1, 2]
+        );
+        assert_eq!(
+            read_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE)[0],
+            1
+        );
+        assert_eq!(
+            read_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE)[0],
+            30
+        );
+    }
+
+    // Test persistent connections with a request limit and an idle timeout
+    // Pipelined requests and static files must be answered in order, and the
+    // last allowed one must carry Connection: close
+    #[test]
+    #[cfg(feature = "static")]
+    fn test_server_keep_alive() {
+        use std::time::{Duration, Instant};
+
+        let mut router = Router::new();
+        router.get("/hello", || Response::ok("text/plain", "hello"));
+        router.post("/echo", |Text(text): Text| Response::ok("text/plain", text));
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("file.txt"), "static").unwrap();
+        let server = Server::new(router, temp_dir.path().to_str().unwrap()).config(ServerConfig {
+            keep_alive_timeout: Some(Duration::from_millis(300)),
+            max_requests_per_connection: Some(3),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping\
+                  GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        client
+            .write_all(b"GET /file.txt HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        let responses: Vec<&str> = responses.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].starts_with("200") && responses[0].ends_with("ping"));
+        assert!(!responses[0].contains("Connection: close"));
+        assert!(responses[1].ends_with("hello"));
+        assert!(responses[2].starts_with("200 OK\r\nConnection: close\r\n"));
+        assert!(responses[2].ends_with("static"));
+
+        // Idle connections are closed after the timeout
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let started = Instant::now();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("hello"));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Clients asking to close get their answer
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.contains("\r\nConnection: close\r\n"));
+    }
+
+    // Test a fixed worker pool with per-worker queues and pinned threads
+    // Connections must be handed to the pooled workers in turn
+    #[test]
+    fn test_server_worker_pool() {
+        let mut router = Router::new();
+        router.get("/thread", || {
+            let name = std::thread::current().name().unwrap_or("").to_string();
+            #[cfg(target_os = "linux")]
+            assert_eq!(unsafe { libc::sched_getcpu() }, 0);
+            Response::ok("text/plain", name)
+        });
+        let server = Server::new(router, "").config(ServerConfig {
+            acceptor_threads: Some(2),
+            worker_threads: Some(2),
+            per_worker_queues: true,
+            cpu_affinity: Some(vec![0]),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        for expected in ["worker-0", "worker-1", "worker-0", "worker-1"] {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /thread HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with(expected), "{}", response);
+        }
+    }
+
+    // Test a pooled worker whose handler panics
+    // The worker must survive and answer the next connection
+    #[test]
+    fn test_server_worker_pool_panic() {
+        let mut router = Router::new();
+        router.get("/panic", || -> Response { panic!("handler failed") });
+        router.get("/hello", || Response::ok("text/plain", "hello"));
+        let server = Server::new(router, "").config(ServerConfig {
+            worker_threads: Some(1),
+            ..ServerConfig::default()
+        });
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.start(listener).unwrap();
+
+        for (path, expected) in [("/panic", ""), ("/hello", "hello")] {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            assert!(response.ends_with(expected), "{}", response);
+        }
+    }
+
+    // Test binding with SO_REUSEPORT
+    // Two servers must bind the same address and both serve requests on it
+    #[test]
+    #[cfg(unix)]
+    fn test_server_reuse_port() {
+        let config = ServerConfig {
+            reuse_port: true,
+            ..ServerConfig::default()
+        };
+        let server = |name: &'static str| {
+            let mut router = Router::new();
+            router.get("/name", move || Response::ok("text/plain", name));
+            Server::new(router, "").config(config.clone())
+        };
+        let first = server("first");
+        let listener = first.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let second = server("second");
+        let shared = second.bind(&addr.to_string()).unwrap();
+        assert_eq!(shared.local_addr().unwrap(), addr);
+        assert!(
+            Server::new(Router::new(), "")
+                .bind(&addr.to_string())
+                .is_err()
+        );
+
+        // The kernel picks either listener for each connection
+        first.start(listener).unwrap();
+        second.start(shared).unwrap();
+        for _ in 0..4 {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /name HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with("first") || response.ends_with("second"));
+        }
+    }
+
+    // Test handing the listener over to a successor process
+    // The child must inherit it as fd 3, and the acceptor must stop once the
+    // request it was serving is answered
+    #[test]
+    #[cfg(unix)]
+    fn test_server_hand_over() {
+        let mut router = Router::new();
+        router.get("/slow", || {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            Response::ok("text/plain", "done")
+        });
+        let server = Server::new(router, "");
+        let listener = server.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(
+            server
+                .hand_over(&mut std::process::Command::new("true"))
+                .is_err()
+        );
+        let acceptor = server.start(listener).unwrap();
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .args(["-c", "echo $LISTEN_FDS; [ -S /dev/fd/3 ] && echo socket"])
+            .stdout(std::process::Stdio::piped());
+        let child = server.hand_over(&mut command).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\nsocket\n");
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("done"));
+        acceptor.join().unwrap();
+    }
+
+    // Test deterministic route precedence
+    // Static segments beat parameters, parameters beat wildcards, whatever the order
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_router_precedence() {
+        let mut router = Router::new();
+        router.get("/{*path}", |Path(path): Path<String>| {
+            Response::ok("text/plain", format!("root wildcard {}", path))
+        });
+        router.get("/files/{*path}", |Path(path): Path<String>| {
+            Response::ok("text/plain", format!("files wildcard {}", path))
+        });
+        router.get("/users/{id}/posts", |Path(id): Path<String>| {
+            Response::ok("text/plain", format!("posts of {}", id))
+        });
+        router.get("/users/{id}", |Path(id): Path<String>| {
+            Response::ok("text/plain", format!("param {}", id))
+        });
+        router.get("/users/me", || Response::ok("text/plain", "static me"));
+        router.get("/users/me/settings", || {
+            Response::ok("text/plain", "settings")
+        });
+
+        let body = |raw: &[u8]| route_request(&router, raw).unwrap().body;
+        assert_eq!(body(b"GET /users/me HTTP/1.1\r\n\r\n"), b"static me");
+        assert_eq!(body(b"GET /users/42 HTTP/1.1\r\n\r\n"), b"param 42");
+        // The static branch fails deeper down, so the parameter branch is used
+        assert_eq!(
+            body(b"GET /users/me/posts HTTP/1.1\r\n\r\n"),
+            b"posts of me"
+        );
+        assert_eq!(
+            body(b"GET /files/a/b.txt HTTP/1.1\r\n\r\n"),
+            b"files wildcard a/b.txt"
+        );
+        assert_eq!(
+            body(b"GET /other/x HTTP/1.1\r\n\r\n"),
+            b"root wildcard other/x"
+        );
+    }
+
+    // Test the compiled route tree directly
+    // Lookups return the route id and raw parameter values in pattern order
+    #[test]
+    fn test_route_tree_lookup() {
+        let mut tree = RouteTree::default();
+        tree.insert(&parse_pattern("/a/{x}/c/{y}"), 0);
+        tree.insert(&parse_pattern("/a/b/c/d"), 1);
+        tree.insert(&parse_pattern("/a/{*rest}"), 2);
+
+        let accept_all = |_| true;
+        assert_eq!(tree.lookup("/a/b/c/d", &accept_all), Some((1, vec![])));
+        assert_eq!(
+            tree.lookup("/a/z/c/9", &accept_all),
+            Some((0, vec!["z".to_string(), "9".to_string()]))
+        );
+        assert_eq!(
+            tree.lookup("/a/z/q", &accept_all),
+            Some((2, vec!["z/q".to_string()]))
+        );
+        assert_eq!(
+            tree.lookup("/a/b/c/d", &|id| id != 1).map(|(id, _)| id),
+            Some(0)
+        );
+        assert_eq!(tree.lookup("/b", &accept_all), None);
+    }
+
+    // Test regex-constrained path parameters
+    // Non-matching segments fall through to other routes or to no match at all
+    #[test]
+    #[cfg(feature = "router")]
+    fn test_router_constrained_params() {
+        let mut router = Router::new();
+        router.get("/posts/{id:[0-9]+}", |Path(id): Path<u64>| {
+            Response::ok("text/plain", format!("post #{}", id))
+        });
+        router.get("/users/{id:[0-9]+}", |Path(id): Path<u64>| {
+            Response::ok("text/plain", format!("user #{}", id))
+        });
+        router.get("/posts/{slug}", |Path(slug): Path<String>| {
+            Response::ok("text/plain", format!("post {}", slug))
+        });
+
+        let body = |raw: &[u8]| {
+            route_request(&router, raw).map(|response| response.body.as_bytes().unwrap().to_vec())
+        };
+        assert_eq!(
+            body(b"GET /posts/42 HTTP/1.1\r\n\r\n"),
+            Some(b"post #42".to_vec())
+        );
+        assert_eq!(
+            body(b"GET /posts/hello HTTP/1.1\r\n\r\n"),
+            Some(b"post hello".to_vec())
+        );
+        // The regex must match the whole segment
+        assert_eq!(
+            body(b"GET /posts/42abc HTTP/1.1\r\n\r\n"),
+            Some(b"post 42abc".to_vec())
+        );
+        assert_eq!(body(b"GET /users/abc HTTP/1.1\r\n\r\n"), None);
+    }
+
+    // Test registration of a route with an invalid constraint
+    // Malformed patterns are programming errors and panic at registration
+    #[test]
+    #[cfg(feature = "router")]
+    #[should_panic(expected = "invalid route constraint")]
+    fn test_router_invalid_constraint() {
+        Router::new().get("/posts/{id:[0-9}", || Response::new(204, "No Content"));
+    }
+
+    // Test routing through the prelude types
+    // Method values register routes and StatusCode supplies the reason phrase
+    #[test]
+    fn test_prelude_method_and_status() {
+        use crate::prelude::{Method, StatusCode};
+
+        let mut router = Router::new();
+        router.route(Method::Patch, "/items/{id}", || {
+            Response::from_status(StatusCode::NO_CONTENT)
+        });
+        let response = route_request(&router, b"PATCH /items/1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(response.reason, "No Content");
+
+        assert_eq!("PATCH".parse::<Method>().unwrap(), Method::Patch);
+        assert_eq!(
+            "PROPFIND".parse::<Method>().unwrap(),
+            Method::Other("PROPFIND".to_string())
+        );
+        assert_eq!(StatusCode::new(404), Some(StatusCode::NOT_FOUND));
+        assert_eq!(StatusCode::NOT_FOUND.to_string(), "404 Not Found");
+        assert_eq!(StatusCode::new(42), None);
+    }
 }
@@ -0,0 +1,145 @@
+// Deadline for route handlers
+// The rest of the chain runs on a thread of its own while the connection's
+// thread waits for it up to the limit. Threads cannot be stopped from the
+// outside, so a handler past its deadline keeps running in the background
+// and its response is dropped; the client gets an error right away and the
+// worker is free to move on. Such overdue handlers are counted, and once
+// there are max_overdue of them, requests are refused without starting a
+// thread until some finish, so a hung backend can't pile up threads.
+
+use crate::{Middleware, Next, Request, Response, StatusCode};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+// Overdue handlers allowed by default
+const DEFAULT_MAX_OVERDUE: usize = 16;
+
+// States of a handler thread
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+// Past its deadline, counted in overdue
+const ABANDONED: u8 = 2;
+
+/// Middleware bounding how long the handlers behind it may take
+/// Late requests are answered with 503 Service Unavailable (or the status
+/// set with status, e.g. 504 for routes proxying to another server) and a
+/// warning is printed. Each request runs on a new thread named "handler",
+/// and its Request is cloned, body included. Once max_overdue handlers are
+/// still running past their deadline, requests get the status right away
+/// Example:
+/// router.scope("/reports", |reports| {
+///     reports.layer(Timeout::new(Duration::from_secs(5)));
+///     reports.get("/yearly", yearly_report);
+/// });
+#[derive(Clone, Debug)]
+pub struct Timeout {
+    limit: Duration,
+    status: StatusCode,
+    max_overdue: usize,
+    // Handlers past their deadline still running, shared by clones
+    overdue: Arc<AtomicUsize>,
+}
+
+// Marks a handler thread finished when it ends, even by panicking, and
+// takes it off the overdue count if it was on it
+struct Finish {
+    state: Arc<AtomicU8>,
+    overdue: Arc<AtomicUsize>,
+}
+
+impl Timeout {
+    /// Creates a timeout answering 503 after limit
+    pub fn new(limit: Duration) -> Timeout {
+        Timeout {
+            limit,
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            max_overdue: DEFAULT_MAX_OVERDUE,
+            overdue: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sets how many handlers may still run past their deadline (16 by
+    /// default) before requests are refused without running theirs
+    /// Clones of the Timeout share the count
+    /// Example: Timeout::new(Duration::from_secs(5)).max_overdue(4)
+    pub fn max_overdue(mut self, handlers: usize) -> Timeout {
+        self.max_overdue = handlers;
+        self
+    }
+
+    /// Replaces the status sent when the deadline passes
+    /// Example: Timeout::new(Duration::from_secs(30)).status(StatusCode::GATEWAY_TIMEOUT)
+    pub fn status(mut self, status: StatusCode) -> Timeout {
+        self.status = status;
+        self
+    }
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        if self.overdue.load(Ordering::Acquire) >= self.max_overdue {
+            eprintln!(
+                "Refusing {} {}: {} handlers are still running past their deadline",
+                request.method, request.path, self.max_overdue
+            );
+            return Response::from_status(self.status);
+        }
+        let chain = next.detach();
+        let detached = request.clone();
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        let finish = Finish {
+            state: Arc::clone(&state),
+            overdue: Arc::clone(&self.overdue),
+        };
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let spawned = thread::Builder::new()
+            .name("handler".to_string())
+            .spawn(move || {
+                let _finish = finish;
+                // Nobody listens anymore if the deadline passed
+                let _ = sender.send(chain(&detached));
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to spawn handler thread: {}", e);
+            return Response::from_status(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        match receiver.recv_timeout(self.limit) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => {
+                // Counted first, so that Finish never takes it off before
+                self.overdue.fetch_add(1, Ordering::AcqRel);
+                let abandoned = state
+                    .compare_exchange(RUNNING, ABANDONED, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok();
+                if !abandoned {
+                    // It finished right at the deadline, its response is sent
+                    self.overdue.fetch_sub(1, Ordering::AcqRel);
+                    return receiver.recv().unwrap_or_else(|_| {
+                        Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                    });
+                }
+                eprintln!(
+                    "Handler for {} {} exceeded its {:?} deadline",
+                    request.method, request.path, self.limit
+                );
+                Response::from_status(self.status)
+            }
+            // The handler panicked
+            Err(RecvTimeoutError::Disconnected) => {
+                Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        if self.state.swap(FINISHED, Ordering::AcqRel) == ABANDONED {
+            self.overdue.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+// TLS for the threaded backend (rustls with the ring provider)
+// Connections are decrypted on their worker thread and then go through the
+// regular pipeline, copying static files through the TLS stream instead of
+// sendfile(2). The certificate is chosen by the hostname the client names
+// through SNI. With client authentication, the certificate the client
+// presented is checked against a CA bundle during the handshake and handed
+// to handlers with its subject.
+
+#[cfg(feature = "acme")]
+use crate::http_date::days_from_civil;
+use crate::keep_alive::{self, KeepAlive, OnSocket};
+use crate::{FileTransfer, Peer, Router, serve_stream};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConnection, StreamOwned};
+use std::io::{self, ErrorKind};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "acme")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Certificates, key and client authentication settings of a TLS listener
+/// (see ServerConfig::tls)
+/// Example:
+/// let tls = TlsConfig::from_pem_files("cert.pem", "key.pem")?
+///     .add_host("*.example.org", "example-org.pem", "example-org-key.pem")?
+///     .require_client_cert("clients-ca.pem")?;
+/// let server = Server::new(router, "static").config(ServerConfig {
+///     tls: Some(tls),
+///     ..ServerConfig::default()
+/// });
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    // Shared by clones, so certificates installed by Acme reach the listener
+    certificates: Arc<RwLock<Certificates>>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    // Built from the fields above
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Loads the certificate chain (leaf first) and its private key from PEM
+    /// files; clients are not asked for certificates
+    /// The certificate is sent to clients whose SNI hostname matches no
+    /// add_host entry, and to clients sending none
+    /// Returns an error if a file cannot be read, holds no certificate or
+    /// key, or the key does not match the certificate
+    pub fn from_pem_files(cert_chain: &str, private_key: &str) -> io::Result<TlsConfig> {
+        let certificates = Certificates {
+            default: Some(load_certified_key(cert_chain, private_key)?),
+            hosts: Vec::new(),
+        };
+        build(certificates, None)
+    }
+
+    /// Creates a configuration without certificates, for Acme to fill in
+    /// Handshakes fail until the first certificate is installed
+    #[cfg(feature = "acme")]
+    pub fn new() -> io::Result<TlsConfig> {
+        build(Certificates::default(), None)
+    }
+
+    /// Sends another certificate to clients asking for hostname through SNI,
+    /// so one listener serves several domains
+    /// hostname is an exact name or a wildcard covering one label, e.g.
+    /// "*.example.org" for "www.example.org" but not "example.org"; exact
+    /// names win over wildcards, then the first entry added wins
+    /// Example:
+    /// let tls = TlsConfig::from_pem_files("default.pem", "default-key.pem")?
+    ///     .add_host("example.com", "example-com.pem", "example-com-key.pem")?
+    ///     .add_host("*.example.com", "wildcard.pem", "wildcard-key.pem")?;
+    pub fn add_host(
+        self,
+        hostname: &str,
+        cert_chain: &str,
+        private_key: &str,
+    ) -> io::Result<TlsConfig> {
+        let key = load_certified_key(cert_chain, private_key)?;
+        let mut certificates = self.certificates.read().unwrap().clone();
+        certificates.hosts.push((normalize(hostname), key));
+        build(certificates, self.client_verifier)
+    }
+
+    /// Requires every client to present a certificate issued by one of the
+    /// CAs in the PEM bundle; other handshakes fail
+    pub fn require_client_cert(self, ca_bundle: &str) -> io::Result<TlsConfig> {
+        self.with_client_auth(ca_bundle, true)
+    }
+
+    /// Asks clients for a certificate issued by one of the CAs in the PEM
+    /// bundle but also accepts clients without one; handlers tell them apart
+    /// with Request::client_subject
+    pub fn request_client_cert(self, ca_bundle: &str) -> io::Result<TlsConfig> {
+        self.with_client_auth(ca_bundle, false)
+    }
+
+    fn with_client_auth(self, ca_bundle: &str, required: bool) -> io::Result<TlsConfig> {
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(ca_bundle)? {
+            roots.add(certificate).map_err(invalid_data)?;
+        }
+        let mut builder = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(crypto_provider()),
+        );
+        if !required {
+            builder = builder.allow_unauthenticated();
+        }
+        let verifier = builder.build().map_err(invalid_data)?;
+        let certificates = self.certificates.read().unwrap().clone();
+        build(certificates, Some(verifier))
+    }
+
+    // Sends the certificate for the hostnames from now on, replacing the
+    // ones added for them before; it also becomes the default if there is none
+    #[cfg(feature = "acme")]
+    pub(crate) fn install(&self, hostnames: &[String], key: CertifiedKey) {
+        let key = Arc::new(key);
+        let mut certificates = self.certificates.write().unwrap();
+        for hostname in hostnames {
+            let hostname = normalize(hostname);
+            certificates.hosts.retain(|(host, _)| *host != hostname);
+            certificates.hosts.push((hostname, Arc::clone(&key)));
+        }
+        certificates.default.get_or_insert(key);
+    }
+}
+
+// The default certificate and those selected by SNI hostname
+#[derive(Clone, Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    // Lowercase exact names or "*." wildcards, in the order they were added
+    hosts: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl Certificates {
+    // Returns the certificate for an SNI hostname
+    fn select(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        let exact = self.hosts.iter().find(|(host, _)| *host == name);
+        let matching = exact.or_else(|| {
+            self.hosts
+                .iter()
+                .find(|(host, _)| Some(host) == wildcard.as_ref())
+        });
+        match matching {
+            Some((_, key)) => Some(Arc::clone(key)),
+            None => self.default.clone(),
+        }
+    }
+}
+
+// Hands rustls the certificate for each handshake
+#[derive(Debug)]
+struct Resolver(Arc<RwLock<Certificates>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.0.read().unwrap().select(client_hello.server_name())
+    }
+}
+
+// Builds the configuration with its own copy of the certificates
+fn build(
+    certificates: Certificates,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> io::Result<TlsConfig> {
+    let certificates = Arc::new(RwLock::new(certificates));
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::new(crypto_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let builder = match &client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(Arc::clone(verifier)),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::new(Resolver(Arc::clone(&certificates))));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConfig {
+        certificates,
+        client_verifier,
+        config: Arc::new(config),
+    })
+}
+
+// Hostnames are matched in lowercase, without the root's trailing dot
+fn normalize(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn crypto_provider() -> CryptoProvider {
+    rustls::crypto::ring::default_provider()
+}
+
+// Reads a certificate chain and its private key, checking that they match
+fn load_certified_key(cert_chain: &str, private_key: &str) -> io::Result<Arc<CertifiedKey>> {
+    let certificates = load_certificates(cert_chain)?;
+    let private_key = PrivateKeyDer::from_pem_file(private_key).map_err(invalid_data)?;
+    let key = CertifiedKey::from_der(certificates, private_key, &crypto_provider())
+        .map_err(invalid_data)?;
+    Ok(Arc::new(key))
+}
+
+// Parses a PEM certificate chain and private key, as issued by Acme
+#[cfg(feature = "acme")]
+pub fn parse_certified_key(cert_chain: &[u8], private_key: &[u8]) -> io::Result<CertifiedKey> {
+    let certificates = CertificateDer::pem_slice_iter(cert_chain)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_data)?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no certificate"));
+    }
+    let private_key = PrivateKeyDer::from_pem_slice(private_key).map_err(invalid_data)?;
+    CertifiedKey::from_der(certificates, private_key, &crypto_provider()).map_err(invalid_data)
+}
+
+// Reads every certificate of a PEM file
+fn load_certificates(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(invalid_data)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_data)?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate in {}", path),
+        ));
+    }
+    Ok(certificates)
+}
+
+fn invalid_data(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+// Performs the handshake and serves the connection over TLS
+// Failed handshakes (wrong protocol, refused client certificates, clients
+// hanging up) just close the connection
+pub fn serve(
+    stream: TcpStream,
+    tls: &TlsConfig,
+    base_dir: &str,
+    router: &Router,
+    mut peer: Peer,
+    keep_alive: Option<KeepAlive>,
+) {
+    let Ok(connection) = ServerConnection::new(Arc::clone(&tls.config)) else {
+        return;
+    };
+    let mut stream = StreamOwned::new(connection, stream);
+    while stream.conn.is_handshaking() {
+        if stream.conn.complete_io(&mut stream.sock).is_err() {
+            return;
+        }
+    }
+
+    if let Some(certificate) = stream
+        .conn
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+    {
+        peer.subject = subject(certificate);
+        peer.certificate = Some(certificate.to_vec());
+    }
+    match keep_alive {
+        Some(limits) => keep_alive::serve(&mut stream, base_dir, router, peer, limits, false),
+        None => serve_stream(
+            &mut stream,
+            base_dir,
+            router,
+            FileTransfer::Copy,
+            peer,
+            true,
+        ),
+    }
+    stream.conn.send_close_notify();
+    let _ = stream.conn.complete_io(&mut stream.sock);
+}
+
+impl OnSocket for StreamOwned<ServerConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+// Returns the subject of a DER certificate as an RFC 4514 string, e.g.
+// "CN=alice,OU=Engineering,O=Example Corp,C=US"
+fn subject(certificate: &[u8]) -> Option<String> {
+    let (_, certificate, _) = read_tlv(certificate)?;
+    let (_, mut tbs, _) = read_tlv(certificate)?;
+    // Skip the optional version, then serial, signature, issuer, validity
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    for _ in 0..4 {
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (_, mut names, _) = read_tlv(tbs)?;
+
+    let mut rdns = Vec::new();
+    while !names.is_empty() {
+        let (_, mut set, rest) = read_tlv(names)?;
+        names = rest;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (_, attribute, rest) = read_tlv(set)?;
+            set = rest;
+            let (_, oid, value) = read_tlv(attribute)?;
+            attributes.push(format!(
+                "{}={}",
+                attribute_name(oid),
+                attribute_value(value)?
+            ));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    // RFC 4514 lists the most specific name first
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+// Returns the end of a DER certificate's validity period
+#[cfg(feature = "acme")]
+pub fn not_after(certificate: &[u8]) -> Option<SystemTime> {
+    let (_, certificate, _) = read_tlv(certificate)?;
+    let (_, mut tbs, _) = read_tlv(certificate)?;
+    // Skip the optional version, then serial, signature, issuer
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    for _ in 0..3 {
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (_, validity, _) = read_tlv(tbs)?;
+    let (_, _, validity) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(validity)?;
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    // UTCTime has a two-digit year (RFC 5280 section 4.1.2.5.1),
+    // GeneralizedTime four
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    if rest.len() != 10 || !rest.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |index: usize| rest[index..index + 2].parse::<u32>().unwrap();
+    let days = u64::try_from(days_from_civil(year, field(0), field(2))).ok()?;
+    let seconds =
+        days * 86_400 + u64::from(field(4)) * 3600 + u64::from(field(6)) * 60 + u64::from(field(8));
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+// Splits a DER element off the input: tag, contents and what follows
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets]
+            .iter()
+            .fold(0usize, |len, &byte| len << 8 | byte as usize);
+        rest = &rest[octets..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+// Short name of an attribute type, or its dotted OID
+fn attribute_name(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        _ => return dotted_oid(oid),
+    };
+    name.to_string()
+}
+
+// Formats an OID as "2.5.4.45"
+fn dotted_oid(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for &byte in oid {
+        arc = arc << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// Formats an attribute value: strings escaped, anything else as "#" and
+// the hex of its encoding
+fn attribute_value(element: &[u8]) -> Option<String> {
+    let (tag, contents, _) = read_tlv(element)?;
+    let text = match tag {
+        // UTF8String, PrintableString, IA5String
+        0x0c | 0x13 | 0x16 => String::from_utf8_lossy(contents).into_owned(),
+        // TeletexString, treated as Latin-1
+        0x14 => contents.iter().map(|&byte| byte as char).collect(),
+        // BMPString
+        0x1e => {
+            let units: Vec<u16> = contents
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => {
+            let len = element.len() - read_tlv(element)?.2.len();
+            let hex: String = element[..len]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            return Some(format!("#{}", hex));
+        }
+    };
+
+    let last = text.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (index == 0 && matches!(c, '#' | ' '))
+            || (index == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        if c == '\0' {
+            escaped.push_str("\\00");
+        } else {
+            escaped.push(c);
+        }
+    }
+    Some(escaped)
+}
@@ -0,0 +1,395 @@
+// Resumable uploads over the tus protocol (https://tus.io, version 1.0.0)
+// Supports the core protocol (HEAD offset query, PATCH append) with the
+// creation and checksum extensions. Uploads live in a directory, one data
+// file named after the upload id and one ".info" file holding the announced
+// length and metadata, so they survive restarts. PATCH bodies are streamed
+// to the data file: when the connection drops halfway, the bytes received
+// are kept and the client resumes from there, unless the request carried a
+// checksum, which can then not be verified.
+
+use crate::credentials::{STANDARD, base64_decode};
+use crate::{Next, Request, Response, Router, StatusCode};
+use ring::digest::{self, Algorithm, Context};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,checksum";
+const CHECKSUM_ALGORITHMS: &str = "sha1,sha256,sha512";
+
+// Size of the buffer PATCH bodies are copied through
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+type CompleteHook = Arc<dyn Fn(&Path, &[(String, String)]) + Send + Sync>;
+
+/// Settings of a tus upload endpoint (see Router::tus)
+/// Example:
+/// let tus = Tus::new("uploads").max_size(10 * 1024 * 1024 * 1024).on_complete(|path, metadata| {
+///     eprintln!("{} finished: {:?}", path.display(), metadata);
+/// });
+/// router.tus("/files", tus).with(require_auth);
+#[derive(Clone)]
+pub struct Tus {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    on_complete: Option<CompleteHook>,
+}
+
+impl Tus {
+    /// Creates the settings for uploads stored in dir, created if missing
+    pub fn new(dir: impl Into<PathBuf>) -> Tus {
+        Tus {
+            dir: dir.into(),
+            max_size: None,
+            on_complete: None,
+        }
+    }
+
+    /// Sets the largest upload accepted, in bytes, announced as Tus-Max-Size
+    pub fn max_size(mut self, bytes: u64) -> Tus {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Registers a function called once an upload received all its bytes,
+    /// with the path of its data file and its decoded Upload-Metadata
+    /// The function may move the file away, the upload is then forgotten
+    pub fn on_complete(
+        mut self,
+        hook: impl Fn(&Path, &[(String, String)]) + Send + Sync + 'static,
+    ) -> Tus {
+        self.on_complete = Some(Arc::new(hook));
+        self
+    }
+}
+
+// An upload endpoint and the uploads being appended to
+struct Endpoint {
+    tus: Tus,
+    busy: Mutex<HashSet<String>>,
+    random: SystemRandom,
+}
+
+// What an upload's ".info" file records
+struct Info {
+    length: u64,
+    // Raw Upload-Metadata header, empty without one
+    metadata: String,
+}
+
+// Registers the endpoint's routes on the router
+pub fn mount(router: &mut Router, prefix: &str, tus: Tus) {
+    if let Err(e) = fs::create_dir_all(&tus.dir) {
+        eprintln!(
+            "Cannot create tus upload directory {}: {}",
+            tus.dir.display(),
+            e
+        );
+    }
+    let endpoint = Arc::new(Endpoint {
+        tus,
+        busy: Mutex::new(HashSet::new()),
+        random: SystemRandom::new(),
+    });
+    router.scope(prefix, |uploads| {
+        uploads.layer(|request: &Request, next: &Next| {
+            let response = match request.method.as_str() {
+                "OPTIONS" => return next.run(request),
+                _ if request.header("Tus-Resumable") != Some(VERSION) => {
+                    Response::from_status(StatusCode::PRECONDITION_FAILED)
+                        .with_header("Tus-Version", VERSION)
+                }
+                _ => next.run(request),
+            };
+            response.with_header("Tus-Resumable", VERSION)
+        });
+
+        let options = Arc::clone(&endpoint);
+        uploads.route("OPTIONS", "/", move |_: Request| options.options());
+        let create = Arc::clone(&endpoint);
+        uploads.post("/", move |request: Request| create.create(&request));
+        let head = Arc::clone(&endpoint);
+        uploads.route("HEAD", "/{id}", move |request: Request| {
+            head.offset(request.param("id").unwrap_or(""))
+        });
+        let append = Arc::clone(&endpoint);
+        uploads
+            .route("PATCH", "/{id}", move |request: Request| {
+                append.append(&request)
+            })
+            .stream_body();
+    });
+}
+
+impl Endpoint {
+    // Describes the server's capabilities
+    fn options(&self) -> Response {
+        let mut response = Response::from_status(StatusCode::NO_CONTENT)
+            .with_header("Tus-Resumable", VERSION)
+            .with_header("Tus-Version", VERSION)
+            .with_header("Tus-Extension", EXTENSIONS)
+            .with_header("Tus-Checksum-Algorithm", CHECKSUM_ALGORITHMS);
+        if let Some(max_size) = self.tus.max_size {
+            response = response.with_header("Tus-Max-Size", &max_size.to_string());
+        }
+        response
+    }
+
+    // Creates an empty upload of the announced length (creation extension)
+    fn create(&self, request: &Request) -> Response {
+        let Some(length) = request.header("Upload-Length").and_then(parse_number) else {
+            return Response::bad_request("Upload-Length is missing or invalid");
+        };
+        if self.tus.max_size.is_some_and(|max| length > max) {
+            return Response::from_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let metadata = request.header("Upload-Metadata").unwrap_or("").trim();
+        if decode_metadata(metadata).is_none() {
+            return Response::bad_request("Upload-Metadata is invalid");
+        }
+
+        let mut id = [0; 16];
+        if self.random.fill(&mut id).is_err() {
+            return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let id: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let info = format!("length {}\nmetadata {}\n", length, metadata);
+        let created =
+            fs::write(self.data_path(&id), b"").and_then(|_| fs::write(self.info_path(&id), info));
+        if let Err(e) = created {
+            return error_response(&e);
+        }
+        if length == 0 {
+            self.complete(&id, metadata);
+        }
+        let location = format!("{}/{}", request.path.trim_end_matches('/'), id);
+        Response::from_status(StatusCode::CREATED).with_header("Location", &location)
+    }
+
+    // Reports how many bytes of an upload were received
+    fn offset(&self, id: &str) -> Response {
+        let Some(info) = self.info(id) else {
+            return Response::from_status(StatusCode::NOT_FOUND);
+        };
+        let offset = match fs::metadata(self.data_path(id)) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return error_response(&e),
+        };
+        let mut response = Response::from_status(StatusCode::OK)
+            .with_header("Upload-Offset", &offset.to_string())
+            .with_header("Upload-Length", &info.length.to_string())
+            .with_header("Cache-Control", "no-store");
+        if !info.metadata.is_empty() {
+            response = response.with_header("Upload-Metadata", &info.metadata);
+        }
+        response
+    }
+
+    // Appends the request body at the offset the client announces
+    fn append(&self, request: &Request) -> Response {
+        let id = request.param("id").unwrap_or("");
+        if request.header("Content-Type") != Some("application/offset+octet-stream") {
+            return Response::from_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        let Some(offset) = request.header("Upload-Offset").and_then(parse_number) else {
+            return Response::bad_request("Upload-Offset is missing or invalid");
+        };
+        let checksum = match request.header("Upload-Checksum").map(parse_checksum) {
+            Some(Some(checksum)) => Some(checksum),
+            Some(None) => {
+                return Response::bad_request("Upload-Checksum is unsupported or invalid");
+            }
+            None => None,
+        };
+        let Some(info) = self.info(id) else {
+            return Response::from_status(StatusCode::NOT_FOUND);
+        };
+
+        // Concurrent appends to one upload would interleave
+        let Some(_lock) = self.lock(id) else {
+            return Response::from_status(StatusCode::CONFLICT);
+        };
+        let path = self.data_path(id);
+        let mut file = match OpenOptions::new().append(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return error_response(&e),
+        };
+        match file.metadata() {
+            Ok(metadata) if metadata.len() == offset => {}
+            Ok(_) => return Response::from_status(StatusCode::CONFLICT),
+            Err(e) => return error_response(&e),
+        }
+
+        let mut digest = checksum
+            .as_ref()
+            .map(|(algorithm, _)| Context::new(algorithm));
+        let copied = copy_body(
+            &mut request.body_reader(),
+            &mut file,
+            info.length - offset,
+            &mut digest,
+        );
+        let rollback = |response: Response| match file.set_len(offset) {
+            Ok(()) => response,
+            Err(e) => error_response(&e),
+        };
+        let received = match copied {
+            Ok(Some(received)) => received,
+            Ok(None) => return rollback(Response::from_status(StatusCode::PAYLOAD_TOO_LARGE)),
+            // A checksum only covers the whole body, without it the part
+            // received is kept for the client to resume after
+            Err(e) if checksum.is_some() => return rollback(Response::bad_request(&e.to_string())),
+            Err(e) => return Response::bad_request(&e.to_string()),
+        };
+        if let (Some(digest), Some((_, expected))) = (digest, &checksum)
+            && digest.finish().as_ref() != expected.as_slice()
+        {
+            return rollback(Response::new(460, "Checksum Mismatch"));
+        }
+
+        let offset = offset + received;
+        if offset == info.length {
+            self.complete(id, &info.metadata);
+        }
+        Response::from_status(StatusCode::NO_CONTENT)
+            .with_header("Upload-Offset", &offset.to_string())
+    }
+
+    // Hands a finished upload to the completion hook, and forgets it if the
+    // hook moved its data away
+    fn complete(&self, id: &str, metadata: &str) {
+        let Some(hook) = &self.tus.on_complete else {
+            return;
+        };
+        let path = self.data_path(id);
+        hook(&path, &decode_metadata(metadata).unwrap_or_default());
+        if !path.exists() {
+            let _ = fs::remove_file(self.info_path(id));
+        }
+    }
+
+    // Returns the recorded length and metadata of an upload
+    fn info(&self, id: &str) -> Option<Info> {
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let info = fs::read_to_string(self.info_path(id)).ok()?;
+        let mut lines = info.lines();
+        let length = parse_number(lines.next()?.strip_prefix("length ")?)?;
+        let metadata = lines.next()?.strip_prefix("metadata ")?.to_string();
+        Some(Info { length, metadata })
+    }
+
+    // Marks an upload as being appended to, until the guard is dropped
+    // Returns None if another request already is
+    fn lock(&self, id: &str) -> Option<UploadLock<'_>> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(UploadLock {
+            busy: &self.busy,
+            id: id.to_string(),
+        })
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.tus.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.tus.dir.join(format!("{}.info", id))
+    }
+}
+
+// Releases an upload locked by Endpoint::lock
+struct UploadLock<'a> {
+    busy: &'a Mutex<HashSet<String>>,
+    id: String,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Copies at most max bytes of the body to the file, feeding the digest
+// Returns the number of bytes copied, or None if the body is longer
+fn copy_body(
+    body: &mut impl Read,
+    file: &mut fs::File,
+    max: u64,
+    digest: &mut Option<Context>,
+) -> io::Result<Option<u64>> {
+    let mut buffer = vec![0; COPY_CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let bytes_read = match body.read(&mut buffer) {
+            Ok(0) => return Ok(Some(copied)),
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        copied += bytes_read as u64;
+        if copied > max {
+            return Ok(None);
+        }
+        file.write_all(&buffer[..bytes_read])?;
+        if let Some(digest) = digest {
+            digest.update(&buffer[..bytes_read]);
+        }
+    }
+}
+
+// Parses an Upload-Checksum header ("sha1 <base64 digest>")
+fn parse_checksum(header: &str) -> Option<(&'static Algorithm, Vec<u8>)> {
+    let (name, encoded) = header.trim().split_once(' ')?;
+    let algorithm = match name {
+        "sha1" => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        "sha256" => &digest::SHA256,
+        "sha512" => &digest::SHA512,
+        _ => return None,
+    };
+    Some((algorithm, base64_decode(encoded.trim(), STANDARD)?))
+}
+
+// Decodes an Upload-Metadata header, comma-separated keys each followed by
+// an optional base64 value
+// Example: "filename d29ybGQudHh0,is_public" -> [("filename", "world.txt"), ("is_public", "")]
+fn decode_metadata(header: &str) -> Option<Vec<(String, String)>> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value =
+                String::from_utf8_lossy(&base64_decode(value.trim(), STANDARD)?).to_string();
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+// Parses a non-negative decimal header value
+fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// Turns a file system error into a response
+fn error_response(error: &io::Error) -> Response {
+    match error.kind() {
+        ErrorKind::NotFound => Response::from_status(StatusCode::NOT_FOUND),
+        _ => {
+            eprintln!("tus request failed: {}", error);
+            Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// Connections taken over by a handler after its response
+// A response can carry a hook that gets the client's socket once the
+// response head is sent, to speak another protocol on it (a CONNECT tunnel,
+// or a WebSocket relayed to a backend). The hook runs on the connection's
+// thread and the connection is closed when it returns. Only plain TCP
+// connections can be handed over: behind TLS, HTTP/2 or the event loops
+// the response is replaced with 501.
+
+use crate::{Response, StatusCode};
+use std::fmt;
+use std::io::Write;
+use std::net::TcpStream;
+
+// Runs on the connection after the response head is written
+pub(crate) struct Upgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+// The connection handed to an upgrade hook
+#[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+pub(crate) struct Upgraded {
+    pub socket: TcpStream,
+    // Bytes the client sent after the request, read along with it
+    pub leftover: Vec<u8>,
+}
+
+impl Upgrade {
+    #[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+    pub fn new(hook: impl FnOnce(Upgraded) + Send + 'static) -> Upgrade {
+        Upgrade(Box::new(hook))
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+// Sends the response head and hands the connection to the hook, or answers
+// 501 if there is no socket to hand over
+pub(crate) fn hand_over(
+    stream: &mut impl Write,
+    response: Response,
+    upgrade: Upgrade,
+    socket: Option<&TcpStream>,
+    leftover: &[u8],
+) {
+    let socket = socket.and_then(|socket| socket.try_clone().ok());
+    let Some(socket) = socket.filter(|socket| socket.set_read_timeout(None).is_ok()) else {
+        let response = Response::from_status(StatusCode::NOT_IMPLEMENTED);
+        if response.write_to(stream).is_ok() {
+            let _ = stream.flush();
+        }
+        return;
+    };
+    if response.write_head(stream).is_err() || stream.flush().is_err() {
+        return;
+    }
+    (upgrade.0)(Upgraded {
+        socket,
+        leftover: leftover.to_vec(),
+    });
+}
@@ -0,0 +1,227 @@
+// Saving uploads to temporary files
+// Upload copies a body (a BodyReader, a multipart part or any other reader)
+// into a new file next to where it will end up, so keeping it is a rename.
+// The file is removed unless the handler persists it, including when the
+// copy fails halfway or the handler returns early.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Size of the buffer the body is copied through
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+// Distinguishes the temporary files created by this process
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Settings for saving request bodies to temporary files
+/// Example:
+/// fn upload(body: BodyReader, Path(name): Path<String>) -> Response {
+///     let upload = Upload::new().dir("uploads").max_size(100 * 1024 * 1024).sync(true);
+///     match upload.save(body) {
+///         Ok(file) => match file.persist(format!("uploads/{}", name)) {
+///             Ok(_) => Response::new(201, "Created"),
+///             Err(_) => Response::new(500, "Internal Server Error"),
+///         },
+///         Err(e) if e.kind() == ErrorKind::FileTooLarge => Response::new(413, "Payload Too Large"),
+///         Err(e) => Response::bad_request(&e.to_string()),
+///     }
+/// }
+#[derive(Clone, Debug)]
+pub struct Upload {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    sync: bool,
+}
+
+impl Upload {
+    /// Creates the settings for files in the system's temporary directory,
+    /// without size limit or fsync
+    pub fn new() -> Upload {
+        Upload {
+            dir: std::env::temp_dir(),
+            max_size: None,
+            sync: false,
+        }
+    }
+
+    /// Sets the directory temporary files are created in
+    /// Use one on the file system of the final location, so persist is a
+    /// rename rather than a copy
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Upload {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Sets the largest body accepted, in bytes
+    /// Longer bodies fail with ErrorKind::FileTooLarge once the limit is
+    /// reached, without reading the rest
+    pub fn max_size(mut self, bytes: u64) -> Upload {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Flushes saved files to disk before save returns, and their directory
+    /// entry once persisted, so a completed upload survives a power loss
+    pub fn sync(mut self, sync: bool) -> Upload {
+        self.sync = sync;
+        self
+    }
+
+    /// Copies the body into a new temporary file
+    /// The file is removed if reading or writing fails or the size limit is
+    /// exceeded, and the error returned
+    pub fn save(&self, mut body: impl Read) -> io::Result<UploadedFile> {
+        let (path, file) = self.create()?;
+        let mut uploaded = UploadedFile {
+            path,
+            file,
+            len: 0,
+            sync: self.sync,
+            persisted: false,
+        };
+
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+        loop {
+            let bytes_read = match body.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            uploaded.len += bytes_read as u64;
+            if self.max_size.is_some_and(|max| uploaded.len > max) {
+                return Err(io::Error::new(
+                    ErrorKind::FileTooLarge,
+                    "upload exceeds the size limit",
+                ));
+            }
+            uploaded.file.write_all(&buffer[..bytes_read])?;
+        }
+        if self.sync {
+            uploaded.file.sync_all()?;
+        }
+        uploaded.file.seek(SeekFrom::Start(0))?;
+        Ok(uploaded)
+    }
+
+    // Creates a file with a name no other upload uses, readable by the
+    // server's user only
+    fn create(&self) -> io::Result<(PathBuf, File)> {
+        loop {
+            let path = self.dir.join(format!(
+                ".upload-{}-{}",
+                process::id(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => return Ok((path, file)),
+                // Left behind by an earlier process with the same id
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for Upload {
+    fn default() -> Upload {
+        Upload::new()
+    }
+}
+
+/// A saved upload, removed when dropped unless persisted
+/// The file is open for reading from the start, e.g. to check its contents
+/// before keeping it
+#[derive(Debug)]
+pub struct UploadedFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    sync: bool,
+    persisted: bool,
+}
+
+impl UploadedFile {
+    /// Returns the path of the temporary file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of bytes saved
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the body was empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the open file
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Moves the file to its final path, replacing any file there
+    /// Falls back to copying when the path is on another file system
+    pub fn persist(mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        match fs::rename(&self.path, path) {
+            Ok(()) => self.persisted = true,
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                fs::copy(&self.path, path)?;
+                if self.sync {
+                    File::open(path)?.sync_all()?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        if self.sync {
+            sync_parent(path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the file now, reporting failures dropping it would ignore
+    pub fn discard(mut self) -> io::Result<()> {
+        self.persisted = true;
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Read for UploadedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Flushes the directory entry of a file to disk
+// Directories can't be opened as files on Windows, where renames are durable
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
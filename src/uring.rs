@@ -0,0 +1,372 @@
+// Experimental io_uring backend (Linux)
+// A single ring accepts connections, receives requests, sends responses and
+// splices static files from the page cache to sockets through a pipe, with
+// one system call per batch of operations instead of one per operation.
+// Each connection has at most one operation in flight, so its buffers stay
+// untouched until the kernel reports that operation complete.
+
+use crate::buffered::{self, expects_continue, request_complete};
+use crate::proxy_protocol::{self, Preamble};
+use crate::server::ACCEPT_CHECK_INTERVAL;
+use crate::stats::OpenConnection;
+use crate::{FileTransfer, IDLE_PROBE_INTERVAL, MAX_IDLE_PROBES, Peer, Server};
+use io_uring::{IoUring, opcode, squeue, types};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const ACCEPT: u64 = 0;
+// Completions of idle timeouts carry no state
+const TIMEOUT: u64 = u64::MAX;
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+// Bytes moved through the pipe per splice
+const SPLICE_CHUNK: u64 = 64 * 1024;
+
+// The operation a connection is waiting on
+enum State {
+    Receiving,
+    SendingContinue,
+    Sending,
+    // Moving file data into the pipe, then from the pipe to the socket
+    FillingPipe,
+    DrainingPipe,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    input: Vec<u8>,
+    chunk: Box<[u8]>,
+    continue_sent: bool,
+    output: Vec<u8>,
+    written: usize,
+    // Static file sent after the head, with the bytes left to send
+    file: Option<(File, u64)>,
+    file_offset: u64,
+    // Read and write ends of the splice pipe and the bytes sitting in it
+    pipe: Option<(OwnedFd, OwnedFd)>,
+    piped: u32,
+    remote_addr: Option<SocketAddr>,
+    // The input should start with a PROXY protocol header not read yet
+    awaiting_proxy_header: bool,
+    _open: OpenConnection,
+}
+
+/// A submission/completion ring serving connections
+pub struct Backend {
+    ring: IoUring,
+}
+
+impl Backend {
+    /// Sets up a ring with room for the given number of submissions
+    /// Fails where io_uring is unavailable (old kernels, seccomp filters)
+    pub fn new(entries: u32) -> io::Result<Backend> {
+        Ok(Backend {
+            ring: IoUring::new(entries)?,
+        })
+    }
+
+    /// Serves connections from the listener until the ring fails, or until
+    /// the listener was handed over and its connections are done
+    pub fn run(mut self, listener: &TcpListener, server: &Server) -> io::Result<()> {
+        let mut connections: Vec<Option<Connection>> = Vec::new();
+        let idle_limit = IDLE_PROBE_INTERVAL * MAX_IDLE_PROBES;
+        let idle_timeout = types::Timespec::new()
+            .sec(idle_limit.as_secs())
+            .nsec(idle_limit.subsec_nanos());
+        // Accepts give up after a while, so a hand over is noticed without traffic
+        let accept_timeout = types::Timespec::new()
+            .sec(ACCEPT_CHECK_INTERVAL.as_secs())
+            .nsec(ACCEPT_CHECK_INTERVAL.subsec_nanos());
+        let accept = opcode::Accept::new(
+            types::Fd(listener.as_raw_fd()),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+        .build()
+        .flags(squeue::Flags::IO_LINK)
+        .user_data(ACCEPT);
+        let accept_limit = opcode::LinkTimeout::new(&accept_timeout)
+            .build()
+            .user_data(TIMEOUT);
+        self.push(&accept)?;
+        self.push(&accept_limit)?;
+        let mut accepting = true;
+
+        let mut completed = Vec::new();
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            completed.extend(
+                self.ring
+                    .completion()
+                    .map(|entry| (entry.user_data(), entry.result())),
+            );
+
+            for (user_data, result) in completed.drain(..) {
+                match user_data {
+                    TIMEOUT => {}
+                    ACCEPT => {
+                        if server.is_accepting() {
+                            self.push(&accept)?;
+                            self.push(&accept_limit)?;
+                        } else {
+                            accepting = false;
+                        }
+                        if result == -libc::ECANCELED {
+                            continue;
+                        }
+                        if result < 0 {
+                            eprintln!(
+                                "Failed to accept connection: {}",
+                                io::Error::from_raw_os_error(-result)
+                            );
+                            continue;
+                        }
+                        // SAFETY: the kernel returned a new socket owned by nobody else
+                        let stream = unsafe { TcpStream::from_raw_fd(result) };
+                        server.configure_connection(&stream);
+                        let id = match connections.iter().position(Option::is_none) {
+                            Some(id) => id,
+                            None => {
+                                connections.push(None);
+                                connections.len() - 1
+                            }
+                        };
+                        let connection = connections[id].insert(Connection::new(stream, server));
+                        self.receive(id, connection, &idle_timeout)?;
+                    }
+                    _ => {
+                        let id = (user_data - 1) as usize;
+                        let Some(connection) = connections[id].as_mut() else {
+                            continue;
+                        };
+                        let keep = self.advance(id, connection, result, server, &idle_timeout)?;
+                        if !keep {
+                            connections[id] = None;
+                        }
+                    }
+                }
+            }
+            if !accepting && connections.iter().all(Option::is_none) {
+                return Ok(());
+            }
+        }
+    }
+
+    // Handles the completion of a connection's operation and queues the next one
+    // Returns false once the connection is finished
+    fn advance(
+        &mut self,
+        id: usize,
+        connection: &mut Connection,
+        result: i32,
+        server: &Server,
+        idle_timeout: &types::Timespec,
+    ) -> io::Result<bool> {
+        if result < 0 {
+            return Ok(false);
+        }
+        let done = result as usize;
+        let socket = types::Fd(connection.stream.as_raw_fd());
+        let entry = match connection.state {
+            State::Receiving => {
+                connection
+                    .input
+                    .extend_from_slice(&connection.chunk[..done]);
+                let closed = done == 0;
+                if closed && connection.input.is_empty() {
+                    return Ok(false);
+                }
+                if connection.awaiting_proxy_header {
+                    match proxy_protocol::parse(&connection.input) {
+                        Preamble::Incomplete(_) if !closed => {
+                            self.receive(id, connection, idle_timeout)?;
+                            return Ok(true);
+                        }
+                        Preamble::Header { len, source } => {
+                            connection.input.drain(..len);
+                            connection.remote_addr = source.or(connection.remote_addr);
+                            connection.awaiting_proxy_header = false;
+                        }
+                        _ => return Ok(false),
+                    }
+                }
+                if !closed && !request_complete(&connection.input, &server.site().router) {
+                    if connection.continue_sent || !expects_continue(&connection.input) {
+                        self.receive(id, connection, idle_timeout)?;
+                        return Ok(true);
+                    }
+                    connection.continue_sent = true;
+                    connection.state = State::SendingContinue;
+                    opcode::Send::new(socket, CONTINUE.as_ptr(), CONTINUE.len() as u32).build()
+                } else {
+                    let input = std::mem::take(&mut connection.input);
+                    let site = server.site();
+                    connection.output = buffered::serve(
+                        input,
+                        &site.base_dir,
+                        &site.router,
+                        FileTransfer::Defer(&mut connection.file),
+                        Peer {
+                            addr: connection.remote_addr,
+                            ..Peer::default()
+                        },
+                    );
+                    connection.state = State::Sending;
+                    connection.send(socket)
+                }
+            }
+            State::SendingContinue => {
+                self.receive(id, connection, idle_timeout)?;
+                return Ok(true);
+            }
+            State::Sending => {
+                connection.written += done;
+                if done == 0 {
+                    return Ok(false);
+                }
+                if connection.written < connection.output.len() {
+                    connection.send(socket)
+                } else if connection.file.is_some() {
+                    connection.pipe = Some(pipe()?);
+                    connection.state = State::FillingPipe;
+                    connection.fill_pipe()
+                } else {
+                    return Ok(false);
+                }
+            }
+            State::FillingPipe => {
+                // Zero means the file shrank after its length was sent
+                if done == 0 {
+                    return Ok(false);
+                }
+                if let Some((_, remaining)) = &mut connection.file {
+                    *remaining -= done as u64;
+                }
+                connection.file_offset += done as u64;
+                connection.piped = done as u32;
+                connection.state = State::DrainingPipe;
+                connection.drain_pipe(socket)
+            }
+            State::DrainingPipe => {
+                if done == 0 {
+                    return Ok(false);
+                }
+                connection.piped -= done as u32;
+                if connection.piped > 0 {
+                    connection.drain_pipe(socket)
+                } else if connection
+                    .file
+                    .as_ref()
+                    .is_some_and(|(_, remaining)| *remaining > 0)
+                {
+                    connection.state = State::FillingPipe;
+                    connection.fill_pipe()
+                } else {
+                    return Ok(false);
+                }
+            }
+        };
+        self.push(&entry.user_data(id as u64 + 1))?;
+        Ok(true)
+    }
+
+    // Queues a receive into the connection's chunk, cancelled after the idle limit
+    fn receive(
+        &mut self,
+        id: usize,
+        connection: &mut Connection,
+        idle_timeout: &types::Timespec,
+    ) -> io::Result<()> {
+        connection.state = State::Receiving;
+        let receive = opcode::Recv::new(
+            types::Fd(connection.stream.as_raw_fd()),
+            connection.chunk.as_mut_ptr(),
+            connection.chunk.len() as u32,
+        )
+        .build()
+        .flags(squeue::Flags::IO_LINK)
+        .user_data(id as u64 + 1);
+        let timeout = opcode::LinkTimeout::new(idle_timeout)
+            .build()
+            .user_data(TIMEOUT);
+        self.push(&receive)?;
+        self.push(&timeout)
+    }
+
+    // Adds an entry to the submission queue, submitting first if it is full
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: every buffer and fd referenced by an entry belongs to a
+            // connection (or the listener) that outlives the operation, since
+            // connections are only dropped after their operation completed
+            if unsafe { self.ring.submission().push(entry) }.is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+}
+
+impl Connection {
+    fn new(stream: TcpStream, server: &Server) -> Connection {
+        Connection {
+            remote_addr: stream.peer_addr().ok(),
+            _open: server.open_connection(stream.try_clone().ok()),
+            stream,
+            state: State::Receiving,
+            input: Vec::new(),
+            chunk: vec![0; 4096].into_boxed_slice(),
+            continue_sent: false,
+            output: Vec::new(),
+            written: 0,
+            file: None,
+            file_offset: 0,
+            pipe: None,
+            piped: 0,
+            awaiting_proxy_header: server.expects_proxy_header(),
+        }
+    }
+
+    // Sends the unsent part of the response head and body
+    fn send(&self, socket: types::Fd) -> squeue::Entry {
+        let unsent = &self.output[self.written..];
+        opcode::Send::new(socket, unsent.as_ptr(), unsent.len() as u32).build()
+    }
+
+    // Splices the next part of the file into the pipe
+    fn fill_pipe(&self) -> squeue::Entry {
+        let (file, remaining) = self.file.as_ref().unwrap();
+        let (_, pipe_in) = self.pipe.as_ref().unwrap();
+        opcode::Splice::new(
+            types::Fd(file.as_raw_fd()),
+            self.file_offset as i64,
+            types::Fd(pipe_in.as_raw_fd()),
+            -1,
+            (*remaining).min(SPLICE_CHUNK) as u32,
+        )
+        .build()
+    }
+
+    // Splices the pipe's contents to the socket
+    fn drain_pipe(&self, socket: types::Fd) -> squeue::Entry {
+        let (pipe_out, _) = self.pipe.as_ref().unwrap();
+        opcode::Splice::new(types::Fd(pipe_out.as_raw_fd()), -1, socket, -1, self.piped).build()
+    }
+}
+
+// Creates a pipe, returning its read and write ends
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 succeeded, so both descriptors are open and owned by us
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
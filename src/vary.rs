@@ -0,0 +1,39 @@
+// Vary header bookkeeping (RFC 9110 section 12.5.5)
+// Request headers read through Request::vary_header are recorded on the
+// request, and added to the Vary header of the response of every handler or
+// middleware run for it, so caches keep one variant per value of every
+// header that picked the representation. Clones of the request share the
+// record, so reads from other threads (Timeout, Async) count too, and it
+// goes away with the request even if a handler panics.
+
+use crate::{Request, Response};
+use std::sync::{Arc, Mutex};
+
+// Header names read through Request::vary_header so far
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Varied(Arc<Mutex<Vec<String>>>);
+
+impl Varied {
+    // Records a request header as selecting the response's representation
+    pub fn record(&self, name: &str) {
+        let mut names = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+            names.push(name.to_string());
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+// Runs a handler or middleware, adding the headers read for the request so
+// far to the Vary header of its response
+pub fn collect(request: &Request, run: impl FnOnce() -> Response) -> Response {
+    let response = run();
+    request
+        .varied
+        .names()
+        .iter()
+        .fold(response, |response, name| response.with_vary(name))
+}
@@ -0,0 +1,213 @@
+// WebAssembly route handlers
+// A module is compiled once when loaded, and every request runs in a fresh
+// instance with its own memory, so requests share no state and a crashing
+// handler only fails its own request. Modules get no imports: they can't
+// reach files, sockets or the clock, only the request they are given.
+// Execution is bounded by fuel (roughly one unit per instruction) and
+// memory by a size limit.
+//
+// ABI: the module exports its memory as "memory", plus
+//   alloc(len: i32) -> i32            reserves len bytes for the request
+//   handle(ptr: i32, len: i32) -> i64 answers the request stored there
+// The request is passed as an HTTP/1.1 message (request line, headers, blank
+// line, body), and handle returns where the response is in memory, as
+// (ptr << 32) | len. The response is an HTTP/1.1 message too; Content-Length
+// and Transfer-Encoding headers in it are ignored.
+
+use crate::{Body, Request, Response, RouteHandler, StatusCode};
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+// Instructions a request may run by default
+const DEFAULT_FUEL: u64 = 100_000_000;
+
+// Memory an instance may grow to by default
+const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// A route handler implemented by a WebAssembly module
+/// Modules export "memory", alloc(len) -> ptr and handle(ptr, len) -> i64,
+/// receiving the request and returning the response as HTTP/1.1 messages
+/// (see the wasm module). Combined with HotSwap, a new build of the module
+/// is deployed without restarting the server
+/// Example:
+/// let handler = WasmHandler::load("handlers/users.wasm")?
+///     .fuel(10_000_000)
+///     .memory_limit(16 * 1024 * 1024);
+/// router.get("/users/{id}", handler);
+#[derive(Clone)]
+pub struct WasmHandler {
+    module: Arc<InstancePre<StoreLimits>>,
+    name: Arc<str>,
+    fuel: u64,
+    memory_limit: usize,
+}
+
+impl WasmHandler {
+    /// Compiles the module in a .wasm (or .wat text) file
+    /// Fails if the file can't be read, is not a valid module, imports
+    /// anything or lacks the ABI exports
+    pub fn load(path: impl AsRef<Path>) -> io::Result<WasmHandler> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        WasmHandler::compile(&path.display().to_string(), &bytes)
+    }
+
+    /// Compiles a module from its binary or text form
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<WasmHandler> {
+        WasmHandler::compile("module", bytes)
+    }
+
+    /// Sets the fuel a request may burn, roughly one unit per instruction;
+    /// requests running out are answered with 500
+    pub fn fuel(mut self, units: u64) -> WasmHandler {
+        self.fuel = units;
+        self
+    }
+
+    /// Sets the size the memory of an instance may grow to, in bytes
+    pub fn memory_limit(mut self, bytes: usize) -> WasmHandler {
+        self.memory_limit = bytes;
+        self
+    }
+
+    fn compile(name: &str, bytes: &[u8]) -> io::Result<WasmHandler> {
+        let invalid = |e: wasmtime::Error| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, e))
+        };
+        let engine = engine()?;
+        let module = Module::new(engine, bytes).map_err(invalid)?;
+        for export in ["memory", "alloc", "handle"] {
+            if module.get_export(export).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: missing export {:?}", name, export),
+                ));
+            }
+        }
+        // No host functions are linked, so modules with imports are refused
+        let linker = Linker::new(engine);
+        let module = linker.instantiate_pre(&module).map_err(invalid)?;
+        Ok(WasmHandler {
+            module: Arc::new(module),
+            name: Arc::from(name),
+            fuel: DEFAULT_FUEL,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+        })
+    }
+
+    // Runs the request through a new instance and returns the raw response
+    fn run(&self, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.memory_limit)
+            .build();
+        let mut store = Store::new(self.module.module().engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.module.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("\"memory\" is not a memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let handle = instance.get_typed_func::<(i32, i32), i64>(&mut store, "handle")?;
+
+        let len =
+            i32::try_from(input.len()).map_err(|_| wasmtime::Error::msg("request too large"))?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let packed = handle.call(&mut store, (ptr, len))? as u64;
+        let (start, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let output = memory
+            .data(&store)
+            .get(start..start + len)
+            .ok_or_else(|| wasmtime::Error::msg("response outside of memory"))?;
+        Ok(output.to_vec())
+    }
+}
+
+impl RouteHandler<()> for WasmHandler {
+    fn call(&self, request: &Request) -> Response {
+        let output = match self.run(&serialize(request)) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("WebAssembly handler {} failed: {}", self.name, e);
+                return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        parse_response(&output).unwrap_or_else(|| {
+            eprintln!(
+                "WebAssembly handler {} returned an invalid response",
+                self.name
+            );
+            Response::from_status(StatusCode::BAD_GATEWAY)
+        })
+    }
+}
+
+impl fmt::Debug for WasmHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmHandler")
+            .field("name", &self.name)
+            .field("fuel", &self.fuel)
+            .field("memory_limit", &self.memory_limit)
+            .finish()
+    }
+}
+
+// The engine all modules are compiled for, metering fuel
+fn engine() -> io::Result<&'static Engine> {
+    static ENGINE: OnceLock<Result<Engine, String>> = OnceLock::new();
+    let engine = ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).map_err(|e| e.to_string())
+    });
+    engine.as_ref().map_err(|e| io::Error::other(e.clone()))
+}
+
+// Formats the request as an HTTP/1.1 message
+fn serialize(request: &Request) -> Vec<u8> {
+    let mut head = format!("{} {}", request.method, request.path);
+    if !request.query.is_empty() {
+        let _ = write!(head, "?{}", request.query);
+    }
+    head.push_str(" HTTP/1.1\r\n");
+    for (name, value) in &request.headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    head.push_str("\r\n");
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&request.body);
+    message
+}
+
+// Parses the HTTP/1.1 message a module answered with
+fn parse_response(output: &[u8]) -> Option<Response> {
+    let head_len = output.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&output[..head_len]).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    if !status_line.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status: u16 = status_line.next()?.parse().ok()?;
+    if !(100..600).contains(&status) {
+        return None;
+    }
+    let mut response = Response::new(status, status_line.next().unwrap_or(""));
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        if !name.eq_ignore_ascii_case("Content-Length")
+            && !name.eq_ignore_ascii_case("Transfer-Encoding")
+        {
+            response = response.with_header(name.trim(), value.trim());
+        }
+    }
+    response.body = Body::from(output[head_len + 4..].to_vec());
+    Some(response)
+}
@@ -0,0 +1,457 @@
+// WebDAV file share over a directory (RFC 4918 subset)
+// Supports OPTIONS, GET, PUT, DELETE, MKCOL, PROPFIND (depth 0 and 1),
+// COPY, MOVE, and LOCK/UNLOCK. Locks are only acknowledged so that clients
+// requiring them (macOS Finder, Windows, office suites) can write; they are
+// not enforced, but If-Match and the other preconditions are, so clients can
+// avoid overwriting each other's changes. Every request must carry HTTP Basic
+// credentials accepted by the authenticate function.
+//
+// Paths follow the router's rules for static files (see the file_rules
+// module): hidden files are missing, links the SymlinkPolicy refuses are
+// forbidden, and neither is listed or copied along with a collection.
+
+use crate::conditional::{self, Validators};
+use crate::credentials::basic_credentials;
+// The same escaping serves XML element content and attribute values
+use crate::escape::escape_html as escape_xml;
+use crate::file_rules::{FileRules, links_allowed};
+use crate::http_date::format_http_date;
+use crate::mime::content_type_for;
+use crate::request::{percent_decode, percent_encode};
+use crate::{Body, Next, Request, Response, Router, StatusCode, Upload};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const METHODS: [&str; 10] = [
+    "OPTIONS", "GET", "PUT", "DELETE", "MKCOL", "PROPFIND", "COPY", "MOVE", "LOCK", "UNLOCK",
+];
+
+// A directory shared under a URL prefix
+struct Share {
+    root: PathBuf,
+    // URL prefix without a trailing slash ("" for the site root)
+    prefix: String,
+    // The router's rules for links and hidden files
+    rules: Arc<RwLock<FileRules>>,
+}
+
+// Registers the share's routes and credential check on the router
+pub fn mount(
+    router: &mut Router,
+    prefix: &str,
+    root: &str,
+    authenticate: impl Fn(&str, &str) -> bool + Send + Sync + 'static,
+) {
+    let share = Arc::new(Share {
+        root: PathBuf::from(root),
+        prefix: prefix.trim_end_matches('/').to_string(),
+        rules: router.share_file_rules(),
+    });
+    router.scope(prefix, |dav| {
+        dav.layer(move |request: &Request, next: &Next| {
+            match basic_credentials(request, "Authorization") {
+                Some((user, password)) if authenticate(&user, &password) => next.run(request),
+                _ => Response::from_status(StatusCode::UNAUTHORIZED)
+                    .with_header("WWW-Authenticate", "Basic realm=\"WebDAV\""),
+            }
+        });
+        for method in METHODS {
+            let share = Arc::clone(&share);
+            let route = dav.route(method, "/{*path}", move |request: Request| {
+                share.handle(&request)
+            });
+            // Uploads go to disk as they arrive, whatever max_body_size says
+            if method == "PUT" {
+                route.stream_body();
+            }
+        }
+    });
+}
+
+impl Share {
+    // Dispatches a request for a path under the share
+    fn handle(&self, request: &Request) -> Response {
+        let path = request.param("path").unwrap_or("");
+        let target = match self.resolve(path) {
+            Ok(target) => target,
+            Err(refused) => return refused,
+        };
+        if conditional::is_conditional(request) {
+            let validators = fs::metadata(&target)
+                .ok()
+                .and_then(|metadata| Validators::of(&metadata));
+            if let Some(response) = conditional::evaluate(request, validators.as_ref()) {
+                return response;
+            }
+        }
+        let result = match request.method.as_str() {
+            "OPTIONS" => Ok(Response::from_status(StatusCode::OK)
+                .with_header("DAV", "1, 2")
+                .with_header("MS-Author-Via", "DAV")
+                .with_header("Allow", &METHODS.join(", "))),
+            "GET" => self.get(path, &target),
+            "PUT" => put(&target, request),
+            "DELETE" => delete(path, &target),
+            "MKCOL" => make_collection(&target, &request.body),
+            "PROPFIND" => self.find_properties(path, &target, request.header("Depth")),
+            "COPY" | "MOVE" => self.transfer(request, path, &target),
+            "LOCK" => self.lock(path, &target),
+            _ => Ok(Response::from_status(StatusCode::NO_CONTENT)),
+        };
+        result.unwrap_or_else(|e| error_response(&e))
+    }
+
+    // Maps a relative path to a file under the root
+    // Answers paths that would leave it with 403, and the others the rules
+    // refuse (see admits)
+    fn resolve(&self, path: &str) -> Result<PathBuf, Response> {
+        let relative = Path::new(path);
+        let escapes = relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            return Err(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        self.admits(relative)?;
+        Ok(self.root.join(relative))
+    }
+
+    // Checks a path under the root against the router's rules, like static
+    // files: hidden files are not found, refused links are forbidden
+    fn admits(&self, relative: &Path) -> Result<(), Response> {
+        let rules = self.rules.read().unwrap();
+        if rules.hides(relative) {
+            return Err(Response::from_status(StatusCode::NOT_FOUND));
+        }
+        if !links_allowed(rules.symlinks, &self.root, relative) {
+            return Err(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        Ok(())
+    }
+
+    // Returns the members of a collection the rules let through, sorted by
+    // name for stable listings
+    fn members(&self, path: &str, dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.retain(|entry| {
+            self.admits(&Path::new(path).join(entry.file_name()))
+                .is_ok()
+        });
+        entries.sort_by_key(|entry| entry.file_name());
+        Ok(entries)
+    }
+
+    // Returns the URL of a path under the share, collections ending with "/"
+    fn href(&self, path: &str, is_dir: bool) -> String {
+        let mut href = self.prefix.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            href.push('/');
+            href.push_str(&percent_encode(segment));
+        }
+        if is_dir || href.is_empty() {
+            href.push('/');
+        }
+        href
+    }
+
+    // Sends a file, or a list of links for a collection
+    fn get(&self, path: &str, target: &Path) -> io::Result<Response> {
+        let metadata = fs::metadata(target)?;
+        if metadata.is_dir() {
+            let mut page = String::from("<!DOCTYPE html>\n<ul>\n");
+            for entry in self.members(path, target)? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let child = format!("{}/{}", path.trim_end_matches('/'), name);
+                let is_dir = entry.file_type()?.is_dir();
+                page.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    self.href(&child, is_dir),
+                    escape_xml(&name)
+                ));
+            }
+            page.push_str("</ul>\n");
+            return Ok(Response::ok("text/html", page));
+        }
+        let file = fs::File::open(target)?;
+        let mut response = Response::from_status(StatusCode::OK)
+            .with_header("Content-Length", &metadata.len().to_string());
+        if let Some(validators) = Validators::of(&metadata) {
+            response = response
+                .with_header("ETag", &validators.etag)
+                .with_header("Last-Modified", &format_http_date(validators.modified));
+        }
+        Ok(response.with_body(
+            content_type_for(&target.to_string_lossy()),
+            Body::from_reader(file),
+        ))
+    }
+
+    // Lists the properties of a resource and, at depth 1, of its members
+    fn find_properties(
+        &self,
+        path: &str,
+        target: &Path,
+        depth: Option<&str>,
+    ) -> io::Result<Response> {
+        // Depth defaults to infinity, which servers may refuse (RFC 4918 section 9.1)
+        let with_members = match depth {
+            Some("0") => false,
+            Some("1") => true,
+            _ => {
+                return Ok(Response::from_status(StatusCode::FORBIDDEN).with_body(
+                    "application/xml; charset=utf-8",
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                     <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n",
+                ));
+            }
+        };
+
+        let metadata = fs::metadata(target)?;
+        let mut body = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+        );
+        body.push_str(&self.properties(path, target, &metadata));
+        if with_members && metadata.is_dir() {
+            for entry in self.members(path, target)? {
+                let child = format!(
+                    "{}/{}",
+                    path.trim_end_matches('/'),
+                    entry.file_name().to_string_lossy()
+                );
+                body.push_str(&self.properties(&child, &entry.path(), &entry.metadata()?));
+            }
+        }
+        body.push_str("</D:multistatus>\n");
+        Ok(Response::from_status(StatusCode::MULTI_STATUS)
+            .with_body("application/xml; charset=utf-8", body))
+    }
+
+    // Describes one resource as a multistatus response element
+    fn properties(&self, path: &str, target: &Path, metadata: &fs::Metadata) -> String {
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut properties = format!("<D:displayname>{}</D:displayname>", escape_xml(&name));
+        if metadata.is_dir() {
+            properties.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            properties.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>{}</D:getcontenttype>",
+                metadata.len(),
+                content_type_for(&name)
+            ));
+        }
+        if let Some(validators) = Validators::of(metadata) {
+            properties.push_str(&format!(
+                "<D:getlastmodified>{}</D:getlastmodified><D:getetag>{}</D:getetag>",
+                format_http_date(validators.modified),
+                escape_xml(&validators.etag)
+            ));
+        }
+        format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            escape_xml(&self.href(path, metadata.is_dir())),
+            properties
+        )
+    }
+
+    // Copies or moves a resource to the Destination header's path
+    fn transfer(&self, request: &Request, path: &str, source: &Path) -> io::Result<Response> {
+        if !source.exists() {
+            return Ok(Response::from_status(StatusCode::NOT_FOUND));
+        }
+        let Some(destination) = request.header("Destination") else {
+            return Ok(Response::bad_request("Missing Destination header"));
+        };
+        // Destinations outside this share are on "another server" as far as we know
+        let Some(destination_path) = self.share_path(destination) else {
+            return Ok(Response::from_status(StatusCode::BAD_GATEWAY));
+        };
+        let destination = match self.resolve(&destination_path) {
+            Ok(destination) => destination,
+            Err(refused) => return Ok(refused),
+        };
+        // A resource can't go onto itself or into its own subtree, which a
+        // copy would never finish filling, nor replace one of its ancestors,
+        // which would delete it first
+        let (from, to) = (Path::new(path), Path::new(&destination_path));
+        if to.starts_with(from) || from.starts_with(to) {
+            return Ok(Response::from_status(StatusCode::FORBIDDEN));
+        }
+        if !destination.parent().is_some_and(Path::is_dir) {
+            return Ok(Response::from_status(StatusCode::CONFLICT));
+        }
+        let existed = destination.exists();
+        if existed && request.header("Overwrite") == Some("F") {
+            return Ok(Response::from_status(StatusCode::PRECONDITION_FAILED));
+        }
+
+        // Everything was checked, the destination can go
+        if existed {
+            remove(&destination)?;
+        }
+        if request.method == "MOVE" {
+            fs::rename(source, &destination)?;
+        } else {
+            self.copy_recursive(from, source, &destination)?;
+        }
+        Ok(Response::from_status(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }))
+    }
+
+    // Copies a file, or a collection with the members the rules let through
+    fn copy_recursive(&self, path: &Path, source: &Path, destination: &Path) -> io::Result<()> {
+        if !source.is_dir() {
+            return fs::copy(source, destination).map(|_| ());
+        }
+        fs::create_dir(destination)?;
+        for entry in self.members(&path.to_string_lossy(), source)? {
+            self.copy_recursive(
+                &path.join(entry.file_name()),
+                &entry.path(),
+                &destination.join(entry.file_name()),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Turns a Destination header (URL or absolute path) into a path under the share
+    fn share_path(&self, destination: &str) -> Option<String> {
+        let path = match destination.split_once("://") {
+            Some((_, rest)) => &rest[rest.find('/')?..],
+            None => destination,
+        };
+        let path = percent_decode(path)?;
+        if self.prefix.is_empty() {
+            return Some(path.trim_start_matches('/').to_string());
+        }
+        let rest = path.strip_prefix(&self.prefix)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        Some(rest.trim_start_matches('/').to_string())
+    }
+
+    // Grants an exclusive write lock, creating an empty file for unmapped paths
+    // The lock is not enforced against other clients
+    fn lock(&self, path: &str, target: &Path) -> io::Result<Response> {
+        static LOCKS: AtomicU64 = AtomicU64::new(0);
+
+        let created = !target.exists();
+        if created {
+            fs::File::create(target)?;
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or(0);
+        let token = format!(
+            "opaquelocktoken:{:x}-{:x}",
+            nanos,
+            LOCKS.fetch_add(1, Ordering::Relaxed)
+        );
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\">\
+             <D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:exclusive/></D:lockscope><D:depth>0</D:depth>\
+             <D:timeout>Second-3600</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot>\
+             </D:activelock></D:lockdiscovery></D:prop>\n",
+            token,
+            escape_xml(&self.href(path, target.is_dir()))
+        );
+        let status = if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        };
+        Ok(Response::from_status(status)
+            .with_header("Lock-Token", &format!("<{}>", token))
+            .with_body("application/xml; charset=utf-8", body))
+    }
+}
+
+// Stores the request body as the file
+// The body is streamed to a temporary file next to it, which then replaces
+// it, so an upload that fails halfway leaves the file as it was
+fn put(target: &Path, request: &Request) -> io::Result<Response> {
+    if target.is_dir() {
+        return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let Some(parent) = target.parent().filter(|parent| parent.is_dir()) else {
+        return Ok(Response::from_status(StatusCode::CONFLICT));
+    };
+    let file = match Upload::new().dir(parent).save(request.body_reader()) {
+        Ok(file) => file,
+        Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+            return Ok(Response::bad_request(&e.to_string()));
+        }
+        Err(e) => return Err(e),
+    };
+    let existed = target.exists();
+    file.persist(target)?;
+    Ok(Response::from_status(if existed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    }))
+}
+
+// Deletes a file or a whole collection, but never the share's root
+fn delete(path: &str, target: &Path) -> io::Result<Response> {
+    if path.trim_matches('/').is_empty() {
+        return Ok(Response::from_status(StatusCode::FORBIDDEN));
+    }
+    remove(target)?;
+    Ok(Response::from_status(StatusCode::NO_CONTENT))
+}
+
+// Creates a collection
+fn make_collection(target: &Path, body: &[u8]) -> io::Result<Response> {
+    // MKCOL request bodies have no standard meaning
+    if !body.is_empty() {
+        return Ok(Response::from_status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    if target.exists() {
+        return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    match fs::create_dir(target) {
+        Ok(()) => Ok(Response::from_status(StatusCode::CREATED)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Ok(Response::from_status(StatusCode::CONFLICT))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Removes a file or a directory with its contents
+fn remove(target: &Path) -> io::Result<()> {
+    if target.is_dir() {
+        fs::remove_dir_all(target)
+    } else {
+        fs::remove_file(target)
+    }
+}
+
+// Maps a file system error to a response
+fn error_response(error: &io::Error) -> Response {
+    match error.kind() {
+        ErrorKind::NotFound => Response::from_status(StatusCode::NOT_FOUND),
+        ErrorKind::PermissionDenied => Response::from_status(StatusCode::FORBIDDEN),
+        _ => {
+            eprintln!("WebDAV request failed: {}", error);
+            Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}